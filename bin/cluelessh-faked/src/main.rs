@@ -69,6 +69,8 @@ async fn main() -> eyre::Result<()> {
         })),
         check_pubkey: None,
         verify_signature: None,
+        keyboard_interactive_prompts: None,
+        verify_keyboard_interactive: None,
         auth_banner: Some(
             "\
             !! this system ONLY allows catgirls to enter !!\r\n\
@@ -104,6 +106,7 @@ async fn main() -> eyre::Result<()> {
         host_keys: pub_host_keys,
         // This is definitely who we are.
         server_identification: b"SSH-2.0-OpenSSH_9.7\r\n".to_vec(),
+        ..Default::default()
     };
 
     let mut listener =
@@ -236,7 +239,9 @@ async fn handle_session_channel(
                             }
                         }
                         ChannelRequest::ExitStatus { .. } => {}
+                        ChannelRequest::ExitSignal { .. } => {}
                         ChannelRequest::Env { .. } => {}
+                        ChannelRequest::WindowChange { .. } => {}
                     };
                 }
                 ChannelUpdateKind::OpenFailed { .. } => todo!(),
@@ -286,11 +291,14 @@ async fn handle_session_channel(
                     }
                 }
                 ChannelUpdateKind::Open(_)
-                | ChannelUpdateKind::Closed
+                | ChannelUpdateKind::OpenConfirmed { .. }
+                | ChannelUpdateKind::Closed { .. }
                 | ChannelUpdateKind::ExtendedData { .. }
                 | ChannelUpdateKind::Eof
                 | ChannelUpdateKind::Success
-                | ChannelUpdateKind::Failure => { /* ignore */ }
+                | ChannelUpdateKind::Failure
+                | ChannelUpdateKind::Drained
+                | ChannelUpdateKind::ReadPressure { .. } => { /* ignore */ }
             },
             Err(err) => return Err(err),
         }
@@ -116,6 +116,7 @@ fn main() -> eyre::Result<()> {
                     key_type: match public_key.key {
                         PublicKey::Ed25519 { .. } => cluelessh_keys::KeyType::Ed25519,
                         PublicKey::EcdsaSha2NistP256 { .. } => cluelessh_keys::KeyType::Ecdsa,
+                        PublicKey::Rsa { .. } => bail!("RSA private keys are not supported"),
                     },
                 },
             );
@@ -141,6 +142,7 @@ fn main() -> eyre::Result<()> {
                     };
                     *fake_public_key = public_key;
                 }
+                PublicKey::Rsa { .. } => unreachable!("rejected above"),
             }
 
             let fake_private_key = fake_private_key.encrypt(KeyEncryptionParams::plaintext())?;
@@ -196,6 +198,13 @@ fn info(id_file: &Path, decrypt: bool, show_private: bool) -> eyre::Result<()> {
                             base64::prelude::BASE64_STANDARD.encode(private_key.to_bytes())
                         )
                     }
+                    PrivateKey::Rsa { private_key, .. } => {
+                        use rsa::traits::PrivateKeyParts;
+                        println!(
+                            "  private exponent: {}",
+                            base64::prelude::BASE64_STANDARD.encode(private_key.d().to_bytes_be())
+                        )
+                    }
                 }
             }
         }
@@ -69,6 +69,8 @@ async fn main() -> eyre::Result<()> {
                     result.wrap_err("failed to prompt password")
                 })
             }),
+            // TODO: check known_hosts
+            verify_host_key: None,
             sign_pubkey: Arc::new(move |session_id| {
                 let mut attempted_public_keys = HashSet::new();
                 let username = username.clone();
@@ -93,8 +95,11 @@ async fn main() -> eyre::Result<()> {
                     }
                     let pubkey = PublicKey::from_wire_encoding(&identity.key_blob)?;
 
-                    let sign_data =
-                        cluelessh_keys::signature::signature_data(session_id.0, &username, &pubkey);
+                    let sign_data = cluelessh_keys::signature::signature_data(
+                        &session_id.0,
+                        &username,
+                        &pubkey,
+                    );
                     let signature = agent
                         .sign(&identity.key_blob, &sign_data, 0)
                         .await
@@ -139,6 +144,8 @@ async fn main_channel(channel: PendingChannel) -> Result<()> {
             width_px: 0,
             height_px: 0,
             term_modes: vec![],
+            input_speed: None,
+            output_speed: None,
         }))
         .await?;
 
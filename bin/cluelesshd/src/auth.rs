@@ -3,8 +3,8 @@
 use std::io;
 
 use cluelessh_keys::{
-    authorized_keys::{self, AuthorizedKeys},
-    public::{PublicKey, PublicKeyWithComment},
+    authorized_keys::{self, AuthorizedKey, AuthorizedKeyOptions, AuthorizedKeys},
+    public::PublicKey,
     signature::Signature,
 };
 use cluelessh_protocol::auth::VerifySignature;
@@ -14,7 +14,7 @@ use users::{os::unix::UserExt, User};
 
 /// A known-authorized public key for a user.
 pub struct UserPublicKey {
-    key: PublicKeyWithComment,
+    key: AuthorizedKey,
     user: User,
 }
 
@@ -60,11 +60,13 @@ impl UserPublicKey {
     }
 
     pub fn verify_signature(&self, data: &[u8], signature: &Signature) -> bool {
-        self.key.key.verify_signature(data, signature)
+        self.key.key.key.verify_signature(data, signature)
     }
 }
 
-pub async fn verify_signature(auth: VerifySignature) -> eyre::Result<Option<User>> {
+pub async fn verify_signature(
+    auth: VerifySignature,
+) -> eyre::Result<Option<(User, AuthorizedKeyOptions)>> {
     let result = UserPublicKey::for_user_and_key(auth.user.clone(), &auth.public_key).await;
 
     debug!(user = %auth.user, err = ?result.as_ref().err(), "Attempting publickey signature");
@@ -73,14 +75,15 @@ pub async fn verify_signature(auth: VerifySignature) -> eyre::Result<Option<User
         Ok(user_key) => {
             // Verify signature...
 
-            let sign_data = cluelessh_keys::signature::signature_data(
-                auth.session_id.0,
+            let sign_data = cluelessh_keys::signature::signature_data_for_algorithm(
+                &auth.session_id.0,
                 &auth.user,
+                auth.signature.algorithm_name(),
                 &auth.public_key,
             );
 
             if user_key.verify_signature(&sign_data, &auth.signature) {
-                Ok(Some(user_key.user))
+                Ok(Some((user_key.user, user_key.key.options)))
             } else {
                 Ok(None)
             }
@@ -55,6 +55,7 @@ async fn connection_inner(state: SerializedConnectionState) -> Result<()> {
     let transport_config = cluelessh_transport::server::ServerConfig {
         host_keys,
         server_identification: b"SSH-2.0-ClueleSSH_0.1\r\n".to_vec(),
+        ..Default::default()
     };
 
     let rpc_client = unsafe { OwnedFd::from_raw_fd(PRIVSEP_CONNECTION_RPC_CLIENT_FD) };
@@ -77,6 +78,8 @@ async fn connection_inner(state: SerializedConnectionState) -> Result<()> {
             let rpc_client = rpc_client2.clone();
             Box::pin(async move { rpc_client.check_public_key(msg.user, msg.public_key).await })
         })),
+        keyboard_interactive_prompts: None,
+        verify_keyboard_interactive: None,
         auth_banner: config.auth.banner,
         do_key_exchange: Arc::new(move |msg| {
             let rpc_client = rpc_client3.clone();
@@ -84,7 +87,12 @@ async fn connection_inner(state: SerializedConnectionState) -> Result<()> {
         }),
     };
 
-    let server_conn = ServerConnection::new(stream, state.peer_addr, auth_verify, transport_config);
+    let mut server_conn =
+        ServerConnection::new(stream, state.peer_addr, auth_verify, transport_config)?;
+    server_conn.set_channel_config(cluelessh_protocol::connection::ChannelConfig {
+        allowed_subsystems: config.subsystem.keys().cloned().collect(),
+        ..Default::default()
+    })?;
 
     if let Err(err) = handle_connection(server_conn, rpc_client4).await {
         if let Some(err) = err.downcast_ref::<std::io::Error>() {
@@ -152,6 +160,8 @@ async fn handle_connection(
 
 struct SessionState {
     pty_term: Option<String>,
+    /// The controller side of the PTY, for applying window size changes.
+    pty_controller: Option<OwnedFd>,
     channel: Channel,
     process_exit_send: mpsc::Sender<Result<Option<i32>>>,
     process_exit_recv: mpsc::Receiver<Result<Option<i32>>>,
@@ -173,6 +183,7 @@ async fn handle_session_channel(channel: Channel, rpc_client: Arc<rpc::Client>)
 
     let mut state = SessionState {
         pty_term: None,
+        pty_controller: None,
         channel,
         process_exit_send,
         process_exit_recv,
@@ -267,6 +278,7 @@ impl SessionState {
                         width_px,
                         height_px,
                         term_modes,
+                        ..
                     } => {
                         match self
                             .pty_req(
@@ -292,6 +304,13 @@ impl SessionState {
                             }
                         }
                     }
+                    ref req @ ChannelRequest::WindowChange { .. } => {
+                        if let Some(controller) = &self.pty_controller {
+                            if let Err(err) = cluelessh_tokio::pty::resize_pty(controller, req) {
+                                debug!(%err, "Failed to resize PTY");
+                            }
+                        }
+                    }
                     ChannelRequest::Shell { want_reply } => match self.shell(None, None).await {
                         Ok(()) => {
                             if want_reply {
@@ -308,23 +327,14 @@ impl SessionState {
                     ChannelRequest::Exec {
                         want_reply,
                         command,
-                    } => match String::from_utf8(command) {
-                        Ok(command) => match self.shell(Some(command), None).await {
-                            Ok(()) => {
-                                if want_reply {
-                                    self.channel.send(ChannelOperationKind::Success).await?;
-                                }
-                            }
-                            Err(err) => {
-                                debug!(%err, "Failed to spawn shell");
-                                if want_reply {
-                                    self.channel.send(ChannelOperationKind::Failure).await?;
-                                }
+                    } => match self.shell(Some(command), None).await {
+                        Ok(()) => {
+                            if want_reply {
+                                self.channel.send(ChannelOperationKind::Success).await?;
                             }
-                        },
+                        }
                         Err(err) => {
-                            debug!(%err, "Exec command is invalid UTF-8");
-
+                            debug!(%err, "Failed to spawn shell");
                             if want_reply {
                                 self.channel.send(ChannelOperationKind::Failure).await?;
                             }
@@ -363,7 +373,9 @@ impl SessionState {
                             }
                         }
                     },
-                    ChannelRequest::ExitStatus { .. } => unreachable!("forbidden"),
+                    ChannelRequest::ExitStatus { .. } | ChannelRequest::ExitSignal { .. } => {
+                        unreachable!("forbidden")
+                    }
                 };
             }
             ChannelUpdateKind::OpenFailed { .. } => todo!(),
@@ -382,10 +394,13 @@ impl SessionState {
                 self.reader_ext = None;
             }
             ChannelUpdateKind::Open(_)
-            | ChannelUpdateKind::Closed
+            | ChannelUpdateKind::OpenConfirmed { .. }
+            | ChannelUpdateKind::Closed { .. }
             | ChannelUpdateKind::ExtendedData { .. }
             | ChannelUpdateKind::Success
-            | ChannelUpdateKind::Failure => { /* ignore */ }
+            | ChannelUpdateKind::Failure
+            | ChannelUpdateKind::Drained
+            | ChannelUpdateKind::ReadPressure { .. } => { /* ignore */ }
         }
         Ok(())
     }
@@ -406,6 +421,7 @@ impl SessionState {
             .await?;
 
         self.pty_term = Some(term);
+        self.pty_controller = Some(controller.try_clone()?);
 
         self.writer = Some(Box::pin(AsyncFdWrapper::from_fd(controller.try_clone()?)?));
         self.reader = Some(Box::pin(AsyncFdWrapper::from_fd(controller)?));
@@ -414,7 +430,7 @@ impl SessionState {
 
     async fn shell(
         &mut self,
-        shell_command: Option<String>,
+        shell_command: Option<Vec<u8>>,
         subsystem: Option<String>,
     ) -> Result<()> {
         let mut fds = self
@@ -429,7 +445,7 @@ impl SessionState {
 
         if self.pty_term.is_some() {
             ensure!(
-                fds.len() == 0,
+                fds.is_empty(),
                 "RPC Server sent back FDs despite being in PTY mode"
             );
         } else {
@@ -297,20 +297,25 @@ async fn load_host_key(key_path: &PathBuf, host_keys: &mut HostKeySet) -> Result
     let key = tokio::fs::read_to_string(key_path)
         .await
         .wrap_err("failed to open")?;
-    let key = EncryptedPrivateKeys::parse(key.as_bytes()).wrap_err("failed to parse")?;
+    let key = if PlaintextPrivateKey::is_pkcs8_pem(key.as_bytes()) {
+        PlaintextPrivateKey::from_pkcs8_pem(key.as_bytes()).wrap_err("failed to parse")?
+    } else {
+        let key = EncryptedPrivateKeys::parse(key.as_bytes()).wrap_err("failed to parse")?;
 
-    if key.requires_passphrase() {
-        bail!("host key requires a passphrase, which is not allowed");
-    }
-    let mut key = key.decrypt(None).wrap_err("failed to parse")?;
-    if key.len() != 1 {
-        bail!("host key must contain a single key");
-    }
-    let key = key.remove(0);
+        if key.requires_passphrase() {
+            bail!("host key requires a passphrase, which is not allowed");
+        }
+        let mut key = key.decrypt(None).wrap_err("failed to parse")?;
+        if key.len() != 1 {
+            bail!("host key must contain a single key");
+        }
+        key.remove(0)
+    };
     let algorithm = key.private_key.algorithm_name();
+    let fingerprint = key.private_key.public_key().fingerprint_sha256();
     host_keys.insert(key)?;
 
-    info!(?key_path, ?algorithm, "Loaded host key");
+    info!(?key_path, ?algorithm, %fingerprint, "Loaded host key");
     Ok(())
 }
 
@@ -1,5 +1,6 @@
 //! [`postcard`]-based RPC between the different processes.
 
+use std::ffi::OsStr;
 use std::fmt::Debug;
 use std::io;
 use std::io::IoSlice;
@@ -7,8 +8,10 @@ use std::io::IoSliceMut;
 use std::os::fd::AsFd;
 use std::os::fd::BorrowedFd;
 use std::os::fd::OwnedFd;
+use std::os::unix::ffi::OsStrExt;
 use std::process::Stdio;
 
+use cluelessh_keys::authorized_keys::AuthorizedKeyOptions;
 use cluelessh_keys::private::PlaintextPrivateKey;
 use cluelessh_keys::public::PublicKey;
 use cluelessh_keys::signature::Signature;
@@ -82,6 +85,8 @@ pub struct KeyExchangeRequest {
     pub server_kexinit: Vec<u8>,
     pub eph_client_public_key: Vec<u8>,
     pub server_host_key: PublicKey,
+    /// The signature algorithm for the host key, which differs from the key algorithm for RSA keys.
+    pub server_host_key_algorithm: String,
     pub kex_algorithm: String,
 }
 
@@ -94,6 +99,7 @@ impl Debug for KeyExchangeRequest {
             .field("server_kexinit", &"[...]")
             .field("eph_client_public_key", &self.eph_client_public_key)
             .field("server_host_key", &self.server_host_key)
+            .field("server_host_key_algorithm", &self.server_host_key_algorithm)
             .field("kex_algorithm", &self.kex_algorithm)
             .finish()
     }
@@ -137,17 +143,13 @@ struct PtyRequest {
 struct ShellRequest {
     /// Whether a PTY is used and if yes, the TERM env var.
     pty_term: Option<String>,
-    command: Option<String>,
+    /// Raw bytes, as commands don't have to be UTF-8.
+    command: Option<Vec<u8>>,
     subsystem: Option<String>,
     env: Vec<(String, String)>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct ShellRequestPty {
-    term: String,
-}
-
-type VerifySignatureResponse = bool;
+type VerifySignatureResponse = Option<AuthorizedKeyOptions>;
 type CheckPublicKeyResponse = bool;
 type ShellResponse = ();
 type PtyReqResponse = ();
@@ -164,6 +166,9 @@ pub struct Server {
     client: UnixDatagram,
     host_keys: Vec<PlaintextPrivateKey>,
     authenticated_user: Option<users::User>,
+    /// The authorized_keys options of the key the user authenticated with.
+    /// Enforced here as well, as the connection process is not trusted.
+    key_options: AuthorizedKeyOptions,
 
     config: Config,
 
@@ -181,6 +186,7 @@ impl Server {
             config,
             host_keys,
             authenticated_user: None,
+            key_options: AuthorizedKeyOptions::default(),
             pty_user: None,
             shell_process: None,
         })
@@ -221,16 +227,24 @@ impl Server {
                     return Ok(());
                 };
 
+                let Some(server_host_key_algorithm) =
+                    cluelessh_transport::crypto::HostKeySigningAlgorithm::with_algorithm(
+                        req.server_host_key,
+                        &req.server_host_key_algorithm,
+                    )
+                else {
+                    self.respond_err("invalid host key algorithm".to_owned())
+                        .await?;
+                    return Ok(());
+                };
+
                 let req = cluelessh_transport::server::KeyExchangeParameters {
                     client_ident: req.client_ident,
                     server_ident: req.server_ident,
                     client_kexinit: req.client_kexinit,
                     server_kexinit: req.server_kexinit,
                     eph_client_public_key: req.eph_client_public_key,
-                    server_host_key_algorithm:
-                        cluelessh_transport::crypto::HostKeySigningAlgorithm::new(
-                            req.server_host_key,
-                        ),
+                    server_host_key_algorithm,
                     kex_algorithm,
                 };
 
@@ -283,11 +297,12 @@ impl Server {
                 .await
                 .map_err(|err| err.to_string())
                 .map(|user| match user {
-                    Some(user) => {
+                    Some((user, key_options)) => {
                         self.authenticated_user = Some(user);
-                        true
+                        self.key_options = key_options.clone();
+                        Some(key_options)
                     }
-                    None => false,
+                    None => None,
                 });
 
                 self.respond::<VerifySignatureResponse>(is_ok).await?;
@@ -298,6 +313,12 @@ impl Server {
 
                     return Ok(());
                 }
+                if self.key_options.no_pty {
+                    self.respond_err("pty allocation is not allowed for this key".to_owned())
+                        .await?;
+
+                    return Ok(());
+                }
 
                 let result = crate::pty::Pty::new(
                     Winsize {
@@ -371,7 +392,12 @@ impl Server {
         Ok(())
     }
 
-    async fn shell(&mut self, user: &User, req: ShellRequest) -> Result<Vec<OwnedFd>> {
+    async fn shell(&mut self, user: &User, mut req: ShellRequest) -> Result<Vec<OwnedFd>> {
+        if let Some(command) = &self.key_options.command {
+            req.command = Some(command.clone().into_bytes());
+            req.subsystem = None;
+        }
+
         let subsystem = match req.subsystem.as_deref() {
             Some(subsystem) => match self.config.subsystem.get(subsystem) {
                 Some(system) => Some(system.path.clone()),
@@ -390,7 +416,7 @@ impl Server {
         if subsystem.is_none() {
             if let Some(shell_command) = req.command {
                 cmd.arg("-c");
-                cmd.arg(shell_command);
+                cmd.arg(OsStr::from_bytes(&shell_command));
             }
         };
 
@@ -485,6 +511,7 @@ impl Client {
                 server_kexinit: params.server_kexinit,
                 eph_client_public_key: params.eph_client_public_key,
                 server_host_key: params.server_host_key_algorithm.public_key(),
+                server_host_key_algorithm: params.server_host_key_algorithm.name().to_owned(),
                 kex_algorithm: params.kex_algorithm.name().to_owned(),
             }))
             .await?;
@@ -512,7 +539,7 @@ impl Client {
         session_id: SessionId,
         public_key: PublicKey,
         signature: Signature,
-    ) -> Result<bool> {
+    ) -> Result<Option<AuthorizedKeyOptions>> {
         self.request_response::<VerifySignatureResponse>(&Request::VerifySignature {
             user,
             session_id,
@@ -553,7 +580,7 @@ impl Client {
 
     pub async fn shell(
         &self,
-        command: Option<String>,
+        command: Option<Vec<u8>>,
         subsystem: Option<String>,
         pty_term: Option<String>,
         env: Vec<(String, String)>,
@@ -628,7 +655,7 @@ async fn send_with_fds(socket: &UnixDatagram, data: &[u8], fds: &[BorrowedFd<'_>
                 &mut ancillary,
                 SendFlags::empty(),
             )
-            .map_err(|errno| io::Error::from(errno))?;
+            .map_err(io::Error::from)?;
             Ok(())
         })
         .await
@@ -648,7 +675,7 @@ async fn receive_with_fds<R: DeserializeOwned>(socket: &UnixDatagram) -> Result<
                 &mut cmesg_buf,
                 RecvFlags::empty(),
             )
-            .map_err(|errno| io::Error::from(errno))
+            .map_err(io::Error::from)
         })
         .await?;
 
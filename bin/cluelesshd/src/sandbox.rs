@@ -187,7 +187,7 @@ fn unshare_namespaces() -> Result<()> {
     let old_root = &new_root.join("old-root");
 
     std::fs::create_dir_all(new_root)?;
-    std::fs::create_dir_all(&old_root)?;
+    std::fs::create_dir_all(old_root)?;
 
     rustix::fs::bind_mount(new_root, new_root).wrap_err("bind mount the empty dir")?;
 
@@ -1,12 +1,21 @@
 use std::cmp;
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 use tracing::{debug, info, trace, warn};
 
 use cluelessh_format::numbers;
+use cluelessh_keys::authorized_keys::AuthorizedKeyOptions;
 use cluelessh_transport::packet::Packet;
 use cluelessh_transport::peer_error;
 use cluelessh_transport::Result;
 
+pub use cluelessh_transport::InvalidConfig;
+
+/// Global requests that `no-port-forwarding` rejects.
+const PORT_FORWARDING_GLOBAL_REQUESTS: &[&str] =
+    &["tcpip-forward", "streamlocal-forward@openssh.com"];
+/// Channel types that `no-port-forwarding` rejects.
+const PORT_FORWARDING_CHANNEL_TYPES: &[&str] = &["direct-tcpip", "direct-streamlocal@openssh.com"];
+
 /// A channel number (on our side).
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct ChannelNumber(pub u32);
@@ -17,6 +26,126 @@ impl std::fmt::Display for ChannelNumber {
     }
 }
 
+/// Configuration for all channels of a connection.
+#[derive(Debug, Clone)]
+pub struct ChannelConfig {
+    /// How we replenish the window of data that the peer may send us.
+    pub window_strategy: WindowStrategy,
+    /// Soft limit for the data that is queued because the peer's window is exhausted,
+    /// applied separately to the normal data and every extended data code of a channel.
+    /// Data above the limit is still queued, but [`OperationResult::WouldBlock`] is returned.
+    pub max_queued_data: usize,
+    /// Ignore an `SSH_MSG_CHANNEL_OPEN_CONFIRMATION` for a channel that we are not opening
+    /// instead of treating it as a fatal protocol violation.
+    pub ignore_unexpected_open_confirmation: bool,
+    /// Subsystems that clients may request, without the ones with a registered handler, which are always allowed.
+    /// Requests for all other subsystems are rejected without being passed on to the consumer.
+    pub allowed_subsystems: HashSet<String>,
+    /// Global requests that are passed on to the consumer, see [`ChannelsState::next_global_request`].
+    /// All other global requests are rejected.
+    pub allowed_global_requests: HashSet<String>,
+    /// The maximum number of bytes of data (including extended data) that may be sent and received on a single channel.
+    /// A channel that exceeds it is aborted with an `exit-signal` of `XFSZ`, see [`ChannelsState::abort_channel`],
+    /// and the consumer gets a [`ChannelUpdateKind::Closed`].
+    pub max_channel_data: Option<u64>,
+    /// Once the peer has confirmed the first session channel that we opened, send `no-more-sessions@openssh.com`
+    /// and reject all session channels that the peer tries to open from then on.
+    /// Clients should enable this to protect against malicious servers, like OpenSSH does.
+    pub no_more_sessions: bool,
+    /// Emit a [`ChannelUpdateKind::ReadPressure`] every time the peer has exhausted our window on a channel this many times.
+    /// Frequently exhausted windows mean that the consumer doesn't read the data as fast as the peer sends it.
+    pub read_pressure_after: Option<u32>,
+    /// The smallest max packet size that we accept from the peer for a channel.
+    /// A tiny max packet size would make us split all data into lots of tiny packets.
+    /// Smaller channel opens from the peer are rejected, smaller confirmations are a protocol violation.
+    pub min_peer_max_packet_size: u32,
+    /// The window size that we advertise for new channels, no matter which side opens them.
+    /// By default, it depends on the [`WindowStrategy`].
+    pub initial_window_size: Option<u32>,
+    /// The max packet size that we advertise for new channels, no matter which side opens them.
+    pub max_packet_size: u32,
+    /// The maximum number of data packets that are created for a channel at once, even if the peer's window
+    /// would allow more. The rest of the data stays queued until [`ChannelsState::packets_to_send`] has been drained.
+    /// This stops a large window from turning all data into packets in memory before any of it can be written.
+    /// Disabled by default. Only enable it for drivers that keep sending until no more packets come out,
+    /// like `cluelessh-tokio`, otherwise the remaining data is stuck until the next packet arrives.
+    pub max_outbound_data_packets: Option<usize>,
+}
+
+impl Default for ChannelConfig {
+    fn default() -> Self {
+        Self {
+            window_strategy: WindowStrategy::default(),
+            max_queued_data: 1024 * 1024,
+            ignore_unexpected_open_confirmation: false,
+            allowed_subsystems: HashSet::new(),
+            allowed_global_requests: HashSet::new(),
+            max_channel_data: None,
+            no_more_sessions: false,
+            read_pressure_after: None,
+            min_peer_max_packet_size: 256,
+            initial_window_size: None,
+            max_packet_size: 32768, // same as OpenSSH
+            max_outbound_data_packets: None,
+        }
+    }
+}
+
+impl ChannelConfig {
+    /// Checks for settings that would leave channels stuck, like a [`WindowStrategy::LowWater`]
+    /// that never adjusts the window.
+    pub fn validate(&self) -> Result<(), InvalidConfig> {
+        if let WindowStrategy::LowWater { threshold, step } = self.window_strategy {
+            if step == 0 {
+                return Err(InvalidConfig(
+                    "low water window strategy with a step of zero".to_owned(),
+                ));
+            }
+            let (initial_window, _) = initial_window(self);
+            if threshold > initial_window {
+                return Err(InvalidConfig(format!(
+                    "low water threshold {threshold} is above the initial window size {initial_window}"
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The initial window size and max packet size that we advertise for a new channel with this config.
+fn initial_window(config: &ChannelConfig) -> (u32, u32) {
+    let our_window_size =
+        config
+            .initial_window_size
+            .unwrap_or_else(|| match config.window_strategy {
+                WindowStrategy::Threshold | WindowStrategy::LowWater { .. } => 2097152, // same as OpenSSH
+                WindowStrategy::Target(target) => target,
+                WindowStrategy::Adaptive { max } => cmp::min(2097152, max),
+            });
+    (our_window_size, config.max_packet_size)
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub enum WindowStrategy {
+    /// Once the peer has used up half of the initial window, increase it back to the initial window size.
+    /// Adjusting this early leaves space in the window for the next packets while the adjustment is underway,
+    /// even if the peer sends packets of the maximum size.
+    #[default]
+    Threshold,
+    /// Once the window drops below `threshold` bytes, increase it by `step` bytes.
+    /// With a small threshold, a peer that sends large packets stalls until the adjustment arrives.
+    LowWater { threshold: u32, step: u32 },
+    /// Keep the advertised window at the target size, adjusting it whenever more than half of it
+    /// has been consumed. This works well for links with a high bandwidth-delay product.
+    Target(u32),
+    /// Like [`Self::Target`], but the target adapts to how fast the consumer reads the data (AIMD).
+    /// It grows by one packet with every adjustment while the consumer keeps up, up to `max`.
+    /// When the data that the consumer has not read yet exceeds half of the target,
+    /// it is halved (down to the max packet size) and the window is not adjusted until the consumer has caught up.
+    /// This bounds the memory used for a slow consumer.
+    Adaptive { max: u32 },
+}
+
 pub struct ChannelsState {
     packets_to_send: VecDeque<Packet>,
     channel_updates: VecDeque<ChannelUpdate>,
@@ -25,6 +154,24 @@ pub struct ChannelsState {
     next_channel_id: ChannelNumber,
 
     is_server: bool,
+    config: ChannelConfig,
+    /// Restrictions from the authorized_keys entry the peer authenticated with.
+    key_options: AuthorizedKeyOptions,
+    /// Subsystems that the consumer has a handler for.
+    subsystem_handlers: HashSet<String>,
+    /// Channels that we have aborted, whose messages are ignored until the peer closes them too.
+    aborted_channels: HashSet<ChannelNumber>,
+
+    global_requests: VecDeque<GlobalRequest>,
+    /// Replies to the global requests that want one, in the order of the requests.
+    /// Replies must be sent in order, so a reply is `None` until the consumer has replied to its request.
+    global_replies: VecDeque<Option<Packet>>,
+    /// Whether we have sent `no-more-sessions@openssh.com`, see [`ChannelConfig::no_more_sessions`].
+    sent_no_more_sessions: bool,
+    /// Remote forwards that we requested with [`ChannelsState::request_tcpip_forward`] and the peer accepted.
+    tcpip_forwards: HashSet<(String, u32)>,
+    /// Our `tcpip-forward` requests that the peer has not replied to yet, in order.
+    pending_tcpip_forwards: VecDeque<(String, u32)>,
 }
 
 enum ChannelState {
@@ -41,6 +188,12 @@ enum ChannelState {
 struct Channel {
     /// Whether our side has closed this channel.
     we_closed: bool,
+    /// Whether our EOF is waiting for the queued data to be sent.
+    eof_deferred: bool,
+    /// Whether our close is waiting for the queued data to be sent.
+    close_deferred: bool,
+    /// How many requests of the peer are waiting for a reply.
+    pending_replies: u32,
     /// The channel number for the other side.
     peer_channel: u32,
     /// The current max window size of our peer, controls how many bytes we can still send.
@@ -55,11 +208,111 @@ struct Channel {
     our_max_packet_size: u32,
     /// By how much we want to increase the window when it gets small.
     our_window_size_increase_step: u32,
+    /// The current target of [`WindowStrategy::Adaptive`].
+    our_adaptive_window_target: u32,
+    /// The amount of data in updates that the consumer has not taken yet.
+    unread_data: u32,
+    /// All data sent and received on this channel, for [`ChannelConfig::max_channel_data`].
+    transferred_data: u64,
+    /// How often the peer has sent data until our window was empty.
+    window_exhaustions: u64,
 
     /// Queued data that we want to send, but have not been able to because of the window limits.
     /// Whenever we get more window space, we will send this data.
-    queued_data_default: Vec<u8>,
-    queued_data_extended: HashMap<u32, Vec<u8>>,
+    queued_data_default: DataQueue,
+    queued_data_extended: HashMap<u32, DataQueue>,
+}
+
+/// Data that is waiting for window space.
+/// Sending from the front only moves an offset, so draining a large queue in pieces
+/// neither reallocates nor shifts the rest of the data.
+#[derive(Debug, Default)]
+struct DataQueue {
+    buf: Vec<u8>,
+    /// The start of the data that has not been sent yet.
+    start: usize,
+}
+
+impl DataQueue {
+    fn len(&self) -> usize {
+        self.buf.len() - self.start
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn extend_from_slice(&mut self, data: &[u8]) {
+        // Reuse the space of the sent data instead of growing.
+        if self.start > 0 && self.buf.len() + data.len() > self.buf.capacity() {
+            self.buf.drain(..self.start);
+            self.start = 0;
+        }
+        self.buf.extend_from_slice(data);
+    }
+
+    /// The next `limit` bytes (or less) to send.
+    fn front(&self, limit: usize) -> &[u8] {
+        let end = cmp::min(self.start + limit, self.buf.len());
+        &self.buf[self.start..end]
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.start += amount;
+        assert!(self.start <= self.buf.len());
+        if self.start == self.buf.len() {
+            self.buf.clear();
+            self.start = 0;
+        }
+    }
+}
+
+impl Channel {
+    /// By how much our window should be increased, zero if it should not be increased right now.
+    fn window_adjustment(&mut self, strategy: WindowStrategy) -> u32 {
+        match strategy {
+            WindowStrategy::Threshold
+                if self.our_window_size < self.our_window_size_increase_step / 2 =>
+            {
+                self.our_window_size_increase_step - self.our_window_size
+            }
+            WindowStrategy::Threshold => 0,
+            WindowStrategy::LowWater { threshold, step } if self.our_window_size < threshold => {
+                // The window may never exceed 2^32 - 1 bytes.
+                step.min(u32::MAX - self.our_window_size)
+            }
+            WindowStrategy::LowWater { .. } => 0,
+            WindowStrategy::Target(target) if self.our_window_size < target / 2 => {
+                target - self.our_window_size
+            }
+            WindowStrategy::Target(_) => 0,
+            // Wait for the consumer to catch up.
+            WindowStrategy::Adaptive { .. }
+                if self.unread_data > self.our_adaptive_window_target / 2 =>
+            {
+                0
+            }
+            WindowStrategy::Adaptive { max }
+                if self.our_window_size < self.our_adaptive_window_target / 2 =>
+            {
+                self.our_adaptive_window_target = self
+                    .our_adaptive_window_target
+                    .saturating_add(self.our_max_packet_size)
+                    .min(max);
+                self.our_adaptive_window_target
+                    .saturating_sub(self.our_window_size)
+            }
+            WindowStrategy::Adaptive { .. } => 0,
+        }
+    }
+
+    fn has_queued_data(&self) -> bool {
+        !self.queued_data_default.is_empty()
+            || self
+                .queued_data_extended
+                .values()
+                .any(|data| !data.is_empty())
+    }
 }
 
 /// An update from a channel.
@@ -73,17 +326,105 @@ pub struct ChannelUpdate {
 pub enum ChannelUpdateKind {
     Success,
     Failure,
+    /// The peer opened a channel.
     Open(ChannelKind),
-    OpenFailed { code: u32, message: String },
+    /// The peer confirmed a channel opened by us with [`ChannelsState::create_channel`].
+    OpenConfirmed {
+        kind: ChannelKind,
+        peer_window_size: u32,
+        peer_max_packet_size: u32,
+    },
+    /// The peer rejected a channel opened by us with [`ChannelsState::create_channel`].
+    OpenFailed {
+        code: u32,
+        message: String,
+    },
     Request(ChannelRequest),
-    Data { data: Vec<u8> },
-    ExtendedData { code: u32, data: Vec<u8> },
+    Data {
+        data: Vec<u8>,
+    },
+    ExtendedData {
+        code: u32,
+        data: Vec<u8>,
+    },
     Eof,
-    Closed,
+    Closed {
+        reason: CloseReason,
+    },
+    /// All data that had to be queued because of the peer's window has been sent.
+    Drained,
+    /// The peer has exhausted our window again, see [`ChannelConfig::read_pressure_after`].
+    /// The consumer should apply backpressure to wherever it forwards the data to.
+    ReadPressure {
+        /// How often the peer has exhausted our window on this channel in total.
+        window_exhaustions: u64,
+    },
+}
+/// Why a channel has been closed, see [`ChannelUpdateKind::Closed`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The peer closed the channel first, we have answered with our close.
+    PeerClosed,
+    /// We closed the channel first and the peer has answered with its close.
+    LocalClosed,
+    /// We tore down the channel because of an error, see [`ChannelConfig::max_channel_data`].
+    Error,
 }
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ChannelKind {
     Session,
+    /// A connection to a port that has been forwarded with a `tcpip-forward` global request,
+    /// opened by the server. <https://datatracker.ietf.org/doc/html/rfc4254#section-7.2>
+    ForwardedTcpip {
+        /// The address and port that the connection was made to.
+        connected_address: String,
+        connected_port: u32,
+        /// The address and port that the connection was made from.
+        originator_address: String,
+        originator_port: u32,
+    },
+}
+
+impl ChannelKind {
+    /// The channel type name on the wire.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Session => "session",
+            Self::ForwardedTcpip { .. } => "forwarded-tcpip",
+        }
+    }
+
+    /// Serializes the `SSH_MSG_CHANNEL_OPEN` for opening a channel of this kind.
+    pub fn open_packet(
+        &self,
+        our_number: ChannelNumber,
+        initial_window_size: u32,
+        max_packet_size: u32,
+    ) -> Packet {
+        match self {
+            Self::Session => Packet::new_msg_channel_open_session(
+                self.name().as_bytes(),
+                our_number.0,
+                initial_window_size,
+                max_packet_size,
+            ),
+            Self::ForwardedTcpip {
+                connected_address,
+                connected_port,
+                originator_address,
+                originator_port,
+            } => Packet::new_msg_channel_open_forwarded_tcpip(
+                self.name().as_bytes(),
+                our_number.0,
+                initial_window_size,
+                max_packet_size,
+                connected_address.as_bytes(),
+                *connected_port,
+                originator_address.as_bytes(),
+                *originator_port,
+            ),
+        }
+    }
 }
 #[derive(Debug)]
 pub enum ChannelRequest {
@@ -95,7 +436,20 @@ pub enum ChannelRequest {
         height_rows: u32,
         width_px: u32,
         height_px: u32,
+        /// The encoded terminal modes, see [`TerminalModes::parse`].
         term_modes: Vec<u8>,
+        /// The input and output baud rates, see [`TerminalModes::input_speed`] and [`TerminalModes::output_speed`].
+        /// They are parsed from `term_modes` when receiving the request and not sent, only `term_modes` is.
+        input_speed: Option<u32>,
+        output_speed: Option<u32>,
+    },
+    /// The terminal of the client has been resized, the new size should be applied to the PTY.
+    /// Never wants a reply. <https://datatracker.ietf.org/doc/html/rfc4254#section-6.7>
+    WindowChange {
+        width_chars: u32,
+        height_rows: u32,
+        width_px: u32,
+        height_px: u32,
     },
     Shell {
         want_reply: bool,
@@ -119,6 +473,133 @@ pub enum ChannelRequest {
     ExitStatus {
         status: u32,
     },
+    /// The command was terminated by a signal. Never wants a reply.
+    /// <https://datatracker.ietf.org/doc/html/rfc4254#section-6.10>
+    ExitSignal {
+        /// The name of the signal without the `SIG` prefix, for example `KILL`.
+        signal_name: String,
+        core_dumped: bool,
+        error_message: String,
+    },
+}
+
+impl ChannelRequest {
+    /// Whether the peer waits for [`ChannelOperationKind::Success`] or [`ChannelOperationKind::Failure`].
+    pub fn want_reply(&self) -> bool {
+        match self {
+            Self::PtyReq { want_reply, .. }
+            | Self::Shell { want_reply }
+            | Self::Exec { want_reply, .. }
+            | Self::Subsystem { want_reply, .. }
+            | Self::Env { want_reply, .. } => *want_reply,
+            Self::WindowChange { .. } | Self::ExitStatus { .. } | Self::ExitSignal { .. } => false,
+        }
+    }
+
+    /// The value of an `env` request if it is valid UTF-8, like most environment variables are.
+    /// Returns `None` for invalid UTF-8 and other requests, the raw value is still available in [`Self::Env`].
+    pub fn env_value_str(&self) -> Option<&str> {
+        match self {
+            Self::Env { value, .. } => std::str::from_utf8(value).ok(),
+            _ => None,
+        }
+    }
+}
+
+/// The encoded terminal modes of a `pty-req`.
+/// <https://datatracker.ietf.org/doc/html/rfc4254#section-8>
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TerminalModes {
+    /// Opcodes and their arguments, in the order they were sent.
+    pub modes: Vec<(u8, u32)>,
+}
+
+impl TerminalModes {
+    pub const TTY_OP_END: u8 = 0;
+    pub const TTY_OP_ISPEED: u8 = 128;
+    pub const TTY_OP_OSPEED: u8 = 129;
+
+    /// Parses the modes leniently, as clients are not always careful about the encoding.
+    /// Parsing stops at `TTY_OP_END` or at the end of the buffer, whichever comes first.
+    /// A trailing opcode without a complete argument is ignored,
+    /// as are opcodes 160 to 255, whose arguments we could not skip over.
+    pub fn parse(mut bytes: &[u8]) -> Self {
+        let mut modes = Vec::new();
+        while let [opcode, rest @ ..] = bytes {
+            if *opcode == Self::TTY_OP_END || *opcode >= 160 {
+                break;
+            }
+            let Some((arg, rest)) = rest.split_first_chunk::<4>() else {
+                break;
+            };
+            modes.push((*opcode, u32::from_be_bytes(*arg)));
+            bytes = rest;
+        }
+        Self { modes }
+    }
+
+    /// The argument of the opcode, the last one if it has been sent multiple times.
+    pub fn get(&self, opcode: u8) -> Option<u32> {
+        self.modes
+            .iter()
+            .rev()
+            .find(|(op, _)| *op == opcode)
+            .map(|(_, arg)| *arg)
+    }
+
+    /// The input baud rate of the terminal from `TTY_OP_ISPEED`.
+    pub fn input_speed(&self) -> Option<u32> {
+        self.get(Self::TTY_OP_ISPEED)
+    }
+
+    /// The output baud rate of the terminal from `TTY_OP_OSPEED`.
+    pub fn output_speed(&self) -> Option<u32> {
+        self.get(Self::TTY_OP_OSPEED)
+    }
+}
+
+/// A global request of the peer, which is not specific to any channel.
+/// <https://datatracker.ietf.org/doc/html/rfc4254#section-4>
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GlobalRequest {
+    pub name: String,
+    pub want_reply: bool,
+    /// The unparsed request-specific data, for example the address and port of a `tcpip-forward` request.
+    pub data: Vec<u8>,
+}
+
+/// The state of an open channel, see [`ChannelsState::channel_info`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelInfo {
+    /// The number of the channel on the peer's side, which it uses in its logs and which we send in packets.
+    pub peer_channel: u32,
+    /// How many bytes we may still send before the peer adjusts the window.
+    pub peer_window_size: u32,
+    pub peer_max_packet_size: u32,
+    /// How many bytes the peer may still send before we adjust the window.
+    pub our_window_size: u32,
+    pub our_max_packet_size: u32,
+    /// How often the peer has sent data until our window was empty, see [`ChannelConfig::read_pressure_after`].
+    pub window_exhaustions: u64,
+}
+
+/// The final status of an aborted channel, see [`ChannelsState::abort_channel`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChannelExit {
+    /// An `exit-status` with the exit code of the command.
+    Status(u32),
+    /// An `exit-signal` with the name of the signal (without the `SIG` prefix), for example `KILL`,
+    /// and a message describing why the channel is aborted.
+    Signal { name: String, message: String },
+}
+
+/// The result of [`ChannelsState::do_operation`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperationResult {
+    Done,
+    /// The data has been queued, but the queue of the channel is over [`ChannelConfig::max_queued_data`].
+    /// The consumer should stop sending data on this channel until the peer adjusts the window.
+    WouldBlock,
 }
 
 impl ChannelNumber {
@@ -147,6 +628,11 @@ pub enum ChannelOperationKind {
 
 impl ChannelsState {
     pub fn new(is_server: bool) -> Self {
+        Self::with_config(is_server, ChannelConfig::default())
+    }
+
+    /// Creates the state with a config that has passed [`ChannelConfig::validate`].
+    pub fn with_config(is_server: bool, config: ChannelConfig) -> Self {
         ChannelsState {
             packets_to_send: VecDeque::new(),
             channels: HashMap::new(),
@@ -154,22 +640,104 @@ impl ChannelsState {
             next_channel_id: ChannelNumber(0),
 
             is_server,
+            config,
+            key_options: AuthorizedKeyOptions::default(),
+            subsystem_handlers: HashSet::new(),
+            aborted_channels: HashSet::new(),
+
+            global_requests: VecDeque::new(),
+            global_replies: VecDeque::new(),
+            sent_no_more_sessions: false,
+            tcpip_forwards: HashSet::new(),
+            pending_tcpip_forwards: VecDeque::new(),
         }
     }
 
+    /// Registers a handler for a subsystem.
+    /// Requests for a registered subsystem are accepted automatically, so the [`ChannelRequest::Subsystem`]
+    /// update will not want a reply anymore.
+    /// Registered subsystems do not need to be in [`ChannelConfig::allowed_subsystems`].
+    pub fn register_subsystem_handler(&mut self, name: impl Into<String>) {
+        self.subsystem_handlers.insert(name.into());
+    }
+
+    /// Enforce the options of the authorized_keys entry the client authenticated with
+    /// on all requests that the client makes.
+    pub fn set_key_options(&mut self, key_options: AuthorizedKeyOptions) {
+        self.key_options = key_options;
+    }
+
     pub fn recv_packet(&mut self, packet: Packet) -> Result<()> {
         // TODO: what if we mostly ignored window and just always increased it again?
         // there's an excention to ignore it entirely that we could also support...
         let mut p = packet.payload_parser();
         let packet_type = p.u8()?;
+
+        if (numbers::SSH_MSG_CHANNEL_WINDOW_ADJUST..=numbers::SSH_MSG_CHANNEL_FAILURE)
+            .contains(&packet_type)
+        {
+            let our_channel = ChannelNumber(p.clone().u32()?);
+            if self.aborted_channels.contains(&our_channel) {
+                // The peer may not have received our close yet, ignore everything until it closes too.
+                if packet_type == numbers::SSH_MSG_CHANNEL_CLOSE {
+                    self.aborted_channels.remove(&our_channel);
+                }
+                debug!(channel = %our_channel, packet_type = %numbers::packet_type_to_string(packet_type), "Ignoring message for aborted channel");
+                return Ok(());
+            }
+        }
+
         match packet_type {
             numbers::SSH_MSG_GLOBAL_REQUEST => {
+                // <https://datatracker.ietf.org/doc/html/rfc4254#section-4>
                 let request_name = p.utf8_string()?;
                 let want_reply = p.bool()?;
                 debug!(%request_name, %want_reply, "Received global request");
 
-                self.packets_to_send
-                    .push_back(Packet::new_msg_request_failure());
+                if self.key_options.no_port_forwarding
+                    && PORT_FORWARDING_GLOBAL_REQUESTS.contains(&request_name)
+                {
+                    debug!(%request_name, "Rejecting global request because of no-port-forwarding");
+                    if want_reply {
+                        self.global_replies
+                            .push_back(Some(Packet::new_msg_request_failure()));
+                        self.flush_global_replies();
+                    }
+                } else if self.config.allowed_global_requests.contains(request_name) {
+                    if want_reply {
+                        self.global_replies.push_back(None);
+                    }
+                    self.global_requests.push_back(GlobalRequest {
+                        name: request_name.to_owned(),
+                        want_reply,
+                        data: p.remaining().to_owned(),
+                    });
+                } else if want_reply {
+                    debug!(%request_name, "Rejecting global request as it is not allowed");
+                    self.global_replies
+                        .push_back(Some(Packet::new_msg_request_failure()));
+                    self.flush_global_replies();
+                }
+            }
+            numbers::SSH_MSG_REQUEST_SUCCESS => {
+                // Only our `tcpip-forward` requests want a reply.
+                let Some((address, port)) = self.pending_tcpip_forwards.pop_front() else {
+                    return Err(peer_error!(
+                        "unexpected SSH_MSG_REQUEST_SUCCESS without a request"
+                    ));
+                };
+                // <https://datatracker.ietf.org/doc/html/rfc4254#section-7.1>
+                let port = if port == 0 { p.u32()? } else { port };
+                debug!(%address, %port, "Peer accepted remote forward");
+                self.tcpip_forwards.insert((address, port));
+            }
+            numbers::SSH_MSG_REQUEST_FAILURE => {
+                let Some((address, port)) = self.pending_tcpip_forwards.pop_front() else {
+                    return Err(peer_error!(
+                        "unexpected SSH_MSG_REQUEST_FAILURE without a request"
+                    ));
+                };
+                debug!(%address, %port, "Peer rejected remote forward");
             }
             numbers::SSH_MSG_CHANNEL_OPEN => {
                 // <https://datatracker.ietf.org/doc/html/rfc4254#section-5.1>
@@ -180,8 +748,84 @@ impl ChannelsState {
 
                 debug!(%channel_type, %sender_channel, "Receving channel open");
 
+                if max_packet_size < self.config.min_peer_max_packet_size {
+                    debug!(%channel_type, %max_packet_size, "Rejecting channel with a tiny max packet size");
+                    self.packets_to_send
+                        .push_back(Packet::new_msg_channel_open_failure(
+                            sender_channel,
+                            numbers::SSH_OPEN_RESOURCE_SHORTAGE,
+                            b"maximum packet size is too small",
+                            b"",
+                        ));
+                    return Ok(());
+                }
+
                 let update_message = match channel_type {
+                    "session" if self.sent_no_more_sessions => {
+                        debug!("Rejecting session channel after no-more-sessions@openssh.com");
+                        self.packets_to_send
+                            .push_back(Packet::new_msg_channel_open_failure(
+                                sender_channel,
+                                numbers::SSH_OPEN_ADMINISTRATIVELY_PROHIBITED,
+                                b"no more sessions",
+                                b"",
+                            ));
+                        return Ok(());
+                    }
                     "session" => ChannelKind::Session,
+                    _ if self.key_options.no_port_forwarding
+                        && PORT_FORWARDING_CHANNEL_TYPES.contains(&channel_type) =>
+                    {
+                        debug!(%channel_type, "Rejecting forwarding channel because of no-port-forwarding");
+                        self.packets_to_send
+                            .push_back(Packet::new_msg_channel_open_failure(
+                                sender_channel,
+                                numbers::SSH_OPEN_ADMINISTRATIVELY_PROHIBITED,
+                                b"port forwarding is not allowed",
+                                b"",
+                            ));
+                        return Ok(());
+                    }
+                    "forwarded-tcpip" if self.is_server => {
+                        // <https://datatracker.ietf.org/doc/html/rfc4254#section-7.2>
+                        debug!("Rejecting forwarded-tcpip channel from the client");
+                        self.packets_to_send
+                            .push_back(Packet::new_msg_channel_open_failure(
+                                sender_channel,
+                                numbers::SSH_OPEN_ADMINISTRATIVELY_PROHIBITED,
+                                b"forwarded-tcpip channels are only opened by servers",
+                                b"",
+                            ));
+                        return Ok(());
+                    }
+                    "forwarded-tcpip" => {
+                        let connected_address = p.utf8_string()?;
+                        let connected_port = p.u32()?;
+                        let originator_address = p.utf8_string()?;
+                        let originator_port = p.u32()?;
+
+                        if !self
+                            .tcpip_forwards
+                            .contains(&(connected_address.to_owned(), connected_port))
+                        {
+                            debug!(%connected_address, %connected_port, "Rejecting forwarded-tcpip channel for a port that we did not forward");
+                            self.packets_to_send
+                                .push_back(Packet::new_msg_channel_open_failure(
+                                    sender_channel,
+                                    numbers::SSH_OPEN_ADMINISTRATIVELY_PROHIBITED,
+                                    b"port has not been forwarded",
+                                    b"",
+                                ));
+                            return Ok(());
+                        }
+
+                        ChannelKind::ForwardedTcpip {
+                            connected_address: connected_address.to_owned(),
+                            connected_port,
+                            originator_address: originator_address.to_owned(),
+                            originator_port,
+                        }
+                    }
                     _ => {
                         self.packets_to_send
                             .push_back(Packet::new_msg_channel_open_failure(
@@ -200,26 +844,35 @@ impl ChannelsState {
                         peer_error!("created too many channels, overflowed the counter")
                     })?);
 
+                let (our_window_size, our_max_packet_size) = self.our_initial_window();
+
                 self.packets_to_send
                     .push_back(Packet::new_msg_channel_open_confirmation(
                         sender_channel,
                         our_number.0,
-                        initial_window_size,
-                        max_packet_size,
+                        our_window_size,
+                        our_max_packet_size,
                     ));
 
                 self.channels.insert(
                     our_number,
                     ChannelState::Open(Channel {
                         we_closed: false,
+                        eof_deferred: false,
+                        close_deferred: false,
+                        pending_replies: 0,
                         peer_channel: sender_channel,
                         peer_max_packet_size: max_packet_size,
                         peer_window_size: initial_window_size,
-                        our_max_packet_size: max_packet_size,
-                        our_window_size: initial_window_size,
-                        our_window_size_increase_step: initial_window_size,
+                        our_max_packet_size,
+                        our_window_size,
+                        our_window_size_increase_step: our_window_size,
+                        our_adaptive_window_target: our_window_size,
+                        unread_data: 0,
+                        transferred_data: 0,
+                        window_exhaustions: 0,
 
-                        queued_data_default: Vec::new(),
+                        queued_data_default: DataQueue::default(),
                         queued_data_extended: HashMap::new(),
                     }),
                 );
@@ -229,7 +882,7 @@ impl ChannelsState {
                     kind: ChannelUpdateKind::Open(update_message),
                 });
 
-                debug!(%channel_type, %our_number, "Successfully opened channel");
+                debug!(%channel_type, %our_number, peer_channel = %sender_channel, "Successfully opened channel");
             }
             numbers::SSH_MSG_CHANNEL_OPEN_CONFIRMATION => {
                 let our_channel = p.u32()?;
@@ -240,35 +893,75 @@ impl ChannelsState {
                     ref update_message,
                 }) = self.channels.get(&our_number)
                 else {
-                    return Err(peer_error!("unknown channel: {our_channel}"));
+                    // This is the peer's fault, not ours: we only ever wait for confirmations of channels we opened.
+                    let already_open = self.channels.contains_key(&our_number);
+                    warn!(channel = %our_number, %already_open, "Peer confirmed a channel that we are not opening");
+                    if self.config.ignore_unexpected_open_confirmation {
+                        return Ok(());
+                    }
+                    return Err(peer_error!(
+                        "unexpected SSH_MSG_CHANNEL_OPEN_CONFIRMATION for channel that is not being opened: {our_channel}"
+                    ));
                 };
 
                 let peer_channel = p.u32()?;
                 let peer_window_size = p.u32()?;
                 let peer_max_packet_size = p.u32()?;
 
+                if peer_max_packet_size < self.config.min_peer_max_packet_size {
+                    return Err(peer_error!(
+                        "channel confirmed with a maximum packet size of {peer_max_packet_size}, while the minimum is {}",
+                        self.config.min_peer_max_packet_size
+                    ));
+                }
+
+                let channel_type = update_message.name();
+                if self.config.no_more_sessions
+                    && !self.sent_no_more_sessions
+                    && *update_message == ChannelKind::Session
+                {
+                    // <https://github.com/openssh/openssh-portable/blob/master/PROTOCOL>
+                    debug!("Sending no-more-sessions@openssh.com");
+                    self.packets_to_send
+                        .push_back(Packet::new_msg_global_request(
+                            b"no-more-sessions@openssh.com",
+                            false,
+                        ));
+                    self.sent_no_more_sessions = true;
+                }
                 self.channel_updates.push_back(ChannelUpdate {
                     number: our_number,
-                    kind: ChannelUpdateKind::Open(update_message.clone()),
+                    kind: ChannelUpdateKind::OpenConfirmed {
+                        kind: update_message.clone(),
+                        peer_window_size,
+                        peer_max_packet_size,
+                    },
                 });
 
                 self.channels.insert(
                     our_number,
                     ChannelState::Open(Channel {
                         we_closed: false,
+                        eof_deferred: false,
+                        close_deferred: false,
+                        pending_replies: 0,
                         peer_channel,
                         peer_max_packet_size,
                         peer_window_size,
                         our_max_packet_size,
                         our_window_size,
                         our_window_size_increase_step: our_window_size,
+                        our_adaptive_window_target: our_window_size,
+                        unread_data: 0,
+                        transferred_data: 0,
+                        window_exhaustions: 0,
 
-                        queued_data_default: Vec::new(),
+                        queued_data_default: DataQueue::default(),
                         queued_data_extended: HashMap::new(),
                     }),
                 );
 
-                debug!(channel_type = %"session", %our_number, "Successfully opened channel");
+                debug!(%channel_type, %our_number, %peer_channel, "Successfully opened channel");
             }
             numbers::SSH_MSG_CHANNEL_OPEN_FAILURE => {
                 let our_channel = p.u32()?;
@@ -300,53 +993,25 @@ impl ChannelsState {
                 let our_channel = self.validate_channel(our_channel)?;
                 let bytes_to_add = p.u32()?;
 
+                if bytes_to_add == 0 {
+                    // Nothing can be sent that couldn't be sent before.
+                    return Ok(());
+                }
+
                 let channel = self.channel(our_channel)?;
                 channel.peer_window_size = channel
                     .peer_window_size
                     .checked_add(bytes_to_add)
                     .ok_or_else(|| peer_error!("window size larger than 2^32"))?;
 
-                if !channel.queued_data_default.is_empty() {
-                    let limit = cmp::min(
-                        channel.queued_data_default.len(),
-                        channel.peer_window_size as usize,
-                    );
-                    let data_to_send = channel
-                        .queued_data_default
-                        .splice(..limit, [])
-                        .collect::<Vec<_>>();
-                    self.send_data(our_channel, &data_to_send, None);
-                }
-
-                // After potentially sending default data, see if we can send some extended data too.
-                let channel = self.channel(our_channel)?;
-                let data_keys = channel
-                    .queued_data_extended
-                    .keys()
-                    .copied()
-                    .collect::<Vec<_>>();
-
-                for number in data_keys {
-                    let channel = self.channel(our_channel)?;
-                    let peer_window_size = channel.peer_window_size;
-                    let queued_data_extended =
-                        channel.queued_data_extended.get_mut(&number).unwrap();
-
-                    if !queued_data_extended.is_empty() {
-                        let limit = cmp::min(queued_data_extended.len(), peer_window_size as usize);
-                        let data_to_send =
-                            queued_data_extended.splice(..limit, []).collect::<Vec<_>>();
-                        if !data_to_send.is_empty() {
-                            self.send_data(our_channel, &data_to_send, Some(number));
-                        }
-                    }
-                }
+                self.send_all_queued_data(our_channel);
             }
             numbers::SSH_MSG_CHANNEL_DATA => {
                 let our_channel = p.u32()?;
                 let our_channel = self.validate_channel(our_channel)?;
                 let data = p.string()?;
 
+                let window_strategy = self.config.window_strategy;
                 let channel = self.channel(our_channel)?;
                 channel.our_window_size = channel
                     .our_window_size
@@ -368,25 +1033,57 @@ impl ChannelsState {
 
                 trace!(channel = %our_channel, window = %channel.our_window_size, "Remaining window on our side");
 
-                // We probably want to make this user-controllable in the future.
-                if channel.our_window_size < 1000 {
-                    let peer = channel.peer_channel;
-                    let bytes_to_add = channel.our_window_size_increase_step;
-                    channel.our_window_size += bytes_to_add;
-                    self.packets_to_send
-                        .push_back(Packet::new_msg_channel_window_adjust(peer, bytes_to_add))
+                if self.count_channel_data(our_channel, data.len())? {
+                    return Ok(());
+                }
+                let read_pressure_after = self.config.read_pressure_after;
+                let channel = self.channel(our_channel)?;
+
+                let mut read_pressure = None;
+                if channel.our_window_size == 0 {
+                    channel.window_exhaustions += 1;
+                    if let Some(after) = read_pressure_after {
+                        if channel.window_exhaustions % u64::from(after.max(1)) == 0 {
+                            debug!(channel = %our_channel, exhaustions = %channel.window_exhaustions, "Peer keeps exhausting our window");
+                            read_pressure = Some(channel.window_exhaustions);
+                        }
+                    }
+                }
+
+                channel.unread_data = channel.unread_data.saturating_add(data.len() as u32);
+                if let WindowStrategy::Adaptive { .. } = window_strategy {
+                    if channel.unread_data > channel.our_adaptive_window_target / 2 {
+                        channel.our_adaptive_window_target = cmp::max(
+                            channel.our_adaptive_window_target / 2,
+                            channel.our_max_packet_size,
+                        );
+                        trace!(channel = %our_channel, target = %channel.our_adaptive_window_target, unread = %channel.unread_data, "Consumer is falling behind, decreasing window target");
+                    }
                 }
 
+                self.adjust_window(our_channel);
+
                 self.channel_updates.push_back(ChannelUpdate {
                     number: our_channel,
                     kind: ChannelUpdateKind::Data {
                         data: data.to_owned(),
                     },
                 });
+                if let Some(window_exhaustions) = read_pressure {
+                    self.channel_updates.push_back(ChannelUpdate {
+                        number: our_channel,
+                        kind: ChannelUpdateKind::ReadPressure { window_exhaustions },
+                    });
+                }
             }
             numbers::SSH_MSG_CHANNEL_EOF => {
                 // <https://datatracker.ietf.org/doc/html/rfc4254#section-5.3>
                 let our_channel = p.u32()?;
+                if self.was_removed(our_channel) {
+                    // The peer may send EOF after a close that crossed its own, like OpenSSH we tolerate that.
+                    debug!(channel = %our_channel, "Ignoring EOF for already closed channel");
+                    return Ok(());
+                }
                 let our_channel = self.validate_channel(our_channel)?;
 
                 self.channel_updates.push_back(ChannelUpdate {
@@ -397,19 +1094,32 @@ impl ChannelsState {
             numbers::SSH_MSG_CHANNEL_CLOSE => {
                 // <https://datatracker.ietf.org/doc/html/rfc4254#section-5.3>
                 let our_channel = p.u32()?;
+                if self.was_removed(our_channel) {
+                    // A buggy peer may send a second close, don't kill the connection for that.
+                    debug!(channel = %our_channel, "Ignoring close for already closed channel");
+                    return Ok(());
+                }
                 let our_channel = self.validate_channel(our_channel)?;
                 let channel = self.channel(our_channel)?;
-                if !channel.we_closed {
+                let reason = if channel.we_closed {
+                    if channel.close_deferred {
+                        // The peer does not want the rest of our data anymore.
+                        let close = Packet::new_msg_channel_close(channel.peer_channel);
+                        self.packets_to_send.push_back(close);
+                    }
+                    CloseReason::LocalClosed
+                } else {
                     info!("closeing here");
                     let close = Packet::new_msg_channel_close(channel.peer_channel);
                     self.packets_to_send.push_back(close);
-                }
+                    CloseReason::PeerClosed
+                };
 
                 self.channels.remove(&our_channel);
 
                 self.channel_updates.push_back(ChannelUpdate {
                     number: our_channel,
-                    kind: ChannelUpdateKind::Closed,
+                    kind: ChannelUpdateKind::Closed { reason },
                 });
 
                 debug!("Channel has been closed");
@@ -437,6 +1147,7 @@ impl ChannelsState {
                         let width_px = p.u32()?;
                         let height_px = p.u32()?;
                         let term_modes = p.string()?;
+                        let modes = TerminalModes::parse(term_modes);
 
                         debug!(
                             channel = %our_channel,
@@ -454,6 +1165,32 @@ impl ChannelsState {
                             width_px,
                             height_px,
                             term_modes: term_modes.to_owned(),
+                            input_speed: modes.input_speed(),
+                            output_speed: modes.output_speed(),
+                        }
+                    }
+                    "window-change" => {
+                        if !self.is_server {
+                            return Err(peer_error!("server tried to change window size"));
+                        }
+
+                        let width_chars = p.u32()?;
+                        let height_rows = p.u32()?;
+                        let width_px = p.u32()?;
+                        let height_px = p.u32()?;
+
+                        debug!(
+                            channel = %our_channel,
+                            %width_chars,
+                            %height_rows,
+                            "Changing window size"
+                        );
+
+                        ChannelRequest::WindowChange {
+                            width_chars,
+                            height_rows,
+                            width_px,
+                            height_px,
                         }
                     }
                     "shell" => {
@@ -483,10 +1220,33 @@ impl ChannelsState {
 
                         let name = p.utf8_string()?;
 
-                        info!(channel = %our_channel, %name, "Starting subsystem");
-                        ChannelRequest::Subsystem {
-                            want_reply,
-                            name: name.to_owned(),
+                        if self.key_options.command.is_some() {
+                            // The forced command replaces the subsystem below, so leave the reply to the consumer.
+                            ChannelRequest::Subsystem {
+                                want_reply,
+                                name: name.to_owned(),
+                            }
+                        } else if self.subsystem_handlers.contains(name) {
+                            info!(channel = %our_channel, %name, "Starting subsystem");
+                            if want_reply {
+                                self.send_channel_success(peer_channel);
+                            }
+                            ChannelRequest::Subsystem {
+                                want_reply: false,
+                                name: name.to_owned(),
+                            }
+                        } else if self.config.allowed_subsystems.contains(name) {
+                            info!(channel = %our_channel, %name, "Starting subsystem");
+                            ChannelRequest::Subsystem {
+                                want_reply,
+                                name: name.to_owned(),
+                            }
+                        } else {
+                            debug!(channel = %our_channel, %name, "Rejecting subsystem as it is not allowed");
+                            if want_reply {
+                                self.send_channel_failure(peer_channel);
+                            }
+                            return Ok(());
                         }
                     }
                     "env" => {
@@ -505,6 +1265,33 @@ impl ChannelsState {
                             value: value.to_owned(),
                         }
                     }
+                    "exit-status" => {
+                        if self.is_server {
+                            return Err(peer_error!("client tried to send exit status"));
+                        }
+
+                        let status = p.u32()?;
+
+                        debug!(channel = %our_channel, %status, "Received exit status");
+                        ChannelRequest::ExitStatus { status }
+                    }
+                    "exit-signal" => {
+                        if self.is_server {
+                            return Err(peer_error!("client tried to send exit signal"));
+                        }
+
+                        let signal_name = p.utf8_string()?;
+                        let core_dumped = p.bool()?;
+                        let error_message = p.utf8_string()?;
+                        let _language_tag = p.string()?;
+
+                        debug!(channel = %our_channel, %signal_name, %core_dumped, "Received exit signal");
+                        ChannelRequest::ExitSignal {
+                            signal_name: signal_name.to_owned(),
+                            core_dumped,
+                            error_message: error_message.to_owned(),
+                        }
+                    }
                     "signal" => {
                         if !self.is_server {
                             return Err(peer_error!("server tried to send signal"));
@@ -521,6 +1308,33 @@ impl ChannelsState {
                     }
                 };
 
+                let channel_request = match (channel_request, &self.key_options.command) {
+                    (ChannelRequest::PtyReq { want_reply, .. }, _) if self.key_options.no_pty => {
+                        debug!(channel = %our_channel, "Rejecting pty-req because of no-pty");
+                        if want_reply {
+                            self.send_channel_failure(peer_channel);
+                        }
+                        return Ok(());
+                    }
+                    (
+                        ChannelRequest::Shell { want_reply }
+                        | ChannelRequest::Exec { want_reply, .. }
+                        | ChannelRequest::Subsystem { want_reply, .. },
+                        Some(command),
+                    ) => {
+                        debug!(channel = %our_channel, %command, "Overriding request with forced command");
+                        ChannelRequest::Exec {
+                            want_reply,
+                            command: command.clone().into_bytes(),
+                        }
+                    }
+                    (channel_request, _) => channel_request,
+                };
+
+                if channel_request.want_reply() {
+                    self.channel(our_channel)?.pending_replies += 1;
+                }
+
                 self.channel_updates.push_back(ChannelUpdate {
                     number: our_channel,
                     kind: ChannelUpdateKind::Request(channel_request),
@@ -545,23 +1359,149 @@ impl ChannelsState {
                 });
             }
             _ => {
-                todo!(
-                    "unsupported packet: {} ({packet_type})",
+                return Err(peer_error!(
+                    "unexpected packet: {} ({packet_type})",
                     numbers::packet_type_to_string(packet_type)
-                );
+                ));
             }
         }
 
         Ok(())
     }
 
-    pub fn packets_to_send(&mut self) -> impl Iterator<Item = Packet> + '_ {
-        self.packets_to_send.drain(..)
+    /// Information about an open channel, `None` if it is not open (yet).
+    /// Mostly useful for correlating our channels with packet captures or the peer's logs.
+    pub fn channel_info(&self, number: ChannelNumber) -> Option<ChannelInfo> {
+        match self.channels.get(&number)? {
+            ChannelState::AwaitingConfirmation { .. } => None,
+            ChannelState::Open(channel) => Some(ChannelInfo {
+                peer_channel: channel.peer_channel,
+                peer_window_size: channel.peer_window_size,
+                peer_max_packet_size: channel.peer_max_packet_size,
+                our_window_size: channel.our_window_size,
+                our_max_packet_size: channel.our_max_packet_size,
+                window_exhaustions: channel.window_exhaustions,
+            }),
+        }
     }
 
-    pub fn next_channel_update(&mut self) -> Option<ChannelUpdate> {
-        self.channel_updates.pop_front()
-    }
+    /// The packets that should be sent to the peer.
+    ///
+    /// Once all packets have been taken, the next call releases more of the data that has been held back
+    /// because of [`ChannelConfig::max_outbound_data_packets`].
+    pub fn packets_to_send(&mut self) -> impl Iterator<Item = Packet> + '_ {
+        if self.packets_to_send.is_empty() {
+            let numbers = self
+                .channels
+                .iter()
+                .filter_map(|(number, state)| match state {
+                    ChannelState::Open(channel)
+                        if channel.has_queued_data() && channel.peer_window_size > 0 =>
+                    {
+                        Some(*number)
+                    }
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            for number in numbers {
+                self.send_all_queued_data(number);
+            }
+        }
+        self.packets_to_send.drain(..)
+    }
+
+    pub fn next_channel_update(&mut self) -> Option<ChannelUpdate> {
+        let update = self.channel_updates.pop_front()?;
+        self.update_taken(&update);
+        Some(update)
+    }
+
+    /// Drains all pending channel updates at once.
+    /// Equivalent to calling [`Self::next_channel_update`] until it returns `None`.
+    pub fn drain_updates(&mut self) -> impl Iterator<Item = ChannelUpdate> + '_ {
+        let updates = std::mem::take(&mut self.channel_updates);
+        for update in &updates {
+            self.update_taken(update);
+        }
+        updates.into_iter()
+    }
+
+    /// Queues an update as if it had been received from the peer, for testing consumers without constructing packets.
+    /// A [`ChannelUpdateKind::Open`] also opens the channel, so that the consumer can do operations on it,
+    /// the peer's side of the channel has the same number.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn push_channel_update(&mut self, update: ChannelUpdate) {
+        if let ChannelUpdateKind::Open(_) = update.kind {
+            let (our_window_size, our_max_packet_size) = self.our_initial_window();
+            self.channels.insert(
+                update.number,
+                ChannelState::Open(Channel {
+                    we_closed: false,
+                    eof_deferred: false,
+                    close_deferred: false,
+                    pending_replies: 0,
+                    peer_channel: update.number.0,
+                    // The peer is imaginary, so give it the same limits as us.
+                    peer_window_size: our_window_size,
+                    peer_max_packet_size: our_max_packet_size,
+                    our_window_size,
+                    our_max_packet_size,
+                    our_window_size_increase_step: our_window_size,
+                    our_adaptive_window_target: our_window_size,
+                    unread_data: 0,
+                    transferred_data: 0,
+                    window_exhaustions: 0,
+
+                    queued_data_default: DataQueue::default(),
+                    queued_data_extended: HashMap::new(),
+                }),
+            );
+            self.next_channel_id = ChannelNumber(cmp::max(
+                self.next_channel_id.0,
+                update.number.0.saturating_add(1),
+            ));
+        }
+        if let ChannelUpdateKind::Request(request) = &update.kind {
+            if let (true, Ok(channel)) = (request.want_reply(), self.channel(update.number)) {
+                channel.pending_replies += 1;
+            }
+        }
+        self.channel_updates.push_back(update);
+    }
+
+    /// Keeps track of the data that the consumer has read, for [`WindowStrategy::Adaptive`].
+    fn update_taken(&mut self, update: &ChannelUpdate) {
+        let ChannelUpdateKind::Data { data } = &update.kind else {
+            return;
+        };
+        let Ok(channel) = self.channel(update.number) else {
+            return;
+        };
+        channel.unread_data = channel.unread_data.saturating_sub(data.len() as u32);
+        if let WindowStrategy::Adaptive { .. } = self.config.window_strategy {
+            // We may have held back an adjustment because the consumer was behind.
+            self.adjust_window(update.number);
+        }
+    }
+
+    fn adjust_window(&mut self, channel_number: ChannelNumber) {
+        let window_strategy = self.config.window_strategy;
+        let Ok(channel) = self.channel(channel_number) else {
+            return;
+        };
+        let bytes_to_add = channel.window_adjustment(window_strategy);
+        if bytes_to_add > 0 {
+            let peer = channel.peer_channel;
+            channel.our_window_size = channel.our_window_size.saturating_add(bytes_to_add);
+            self.packets_to_send
+                .push_back(Packet::new_msg_channel_window_adjust(peer, bytes_to_add))
+        }
+    }
+
+    /// The initial window size and max packet size that we advertise for a new channel.
+    fn our_initial_window(&self) -> (u32, u32) {
+        initial_window(&self.config)
+    }
 
     /// Create a new channel
     pub fn create_channel(&mut self, kind: ChannelKind) -> ChannelNumber {
@@ -573,18 +1513,14 @@ impl ChannelsState {
                 .expect("created too many channels"),
         );
 
-        assert_eq!(kind, ChannelKind::Session, "TODO");
+        let (our_window_size, our_max_packet_size) = self.our_initial_window();
 
-        let our_window_size = 2097152; // same as OpenSSH
-        let our_max_packet_size = 32768; // same as OpenSSH
-
-        self.packets_to_send
-            .push_back(Packet::new_msg_channel_open_session(
-                b"session",
-                our_number.0,
-                our_window_size,
-                our_max_packet_size,
-            ));
+        self.packets_to_send.push_back(kind.open_packet(
+            our_number,
+            our_window_size,
+            our_max_packet_size,
+        ));
+        let channel_type = kind.name();
 
         self.channels.insert(
             our_number,
@@ -595,35 +1531,106 @@ impl ChannelsState {
             },
         );
 
-        debug!(channel_type = %"session", %our_number, "Opening channel");
+        debug!(%channel_type, %our_number, "Opening channel");
 
         our_number
     }
 
+    /// Executes a batch of operations in order.
+    /// Consecutive data operations for the same channel and stream are coalesced,
+    /// so that they are sent in as few packets as possible.
+    /// Returns [`OperationResult::WouldBlock`] if any of the operations would block.
+    pub fn do_operations(
+        &mut self,
+        ops: impl IntoIterator<Item = ChannelOperation>,
+    ) -> OperationResult {
+        let mut result = OperationResult::Done;
+        let mut pending: Option<ChannelOperation> = None;
+
+        for op in ops {
+            if let Some(pending) = &mut pending {
+                if pending.number == op.number {
+                    match (&mut pending.kind, &op.kind) {
+                        (ChannelOperationKind::Data(data), ChannelOperationKind::Data(more)) => {
+                            data.extend_from_slice(more);
+                            continue;
+                        }
+                        (
+                            ChannelOperationKind::ExtendedData(code, data),
+                            ChannelOperationKind::ExtendedData(more_code, more),
+                        ) if code == more_code => {
+                            data.extend_from_slice(more);
+                            continue;
+                        }
+                        _ => {}
+                    }
+                }
+            }
+
+            if let Some(pending) = pending.replace(op) {
+                if self.do_operation(pending) == OperationResult::WouldBlock {
+                    result = OperationResult::WouldBlock;
+                }
+            }
+        }
+        if let Some(pending) = pending {
+            if self.do_operation(pending) == OperationResult::WouldBlock {
+                result = OperationResult::WouldBlock;
+            }
+        }
+
+        result
+    }
+
     /// Executes an operation on the channel.
     /// If the channel has already been closed, the operation is dropped.
-    pub fn do_operation(&mut self, op: ChannelOperation) {
+    /// The only exception are replies to requests after we have sent our close,
+    /// as the peer may still be waiting for them until it closes the channel too.
+    pub fn do_operation(&mut self, op: ChannelOperation) -> OperationResult {
         op.trace();
 
         let Ok(channel) = self.channel(op.number) else {
             debug!(number = %op.number, "Dropping operation as channel does not exist, probably because it has been closed");
-            return;
+            return OperationResult::Done;
         };
         let peer = channel.peer_channel;
 
-        if channel.we_closed {
+        let is_reply = matches!(
+            op.kind,
+            ChannelOperationKind::Success | ChannelOperationKind::Failure
+        );
+        if channel.we_closed && !(is_reply && channel.pending_replies > 0) {
             debug!(number = %op.number, "Dropping operation as channel has been closed already");
-            return;
+            return OperationResult::Done;
+        }
+
+        if is_reply {
+            channel.pending_replies = channel.pending_replies.saturating_sub(1);
         }
 
         match op.kind {
             ChannelOperationKind::Success => self.send_channel_success(peer),
             ChannelOperationKind::Failure => self.send_channel_failure(peer),
             ChannelOperationKind::Data(data) => {
+                // The channel has been looked up above, so it cannot be unknown here.
+                if self
+                    .count_channel_data(op.number, data.len())
+                    .unwrap_or(true)
+                {
+                    return OperationResult::Done;
+                }
                 self.send_data(op.number, &data, None);
+                return self.queue_backpressure(op.number, None);
             }
             ChannelOperationKind::ExtendedData(code, data) => {
+                if self
+                    .count_channel_data(op.number, data.len())
+                    .unwrap_or(true)
+                {
+                    return OperationResult::Done;
+                }
                 self.send_data(op.number, &data, Some(code));
+                return self.queue_backpressure(op.number, Some(code));
             }
             ChannelOperationKind::Request(req) => {
                 let packet = match req {
@@ -635,6 +1642,8 @@ impl ChannelsState {
                         width_px,
                         height_px,
                         term_modes,
+                        input_speed: _,
+                        output_speed: _,
                     } => Packet::new_msg_channel_request_pty_req(
                         peer,
                         b"pty-req",
@@ -646,12 +1655,46 @@ impl ChannelsState {
                         height_px,
                         &term_modes,
                     ),
+                    ChannelRequest::WindowChange {
+                        width_chars,
+                        height_rows,
+                        width_px,
+                        height_px,
+                    } => Packet::new_msg_channel_request_window_change(
+                        peer,
+                        b"window-change",
+                        false,
+                        width_chars,
+                        height_rows,
+                        width_px,
+                        height_px,
+                    ),
                     ChannelRequest::Shell { want_reply } => {
                         Packet::new_msg_channel_request_shell(peer, b"shell", want_reply)
                     }
-                    ChannelRequest::Exec { .. } => todo!("exec"),
-                    ChannelRequest::Subsystem { .. } => todo!("subsystem"),
-                    ChannelRequest::Env { .. } => todo!("env"),
+                    ChannelRequest::Exec {
+                        want_reply,
+                        command,
+                    } => Packet::new_msg_channel_request_exec(peer, b"exec", want_reply, &command),
+                    ChannelRequest::Subsystem { want_reply, name } => {
+                        Packet::new_msg_channel_request_subsystem(
+                            peer,
+                            b"subsystem",
+                            want_reply,
+                            name.as_bytes(),
+                        )
+                    }
+                    ChannelRequest::Env {
+                        want_reply,
+                        name,
+                        value,
+                    } => Packet::new_msg_channel_request_env(
+                        peer,
+                        b"env",
+                        want_reply,
+                        name.as_bytes(),
+                        &value,
+                    ),
                     ChannelRequest::ExitStatus { status } => {
                         Packet::new_msg_channel_request_exit_status(
                             peer,
@@ -660,107 +1703,350 @@ impl ChannelsState {
                             status,
                         )
                     }
+                    ChannelRequest::ExitSignal {
+                        signal_name,
+                        core_dumped,
+                        error_message,
+                    } => Packet::new_msg_channel_request_exit_signal(
+                        peer,
+                        b"exit-signal",
+                        false,
+                        signal_name.as_bytes(),
+                        core_dumped,
+                        error_message.as_bytes(),
+                        b"",
+                    ),
                 };
                 self.packets_to_send.push_back(packet);
             }
             ChannelOperationKind::Eof => {
-                self.packets_to_send
-                    .push_back(Packet::new_msg_channel_eof(peer));
+                let channel = self.channel(op.number).unwrap();
+                if channel.has_queued_data() {
+                    debug!(number = %op.number, "Deferring EOF until the queued data has been sent");
+                    channel.eof_deferred = true;
+                } else {
+                    self.packets_to_send
+                        .push_back(Packet::new_msg_channel_eof(peer));
+                }
             }
             ChannelOperationKind::Close => {
                 // <https://datatracker.ietf.org/doc/html/rfc4254#section-5.3>
-                self.packets_to_send
-                    .push_back(Packet::new_msg_channel_close(peer));
-
                 let channel = self.channel(op.number).unwrap();
                 channel.we_closed = true;
+                if channel.has_queued_data() {
+                    debug!(number = %op.number, "Deferring close until the queued data has been sent");
+                    channel.close_deferred = true;
+                } else {
+                    self.packets_to_send
+                        .push_back(Packet::new_msg_channel_close(peer));
+                }
             }
         }
+        OperationResult::Done
     }
 
-    fn send_data(
-        &mut self,
-        channel_number: ChannelNumber,
-        data: &[u8],
-        extended_code: Option<u32>,
-    ) {
-        assert!(!data.is_empty());
+    /// Asks the server to listen on `address` and `port` and to open a [`ChannelKind::ForwardedTcpip`]
+    /// channel for every connection to it. Port 0 lets the server choose the port.
+    /// Only channels for forwards that the server has accepted are accepted, see [`Self::tcpip_forwards`].
+    /// <https://datatracker.ietf.org/doc/html/rfc4254#section-7.1>
+    pub fn request_tcpip_forward(&mut self, address: &str, port: u32) {
+        debug!(%address, %port, "Requesting remote forward");
+        self.packets_to_send
+            .push_back(Packet::new_msg_global_request_tcpip_forward(
+                b"tcpip-forward",
+                true,
+                address.as_bytes(),
+                port,
+            ));
+        self.pending_tcpip_forwards
+            .push_back((address.to_owned(), port));
+    }
 
-        let channel = self.channel(channel_number).unwrap();
+    /// The addresses and ports of the remote forwards that the server has accepted,
+    /// with the port that the server chose for requests with port 0.
+    pub fn tcpip_forwards(&self) -> impl Iterator<Item = (&str, u32)> + '_ {
+        self.tcpip_forwards
+            .iter()
+            .map(|(address, port)| (address.as_str(), *port))
+    }
 
-        let mut chunks = data.chunks(channel.peer_max_packet_size as usize);
-
-        while let Some(data) = chunks.next() {
-            let channel = self.channel(channel_number).unwrap();
-            let remaining_window_space_after =
-                channel.peer_window_size.checked_sub(data.len() as u32);
-            match remaining_window_space_after {
-                None => {
-                    let rest = channel.peer_window_size;
-                    let (to_send, to_keep) = data.split_at(rest as usize);
-
-                    if !to_send.is_empty() {
-                        // Send everything we can, which empties the window.
-                        channel.peer_window_size -= rest;
-                        assert_eq!(channel.peer_window_size, 0);
-                        self.send_data_packet(channel_number, to_send, extended_code);
-                    }
+    /// Returns the next global request of the peer that is in [`ChannelConfig::allowed_global_requests`].
+    /// If it wants a reply, the consumer must reply to it with [`Self::reply_global_request`].
+    pub fn next_global_request(&mut self) -> Option<GlobalRequest> {
+        self.global_requests.pop_front()
+    }
 
-                    // It's over, we have exhausted all window space.
-                    // Queue the rest of the bytes.
-                    let channel = self.channel(channel_number).unwrap();
-                    match extended_code {
-                        Some(extended) => {
-                            let queued_data_extended =
-                                channel.queued_data_extended.entry(extended).or_default();
-                            queued_data_extended.extend_from_slice(to_keep);
-                            for data in chunks {
-                                queued_data_extended.extend_from_slice(data);
-                            }
-                            debug!(channel = %channel_number, queue_len = %channel.queued_data_extended.len(), "Exhausted window space, queueing the rest of the data");
-                        }
-                        None => {
-                            channel.queued_data_default.extend_from_slice(to_keep);
-                            for data in chunks {
-                                channel.queued_data_default.extend_from_slice(data);
-                            }
-                            debug!(channel = %channel_number, queue_len = %channel.queued_data_default.len(), "Exhausted window space, queueing the rest of the data");
-                        }
-                    }
-                    return;
+    /// Replies to the oldest global request that wants a reply and has not been replied to yet.
+    /// `Some` sends `SSH_MSG_REQUEST_SUCCESS` with the request-specific response data, `None` sends `SSH_MSG_REQUEST_FAILURE`.
+    pub fn reply_global_request(&mut self, response: Option<&[u8]>) {
+        let Some(reply) = self.global_replies.iter_mut().find(|reply| reply.is_none()) else {
+            warn!("Dropping reply to global request as there is no request waiting for a reply");
+            return;
+        };
+        *reply = Some(match response {
+            Some(data) => {
+                let mut packet = Packet::new_msg_request_success();
+                packet.payload.extend_from_slice(data);
+                packet
+            }
+            None => Packet::new_msg_request_failure(),
+        });
+        self.flush_global_replies();
+    }
+
+    fn flush_global_replies(&mut self) {
+        while let Some(Some(_)) = self.global_replies.front() {
+            let reply = self.global_replies.pop_front().unwrap().unwrap();
+            self.packets_to_send.push_back(reply);
+        }
+    }
+
+    /// Tears down a channel immediately, for example because the process backing it died unexpectedly.
+    /// Sends `exit` (if any), EOF and close without waiting for the peer and discards all queued data.
+    /// The channel is removed right away, so there will be no further updates for it,
+    /// not even [`ChannelUpdateKind::Closed`].
+    pub fn abort_channel(&mut self, number: ChannelNumber, exit: Option<ChannelExit>) {
+        let Ok(channel) = self.channel(number) else {
+            debug!(%number, "Not aborting channel as it does not exist, probably because it has been closed");
+            return;
+        };
+        let peer = channel.peer_channel;
+        let we_closed = channel.we_closed;
+        let eof_deferred = channel.eof_deferred;
+        let close_deferred = channel.close_deferred;
+
+        debug!(%number, ?exit, "Aborting channel");
+
+        if !we_closed {
+            self.fail_pending_replies(number);
+            match exit {
+                // <https://datatracker.ietf.org/doc/html/rfc4254#section-6.10>
+                Some(ChannelExit::Status(status)) => {
+                    self.packets_to_send
+                        .push_back(Packet::new_msg_channel_request_exit_status(
+                            peer,
+                            b"exit-status",
+                            false,
+                            status,
+                        ));
                 }
-                Some(space) => channel.peer_window_size = space,
+                Some(ChannelExit::Signal { name, message }) => {
+                    self.packets_to_send
+                        .push_back(Packet::new_msg_channel_request_exit_signal(
+                            peer,
+                            b"exit-signal",
+                            false,
+                            name.as_bytes(),
+                            false,
+                            message.as_bytes(),
+                            b"",
+                        ));
+                }
+                None => {}
+            }
+            self.packets_to_send
+                .push_back(Packet::new_msg_channel_eof(peer));
+            self.packets_to_send
+                .push_back(Packet::new_msg_channel_close(peer));
+        } else if close_deferred {
+            // Our close was still waiting for the queued data, which is discarded now.
+            if eof_deferred {
+                self.packets_to_send
+                    .push_back(Packet::new_msg_channel_eof(peer));
+            }
+            self.packets_to_send
+                .push_back(Packet::new_msg_channel_close(peer));
+        }
+
+        self.channels.remove(&number);
+        self.aborted_channels.insert(number);
+    }
+
+    /// Accounts for `len` bytes of data on the channel, aborting it if that exceeds [`ChannelConfig::max_channel_data`].
+    /// Returns whether the channel has been aborted.
+    fn count_channel_data(&mut self, number: ChannelNumber, len: usize) -> Result<bool> {
+        let max_channel_data = self.config.max_channel_data;
+        let channel = self.channel(number)?;
+        channel.transferred_data = channel.transferred_data.saturating_add(len as u64);
+        let transferred_data = channel.transferred_data;
+
+        match max_channel_data {
+            Some(max) if transferred_data > max => {
+                info!(%number, %transferred_data, %max, "Channel exceeded its data quota, aborting it");
+                self.abort_channel(
+                    number,
+                    Some(ChannelExit::Signal {
+                        name: "XFSZ".to_owned(),
+                        message: "channel data quota exceeded".to_owned(),
+                    }),
+                );
+                self.channel_updates.push_back(ChannelUpdate {
+                    number,
+                    kind: ChannelUpdateKind::Closed {
+                        reason: CloseReason::Error,
+                    },
+                });
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Whether a consumer that stopped sending data after [`OperationResult::WouldBlock`] may continue.
+    /// Returns [`OperationResult::WouldBlock`] as long as any queue of the channel is still over
+    /// [`ChannelConfig::max_queued_data`]. Queued data is sent whenever the peer adjusts the window,
+    /// so this should be checked after window adjustments, before waiting for [`ChannelUpdateKind::Drained`].
+    pub fn resume_channel(&mut self, number: ChannelNumber) -> OperationResult {
+        let Ok(channel) = self.channel(number) else {
+            return OperationResult::Done;
+        };
+        let codes = channel
+            .queued_data_extended
+            .keys()
+            .copied()
+            .map(Some)
+            .collect::<Vec<_>>();
+        for code in [None].into_iter().chain(codes) {
+            if self.queue_backpressure(number, code) == OperationResult::WouldBlock {
+                return OperationResult::WouldBlock;
             }
-            trace!(channel = %channel_number, window = %channel.peer_window_size, "Remaining window on their side");
+        }
+        OperationResult::Done
+    }
 
-            self.send_data_packet(channel_number, data, extended_code);
+    fn queue_backpressure(
+        &mut self,
+        channel_number: ChannelNumber,
+        extended_code: Option<u32>,
+    ) -> OperationResult {
+        let max_queued_data = self.config.max_queued_data;
+        let channel = self.channel(channel_number).unwrap();
+        let queued = match extended_code {
+            Some(code) => channel
+                .queued_data_extended
+                .get(&code)
+                .map_or(0, DataQueue::len),
+            None => channel.queued_data_default.len(),
+        };
+        if queued > max_queued_data {
+            debug!(channel = %channel_number, %queued, ?extended_code, "Queue is over its limit, applying backpressure");
+            OperationResult::WouldBlock
+        } else {
+            OperationResult::Done
         }
     }
 
-    /// Send a single data packet.
-    /// The caller needs to ensure the windowing and packet size requirements are upheld.
-    fn send_data_packet(
+    fn send_data(
         &mut self,
         channel_number: ChannelNumber,
         data: &[u8],
         extended_code: Option<u32>,
     ) {
-        assert!(!data.is_empty(), "Trying to send empty data packet");
+        assert!(!data.is_empty());
 
-        if let Some(extended_code) = extended_code {
-            trace!(%channel_number, amount = %data.len(), %extended_code, "Sending extended channel data");
-        } else {
-            trace!(%channel_number, amount = %data.len(), "Sending channel data");
+        // Data always goes through the queue, so that it stays in order with the data that is still queued.
+        let channel = self.channel(channel_number).unwrap();
+        match extended_code {
+            Some(extended) => channel
+                .queued_data_extended
+                .entry(extended)
+                .or_default()
+                .extend_from_slice(data),
+            None => channel.queued_data_default.extend_from_slice(data),
+        }
+
+        self.send_queued_data(channel_number, extended_code);
+    }
+
+    /// Sends the queued data of all streams of the channel, emitting [`ChannelUpdateKind::Drained`] once all of it has been sent.
+    fn send_all_queued_data(&mut self, channel_number: ChannelNumber) {
+        let Some(ChannelState::Open(channel)) = self.channels.get(&channel_number) else {
+            return;
+        };
+        let had_queued_data = channel.has_queued_data();
+        let data_keys = channel
+            .queued_data_extended
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+
+        self.send_queued_data(channel_number, None);
+
+        // After potentially sending default data, see if we can send some extended data too.
+        for number in data_keys {
+            self.send_queued_data(channel_number, Some(number));
+        }
+
+        if had_queued_data && !self.channel(channel_number).unwrap().has_queued_data() {
+            self.channel_updates.push_back(ChannelUpdate {
+                number: channel_number,
+                kind: ChannelUpdateKind::Drained,
+            });
+            self.send_deferred_eof_and_close(channel_number);
         }
+    }
+
+    /// Fails the requests that are still waiting for a reply, as there will be none after the channel is aborted.
+    fn fail_pending_replies(&mut self, channel_number: ChannelNumber) {
         let channel = self.channel(channel_number).unwrap();
         let peer = channel.peer_channel;
-        assert!(channel.peer_max_packet_size >= data.len() as u32);
-        let packet = if let Some(extended_code) = extended_code {
-            Packet::new_msg_channel_extended_data(peer, extended_code, data)
-        } else {
-            Packet::new_msg_channel_data(peer, data)
+        let pending_replies = std::mem::take(&mut channel.pending_replies);
+        if pending_replies > 0 {
+            debug!(channel = %channel_number, %pending_replies, "Failing requests before closing");
+        }
+        for _ in 0..pending_replies {
+            self.send_channel_failure(peer);
+        }
+    }
+
+    /// Sends the EOF and close that had to wait until all queued data has been sent.
+    fn send_deferred_eof_and_close(&mut self, channel_number: ChannelNumber) {
+        let channel = self.channel(channel_number).unwrap();
+        let peer = channel.peer_channel;
+        if std::mem::take(&mut channel.eof_deferred) {
+            self.packets_to_send
+                .push_back(Packet::new_msg_channel_eof(peer));
+        }
+        let channel = self.channel(channel_number).unwrap();
+        if std::mem::take(&mut channel.close_deferred) {
+            self.packets_to_send
+                .push_back(Packet::new_msg_channel_close(peer));
+        }
+    }
+
+    /// Sends as much of the queued data as the window and [`ChannelConfig::max_outbound_data_packets`] allow,
+    /// directly out of the queue.
+    fn send_queued_data(&mut self, channel_number: ChannelNumber, extended_code: Option<u32>) {
+        let mut packets_left = self.config.max_outbound_data_packets.unwrap_or(usize::MAX);
+        let Some(ChannelState::Open(channel)) = self.channels.get_mut(&channel_number) else {
+            return;
+        };
+        let queue = match extended_code {
+            Some(code) => match channel.queued_data_extended.get_mut(&code) {
+                Some(queue) => queue,
+                None => return,
+            },
+            None => &mut channel.queued_data_default,
         };
-        self.packets_to_send.push_back(packet);
+
+        while !queue.is_empty() && channel.peer_window_size > 0 && packets_left > 0 {
+            let limit = cmp::min(channel.peer_window_size, channel.peer_max_packet_size);
+            let data = queue.front(limit as usize);
+            trace!(channel = %channel_number, amount = %data.len(), ?extended_code, "Sending queued channel data");
+            let packet = match extended_code {
+                Some(code) => {
+                    Packet::new_msg_channel_extended_data(channel.peer_channel, code, data)
+                }
+                None => Packet::new_msg_channel_data(channel.peer_channel, data),
+            };
+            channel.peer_window_size -= data.len() as u32;
+            queue.consume(data.len());
+            self.packets_to_send.push_back(packet);
+            packets_left -= 1;
+        }
+        if !queue.is_empty() {
+            debug!(channel = %channel_number, queue_len = %queue.len(), window = %channel.peer_window_size, ?extended_code, "Queueing the rest of the data");
+        }
     }
 
     fn send_channel_success(&mut self, recipient_channel: u32) {
@@ -780,6 +2066,12 @@ impl ChannelsState {
         Ok(ChannelNumber(number))
     }
 
+    /// Whether the channel number has been handed out before but is no longer in use.
+    /// Channel numbers are never reused, so this is the case for all closed channels.
+    fn was_removed(&self, number: u32) -> bool {
+        number < self.next_channel_id.0 && !self.channels.contains_key(&ChannelNumber(number))
+    }
+
     fn channel(&mut self, number: ChannelNumber) -> Result<&mut Channel> {
         let state = self
             .channels
@@ -804,11 +2096,13 @@ impl ChannelOperation {
             ChannelOperationKind::ExtendedData(_, _) => "extended-data",
             ChannelOperationKind::Request(req) => match req {
                 ChannelRequest::PtyReq { .. } => "pty-req",
+                ChannelRequest::WindowChange { .. } => "window-change",
                 ChannelRequest::Shell { .. } => "shell",
                 ChannelRequest::Exec { .. } => "exec",
                 ChannelRequest::Subsystem { .. } => "subsystem",
                 ChannelRequest::Env { .. } => "env",
                 ChannelRequest::ExitStatus { .. } => "exit-status",
+                ChannelRequest::ExitSignal { .. } => "exit-signal",
             },
             ChannelOperationKind::Eof => "eof",
             ChannelOperationKind::Close => "close",
@@ -819,10 +2113,18 @@ impl ChannelOperation {
 
 #[cfg(test)]
 mod tests {
-    use cluelessh_format::numbers;
+    use std::collections::HashSet;
+
+    use cluelessh_format::{numbers, Writer};
+    use cluelessh_keys::authorized_keys::AuthorizedKeyOptions;
     use cluelessh_transport::packet::Packet;
+    use cluelessh_transport::SshStatus;
 
-    use crate::{ChannelNumber, ChannelOperation, ChannelOperationKind, ChannelsState};
+    use crate::{
+        ChannelConfig, ChannelExit, ChannelInfo, ChannelKind, ChannelNumber, ChannelOperation,
+        ChannelOperationKind, ChannelRequest, ChannelUpdate, ChannelUpdateKind, ChannelsState,
+        CloseReason, GlobalRequest, OperationResult, TerminalModes, WindowStrategy,
+    };
 
     /// If a test fails, add this to the test to get logs.
     #[allow(dead_code)]
@@ -887,78 +2189,2091 @@ mod tests {
     }
 
     #[test]
-    fn only_single_close_for_double_close_operation() {
+    fn drain_updates_empties_queue() {
         let state = &mut ChannelsState::new(true);
         open_session_channel(state);
-        state.do_operation(ChannelOperation {
-            number: ChannelNumber(0),
-            kind: ChannelOperationKind::Close,
-        });
-        state.do_operation(ChannelOperation {
-            number: ChannelNumber(0),
-            kind: ChannelOperationKind::Close,
-        });
-        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_CLOSE]);
+        state
+            .recv_packet(Packet::new_msg_channel_data(0, b"hello"))
+            .unwrap();
+        state.recv_packet(Packet::new_msg_channel_eof(0)).unwrap();
+
+        let updates = state.drain_updates().collect::<Vec<_>>();
+        assert_eq!(updates.len(), 3);
+        assert!(matches!(updates[0].kind, ChannelUpdateKind::Open(_)));
+        assert!(matches!(updates[1].kind, ChannelUpdateKind::Data { .. }));
+        assert!(matches!(updates[2].kind, ChannelUpdateKind::Eof));
+
+        assert!(state.next_channel_update().is_none());
+        assert_eq!(state.drain_updates().count(), 0);
     }
 
     #[test]
-    fn ignore_operation_after_close() {
-        let mut state = &mut ChannelsState::new(true);
+    fn exec_command_is_raw_bytes() {
+        let state = &mut ChannelsState::new(true);
         open_session_channel(state);
-        state.recv_packet(Packet::new_msg_channel_close(0)).unwrap();
-        assert_response_types(&mut state, &[numbers::SSH_MSG_CHANNEL_CLOSE]);
-        state.do_operation(ChannelOperation {
-            number: ChannelNumber(0),
-            kind: ChannelOperationKind::Data(vec![0]),
-        });
-        assert_response_types(state, &[]);
+
+        let command = b"echo \xff\xfe\x00 done";
+        let mut w = Writer::new();
+        w.u8(numbers::SSH_MSG_CHANNEL_REQUEST);
+        w.u32(0);
+        w.string(b"exec");
+        w.bool(true);
+        w.string(command);
+        state
+            .recv_packet(Packet {
+                payload: w.finish(),
+            })
+            .unwrap();
+
+        let _open = state.next_channel_update().unwrap();
+        let update = state.next_channel_update().unwrap();
+        match update.kind {
+            ChannelUpdateKind::Request(ChannelRequest::Exec {
+                want_reply,
+                command: delivered,
+            }) => {
+                assert!(want_reply);
+                assert_eq!(delivered, command);
+            }
+            kind => panic!("unexpected update: {kind:?}"),
+        }
     }
 
-    // TODO: test with extended data
     #[test]
-    fn respect_peer_windowing() {
+    fn env_value() {
         let state = &mut ChannelsState::new(true);
-        state
-            .recv_packet(Packet::new_msg_channel_open_session(b"session", 0, 10, 50))
-            .unwrap();
-        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_OPEN_CONFIRMATION]);
+        open_session_channel(state);
+        let _open = state.next_channel_update().unwrap();
 
-        // Send 100 bytes.
-        state.do_operation(
-            ChannelNumber(0)
-                .construct_op(ChannelOperationKind::Data((0_u8..200).collect::<Vec<_>>())),
+        for value in [b"en_US.UTF-8".as_slice(), b"\xff\xfe"] {
+            let mut w = Writer::new();
+            w.u8(numbers::SSH_MSG_CHANNEL_REQUEST);
+            w.u32(0);
+            w.string(b"env");
+            w.bool(false);
+            w.string(b"LANG");
+            w.string(value);
+            state
+                .recv_packet(Packet {
+                    payload: w.finish(),
+                })
+                .unwrap();
+
+            let update = state.next_channel_update().unwrap();
+            let ChannelUpdateKind::Request(req) = update.kind else {
+                panic!("unexpected update: {:?}", update.kind);
+            };
+            assert_eq!(req.env_value_str(), std::str::from_utf8(value).ok());
+            match req {
+                ChannelRequest::Env {
+                    name,
+                    value: delivered,
+                    ..
+                } => {
+                    assert_eq!(name, "LANG");
+                    assert_eq!(delivered, value);
+                }
+                req => panic!("unexpected request: {req:?}"),
+            }
+        }
+        assert_eq!(
+            ChannelRequest::Shell { want_reply: false }.env_value_str(),
+            None
+        );
+    }
+
+    #[test]
+    fn forced_command_overrides_request() {
+        let state = &mut ChannelsState::new(true);
+        state.set_key_options(AuthorizedKeyOptions {
+            command: Some("uptime".into()),
+            ..Default::default()
+        });
+        open_session_channel(state);
+
+        state
+            .recv_packet(Packet::new_msg_channel_request_shell(0, b"shell", true))
+            .unwrap();
+        let _open = state.next_channel_update().unwrap();
+        let update = state.next_channel_update().unwrap();
+        match update.kind {
+            ChannelUpdateKind::Request(ChannelRequest::Exec {
+                want_reply,
+                command,
+            }) => {
+                assert!(want_reply);
+                assert_eq!(command, b"uptime");
+            }
+            kind => panic!("unexpected update: {kind:?}"),
+        }
+    }
+
+    #[test]
+    fn no_pty_rejects_pty_req() {
+        let state = &mut ChannelsState::new(true);
+        state.set_key_options(AuthorizedKeyOptions {
+            no_pty: true,
+            ..Default::default()
+        });
+        open_session_channel(state);
+
+        state
+            .recv_packet(Packet::new_msg_channel_request_pty_req(
+                0, b"pty-req", true, b"xterm", 80, 24, 0, 0, b"",
+            ))
+            .unwrap();
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_FAILURE]);
+        let _open = state.next_channel_update().unwrap();
+        assert!(state.next_channel_update().is_none());
+    }
+
+    fn request_sftp(state: &mut ChannelsState) {
+        request_subsystem(state, "sftp");
+    }
+
+    fn request_subsystem(state: &mut ChannelsState, name: &str) {
+        state
+            .recv_packet(Packet::new_msg_channel_request_subsystem(
+                0,
+                b"subsystem",
+                true,
+                name.as_bytes(),
+            ))
+            .unwrap();
+    }
+
+    #[test]
+    fn sftp_subsystem_without_handler() {
+        let state = &mut ChannelsState::new(true);
+        open_session_channel(state);
+        request_sftp(state);
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_FAILURE]);
+        let _open = state.next_channel_update().unwrap();
+        assert!(state.next_channel_update().is_none());
+    }
+
+    #[test]
+    fn sftp_subsystem_with_handler() {
+        let state = &mut ChannelsState::new(true);
+        state.register_subsystem_handler("sftp");
+        open_session_channel(state);
+        request_sftp(state);
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_SUCCESS]);
+        let _open = state.next_channel_update().unwrap();
+        let update = state.next_channel_update().unwrap();
+        assert!(matches!(
+            update.kind,
+            ChannelUpdateKind::Request(ChannelRequest::Subsystem {
+                want_reply: false,
+                ref name,
+            }) if name == "sftp"
+        ));
+    }
+
+    #[test]
+    fn forced_command_overrides_subsystem_with_handler() {
+        let state = &mut ChannelsState::new(true);
+        state.register_subsystem_handler("sftp");
+        state.set_key_options(AuthorizedKeyOptions {
+            command: Some("uptime".into()),
+            ..Default::default()
+        });
+        open_session_channel(state);
+        request_sftp(state);
+        // The forced command runs instead of the subsystem, so we must not tell the client that it started.
+        assert_response_types(state, &[]);
+        let _open = state.next_channel_update().unwrap();
+        let update = state.next_channel_update().unwrap();
+        match update.kind {
+            ChannelUpdateKind::Request(ChannelRequest::Exec {
+                want_reply,
+                command,
+            }) => {
+                assert!(want_reply);
+                assert_eq!(command, b"uptime");
+            }
+            kind => panic!("unexpected update: {kind:?}"),
+        }
+    }
+
+    #[test]
+    fn allowed_subsystems() {
+        let state = &mut ChannelsState::with_config(
+            true,
+            ChannelConfig {
+                allowed_subsystems: HashSet::from(["sftp".to_owned()]),
+                ..Default::default()
+            },
+        );
+        open_session_channel(state);
+        let _open = state.next_channel_update().unwrap();
+
+        request_subsystem(state, "unknown");
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_FAILURE]);
+        assert!(state.next_channel_update().is_none());
+
+        // Without a handler, the consumer has to reply.
+        request_sftp(state);
+        assert_response_types(state, &[]);
+        let update = state.next_channel_update().unwrap();
+        assert!(matches!(
+            update.kind,
+            ChannelUpdateKind::Request(ChannelRequest::Subsystem {
+                want_reply: true,
+                ref name,
+            }) if name == "sftp"
+        ));
+    }
+
+    #[test]
+    fn every_channel_kind_has_open_packet() {
+        // Make sure to add new kinds to the list below.
+        fn _all_kinds_listed(kind: ChannelKind) {
+            match kind {
+                ChannelKind::Session => {}
+                ChannelKind::ForwardedTcpip { .. } => {}
+            }
+        }
+        let all_kinds = [
+            ChannelKind::Session,
+            ChannelKind::ForwardedTcpip {
+                connected_address: "0.0.0.0".to_owned(),
+                connected_port: 8080,
+                originator_address: "192.0.2.1".to_owned(),
+                originator_port: 50000,
+            },
+        ];
+
+        for kind in &all_kinds {
+            let packet = kind.open_packet(ChannelNumber(3), 2048, 1024);
+            let mut p = packet.payload_parser();
+            assert_eq!(p.u8().unwrap(), numbers::SSH_MSG_CHANNEL_OPEN);
+            assert_eq!(p.utf8_string().unwrap(), kind.name());
+            assert_eq!(p.u32().unwrap(), 3);
+            assert_eq!(p.u32().unwrap(), 2048);
+            assert_eq!(p.u32().unwrap(), 1024);
+        }
+    }
+
+    fn forwarded_tcpip() -> ChannelKind {
+        ChannelKind::ForwardedTcpip {
+            connected_address: "127.0.0.1".to_owned(),
+            connected_port: 8080,
+            originator_address: "192.0.2.1".to_owned(),
+            originator_port: 50000,
+        }
+    }
+
+    fn open_forwarded_tcpip() -> Packet {
+        Packet::new_msg_channel_open_forwarded_tcpip(
+            b"forwarded-tcpip",
+            7,
+            2048,
+            1024,
+            b"127.0.0.1",
+            8080,
+            b"192.0.2.1",
+            50000,
+        )
+    }
+
+    /// Requests a remote forward of 127.0.0.1:8080 from `server` and delivers its reply.
+    fn forward_port(client: &mut ChannelsState, server: &mut ChannelsState) {
+        client.request_tcpip_forward("127.0.0.1", 8080);
+        for packet in client.packets_to_send().collect::<Vec<_>>() {
+            server.recv_packet(packet).unwrap();
+        }
+        let request = server.next_global_request().unwrap();
+        assert_eq!(request.name, "tcpip-forward");
+        server.reply_global_request(Some(&[]));
+        for packet in server.packets_to_send().collect::<Vec<_>>() {
+            client.recv_packet(packet).unwrap();
+        }
+    }
+
+    fn forwarding_server() -> ChannelsState {
+        ChannelsState::with_config(
+            true,
+            ChannelConfig {
+                allowed_global_requests: HashSet::from(["tcpip-forward".to_owned()]),
+                ..Default::default()
+            },
+        )
+    }
+
+    fn assert_open_failure(state: &mut ChannelsState, reason: u32) {
+        let packets = state.packets_to_send().collect::<Vec<_>>();
+        assert_eq!(packets.len(), 1);
+        let mut p = packets[0].payload_parser();
+        assert_eq!(p.u8().unwrap(), numbers::SSH_MSG_CHANNEL_OPEN_FAILURE);
+        assert_eq!(p.u32().unwrap(), 7);
+        assert_eq!(p.u32().unwrap(), reason);
+        assert!(state.next_channel_update().is_none());
+    }
+
+    #[test]
+    fn server_rejects_forwarded_tcpip() {
+        let state = &mut ChannelsState::new(true);
+        state.recv_packet(open_forwarded_tcpip()).unwrap();
+        assert_open_failure(state, numbers::SSH_OPEN_ADMINISTRATIVELY_PROHIBITED);
+    }
+
+    #[test]
+    fn client_rejects_unrequested_forwarded_tcpip() {
+        let state = &mut ChannelsState::new(false);
+        state.recv_packet(open_forwarded_tcpip()).unwrap();
+        assert_open_failure(state, numbers::SSH_OPEN_ADMINISTRATIVELY_PROHIBITED);
+    }
+
+    #[test]
+    fn client_rejects_forwarded_tcpip_after_rejected_forward() {
+        let client = &mut ChannelsState::new(false);
+        // Does not allow tcpip-forward.
+        let server = &mut ChannelsState::new(true);
+        client.request_tcpip_forward("127.0.0.1", 8080);
+        for packet in client.packets_to_send().collect::<Vec<_>>() {
+            server.recv_packet(packet).unwrap();
+        }
+        for packet in server.packets_to_send().collect::<Vec<_>>() {
+            client.recv_packet(packet).unwrap();
+        }
+        assert_eq!(client.tcpip_forwards().count(), 0);
+
+        client.recv_packet(open_forwarded_tcpip()).unwrap();
+        assert_open_failure(client, numbers::SSH_OPEN_ADMINISTRATIVELY_PROHIBITED);
+    }
+
+    #[test]
+    fn accept_forwarded_tcpip() {
+        let client = &mut ChannelsState::new(false);
+        forward_port(client, &mut forwarding_server());
+        assert_eq!(
+            client.tcpip_forwards().collect::<Vec<_>>(),
+            [("127.0.0.1", 8080)]
+        );
+
+        client.recv_packet(open_forwarded_tcpip()).unwrap();
+        let packets = client.packets_to_send().collect::<Vec<_>>();
+        assert_eq!(packets.len(), 1);
+        let mut p = packets[0].payload_parser();
+        assert_eq!(p.u8().unwrap(), numbers::SSH_MSG_CHANNEL_OPEN_CONFIRMATION);
+        assert_eq!(p.u32().unwrap(), 7);
+
+        let update = client.next_channel_update().unwrap();
+        assert!(matches!(update.kind, ChannelUpdateKind::Open(kind) if kind == forwarded_tcpip()));
+    }
+
+    #[test]
+    fn forward_with_allocated_port() {
+        let client = &mut ChannelsState::new(false);
+        let server = &mut forwarding_server();
+        client.request_tcpip_forward("127.0.0.1", 0);
+        for packet in client.packets_to_send().collect::<Vec<_>>() {
+            server.recv_packet(packet).unwrap();
+        }
+        server.next_global_request().unwrap();
+        server.reply_global_request(Some(&8080_u32.to_be_bytes()));
+        for packet in server.packets_to_send().collect::<Vec<_>>() {
+            client.recv_packet(packet).unwrap();
+        }
+        assert_eq!(
+            client.tcpip_forwards().collect::<Vec<_>>(),
+            [("127.0.0.1", 8080)]
+        );
+
+        client.recv_packet(open_forwarded_tcpip()).unwrap();
+        let update = client.next_channel_update().unwrap();
+        assert!(matches!(update.kind, ChannelUpdateKind::Open(kind) if kind == forwarded_tcpip()));
+    }
+
+    #[test]
+    fn unexpected_request_success() {
+        let state = &mut ChannelsState::new(false);
+        assert!(state
+            .recv_packet(Packet::new_msg_request_success())
+            .is_err());
+    }
+
+    #[test]
+    fn initiate_forwarded_tcpip() {
+        let server = &mut forwarding_server();
+        let client = &mut ChannelsState::new(false);
+        forward_port(client, server);
+
+        let number = server.create_channel(forwarded_tcpip());
+        let mut packets = server.packets_to_send().collect::<Vec<_>>();
+        assert_eq!(packets.len(), 1);
+        let open = packets.remove(0);
+        let mut p = open.payload_parser();
+        assert_eq!(p.u8().unwrap(), numbers::SSH_MSG_CHANNEL_OPEN);
+        assert_eq!(p.utf8_string().unwrap(), "forwarded-tcpip");
+        assert_eq!(p.u32().unwrap(), number.0);
+        let _window = p.u32().unwrap();
+        let _max_packet_size = p.u32().unwrap();
+        assert_eq!(p.utf8_string().unwrap(), "127.0.0.1");
+        assert_eq!(p.u32().unwrap(), 8080);
+        assert_eq!(p.utf8_string().unwrap(), "192.0.2.1");
+        assert_eq!(p.u32().unwrap(), 50000);
+        assert!(p.remaining().is_empty());
+
+        client.recv_packet(open).unwrap();
+        let update = client.next_channel_update().unwrap();
+        assert!(matches!(update.kind, ChannelUpdateKind::Open(kind) if kind == forwarded_tcpip()));
+        for packet in client.packets_to_send().collect::<Vec<_>>() {
+            server.recv_packet(packet).unwrap();
+        }
+        let update = server.next_channel_update().unwrap();
+        assert_eq!(update.number, number);
+        assert!(matches!(
+            update.kind,
+            ChannelUpdateKind::OpenConfirmed { kind, .. } if kind == forwarded_tcpip()
+        ));
+    }
+
+    #[test]
+    fn only_single_close_for_double_close_operation() {
+        let state = &mut ChannelsState::new(true);
+        open_session_channel(state);
+        state.do_operation(ChannelOperation {
+            number: ChannelNumber(0),
+            kind: ChannelOperationKind::Close,
+        });
+        state.do_operation(ChannelOperation {
+            number: ChannelNumber(0),
+            kind: ChannelOperationKind::Close,
+        });
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_CLOSE]);
+    }
+
+    #[test]
+    fn close_reason() {
+        let state = &mut ChannelsState::new(true);
+        open_session_channel(state);
+        let _open = state.next_channel_update().unwrap();
+        state.recv_packet(Packet::new_msg_channel_close(0)).unwrap();
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_CLOSE]);
+        assert!(matches!(
+            state.next_channel_update().unwrap().kind,
+            ChannelUpdateKind::Closed {
+                reason: CloseReason::PeerClosed
+            }
+        ));
+
+        let state = &mut ChannelsState::new(true);
+        open_session_channel(state);
+        let _open = state.next_channel_update().unwrap();
+        state.do_operation(ChannelNumber(0).construct_op(ChannelOperationKind::Close));
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_CLOSE]);
+        state.recv_packet(Packet::new_msg_channel_close(0)).unwrap();
+        assert_response_types(state, &[]);
+        assert!(matches!(
+            state.next_channel_update().unwrap().kind,
+            ChannelUpdateKind::Closed {
+                reason: CloseReason::LocalClosed
+            }
+        ));
+    }
+
+    #[test]
+    fn unexpected_packet_type() {
+        let state = &mut ChannelsState::new(true);
+        open_session_channel(state);
+
+        for packet_type in [numbers::SSH_MSG_USERAUTH_REQUEST, 127, 200] {
+            let err = state
+                .recv_packet(Packet {
+                    payload: vec![packet_type],
+                })
+                .unwrap_err();
+            assert!(matches!(err, SshStatus::PeerError(_)), "{err:?}");
+        }
+        assert_response_types(state, &[]);
+    }
+
+    #[test]
+    fn ignore_duplicate_peer_close() {
+        let state = &mut ChannelsState::new(true);
+        open_session_channel(state);
+        state.recv_packet(Packet::new_msg_channel_close(0)).unwrap();
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_CLOSE]);
+        state.recv_packet(Packet::new_msg_channel_close(0)).unwrap();
+        assert_response_types(state, &[]);
+
+        let closed = state
+            .drain_updates()
+            .filter(|update| matches!(update.kind, ChannelUpdateKind::Closed { .. }))
+            .count();
+        assert_eq!(closed, 1);
+    }
+
+    #[test]
+    fn ignore_eof_after_close() {
+        let state = &mut ChannelsState::new(true);
+        open_session_channel(state);
+        state.recv_packet(Packet::new_msg_channel_close(0)).unwrap();
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_CLOSE]);
+        state.recv_packet(Packet::new_msg_channel_eof(0)).unwrap();
+        assert_response_types(state, &[]);
+        assert!(!state
+            .drain_updates()
+            .any(|update| matches!(update.kind, ChannelUpdateKind::Eof)));
+
+        // The connection is still usable.
+        open_session_channel(state);
+        // Channels that never existed are still an error.
+        assert!(state.recv_packet(Packet::new_msg_channel_eof(5)).is_err());
+    }
+
+    #[test]
+    fn error_propagates_into_box_dyn_error() {
+        fn recv_data_on_unknown_channel() -> Result<(), Box<dyn std::error::Error>> {
+            let state = &mut ChannelsState::new(true);
+            state.recv_packet(Packet::new_msg_channel_data(0, b"hello"))?;
+            Ok(())
+        }
+
+        let err = recv_data_on_unknown_channel().unwrap_err();
+        assert_eq!(err.to_string(), "peer error: unknown channel: 0");
+    }
+
+    fn global_request(name: &str, want_reply: bool, data: &[u8]) -> Packet {
+        let mut w = Writer::new();
+        w.u8(numbers::SSH_MSG_GLOBAL_REQUEST);
+        w.string(name);
+        w.bool(want_reply);
+        w.raw(data);
+        Packet {
+            payload: w.finish(),
+        }
+    }
+
+    #[test]
+    fn tcpip_forward_global_request() {
+        let state = &mut ChannelsState::with_config(
+            true,
+            ChannelConfig {
+                allowed_global_requests: HashSet::from(["tcpip-forward".to_owned()]),
+                ..Default::default()
+            },
+        );
+
+        // <https://datatracker.ietf.org/doc/html/rfc4254#section-7.1>
+        let mut data = Writer::new();
+        data.string("127.0.0.1");
+        data.u32(8080);
+        let data = data.finish();
+        state
+            .recv_packet(global_request("tcpip-forward", true, &data))
+            .unwrap();
+        assert_response_types(state, &[]);
+
+        assert_eq!(
+            state.next_global_request(),
+            Some(GlobalRequest {
+                name: "tcpip-forward".into(),
+                want_reply: true,
+                data,
+            })
+        );
+        assert_eq!(state.next_global_request(), None);
+
+        state.reply_global_request(Some(&8080_u32.to_be_bytes()));
+        let reply = state.packets_to_send().collect::<Vec<_>>();
+        assert_eq!(reply.len(), 1);
+        let mut p = reply[0].payload_parser();
+        assert_eq!(p.u8().unwrap(), numbers::SSH_MSG_REQUEST_SUCCESS);
+        assert_eq!(p.u32().unwrap(), 8080);
+        assert!(!p.has_data());
+    }
+
+    #[test]
+    fn no_port_forwarding_rejects_tcpip_forward() {
+        let state = &mut ChannelsState::with_config(
+            true,
+            ChannelConfig {
+                allowed_global_requests: HashSet::from(["tcpip-forward".to_owned()]),
+                ..Default::default()
+            },
+        );
+        state.set_key_options(AuthorizedKeyOptions {
+            no_port_forwarding: true,
+            ..Default::default()
+        });
+
+        let mut data = Writer::new();
+        data.string("127.0.0.1");
+        data.u32(8080);
+        state
+            .recv_packet(global_request("tcpip-forward", true, &data.finish()))
+            .unwrap();
+        assert_response_types(state, &[numbers::SSH_MSG_REQUEST_FAILURE]);
+        assert_eq!(state.next_global_request(), None);
+    }
+
+    #[test]
+    fn no_port_forwarding_rejects_streamlocal_forward() {
+        let state = &mut ChannelsState::with_config(
+            true,
+            ChannelConfig {
+                allowed_global_requests: HashSet::from([
+                    "streamlocal-forward@openssh.com".to_owned()
+                ]),
+                ..Default::default()
+            },
+        );
+        state.set_key_options(AuthorizedKeyOptions {
+            no_port_forwarding: true,
+            ..Default::default()
+        });
+
+        let mut data = Writer::new();
+        data.string("/tmp/socket");
+        state
+            .recv_packet(global_request(
+                "streamlocal-forward@openssh.com",
+                true,
+                &data.finish(),
+            ))
+            .unwrap();
+        assert_response_types(state, &[numbers::SSH_MSG_REQUEST_FAILURE]);
+        assert_eq!(state.next_global_request(), None);
+    }
+
+    #[test]
+    fn no_port_forwarding_rejects_forwarding_channels() {
+        for channel_type in ["direct-tcpip", "direct-streamlocal@openssh.com"] {
+            let open = || {
+                Packet::new_msg_channel_open_forwarded_tcpip(
+                    channel_type.as_bytes(),
+                    7,
+                    2048,
+                    1024,
+                    b"127.0.0.1",
+                    8080,
+                    b"192.0.2.1",
+                    50000,
+                )
+            };
+
+            // We do not support these channels, so they are unknown without the option.
+            let state = &mut ChannelsState::new(true);
+            state.recv_packet(open()).unwrap();
+            assert_open_failure(state, numbers::SSH_OPEN_UNKNOWN_CHANNEL_TYPE);
+
+            // With it, the client learns that forwarding is prohibited for its key.
+            let state = &mut ChannelsState::new(true);
+            state.set_key_options(AuthorizedKeyOptions {
+                no_port_forwarding: true,
+                ..Default::default()
+            });
+            state.recv_packet(open()).unwrap();
+            assert_open_failure(state, numbers::SSH_OPEN_ADMINISTRATIVELY_PROHIBITED);
+        }
+    }
+
+    #[test]
+    fn global_replies_are_ordered() {
+        let state = &mut ChannelsState::with_config(
+            true,
+            ChannelConfig {
+                allowed_global_requests: HashSet::from(["tcpip-forward".to_owned()]),
+                ..Default::default()
+            },
+        );
+
+        state
+            .recv_packet(global_request("tcpip-forward", true, b""))
+            .unwrap();
+        // Must wait for the reply to the first request.
+        state
+            .recv_packet(global_request("keepalive@openssh.com", true, b""))
+            .unwrap();
+        assert_response_types(state, &[]);
+        // No reply wanted.
+        state
+            .recv_packet(global_request("no-more-sessions@openssh.com", false, b""))
+            .unwrap();
+        assert_response_types(state, &[]);
+
+        state.reply_global_request(None);
+        assert_response_types(
+            state,
+            &[
+                numbers::SSH_MSG_REQUEST_FAILURE,
+                numbers::SSH_MSG_REQUEST_FAILURE,
+            ],
+        );
+    }
+
+    #[test]
+    fn finish_waits_for_queued_data() {
+        let state = &mut ChannelsState::new(true);
+        open_session_channel(state);
+        state.do_operations([
+            ChannelNumber(0).construct_op(ChannelOperationKind::Data(vec![0; 3000])),
+            ChannelNumber(0).construct_op(ChannelOperationKind::Eof),
+            ChannelNumber(0).construct_op(ChannelOperationKind::Close),
+        ]);
+        // Only the window of 2048 bytes has been sent, EOF and close must wait for the rest.
+        assert_response_types(
+            state,
+            &[numbers::SSH_MSG_CHANNEL_DATA, numbers::SSH_MSG_CHANNEL_DATA],
+        );
+
+        state
+            .recv_packet(Packet::new_msg_channel_window_adjust(0, 4096))
+            .unwrap();
+        assert_response_types(
+            state,
+            &[
+                numbers::SSH_MSG_CHANNEL_DATA,
+                numbers::SSH_MSG_CHANNEL_EOF,
+                numbers::SSH_MSG_CHANNEL_CLOSE,
+            ],
+        );
+
+        state.recv_packet(Packet::new_msg_channel_close(0)).unwrap();
+        assert_response_types(state, &[]);
+    }
+
+    #[test]
+    fn peer_close_with_deferred_close() {
+        let state = &mut ChannelsState::new(true);
+        open_session_channel(state);
+        state.do_operations([
+            ChannelNumber(0).construct_op(ChannelOperationKind::Data(vec![0; 3000])),
+            ChannelNumber(0).construct_op(ChannelOperationKind::Close),
+        ]);
+        assert_response_types(
+            state,
+            &[numbers::SSH_MSG_CHANNEL_DATA, numbers::SSH_MSG_CHANNEL_DATA],
+        );
+
+        // The peer gives up on the rest of the data, we still owe it our close.
+        state.recv_packet(Packet::new_msg_channel_close(0)).unwrap();
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_CLOSE]);
+    }
+
+    #[test]
+    fn abort_channel() {
+        let state = &mut ChannelsState::new(true);
+        open_session_channel(state);
+        assert_eq!(state.drain_updates().count(), 1);
+        state
+            .do_operation(ChannelNumber(0).construct_op(ChannelOperationKind::Data(vec![0; 4096])));
+        assert_response_types(
+            state,
+            &[numbers::SSH_MSG_CHANNEL_DATA, numbers::SSH_MSG_CHANNEL_DATA],
+        );
+
+        state.abort_channel(
+            ChannelNumber(0),
+            Some(ChannelExit::Signal {
+                name: "KILL".into(),
+                message: "process died".into(),
+            }),
+        );
+        let packets = state.packets_to_send().collect::<Vec<_>>();
+        let types = packets
+            .iter()
+            .map(|p| numbers::packet_type_to_string(p.packet_type()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            types,
+            [
+                numbers::packet_type_to_string(numbers::SSH_MSG_CHANNEL_REQUEST),
+                numbers::packet_type_to_string(numbers::SSH_MSG_CHANNEL_EOF),
+                numbers::packet_type_to_string(numbers::SSH_MSG_CHANNEL_CLOSE),
+            ]
+        );
+        let mut p = packets[0].payload_parser();
+        p.u8().unwrap();
+        assert_eq!(p.u32().unwrap(), 0);
+        assert_eq!(p.utf8_string().unwrap(), "exit-signal");
+        assert!(!p.bool().unwrap());
+        assert_eq!(p.utf8_string().unwrap(), "KILL");
+        assert!(!p.bool().unwrap());
+        assert_eq!(p.utf8_string().unwrap(), "process died");
+
+        // The queued data is gone and in-flight messages of the peer are ignored.
+        state
+            .recv_packet(Packet::new_msg_channel_window_adjust(0, 4096))
+            .unwrap();
+        state
+            .recv_packet(Packet::new_msg_channel_data(0, b"hello"))
+            .unwrap();
+        state.recv_packet(Packet::new_msg_channel_close(0)).unwrap();
+        assert_response_types(state, &[]);
+        assert_eq!(state.drain_updates().count(), 0);
+
+        // Once the peer has closed, the channel is gone for good.
+        assert!(state
+            .recv_packet(Packet::new_msg_channel_data(0, b"hello"))
+            .is_err());
+    }
+
+    #[test]
+    fn abort_channel_with_exit_status() {
+        let state = &mut ChannelsState::new(true);
+        open_session_channel(state);
+        state.abort_channel(ChannelNumber(0), Some(ChannelExit::Status(1)));
+        assert_response_types(
+            state,
+            &[
+                numbers::SSH_MSG_CHANNEL_REQUEST,
+                numbers::SSH_MSG_CHANNEL_EOF,
+                numbers::SSH_MSG_CHANNEL_CLOSE,
+            ],
+        );
+
+        state.abort_channel(ChannelNumber(0), None);
+        assert_response_types(state, &[]);
+    }
+
+    #[test]
+    fn channel_data_quota() {
+        let state = &mut ChannelsState::with_config(
+            true,
+            ChannelConfig {
+                max_channel_data: Some(100),
+                ..Default::default()
+            },
+        );
+        open_session_channel(state);
+        state.drain_updates().for_each(drop);
+
+        // Both directions count towards the quota.
+        state
+            .recv_packet(Packet::new_msg_channel_data(0, &[0; 60]))
+            .unwrap();
+        state.do_operation(ChannelNumber(0).construct_op(ChannelOperationKind::Data(vec![0; 40])));
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_DATA]);
+        assert!(matches!(
+            state.next_channel_update().unwrap().kind,
+            ChannelUpdateKind::Data { .. }
+        ));
+
+        state
+            .recv_packet(Packet::new_msg_channel_data(0, &[0; 1]))
+            .unwrap();
+        assert_response_types(
+            state,
+            &[
+                numbers::SSH_MSG_CHANNEL_REQUEST,
+                numbers::SSH_MSG_CHANNEL_EOF,
+                numbers::SSH_MSG_CHANNEL_CLOSE,
+            ],
+        );
+        assert!(matches!(
+            state.next_channel_update().unwrap().kind,
+            ChannelUpdateKind::Closed {
+                reason: CloseReason::Error
+            }
+        ));
+        assert!(state.next_channel_update().is_none());
+
+        // The channel is gone.
+        state.do_operation(ChannelNumber(0).construct_op(ChannelOperationKind::Data(vec![0; 1])));
+        assert_response_types(state, &[]);
+        state.recv_packet(Packet::new_msg_channel_close(0)).unwrap();
+        assert_response_types(state, &[]);
+    }
+
+    #[test]
+    #[allow(clippy::needless_borrow)]
+    fn ignore_operation_after_close() {
+        let mut state = &mut ChannelsState::new(true);
+        open_session_channel(state);
+        state.recv_packet(Packet::new_msg_channel_close(0)).unwrap();
+        assert_response_types(&mut state, &[numbers::SSH_MSG_CHANNEL_CLOSE]);
+        state.do_operation(ChannelOperation {
+            number: ChannelNumber(0),
+            kind: ChannelOperationKind::Data(vec![0]),
+        });
+        assert_response_types(state, &[]);
+    }
+
+    #[test]
+    fn reply_after_our_close() {
+        let state = &mut ChannelsState::new(true);
+        open_session_channel(state);
+        state
+            .recv_packet(Packet::new_msg_channel_request_shell(0, b"shell", true))
+            .unwrap();
+        state.do_operation(ChannelNumber(0).construct_op(ChannelOperationKind::Close));
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_CLOSE]);
+
+        // The peer has not closed the channel yet, so it is still waiting for the replies.
+        state.do_operation(ChannelNumber(0).construct_op(ChannelOperationKind::Success));
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_SUCCESS]);
+        // Replies without a pending request and everything else are dropped.
+        state.do_operation(ChannelNumber(0).construct_op(ChannelOperationKind::Success));
+        assert_response_types(state, &[]);
+        state.do_operation(ChannelNumber(0).construct_op(ChannelOperationKind::Data(vec![0])));
+        assert_response_types(state, &[]);
+
+        // Once the peer has closed the channel too, nobody is waiting anymore.
+        state.recv_packet(Packet::new_msg_channel_close(0)).unwrap();
+        assert_response_types(state, &[]);
+        state.do_operation(ChannelNumber(0).construct_op(ChannelOperationKind::Failure));
+        assert_response_types(state, &[]);
+    }
+
+    // TODO: test with extended data
+    #[test]
+    fn respect_peer_windowing() {
+        let state = &mut ChannelsState::with_config(
+            true,
+            ChannelConfig {
+                min_peer_max_packet_size: 0,
+                ..Default::default()
+            },
+        );
+        state
+            .recv_packet(Packet::new_msg_channel_open_session(b"session", 0, 10, 50))
+            .unwrap();
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_OPEN_CONFIRMATION]);
+
+        // Send 100 bytes.
+        state.do_operation(
+            ChannelNumber(0)
+                .construct_op(ChannelOperationKind::Data((0_u8..200).collect::<Vec<_>>())),
         );
 
         // 0..10
         assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_DATA]);
 
         state
-            .recv_packet(Packet::new_msg_channel_window_adjust(0, 90))
+            .recv_packet(Packet::new_msg_channel_window_adjust(0, 90))
+            .unwrap();
+        // 10..60, 60..100
+        assert_response_types(
+            state,
+            &[numbers::SSH_MSG_CHANNEL_DATA, numbers::SSH_MSG_CHANNEL_DATA],
+        );
+
+        state
+            .recv_packet(Packet::new_msg_channel_window_adjust(0, 100))
+            .unwrap();
+        // 100..150, 150..20
+        assert_response_types(
+            state,
+            &[numbers::SSH_MSG_CHANNEL_DATA, numbers::SSH_MSG_CHANNEL_DATA],
+        );
+
+        state
+            .recv_packet(Packet::new_msg_channel_window_adjust(0, 100))
+            .unwrap();
+        assert_response_types(state, &[]);
+    }
+
+    #[test]
+    fn drain_large_queue_without_reallocating() {
+        let state = &mut ChannelsState::new(true);
+        open_session_channel(state);
+
+        let data = (0..64 * 1024).map(|i| i as u8).collect::<Vec<_>>();
+        state.do_operation(ChannelNumber(0).construct_op(ChannelOperationKind::Data(data.clone())));
+        let mut sent = state
+            .packets_to_send()
+            .flat_map(|p| p.payload[9..].to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(sent.len(), 2048);
+
+        let queue = &state.channel(ChannelNumber(0)).unwrap().queued_data_default;
+        let (buf, capacity) = (queue.buf.as_ptr(), queue.buf.capacity());
+
+        for _ in 0..10 {
+            state
+                .recv_packet(Packet::new_msg_channel_window_adjust(0, 4096))
+                .unwrap();
+            for packet in state.packets_to_send() {
+                assert_eq!(packet.packet_type(), numbers::SSH_MSG_CHANNEL_DATA);
+                // type, recipient channel, data length
+                assert!(packet.payload.len() - 9 <= 1024);
+                sent.extend_from_slice(&packet.payload[9..]);
+            }
+
+            // The rest of the queue has neither been moved nor copied.
+            let queue = &state.channel(ChannelNumber(0)).unwrap().queued_data_default;
+            assert_eq!(queue.buf.as_ptr(), buf);
+            assert_eq!(queue.buf.capacity(), capacity);
+        }
+        assert_eq!(sent, data[..sent.len()]);
+        assert_eq!(sent.len(), 2048 + 10 * 4096);
+
+        state
+            .recv_packet(Packet::new_msg_channel_window_adjust(0, 1024 * 1024))
+            .unwrap();
+        sent.extend(
+            state
+                .packets_to_send()
+                .flat_map(|p| p.payload[9..].to_vec()),
+        );
+        assert_eq!(sent, data);
+        assert!(matches!(
+            state.next_channel_update().unwrap().kind,
+            ChannelUpdateKind::Open(_)
+        ));
+        assert!(matches!(
+            state.next_channel_update().unwrap().kind,
+            ChannelUpdateKind::Drained
+        ));
+    }
+
+    #[test]
+    fn bounded_outbound_data_packets() {
+        let state = &mut ChannelsState::with_config(
+            true,
+            ChannelConfig {
+                max_outbound_data_packets: Some(4),
+                ..Default::default()
+            },
+        );
+        open_session_channel(state);
+        state
+            .recv_packet(Packet::new_msg_channel_window_adjust(0, 1024 * 1024))
+            .unwrap();
+
+        let data = (0..64 * 1024).map(|i| i as u8).collect::<Vec<_>>();
+        let (first, second) = data.split_at(40 * 1024);
+        state.do_operation(
+            ChannelNumber(0).construct_op(ChannelOperationKind::Data(first.to_vec())),
+        );
+        // Sent after the first data, but before most of it could be sent.
+        state.do_operation(
+            ChannelNumber(0).construct_op(ChannelOperationKind::Data(second.to_vec())),
+        );
+
+        let mut sent = Vec::new();
+        let mut batches = 0;
+        loop {
+            let packets = state.packets_to_send().collect::<Vec<_>>();
+            if packets.is_empty() {
+                break;
+            }
+            // Both operations have released a batch for the first call.
+            assert!(packets.len() <= 8, "{} packets at once", packets.len());
+            for packet in packets {
+                assert_eq!(packet.packet_type(), numbers::SSH_MSG_CHANNEL_DATA);
+                sent.extend_from_slice(&packet.payload[9..]);
+            }
+            batches += 1;
+        }
+        assert_eq!(sent, data);
+        assert_eq!(batches, 15);
+
+        assert!(matches!(
+            state.next_channel_update().unwrap().kind,
+            ChannelUpdateKind::Open(_)
+        ));
+        assert!(matches!(
+            state.next_channel_update().unwrap().kind,
+            ChannelUpdateKind::Drained
+        ));
+    }
+
+    #[test]
+    fn zero_window_adjust() {
+        let state = &mut ChannelsState::with_config(
+            true,
+            ChannelConfig {
+                max_outbound_data_packets: Some(1),
+                ..Default::default()
+            },
+        );
+        open_session_channel(state);
+        state
+            .do_operation(ChannelNumber(0).construct_op(ChannelOperationKind::Data(vec![0; 2048])));
+        assert_eq!(state.packets_to_send.len(), 1);
+        state.packets_to_send.clear();
+
+        // The held back data would be sent if the adjustment was handled like any other.
+        for _ in 0..10 {
+            state
+                .recv_packet(Packet::new_msg_channel_window_adjust(0, 0))
+                .unwrap();
+        }
+        assert!(state.packets_to_send.is_empty());
+        let channel = state.channel(ChannelNumber(0)).unwrap();
+        assert_eq!(channel.queued_data_default.len(), 1024);
+        assert_eq!(channel.peer_window_size, 1024);
+    }
+
+    #[test]
+    fn window_low_water_strategy() {
+        // After how many KiB of data the window is adjusted, and by how much.
+        let adjustments = |window_strategy| {
+            let state = &mut ChannelsState::with_config(
+                true,
+                ChannelConfig {
+                    window_strategy,
+                    initial_window_size: Some(64 * 1024),
+                    ..Default::default()
+                },
+            );
+            state
+                .recv_packet(Packet::new_msg_channel_open_session(
+                    b"session", 0, 2048, 4096,
+                ))
+                .unwrap();
+            assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_OPEN_CONFIRMATION]);
+
+            let mut adjustments = Vec::new();
+            for i in 1..=64 {
+                state
+                    .recv_packet(Packet::new_msg_channel_data(0, &[0; 1024]))
+                    .unwrap();
+                for packet in state.packets_to_send() {
+                    let mut p = packet.payload_parser();
+                    assert_eq!(p.u8().unwrap(), numbers::SSH_MSG_CHANNEL_WINDOW_ADJUST);
+                    assert_eq!(p.u32().unwrap(), 0);
+                    adjustments.push((i, p.u32().unwrap()));
+                }
+            }
+            adjustments
+        };
+
+        // Refilled once half of it has been used.
+        assert_eq!(adjustments(WindowStrategy::Threshold), [(33, 33 * 1024)]);
+        assert_eq!(
+            adjustments(WindowStrategy::LowWater {
+                threshold: 1000,
+                step: 64 * 1024
+            }),
+            [(64, 64 * 1024)]
+        );
+        assert_eq!(
+            adjustments(WindowStrategy::LowWater {
+                threshold: 32 * 1024 + 1,
+                step: 16 * 1024,
+            }),
+            [(32, 16 * 1024), (48, 16 * 1024), (64, 16 * 1024)]
+        );
+    }
+
+    #[test]
+    fn window_low_water_strategy_caps_window() {
+        let state = &mut ChannelsState::with_config(
+            true,
+            ChannelConfig {
+                window_strategy: WindowStrategy::LowWater {
+                    threshold: u32::MAX - 1000,
+                    step: u32::MAX,
+                },
+                initial_window_size: Some(u32::MAX - 1000),
+                ..Default::default()
+            },
+        );
+        state.config.validate().unwrap();
+        state
+            .recv_packet(Packet::new_msg_channel_open_session(
+                b"session", 0, 2048, 4096,
+            ))
+            .unwrap();
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_OPEN_CONFIRMATION]);
+
+        for expected in [2024, 1024] {
+            state
+                .recv_packet(Packet::new_msg_channel_data(0, &[0; 1024]))
+                .unwrap();
+            let packets = state.packets_to_send().collect::<Vec<_>>();
+            assert_eq!(packets.len(), 1);
+            let mut p = packets[0].payload_parser();
+            assert_eq!(p.u8().unwrap(), numbers::SSH_MSG_CHANNEL_WINDOW_ADJUST);
+            assert_eq!(p.u32().unwrap(), 0);
+            assert_eq!(p.u32().unwrap(), expected);
+            assert_eq!(
+                state.channel(ChannelNumber(0)).unwrap().our_window_size,
+                u32::MAX
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_low_water_config() {
+        let config = |threshold, step| ChannelConfig {
+            window_strategy: WindowStrategy::LowWater { threshold, step },
+            initial_window_size: Some(64 * 1024),
+            ..Default::default()
+        };
+        config(1000, 1000).validate().unwrap();
+        config(64 * 1024, 1000).validate().unwrap();
+        config(1000, 0).validate().unwrap_err();
+        config(64 * 1024 + 1, 1000).validate().unwrap_err();
+    }
+
+    #[test]
+    fn window_threshold_strategy_max_sized_packets() {
+        const PACKET_SIZE: u32 = 32768;
+
+        let state = &mut ChannelsState::with_config(
+            true,
+            ChannelConfig {
+                initial_window_size: Some(PACKET_SIZE * 4),
+                max_packet_size: PACKET_SIZE,
+                ..Default::default()
+            },
+        );
+        state
+            .recv_packet(Packet::new_msg_channel_open_session(
+                b"session", 0, 2048, 1024,
+            ))
+            .unwrap();
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_OPEN_CONFIRMATION]);
+
+        // The peer always has space for another packet of the maximum size once the adjustments have arrived.
+        let mut window = PACKET_SIZE * 4;
+        let data = vec![0; PACKET_SIZE as usize];
+        for _ in 0..100 {
+            state
+                .recv_packet(Packet::new_msg_channel_data(0, &data))
+                .unwrap();
+            window -= PACKET_SIZE;
+            for packet in state.packets_to_send() {
+                let mut p = packet.payload_parser();
+                assert_eq!(p.u8().unwrap(), numbers::SSH_MSG_CHANNEL_WINDOW_ADJUST);
+                assert_eq!(p.u32().unwrap(), 0);
+                window += p.u32().unwrap();
+            }
+            assert!(window >= PACKET_SIZE, "window has shrunk to {window}");
+            assert!(window <= PACKET_SIZE * 4);
+        }
+    }
+
+    #[test]
+    fn window_target_strategy() {
+        const TARGET: u32 = 8 * 1024 * 1024;
+        const PACKET_SIZE: u32 = 32768;
+
+        let state = &mut ChannelsState::with_config(
+            true,
+            ChannelConfig {
+                window_strategy: WindowStrategy::Target(TARGET),
+                initial_window_size: Some(PACKET_SIZE * 4),
+                ..Default::default()
+            },
+        );
+        state
+            .recv_packet(Packet::new_msg_channel_open_session(
+                b"session",
+                0,
+                PACKET_SIZE * 4,
+                PACKET_SIZE,
+            ))
+            .unwrap();
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_OPEN_CONFIRMATION]);
+
+        let mut window = PACKET_SIZE * 4;
+        let mut adjustments = 0;
+        let data = vec![0; PACKET_SIZE as usize];
+        for _ in 0..2000 {
+            state
+                .recv_packet(Packet::new_msg_channel_data(0, &data))
+                .unwrap();
+            window -= PACKET_SIZE;
+
+            for packet in state.packets_to_send() {
+                let mut p = packet.payload_parser();
+                assert_eq!(p.u8().unwrap(), numbers::SSH_MSG_CHANNEL_WINDOW_ADJUST);
+                assert_eq!(p.u32().unwrap(), 0);
+                window += p.u32().unwrap();
+                adjustments += 1;
+                assert_eq!(window, TARGET);
+            }
+            assert!(window >= PACKET_SIZE);
+        }
+
+        // The window quickly grows to the target, and then only needs an adjustment every half target.
+        let consumed = 2000 * PACKET_SIZE;
+        assert!(adjustments <= consumed / (TARGET / 2) + 1, "{adjustments}");
+        assert!(window >= TARGET / 2);
+    }
+
+    #[test]
+    fn adaptive_window_slow_consumer() {
+        const MAX: u32 = 64 * PACKET_SIZE;
+        const PACKET_SIZE: u32 = 32768;
+
+        let state = &mut ChannelsState::with_config(
+            true,
+            ChannelConfig {
+                window_strategy: WindowStrategy::Adaptive { max: MAX },
+                initial_window_size: Some(PACKET_SIZE * 4),
+                ..Default::default()
+            },
+        );
+        state
+            .recv_packet(Packet::new_msg_channel_open_session(
+                b"session",
+                0,
+                PACKET_SIZE * 4,
+                PACKET_SIZE,
+            ))
+            .unwrap();
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_OPEN_CONFIRMATION]);
+        assert_eq!(state.drain_updates().count(), 1);
+
+        let mut window = PACKET_SIZE * 4;
+        let data = vec![0; PACKET_SIZE as usize];
+        // Sends data like a peer respecting the window, returning the advertised windows.
+        let mut send = |state: &mut ChannelsState, slow: bool| {
+            let mut advertised = Vec::new();
+            for i in 0..2000 {
+                if window >= PACKET_SIZE {
+                    state
+                        .recv_packet(Packet::new_msg_channel_data(0, &data))
+                        .unwrap();
+                    window -= PACKET_SIZE;
+                }
+                // The slow consumer only reads every fourth round.
+                if !slow || i % 4 == 0 {
+                    state.next_channel_update();
+                }
+                for packet in state.packets_to_send() {
+                    let mut p = packet.payload_parser();
+                    assert_eq!(p.u8().unwrap(), numbers::SSH_MSG_CHANNEL_WINDOW_ADJUST);
+                    assert_eq!(p.u32().unwrap(), 0);
+                    window += p.u32().unwrap();
+                    advertised.push(window);
+                }
+            }
+            advertised
+        };
+
+        // While the consumer keeps up, the window grows to the max.
+        let fast = send(state, false);
+        assert!(fast.windows(2).all(|w| w[0] <= w[1]), "{fast:?}");
+        assert_eq!(fast.last(), Some(&MAX));
+
+        // Once it falls behind, the window shrinks, but the peer can still make progress.
+        let slow = send(state, true);
+        assert!(slow.windows(2).all(|w| w[0] >= w[1]), "{slow:?}");
+        assert!(slow.len() > 100, "{slow:?}");
+        assert!(*slow.last().unwrap() <= 2 * PACKET_SIZE, "{slow:?}");
+    }
+
+    #[test]
+    fn unexpected_open_confirmation() {
+        let confirmation =
+            |channel| Packet::new_msg_channel_open_confirmation(channel, 5, 2048, 1024);
+
+        let state = &mut ChannelsState::new(false);
+        let err = state.recv_packet(confirmation(0)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "peer error: unexpected SSH_MSG_CHANNEL_OPEN_CONFIRMATION for channel that is not being opened: 0"
+        );
+
+        // Confirming a channel twice is just as wrong.
+        let state = &mut ChannelsState::new(false);
+        let number = state.create_channel(ChannelKind::Session);
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_OPEN]);
+        state.recv_packet(confirmation(number.0)).unwrap();
+        assert!(state.recv_packet(confirmation(number.0)).is_err());
+
+        let state = &mut ChannelsState::with_config(
+            false,
+            ChannelConfig {
+                ignore_unexpected_open_confirmation: true,
+                ..Default::default()
+            },
+        );
+        state.recv_packet(confirmation(0)).unwrap();
+        assert_response_types(state, &[]);
+        assert!(state.next_channel_update().is_none());
+    }
+
+    #[test]
+    fn open_direction() {
+        // The peer opens.
+        let state = &mut ChannelsState::new(true);
+        open_session_channel(state);
+        let update = state.next_channel_update().unwrap();
+        assert!(matches!(
+            update.kind,
+            ChannelUpdateKind::Open(ChannelKind::Session)
+        ));
+
+        // We open.
+        let state = &mut ChannelsState::new(false);
+        let number = state.create_channel(ChannelKind::Session);
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_OPEN]);
+        assert!(state.next_channel_update().is_none());
+        state
+            .recv_packet(Packet::new_msg_channel_open_confirmation(
+                number.0, 5, 4096, 512,
+            ))
+            .unwrap();
+        let update = state.next_channel_update().unwrap();
+        assert_eq!(update.number, number);
+        assert!(matches!(
+            update.kind,
+            ChannelUpdateKind::OpenConfirmed {
+                kind: ChannelKind::Session,
+                peer_window_size: 4096,
+                peer_max_packet_size: 512,
+            }
+        ));
+    }
+
+    #[test]
+    fn exec_operation() {
+        let state = &mut ChannelsState::new(false);
+        let number = state.create_channel(ChannelKind::Session);
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_OPEN]);
+        state
+            .recv_packet(Packet::new_msg_channel_open_confirmation(
+                number.0, 5, 4096, 512,
+            ))
+            .unwrap();
+
+        let exec = || {
+            number.construct_op(ChannelOperationKind::Request(ChannelRequest::Exec {
+                want_reply: true,
+                command: b"uname -a".to_vec(),
+            }))
+        };
+        assert_eq!(state.do_operation(exec()), OperationResult::Done);
+        let packets = state.packets_to_send().collect::<Vec<_>>();
+        assert_eq!(packets.len(), 1);
+        let mut p = packets[0].payload_parser();
+        assert_eq!(p.u8().unwrap(), numbers::SSH_MSG_CHANNEL_REQUEST);
+        assert_eq!(p.u32().unwrap(), 5);
+        assert_eq!(p.utf8_string().unwrap(), "exec");
+        assert!(p.bool().unwrap());
+        assert_eq!(p.string().unwrap(), b"uname -a");
+        assert!(p.remaining().is_empty());
+
+        // Like all other operations, it is dropped once we have closed the channel.
+        state.do_operation(number.construct_op(ChannelOperationKind::Close));
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_CLOSE]);
+        assert_eq!(state.do_operation(exec()), OperationResult::Done);
+        assert_response_types(state, &[]);
+    }
+
+    #[test]
+    fn window_change_after_shell() {
+        let client = &mut ChannelsState::new(false);
+        let server = &mut ChannelsState::new(true);
+        let pump = |from: &mut ChannelsState, to: &mut ChannelsState| {
+            for packet in from.packets_to_send().collect::<Vec<_>>() {
+                to.recv_packet(packet).unwrap();
+            }
+        };
+
+        let number = client.create_channel(ChannelKind::Session);
+        pump(client, server);
+        let open = server.next_channel_update().unwrap();
+        pump(server, client);
+        let _confirmed = client.next_channel_update().unwrap();
+
+        let request = |req| number.construct_op(ChannelOperationKind::Request(req));
+        client.do_operation(request(ChannelRequest::PtyReq {
+            want_reply: false,
+            term: "xterm".to_owned(),
+            width_chars: 80,
+            height_rows: 24,
+            width_px: 0,
+            height_px: 0,
+            term_modes: vec![],
+            input_speed: None,
+            output_speed: None,
+        }));
+        client.do_operation(request(ChannelRequest::Shell { want_reply: false }));
+        client.do_operation(request(ChannelRequest::WindowChange {
+            width_chars: 120,
+            height_rows: 40,
+            width_px: 960,
+            height_px: 640,
+        }));
+
+        let packets = client.packets_to_send().collect::<Vec<_>>();
+        let mut p = packets[2].payload_parser();
+        assert_eq!(p.u8().unwrap(), numbers::SSH_MSG_CHANNEL_REQUEST);
+        assert_eq!(p.u32().unwrap(), open.number.0);
+        assert_eq!(p.utf8_string().unwrap(), "window-change");
+        // Never wants a reply.
+        assert!(!p.bool().unwrap());
+        for packet in packets {
+            server.recv_packet(packet).unwrap();
+        }
+
+        let kinds = std::iter::from_fn(|| server.next_channel_update())
+            .map(|update| update.kind)
+            .collect::<Vec<_>>();
+        assert!(matches!(
+            kinds.as_slice(),
+            [
+                ChannelUpdateKind::Request(ChannelRequest::PtyReq { .. }),
+                ChannelUpdateKind::Request(ChannelRequest::Shell { .. }),
+                ChannelUpdateKind::Request(ChannelRequest::WindowChange {
+                    width_chars: 120,
+                    height_rows: 40,
+                    width_px: 960,
+                    height_px: 640,
+                }),
+            ]
+        ));
+        assert_response_types(server, &[]);
+    }
+
+    #[test]
+    fn exit_signal() {
+        let server = &mut ChannelsState::new(true);
+        let client = &mut ChannelsState::new(false);
+        let number = client.create_channel(ChannelKind::Session);
+        for packet in client.packets_to_send().collect::<Vec<_>>() {
+            server.recv_packet(packet).unwrap();
+        }
+        let open = server.next_channel_update().unwrap();
+        for packet in server.packets_to_send().collect::<Vec<_>>() {
+            client.recv_packet(packet).unwrap();
+        }
+        let _confirmed = client.next_channel_update().unwrap();
+
+        server.do_operation(open.number.construct_op(ChannelOperationKind::Request(
+            ChannelRequest::ExitSignal {
+                signal_name: "SEGV".to_owned(),
+                core_dumped: true,
+                error_message: "Segmentation fault".to_owned(),
+            },
+        )));
+        let packets = server.packets_to_send().collect::<Vec<_>>();
+        assert_eq!(packets.len(), 1);
+        let mut p = packets[0].payload_parser();
+        assert_eq!(p.u8().unwrap(), numbers::SSH_MSG_CHANNEL_REQUEST);
+        assert_eq!(p.u32().unwrap(), number.0);
+        assert_eq!(p.utf8_string().unwrap(), "exit-signal");
+        assert!(!p.bool().unwrap());
+        assert_eq!(p.utf8_string().unwrap(), "SEGV");
+        assert!(p.bool().unwrap());
+        assert_eq!(p.utf8_string().unwrap(), "Segmentation fault");
+        assert_eq!(p.utf8_string().unwrap(), "");
+        assert!(p.remaining().is_empty());
+
+        for packet in packets {
+            client.recv_packet(packet).unwrap();
+        }
+        let update = client.next_channel_update().unwrap();
+        assert_eq!(update.number, number);
+        assert!(matches!(
+            update.kind,
+            ChannelUpdateKind::Request(ChannelRequest::ExitSignal {
+                ref signal_name,
+                core_dumped: true,
+                ref error_message,
+            }) if signal_name == "SEGV" && error_message == "Segmentation fault"
+        ));
+        assert_response_types(client, &[]);
+
+        // Only servers send it.
+        let mut w = Writer::new();
+        w.u8(numbers::SSH_MSG_CHANNEL_REQUEST);
+        w.u32(open.number.0);
+        w.string(b"exit-signal");
+        w.bool(false);
+        w.string(b"KILL");
+        w.bool(false);
+        w.string(b"");
+        w.string(b"");
+        assert!(server
+            .recv_packet(Packet {
+                payload: w.finish()
+            })
+            .is_err());
+    }
+
+    #[test]
+    fn subsystem_operation() {
+        let client = &mut ChannelsState::new(false);
+        let server = &mut ChannelsState::with_config(
+            true,
+            ChannelConfig {
+                allowed_subsystems: HashSet::from(["netconf".to_owned()]),
+                ..Default::default()
+            },
+        );
+        let pump = |from: &mut ChannelsState, to: &mut ChannelsState| {
+            for packet in from.packets_to_send().collect::<Vec<_>>() {
+                to.recv_packet(packet).unwrap();
+            }
+        };
+
+        let number = client.create_channel(ChannelKind::Session);
+        pump(client, server);
+        let open = server.next_channel_update().unwrap();
+        pump(server, client);
+        assert!(matches!(
+            client.next_channel_update().unwrap().kind,
+            ChannelUpdateKind::OpenConfirmed { .. }
+        ));
+
+        client.do_operation(number.construct_op(ChannelOperationKind::Request(
+            ChannelRequest::Subsystem {
+                want_reply: true,
+                name: "netconf".to_owned(),
+            },
+        )));
+        pump(client, server);
+        let update = server.next_channel_update().unwrap();
+        assert_eq!(update.number, open.number);
+        assert!(matches!(
+            update.kind,
+            ChannelUpdateKind::Request(ChannelRequest::Subsystem {
+                want_reply: true,
+                ref name,
+            }) if name == "netconf"
+        ));
+
+        server.do_operation(open.number.construct_op(ChannelOperationKind::Success));
+        pump(server, client);
+        assert!(matches!(
+            client.next_channel_update().unwrap().kind,
+            ChannelUpdateKind::Success
+        ));
+    }
+
+    #[test]
+    fn env_operation() {
+        let state = &mut ChannelsState::new(false);
+        let number = state.create_channel(ChannelKind::Session);
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_OPEN]);
+        state
+            .recv_packet(Packet::new_msg_channel_open_confirmation(
+                number.0, 5, 4096, 512,
+            ))
             .unwrap();
-        // 10..60, 60..100
-        assert_response_types(
-            state,
-            &[numbers::SSH_MSG_CHANNEL_DATA, numbers::SSH_MSG_CHANNEL_DATA],
+
+        let env = |name: &str, value: &[u8]| {
+            number.construct_op(ChannelOperationKind::Request(ChannelRequest::Env {
+                want_reply: false,
+                name: name.to_owned(),
+                value: value.to_vec(),
+            }))
+        };
+        state.do_operation(env("LANG", b"en_US.UTF-8"));
+        state.do_operation(env("TZ", b"UTC"));
+        state.do_operation(number.construct_op(ChannelOperationKind::Request(
+            ChannelRequest::Shell { want_reply: true },
+        )));
+
+        let packets = state.packets_to_send().collect::<Vec<_>>();
+        assert_eq!(packets.len(), 3);
+        for (packet, (name, value)) in packets.iter().zip([("LANG", "en_US.UTF-8"), ("TZ", "UTC")])
+        {
+            let mut p = packet.payload_parser();
+            assert_eq!(p.u8().unwrap(), numbers::SSH_MSG_CHANNEL_REQUEST);
+            assert_eq!(p.u32().unwrap(), 5);
+            assert_eq!(p.utf8_string().unwrap(), "env");
+            assert!(!p.bool().unwrap());
+            assert_eq!(p.utf8_string().unwrap(), name);
+            assert_eq!(p.utf8_string().unwrap(), value);
+            assert!(p.remaining().is_empty());
+        }
+        let mut p = packets[2].payload_parser();
+        assert_eq!(p.u8().unwrap(), numbers::SSH_MSG_CHANNEL_REQUEST);
+        assert_eq!(p.u32().unwrap(), 5);
+        assert_eq!(p.utf8_string().unwrap(), "shell");
+        assert!(p.bool().unwrap());
+    }
+
+    #[test]
+    fn channel_info() {
+        let state = &mut ChannelsState::new(true);
+        state
+            .recv_packet(Packet::new_msg_channel_open_session(
+                b"session", 42, 2048, 1024,
+            ))
+            .unwrap();
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_OPEN_CONFIRMATION]);
+
+        let update = state.next_channel_update().unwrap();
+        assert_eq!(
+            state.channel_info(update.number),
+            Some(ChannelInfo {
+                peer_channel: 42,
+                peer_window_size: 2048,
+                peer_max_packet_size: 1024,
+                our_window_size: 2097152,
+                our_max_packet_size: 32768,
+                window_exhaustions: 0,
+            })
         );
 
+        // Channels that we are opening only have information once they are confirmed.
+        let number = state.create_channel(ChannelKind::Session);
+        assert_eq!(state.channel_info(number), None);
         state
-            .recv_packet(Packet::new_msg_channel_window_adjust(0, 100))
+            .recv_packet(Packet::new_msg_channel_open_confirmation(
+                number.0, 7, 4096, 512,
+            ))
             .unwrap();
-        // 100..150, 150..20
+        assert_eq!(state.channel_info(number).unwrap().peer_channel, 7);
+
+        state
+            .recv_packet(Packet::new_msg_channel_close(number.0))
+            .unwrap();
+        assert_eq!(state.channel_info(number), None);
+    }
+
+    #[test]
+    fn open_confirmation_advertises_our_window() {
+        let state = &mut ChannelsState::with_config(
+            true,
+            ChannelConfig {
+                initial_window_size: Some(100_000),
+                max_packet_size: 4096,
+                ..Default::default()
+            },
+        );
+        state
+            .recv_packet(Packet::new_msg_channel_open_session(
+                b"session", 3, 2048, 1024,
+            ))
+            .unwrap();
+
+        let packets = state.packets_to_send().collect::<Vec<_>>();
+        assert_eq!(packets.len(), 1);
+        let mut p = packets[0].payload_parser();
+        assert_eq!(p.u8().unwrap(), numbers::SSH_MSG_CHANNEL_OPEN_CONFIRMATION);
+        assert_eq!(p.u32().unwrap(), 3);
+        assert_eq!(p.u32().unwrap(), 0);
+        assert_eq!(p.u32().unwrap(), 100_000);
+        assert_eq!(p.u32().unwrap(), 4096);
+
+        let info = state.channel_info(ChannelNumber(0)).unwrap();
+        assert_eq!(info.our_window_size, 100_000);
+        assert_eq!(info.our_max_packet_size, 4096);
+        assert_eq!(info.peer_window_size, 2048);
+        assert_eq!(info.peer_max_packet_size, 1024);
+    }
+
+    #[test]
+    fn channel_open_advertises_our_window() {
+        let state = &mut ChannelsState::with_config(
+            false,
+            ChannelConfig {
+                initial_window_size: Some(16 * 1024 * 1024),
+                max_packet_size: 1024,
+                ..Default::default()
+            },
+        );
+        let number = state.create_channel(ChannelKind::Session);
+
+        let packets = state.packets_to_send().collect::<Vec<_>>();
+        assert_eq!(packets.len(), 1);
+        let mut p = packets[0].payload_parser();
+        assert_eq!(p.u8().unwrap(), numbers::SSH_MSG_CHANNEL_OPEN);
+        assert_eq!(p.utf8_string().unwrap(), "session");
+        assert_eq!(p.u32().unwrap(), number.0);
+        assert_eq!(p.u32().unwrap(), 16 * 1024 * 1024);
+        assert_eq!(p.u32().unwrap(), 1024);
+
+        state
+            .recv_packet(Packet::new_msg_channel_open_confirmation(
+                number.0, 9, 2048, 512,
+            ))
+            .unwrap();
+        let info = state.channel_info(number).unwrap();
+        assert_eq!(info.our_window_size, 16 * 1024 * 1024);
+        assert_eq!(info.our_max_packet_size, 1024);
+        assert_eq!(info.peer_window_size, 2048);
+        assert_eq!(info.peer_max_packet_size, 512);
+    }
+
+    #[test]
+    fn read_pressure() {
+        let state = &mut ChannelsState::with_config(
+            true,
+            ChannelConfig {
+                read_pressure_after: Some(2),
+                initial_window_size: Some(2048),
+                ..Default::default()
+            },
+        );
+        open_session_channel(state);
+        let _open = state.next_channel_update().unwrap();
+
+        let mut pressure = Vec::new();
+        for _ in 0..5 {
+            // Two full packets exhaust the window of 2048, which is then adjusted again.
+            for _ in 0..2 {
+                state
+                    .recv_packet(Packet::new_msg_channel_data(0, &[0; 1024]))
+                    .unwrap();
+            }
+            assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_WINDOW_ADJUST]);
+            while let Some(update) = state.next_channel_update() {
+                match update.kind {
+                    ChannelUpdateKind::Data { .. } => {}
+                    ChannelUpdateKind::ReadPressure { window_exhaustions } => {
+                        pressure.push(window_exhaustions)
+                    }
+                    kind => panic!("unexpected update: {kind:?}"),
+                }
+            }
+        }
+        assert_eq!(pressure, [2, 4]);
+        assert_eq!(
+            state
+                .channel_info(ChannelNumber(0))
+                .unwrap()
+                .window_exhaustions,
+            5
+        );
+
+        // Data that leaves some of the window is not an exhaustion.
+        state
+            .recv_packet(Packet::new_msg_channel_data(0, &[0; 1000]))
+            .unwrap();
+        assert_eq!(
+            state
+                .channel_info(ChannelNumber(0))
+                .unwrap()
+                .window_exhaustions,
+            5
+        );
+    }
+
+    #[test]
+    fn tiny_peer_max_packet_size() {
+        let state = &mut ChannelsState::new(true);
+        state
+            .recv_packet(Packet::new_msg_channel_open_session(b"session", 0, 2048, 1))
+            .unwrap();
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_OPEN_FAILURE]);
+        assert!(state.next_channel_update().is_none());
+
+        let number = state.create_channel(ChannelKind::Session);
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_OPEN]);
+        assert!(state
+            .recv_packet(Packet::new_msg_channel_open_confirmation(
+                number.0, 0, 2048, 1,
+            ))
+            .is_err());
+    }
+
+    /// A consumer that only allows shells, as an example for testing with [`ChannelsState::push_channel_update`].
+    fn handle_shell_requests(state: &mut ChannelsState) -> Vec<ChannelNumber> {
+        let mut shells = Vec::new();
+        while let Some(update) = state.next_channel_update() {
+            let ChannelUpdateKind::Request(req) = update.kind else {
+                continue;
+            };
+            let reply = match req {
+                ChannelRequest::Shell { want_reply } => {
+                    shells.push(update.number);
+                    want_reply.then_some(ChannelOperationKind::Success)
+                }
+                ChannelRequest::Exec { want_reply, .. } => {
+                    want_reply.then_some(ChannelOperationKind::Failure)
+                }
+                _ => None,
+            };
+            if let Some(reply) = reply {
+                state.do_operation(update.number.construct_op(reply));
+            }
+        }
+        shells
+    }
+
+    #[test]
+    fn pushed_channel_updates() {
+        let state = &mut ChannelsState::new(true);
+        for kind in [
+            ChannelUpdateKind::Open(ChannelKind::Session),
+            ChannelUpdateKind::Request(ChannelRequest::Exec {
+                want_reply: true,
+                command: b"uptime".to_vec(),
+            }),
+            ChannelUpdateKind::Request(ChannelRequest::Shell { want_reply: true }),
+        ] {
+            state.push_channel_update(ChannelUpdate {
+                number: ChannelNumber(3),
+                kind,
+            });
+        }
+
+        assert_eq!(handle_shell_requests(state), [ChannelNumber(3)]);
         assert_response_types(
             state,
-            &[numbers::SSH_MSG_CHANNEL_DATA, numbers::SSH_MSG_CHANNEL_DATA],
+            &[
+                numbers::SSH_MSG_CHANNEL_FAILURE,
+                numbers::SSH_MSG_CHANNEL_SUCCESS,
+            ],
+        );
+
+        // Channels that the peer opens later don't collide with the pushed one.
+        open_session_channel(state);
+        let update = state.next_channel_update().unwrap();
+        assert_eq!(update.number, ChannelNumber(4));
+    }
+
+    #[test]
+    fn pushed_channel_uses_config() {
+        let state = &mut ChannelsState::with_config(
+            true,
+            ChannelConfig {
+                initial_window_size: Some(4096),
+                max_packet_size: 1024,
+                ..Default::default()
+            },
+        );
+        state.push_channel_update(ChannelUpdate {
+            number: ChannelNumber(0),
+            kind: ChannelUpdateKind::Open(ChannelKind::Session),
+        });
+        let channel = state.channel(ChannelNumber(0)).unwrap();
+        assert_eq!(channel.our_window_size, 4096);
+        assert_eq!(channel.our_max_packet_size, 1024);
+
+        // Data from the imaginary peer is checked against our window like any other.
+        state
+            .recv_packet(Packet::new_msg_channel_data(0, &[0; 1025]))
+            .unwrap_err();
+    }
+
+    #[test]
+    fn no_more_sessions() {
+        let state = &mut ChannelsState::with_config(
+            false,
+            ChannelConfig {
+                no_more_sessions: true,
+                ..Default::default()
+            },
         );
 
+        // Before our session has been opened, the peer may still open one.
+        open_session_channel(state);
+        state.drain_updates().for_each(drop);
+
+        let number = state.create_channel(ChannelKind::Session);
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_OPEN]);
         state
-            .recv_packet(Packet::new_msg_channel_window_adjust(0, 100))
+            .recv_packet(Packet::new_msg_channel_open_confirmation(
+                number.0, 5, 2048, 1024,
+            ))
+            .unwrap();
+        let packets = state.packets_to_send().collect::<Vec<_>>();
+        assert_eq!(
+            packets,
+            [Packet::new_msg_global_request(
+                b"no-more-sessions@openssh.com",
+                false
+            )]
+        );
+
+        state
+            .recv_packet(Packet::new_msg_channel_open_session(
+                b"session", 6, 2048, 1024,
+            ))
+            .unwrap();
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_OPEN_FAILURE]);
+        assert!(matches!(
+            state.next_channel_update().unwrap().kind,
+            ChannelUpdateKind::OpenConfirmed { .. }
+        ));
+        assert!(state.next_channel_update().is_none());
+
+        // It is only sent once.
+        let number = state.create_channel(ChannelKind::Session);
+        state
+            .recv_packet(Packet::new_msg_channel_open_confirmation(
+                number.0, 7, 2048, 1024,
+            ))
+            .unwrap();
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_OPEN]);
+    }
+
+    #[test]
+    fn batched_data_is_coalesced() {
+        let state = &mut ChannelsState::new(true);
+        open_session_channel(state);
+
+        let data = |byte| ChannelNumber(0).construct_op(ChannelOperationKind::Data(vec![byte; 10]));
+        let result = state.do_operations([
+            data(1),
+            data(2),
+            data(3),
+            ChannelNumber(0).construct_op(ChannelOperationKind::ExtendedData(1, vec![4; 10])),
+            ChannelNumber(0).construct_op(ChannelOperationKind::ExtendedData(1, vec![5; 10])),
+            data(6),
+            ChannelNumber(0).construct_op(ChannelOperationKind::Eof),
+        ]);
+        assert_eq!(result, OperationResult::Done);
+
+        let packets = state.packets_to_send().collect::<Vec<_>>();
+        let types = packets
+            .iter()
+            .map(|packet| packet.packet_type())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            types,
+            [
+                numbers::SSH_MSG_CHANNEL_DATA,
+                numbers::SSH_MSG_CHANNEL_EXTENDED_DATA,
+                numbers::SSH_MSG_CHANNEL_DATA,
+                numbers::SSH_MSG_CHANNEL_EOF,
+            ]
+        );
+
+        let mut p = packets[0].payload_parser();
+        p.u8().unwrap();
+        p.u32().unwrap();
+        let combined = [vec![1; 10], vec![2; 10], vec![3; 10]].concat();
+        assert_eq!(p.string().unwrap(), combined);
+    }
+
+    #[test]
+    fn extended_data_backpressure() {
+        let state = &mut ChannelsState::with_config(
+            true,
+            ChannelConfig {
+                max_queued_data: 100,
+                min_peer_max_packet_size: 0,
+                ..Default::default()
+            },
+        );
+        state
+            .recv_packet(Packet::new_msg_channel_open_session(b"session", 0, 10, 50))
             .unwrap();
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_OPEN_CONFIRMATION]);
+
+        // 10 bytes are sent, 90 are queued.
+        let result = state.do_operation(
+            ChannelNumber(0).construct_op(ChannelOperationKind::ExtendedData(1, vec![0; 100])),
+        );
+        assert_eq!(result, OperationResult::Done);
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_EXTENDED_DATA]);
+
+        let result = state.do_operation(
+            ChannelNumber(0).construct_op(ChannelOperationKind::ExtendedData(1, vec![0; 20])),
+        );
+        assert_eq!(result, OperationResult::WouldBlock);
+
+        // The limit applies to every stream separately.
+        let result = state.do_operation(
+            ChannelNumber(0).construct_op(ChannelOperationKind::ExtendedData(2, vec![0; 20])),
+        );
+        assert_eq!(result, OperationResult::Done);
+        let result = state
+            .do_operation(ChannelNumber(0).construct_op(ChannelOperationKind::Data(vec![0; 20])));
+        assert_eq!(result, OperationResult::Done);
         assert_response_types(state, &[]);
     }
 
     #[test]
+    #[allow(clippy::useless_vec)]
     fn send_windowing_adjustments() {
-        let state = &mut ChannelsState::new(true);
+        let state = &mut ChannelsState::with_config(
+            true,
+            ChannelConfig {
+                initial_window_size: Some(2000),
+                max_packet_size: 2000,
+                ..Default::default()
+            },
+        );
         state
             .recv_packet(Packet::new_msg_channel_open_session(
                 b"session", 0, 2000, 2000,
@@ -981,4 +4296,183 @@ mod tests {
             .unwrap();
         assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_WINDOW_ADJUST]);
     }
+
+    #[test]
+    fn terminal_modes() {
+        // VINTR = ^C, ISIG = 1, TTY_OP_END, and garbage after the end.
+        let modes = TerminalModes::parse(&[1, 0, 0, 0, 3, 50, 0, 0, 0, 1, 0, 2, 0, 0, 0, 4]);
+        assert_eq!(modes.modes, [(1, 3), (50, 1)]);
+    }
+
+    #[test]
+    fn pty_req_speeds() {
+        let state = &mut ChannelsState::new(true);
+        open_session_channel(state);
+        let _open = state.next_channel_update().unwrap();
+
+        let mut modes = vec![TerminalModes::TTY_OP_ISPEED];
+        modes.extend_from_slice(&115200_u32.to_be_bytes());
+        modes.push(TerminalModes::TTY_OP_OSPEED);
+        modes.extend_from_slice(&115200_u32.to_be_bytes());
+        modes.push(TerminalModes::TTY_OP_END);
+        state
+            .recv_packet(Packet::new_msg_channel_request_pty_req(
+                0, b"pty-req", false, b"vt100", 80, 24, 0, 0, &modes,
+            ))
+            .unwrap();
+
+        let update = state.next_channel_update().unwrap();
+        let ChannelUpdateKind::Request(ChannelRequest::PtyReq {
+            term_modes,
+            input_speed,
+            output_speed,
+            ..
+        }) = update.kind
+        else {
+            panic!("expected pty-req: {update:?}");
+        };
+        assert_eq!(term_modes, modes);
+        assert_eq!(input_speed, Some(115200));
+        assert_eq!(output_speed, Some(115200));
+
+        // Without the speed modes.
+        state
+            .recv_packet(Packet::new_msg_channel_request_pty_req(
+                0,
+                b"pty-req",
+                false,
+                b"vt100",
+                80,
+                24,
+                0,
+                0,
+                &[1, 0, 0, 0, 3],
+            ))
+            .unwrap();
+        let update = state.next_channel_update().unwrap();
+        assert!(matches!(
+            update.kind,
+            ChannelUpdateKind::Request(ChannelRequest::PtyReq {
+                input_speed: None,
+                output_speed: None,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn terminal_modes_without_end() {
+        let modes = TerminalModes::parse(&[1, 0, 0, 0, 3, 50, 0, 0, 0, 1]);
+        assert_eq!(modes.modes, [(1, 3), (50, 1)]);
+    }
+
+    #[test]
+    fn terminal_modes_trailing_byte() {
+        let modes = TerminalModes::parse(&[1, 0, 0, 0, 3, 50]);
+        assert_eq!(modes.modes, [(1, 3)]);
+
+        let modes = TerminalModes::parse(&[1, 0, 0]);
+        assert_eq!(modes.modes, []);
+    }
+
+    #[test]
+    fn drained_after_window_adjust() {
+        let state = &mut ChannelsState::with_config(
+            true,
+            ChannelConfig {
+                min_peer_max_packet_size: 0,
+                ..Default::default()
+            },
+        );
+        state
+            .recv_packet(Packet::new_msg_channel_open_session(b"session", 0, 10, 50))
+            .unwrap();
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_OPEN_CONFIRMATION]);
+        state.next_channel_update().unwrap();
+
+        state.do_operation(ChannelNumber(0).construct_op(ChannelOperationKind::Data(vec![0; 20])));
+        state.do_operation(
+            ChannelNumber(0).construct_op(ChannelOperationKind::ExtendedData(1, vec![0; 10])),
+        );
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_DATA]);
+
+        // Only the default data fits.
+        state
+            .recv_packet(Packet::new_msg_channel_window_adjust(0, 10))
+            .unwrap();
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_DATA]);
+        assert!(state.next_channel_update().is_none());
+
+        state
+            .recv_packet(Packet::new_msg_channel_window_adjust(0, 100))
+            .unwrap();
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_EXTENDED_DATA]);
+        let update = state.next_channel_update().unwrap();
+        assert!(matches!(update.kind, ChannelUpdateKind::Drained));
+
+        // Nothing was queued this time, so there is nothing to report.
+        state
+            .recv_packet(Packet::new_msg_channel_window_adjust(0, 100))
+            .unwrap();
+        assert!(state.next_channel_update().is_none());
+    }
+
+    #[test]
+    fn resume_after_backpressure() {
+        let state = &mut ChannelsState::with_config(
+            true,
+            ChannelConfig {
+                max_queued_data: 100,
+                min_peer_max_packet_size: 0,
+                ..Default::default()
+            },
+        );
+        state
+            .recv_packet(Packet::new_msg_channel_open_session(b"session", 0, 10, 50))
+            .unwrap();
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_OPEN_CONFIRMATION]);
+
+        let mut sent = Vec::new();
+        let mut collect_sent = |state: &mut ChannelsState| {
+            for packet in state.packets_to_send() {
+                assert_eq!(packet.packet_type(), numbers::SSH_MSG_CHANNEL_DATA);
+                sent.extend_from_slice(&packet.payload[9..]);
+            }
+        };
+
+        let data = (0_u8..230).collect::<Vec<_>>();
+        let result = state.do_operation(
+            ChannelNumber(0).construct_op(ChannelOperationKind::Data(data[..200].to_vec())),
+        );
+        assert_eq!(result, OperationResult::WouldBlock);
+        assert_eq!(
+            state.resume_channel(ChannelNumber(0)),
+            OperationResult::WouldBlock
+        );
+
+        // 190 bytes are queued, 40 are left after this.
+        state
+            .recv_packet(Packet::new_msg_channel_window_adjust(0, 150))
+            .unwrap();
+        collect_sent(state);
+        assert_eq!(
+            state.resume_channel(ChannelNumber(0)),
+            OperationResult::Done
+        );
+
+        let result = state.do_operation(
+            ChannelNumber(0).construct_op(ChannelOperationKind::Data(data[200..].to_vec())),
+        );
+        assert_eq!(result, OperationResult::Done);
+
+        state
+            .recv_packet(Packet::new_msg_channel_window_adjust(0, 1000))
+            .unwrap();
+        collect_sent(state);
+        assert_eq!(sent, data);
+        assert_eq!(
+            state.resume_channel(ChannelNumber(0)),
+            OperationResult::Done
+        );
+    }
 }
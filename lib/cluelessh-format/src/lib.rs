@@ -145,7 +145,12 @@ impl Writer {
         crypto_bigint::Uint<LIMBS>: crypto_bigint::ArrayEncoding,
     {
         let bytes = crypto_bigint::ArrayEncoding::to_be_byte_array(&uint);
-        let (bytes, pad_zero) = fixup_mpint(&bytes);
+        self.mpint_bytes(&bytes);
+    }
+
+    /// Writes a big-endian unsigned integer as an mpint.
+    pub fn mpint_bytes(&mut self, bytes: &[u8]) {
+        let (bytes, pad_zero) = fixup_mpint(bytes);
         let len = bytes.len() + (pad_zero as usize);
         self.u32(len as u32);
         if pad_zero {
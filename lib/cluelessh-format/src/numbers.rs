@@ -59,6 +59,8 @@ consts! {
 
     //  60 to 79   User authentication method specific (numbers can be reused for different authentication methods)
     const SSH_MSG_USERAUTH_PK_OK = 60;
+    const SSH_MSG_USERAUTH_INFO_REQUEST = 60; // Same number
+    const SSH_MSG_USERAUTH_INFO_RESPONSE = 61;
 
     // -----
     // Connection protocol:
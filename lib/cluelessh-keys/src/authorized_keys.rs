@@ -1,7 +1,26 @@
 use crate::public::{PublicKey, PublicKeyWithComment};
 
 pub struct AuthorizedKeys {
-    pub keys: Vec<PublicKeyWithComment>,
+    pub keys: Vec<AuthorizedKey>,
+}
+
+/// A single line of an authorized_keys file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuthorizedKey {
+    pub options: AuthorizedKeyOptions,
+    pub key: PublicKeyWithComment,
+}
+
+/// The restrictions placed on a key in the authorized_keys file.
+/// See the AUTHORIZED_KEYS FILE FORMAT section of sshd(8).
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct AuthorizedKeyOptions {
+    /// `command="..."`: The command that is executed instead of whatever the client requested.
+    pub command: Option<String>,
+    /// `no-pty`: Pseudo-terminal allocation is forbidden.
+    pub no_pty: bool,
+    /// `no-port-forwarding`: Opening TCP forwarding channels is forbidden.
+    pub no_port_forwarding: bool,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -11,54 +30,146 @@ pub struct Error(String);
 impl AuthorizedKeys {
     pub fn parse(authorized_keys: &str) -> Result<Self, Error> {
         let lines = authorized_keys.lines();
-        let mut keys: Vec<PublicKeyWithComment> = Vec::new();
+        let mut keys: Vec<AuthorizedKey> = Vec::new();
 
         for line in lines {
-            let key = line
-                .parse::<PublicKeyWithComment>()
-                .map_err(|err| Error(err.0))?;
-            keys.push(key);
+            keys.push(AuthorizedKey::parse(line)?);
         }
 
         Ok(Self { keys })
     }
 
-    pub fn contains(&self, provided_key: &PublicKey) -> Option<&PublicKeyWithComment> {
-        self.keys.iter().find(|key| key.key == *provided_key)
+    pub fn contains(&self, provided_key: &PublicKey) -> Option<&AuthorizedKey> {
+        self.keys.iter().find(|key| key.key.key == *provided_key)
+    }
+}
+
+impl AuthorizedKey {
+    /// Parses a single line of an authorized_keys file, with the options in front of the key.
+    pub fn parse(line: &str) -> Result<Self, Error> {
+        let (options, key) = if starts_with_key_type(line) {
+            (AuthorizedKeyOptions::default(), line)
+        } else {
+            let (options, rest) = split_options(line)?;
+            (AuthorizedKeyOptions::parse(options)?, rest)
+        };
+
+        let key = key
+            .parse::<PublicKeyWithComment>()
+            .map_err(|err| Error(err.0))?;
+        Ok(Self { options, key })
+    }
+}
+
+impl AuthorizedKeyOptions {
+    /// Parses the comma-separated option list at the start of a line.
+    fn parse(options: &str) -> Result<Self, Error> {
+        let mut result = Self::default();
+
+        for option in split_unquoted(options, |c| c == ',') {
+            let (name, value) = match option.split_once('=') {
+                Some((name, value)) => (name, Some(unquote(value)?)),
+                None => (option, None),
+            };
+
+            match (name.to_ascii_lowercase().as_str(), value) {
+                ("command", Some(command)) => result.command = Some(command),
+                ("no-pty", None) => result.no_pty = true,
+                ("pty", None) => result.no_pty = false,
+                ("no-port-forwarding", None) => result.no_port_forwarding = true,
+                ("port-forwarding", None) => result.no_port_forwarding = false,
+                ("restrict", None) => {
+                    result.no_pty = true;
+                    result.no_port_forwarding = true;
+                }
+                // Ignoring an option could grant more access than intended, so refuse the entire line.
+                _ => return Err(Error(format!("unsupported option: {option}"))),
+            }
+        }
+
+        Ok(result)
     }
 }
 
+fn starts_with_key_type(line: &str) -> bool {
+    ["ssh-", "ecdsa-", "sk-"]
+        .iter()
+        .any(|prefix| line.starts_with(prefix))
+}
+
+/// Splits a line into the options and the rest at the first whitespace outside of quotes.
+fn split_options(line: &str) -> Result<(&str, &str), Error> {
+    let options = split_unquoted(line, |c| c.is_ascii_whitespace())
+        .next()
+        .unwrap_or_default();
+    if options.len() == line.len() {
+        return Err(Error("missing key after options".to_owned()));
+    }
+    Ok((options, line[options.len()..].trim_start()))
+}
+
+/// Splits at every `is_separator` that is not inside double quotes.
+fn split_unquoted(s: &str, is_separator: impl Fn(char) -> bool) -> impl Iterator<Item = &str> {
+    let mut in_quotes = false;
+    let mut escaped = false;
+    s.split(move |c: char| {
+        if escaped {
+            escaped = false;
+            return false;
+        }
+        match c {
+            '\\' if in_quotes => escaped = true,
+            '"' => in_quotes = !in_quotes,
+            _ => return !in_quotes && is_separator(c),
+        }
+        false
+    })
+}
+
+fn unquote(value: &str) -> Result<String, Error> {
+    let inner = value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .ok_or_else(|| Error(format!("option value must be quoted: {value}")))?;
+    Ok(inner.replace("\\\"", "\""))
+}
+
 #[cfg(test)]
 mod tests {
     use crate::public::{PublicKey, PublicKeyWithComment};
 
-    use super::AuthorizedKeys;
+    use super::{AuthorizedKey, AuthorizedKeyOptions, AuthorizedKeys};
 
     #[test]
+    #[allow(clippy::useless_conversion)]
     fn parse_single() {
         let keys = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIG0n1ikUG9rYqobh7WpAyXrqZqxQoQ2zNJrFPj12gTpP nora\n";
         let keys = AuthorizedKeys::parse(keys).unwrap();
         assert_eq!(
             keys.keys.as_slice(),
-            [PublicKeyWithComment {
-                key: PublicKey::Ed25519 {
-                    public_key: ed25519_dalek::VerifyingKey::from_bytes(
-                        &[
-                            109, 39, 214, 41, 20, 27, 218, 216, 170, 134, 225, 237, 106, 64, 201,
-                            122, 234, 102, 172, 80, 161, 13, 179, 52, 154, 197, 62, 61, 118, 129,
-                            58, 79,
-                        ]
-                        .try_into()
-                        .unwrap()
-                    )
-                    .unwrap(),
+            [AuthorizedKey {
+                options: AuthorizedKeyOptions::default(),
+                key: PublicKeyWithComment {
+                    key: PublicKey::Ed25519 {
+                        public_key: ed25519_dalek::VerifyingKey::from_bytes(
+                            &[
+                                109, 39, 214, 41, 20, 27, 218, 216, 170, 134, 225, 237, 106, 64,
+                                201, 122, 234, 102, 172, 80, 161, 13, 179, 52, 154, 197, 62, 61,
+                                118, 129, 58, 79,
+                            ]
+                            .try_into()
+                            .unwrap()
+                        )
+                        .unwrap(),
+                    },
+                    comment: "nora".into(),
                 },
-                comment: "nora".into(),
             }]
         );
     }
 
     #[test]
+    #[allow(clippy::useless_conversion)]
     fn contains() {
         let keys = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIG0n1ikUG9rYqobh7WpAyXrqZqxQoQ2zNJrFPj12gTpP nora\n";
         let keys = AuthorizedKeys::parse(keys).unwrap();
@@ -99,26 +210,30 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::useless_conversion)]
     fn no_comment() {
         let keys =
             "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIG0n1ikUG9rYqobh7WpAyXrqZqxQoQ2zNJrFPj12gTpP\n";
         let keys = AuthorizedKeys::parse(keys).unwrap();
         assert_eq!(
             keys.keys.as_slice(),
-            [PublicKeyWithComment {
-                key: PublicKey::Ed25519 {
-                    public_key: ed25519_dalek::VerifyingKey::from_bytes(
-                        &[
-                            109, 39, 214, 41, 20, 27, 218, 216, 170, 134, 225, 237, 106, 64, 201,
-                            122, 234, 102, 172, 80, 161, 13, 179, 52, 154, 197, 62, 61, 118, 129,
-                            58, 79,
-                        ]
-                        .try_into()
-                        .unwrap()
-                    )
-                    .unwrap(),
+            [AuthorizedKey {
+                options: AuthorizedKeyOptions::default(),
+                key: PublicKeyWithComment {
+                    key: PublicKey::Ed25519 {
+                        public_key: ed25519_dalek::VerifyingKey::from_bytes(
+                            &[
+                                109, 39, 214, 41, 20, 27, 218, 216, 170, 134, 225, 237, 106, 64,
+                                201, 122, 234, 102, 172, 80, 161, 13, 179, 52, 154, 197, 62, 61,
+                                118, 129, 58, 79,
+                            ]
+                            .try_into()
+                            .unwrap()
+                        )
+                        .unwrap(),
+                    },
+                    comment: "".into(),
                 },
-                comment: "".into(),
             }]
         );
     }
@@ -145,4 +260,40 @@ mod tests {
         let keys = AuthorizedKeys::parse(keys);
         assert!(keys.is_err());
     }
+
+    #[test]
+    fn options() {
+        let keys = "command=\"echo \\\"hi, there\\\"\",no-pty ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIG0n1ikUG9rYqobh7WpAyXrqZqxQoQ2zNJrFPj12gTpP nora\n";
+        let keys = AuthorizedKeys::parse(keys).unwrap();
+        assert_eq!(
+            keys.keys[0].options,
+            AuthorizedKeyOptions {
+                command: Some("echo \"hi, there\"".into()),
+                no_pty: true,
+                no_port_forwarding: false,
+            }
+        );
+        assert_eq!(keys.keys[0].key.comment, "nora");
+    }
+
+    #[test]
+    fn restrict() {
+        let keys = "restrict,pty ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIG0n1ikUG9rYqobh7WpAyXrqZqxQoQ2zNJrFPj12gTpP\n";
+        let keys = AuthorizedKeys::parse(keys).unwrap();
+        assert_eq!(
+            keys.keys[0].options,
+            AuthorizedKeyOptions {
+                command: None,
+                no_pty: false,
+                no_port_forwarding: true,
+            }
+        );
+    }
+
+    #[test]
+    fn unsupported_option() {
+        let keys = "from=\"10.0.0.1\" ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIG0n1ikUG9rYqobh7WpAyXrqZqxQoQ2zNJrFPj12gTpP\n";
+        let keys = AuthorizedKeys::parse(keys);
+        assert!(keys.is_err());
+    }
 }
@@ -3,8 +3,6 @@ use std::str::FromStr;
 use aes::cipher::{KeySizeUser, StreamCipher};
 use cluelessh_format::{Reader, Writer};
 
-use crate::private::PrivateKey;
-
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Cipher {
     None,
@@ -149,24 +147,3 @@ pub enum KeyType {
 pub struct KeyGenerationParams {
     pub key_type: KeyType,
 }
-
-pub(crate) fn generate_private_key(params: KeyGenerationParams) -> PrivateKey {
-    match params.key_type {
-        KeyType::Ed25519 => {
-            let private_key = ed25519_dalek::SigningKey::generate(&mut rand::rngs::OsRng);
-
-            PrivateKey::Ed25519 {
-                public_key: private_key.verifying_key(),
-                private_key,
-            }
-        }
-        KeyType::Ecdsa => {
-            let private_key = p256::ecdsa::SigningKey::random(&mut rand::rngs::OsRng);
-
-            PrivateKey::EcdsaSha2NistP256 {
-                public_key: *private_key.verifying_key(),
-                private_key,
-            }
-        }
-    }
-}
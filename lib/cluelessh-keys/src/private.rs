@@ -1,10 +1,10 @@
 use std::fmt::Debug;
 
-use crate::crypto::{self, Cipher, Kdf};
-use cluelessh_format::{Reader, Writer};
+use crate::crypto::{Cipher, Kdf};
+use cluelessh_format::{ParseError, Reader, Writer};
 
 use crate::public::PublicKey;
-use crate::KeyGenerationParams;
+use crate::{KeyGenerationParams, KeyType};
 
 pub struct EncryptedPrivateKeys {
     pub public_keys: Vec<PublicKey>,
@@ -43,6 +43,25 @@ pub enum PrivateKey {
         public_key: p256::ecdsa::VerifyingKey,
         private_key: p256::ecdsa::SigningKey,
     },
+    /// An RSA key, which signs with `rsa-sha2-256` or `rsa-sha2-512`, never with the SHA-1 based `ssh-rsa`.
+    Rsa {
+        public_key: rsa::RsaPublicKey,
+        private_key: rsa::RsaPrivateKey,
+    },
+}
+
+/// The smallest RSA keys that we accept, the same limit as OpenSSH.
+const MIN_RSA_KEY_BITS: usize = 1024;
+
+fn check_rsa_key_size(private_key: &rsa::RsaPrivateKey) -> cluelessh_format::Result<()> {
+    use rsa::traits::PublicKeyParts;
+    let bits = private_key.n().bits();
+    if bits < MIN_RSA_KEY_BITS {
+        return Err(ParseError(format!(
+            "RSA key of {bits} bits is too small, it needs at least {MIN_RSA_KEY_BITS} bits"
+        )));
+    }
+    Ok(())
 }
 
 const MAGIC: &[u8; 15] = b"openssh-key-v1\0";
@@ -232,6 +251,40 @@ impl EncryptedPrivateKeys {
                         private_key,
                     }
                 }
+                PublicKey::Rsa { ref public_key } => {
+                    // <https://datatracker.ietf.org/doc/html/draft-miller-ssh-agent#name-rsa-keys>
+                    let alg = p.utf8_string()?;
+                    if alg != pubkey.algorithm_name() {
+                        return Err(cluelessh_format::ParseError(format!(
+                            "algorithm mismatch. pubkey: {}, privkey: {alg}",
+                            pubkey.algorithm_name()
+                        )));
+                    }
+
+                    let n = rsa::BigUint::from_bytes_be(p.mpint()?);
+                    let e = rsa::BigUint::from_bytes_be(p.mpint()?);
+                    let d = rsa::BigUint::from_bytes_be(p.mpint()?);
+                    let _iqmp = p.mpint()?; // recomputed from p and q
+                    let prime_p = rsa::BigUint::from_bytes_be(p.mpint()?);
+                    let prime_q = rsa::BigUint::from_bytes_be(p.mpint()?);
+
+                    let private_key =
+                        rsa::RsaPrivateKey::from_components(n, e, d, vec![prime_p, prime_q])
+                            .map_err(|err| {
+                                cluelessh_format::ParseError(format!(
+                                    "invalid RSA private key: {err}"
+                                ))
+                            })?;
+                    if private_key.to_public_key() != *public_key {
+                        return Err(cluelessh_format::ParseError(format!("public key mismatch")));
+                    }
+                    check_rsa_key_size(&private_key)?;
+
+                    PrivateKey::Rsa {
+                        public_key: public_key.clone(),
+                        private_key,
+                    }
+                }
             };
 
             let comment = p.utf8_string()?;
@@ -300,8 +353,8 @@ impl KeyEncryptionParams {
 
 impl PlaintextPrivateKey {
     pub fn generate(comment: String, params: KeyGenerationParams) -> Self {
-        let keytype = crypto::generate_private_key(params);
-        Self::new(comment, keytype)
+        let private_key = PrivateKey::generate(params.key_type, &mut rand::rngs::OsRng);
+        Self::new(comment, private_key)
     }
 
     pub fn new(comment: String, private_key: PrivateKey) -> Self {
@@ -312,6 +365,62 @@ impl PlaintextPrivateKey {
         }
     }
 
+    /// Whether `content` is a PEM-encoded PKCS#8 private key, see [`Self::from_pkcs8_pem`].
+    /// This includes encrypted keys, so that they get a clear error instead of failing to parse as OpenSSH keys.
+    pub fn is_pkcs8_pem(content: &[u8]) -> bool {
+        pem::parse(content)
+            .is_ok_and(|pem| matches!(pem.tag(), "PRIVATE KEY" | "ENCRYPTED PRIVATE KEY"))
+    }
+
+    /// Parses an unencrypted PEM-encoded PKCS#8 private key, as produced by `openssl genpkey`.
+    /// Only ed25519, ecdsa-sha2-nistp256 and RSA keys are supported.
+    pub fn from_pkcs8_pem(content: &[u8]) -> cluelessh_format::Result<Self> {
+        use p256::pkcs8::DecodePrivateKey;
+
+        let pem =
+            pem::parse(content).map_err(|err| ParseError(format!("invalid PEM format: {err}")))?;
+        match pem.tag() {
+            "PRIVATE KEY" => {}
+            "ENCRYPTED PRIVATE KEY" => {
+                return Err(ParseError(
+                    "encrypted PKCS#8 private keys are not supported, decrypt it with `openssl pkcs8` first"
+                        .to_owned(),
+                ));
+            }
+            tag => {
+                return Err(ParseError(format!(
+                    "invalid PEM tag, expected PRIVATE KEY: {tag}"
+                )));
+            }
+        }
+        let der = pem.contents();
+
+        let private_key = if let Ok(private_key) = ed25519_dalek::SigningKey::from_pkcs8_der(der) {
+            PrivateKey::Ed25519 {
+                public_key: private_key.verifying_key(),
+                private_key,
+            }
+        } else if let Ok(private_key) = p256::ecdsa::SigningKey::from_pkcs8_der(der) {
+            PrivateKey::EcdsaSha2NistP256 {
+                public_key: *private_key.verifying_key(),
+                private_key,
+            }
+        } else if let Ok(private_key) = rsa::RsaPrivateKey::from_pkcs8_der(der) {
+            check_rsa_key_size(&private_key)?;
+            PrivateKey::Rsa {
+                public_key: private_key.to_public_key(),
+                private_key,
+            }
+        } else {
+            return Err(ParseError(
+                "unsupported PKCS#8 private key, only ed25519, ecdsa-sha2-nistp256 and RSA are supported"
+                    .to_owned(),
+            ));
+        };
+
+        Ok(Self::new(String::new(), private_key))
+    }
+
     pub fn encrypt(
         &self,
         params: KeyEncryptionParams,
@@ -345,6 +454,26 @@ impl PlaintextPrivateKey {
                 enc.string(public_key.to_encoded_point(false));
                 enc.mpint(p256::U256::from(private_key.as_nonzero_scalar().as_ref()));
             }
+            PrivateKey::Rsa { private_key, .. } => {
+                // <https://datatracker.ietf.org/doc/html/draft-miller-ssh-agent#name-rsa-keys>
+                use rsa::traits::{PrivateKeyParts, PublicKeyParts};
+                let [prime_p, prime_q] = private_key.primes() else {
+                    return Err(ParseError(
+                        "multi-prime RSA keys are not supported".to_owned(),
+                    ));
+                };
+                let iqmp = private_key
+                    .crt_coefficient()
+                    .ok_or_else(|| ParseError("invalid RSA private key".to_owned()))?;
+
+                enc.string(self.private_key.algorithm_name());
+                enc.mpint_bytes(&private_key.n().to_bytes_be());
+                enc.mpint_bytes(&private_key.e().to_bytes_be());
+                enc.mpint_bytes(&private_key.d().to_bytes_be());
+                enc.mpint_bytes(&iqmp.to_bytes_be());
+                enc.mpint_bytes(&prime_p.to_bytes_be());
+                enc.mpint_bytes(&prime_q.to_bytes_be());
+            }
         }
 
         enc.string(self.comment.as_bytes());
@@ -385,12 +514,57 @@ impl PlaintextPrivateKey {
 }
 
 impl PrivateKey {
-    pub fn public_key(&self) -> PublicKey {
-        match *self {
-            Self::Ed25519 { public_key, .. } => PublicKey::Ed25519 { public_key },
-            Self::EcdsaSha2NistP256 { public_key, .. } => {
-                PublicKey::EcdsaSha2NistP256 { public_key }
+    /// Generates a new key, for example a host key for a server that doesn't have one yet.
+    /// Use [`PlaintextPrivateKey::new`] and [`PlaintextPrivateKey::encrypt`] to store it in the OpenSSH format.
+    pub fn generate(key_type: KeyType, rng: &mut (impl rand::CryptoRng + rand::RngCore)) -> Self {
+        match key_type {
+            KeyType::Ed25519 => {
+                let private_key = ed25519_dalek::SigningKey::generate(rng);
+
+                PrivateKey::Ed25519 {
+                    public_key: private_key.verifying_key(),
+                    private_key,
+                }
+            }
+            KeyType::Ecdsa => {
+                let private_key = p256::ecdsa::SigningKey::random(rng);
+
+                PrivateKey::EcdsaSha2NistP256 {
+                    public_key: *private_key.verifying_key(),
+                    private_key,
+                }
+            }
+        }
+    }
+
+    /// Serializes the key in the armored OpenSSH format, like `ssh-keygen` writes it.
+    /// With a non-empty passphrase, the key is encrypted with [`KeyEncryptionParams::secure_encrypted`].
+    pub fn to_openssh(
+        &self,
+        comment: String,
+        passphrase: Option<String>,
+    ) -> cluelessh_format::Result<String> {
+        let params = match passphrase {
+            Some(passphrase) if !passphrase.is_empty() => {
+                KeyEncryptionParams::secure_encrypted(passphrase)
             }
+            _ => KeyEncryptionParams::plaintext(),
+        };
+        let key = PlaintextPrivateKey::new(comment, self.clone());
+        Ok(key.encrypt(params)?.to_bytes_armored())
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        match self {
+            Self::Ed25519 { public_key, .. } => PublicKey::Ed25519 {
+                public_key: *public_key,
+            },
+            Self::EcdsaSha2NistP256 { public_key, .. } => PublicKey::EcdsaSha2NistP256 {
+                public_key: *public_key,
+            },
+            Self::Rsa { public_key, .. } => PublicKey::Rsa {
+                public_key: public_key.clone(),
+            },
         }
     }
 
@@ -401,7 +575,11 @@ impl PrivateKey {
 
 #[cfg(test)]
 mod tests {
-    use crate::private::{EncryptedPrivateKeys, KeyEncryptionParams, PrivateKey};
+    use crate::{
+        private::{EncryptedPrivateKeys, KeyEncryptionParams, PlaintextPrivateKey, PrivateKey},
+        signature::Signature,
+        KeyType,
+    };
 
     // ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIP60Q8iOyatiPeJbpQ8JVoZazukcSwhnKrg+wzw7/JZQ uwu
     // no password
@@ -449,12 +627,25 @@ f8eMUtjTcN9zoUi67Ho+RIxqN8mYxLy7YDlM54vJ45VhNtcBdoIrQFdrT3QngvZ26Hk6M1
 NZ1XxE87G/z54ftU4Nhj9SCIDPNXB5/1xu/6mA==
 -----END OPENSSH PRIVATE KEY-----";
 
+    // openssl genpkey -algorithm ed25519
+    const TEST_ED25519_PKCS8: &[u8] = include_bytes!("../testdata/ed25519_pkcs8.pem");
+    // openssl genpkey -algorithm EC -pkeyopt ec_paramgen_curve:P-256
+    const TEST_ECDSA_SHA2_NISTP256_PKCS8: &[u8] =
+        include_bytes!("../testdata/ecdsa_p256_pkcs8.pem");
+    // openssl genpkey -algorithm RSA -pkeyopt rsa_keygen_bits:2048
+    const TEST_RSA_PKCS8: &[u8] = include_bytes!("../testdata/rsa_pkcs8.pem");
+    // openssl pkcs8 -topk8 -v2 aes-256-cbc -in ed25519_pkcs8.pem -passout pass:test
+    const TEST_ED25519_PKCS8_ENCRYPTED: &[u8] =
+        include_bytes!("../testdata/ed25519_pkcs8_encrypted.pem");
+    // TEST_RSA_PKCS8, converted with ssh-keygen -p -N ""
+    const TEST_RSA_NONE: &[u8] = include_bytes!("../testdata/rsa_openssh.pem");
+
     #[track_caller]
     fn roundtrip(keys: &[&[u8]], passphrase: Option<&str>) {
         for key_bytes in keys {
             let key_bytes = pem::parse(key_bytes).unwrap();
             let key_bytes = key_bytes.contents();
-            let keys = EncryptedPrivateKeys::parse(&key_bytes).unwrap();
+            let keys = EncryptedPrivateKeys::parse(key_bytes).unwrap();
             let decrypted = keys.decrypt(passphrase).unwrap();
 
             let encrypted = decrypted[0]
@@ -499,6 +690,46 @@ NZ1XxE87G/z54ftU4Nhj9SCIDPNXB5/1xu/6mA==
         ));
     }
 
+    #[test]
+    fn ecdsa_sha2_nistp256_signature() {
+        let private_key = parse_private_key(TEST_ECDSA_SHA2_NISTP256_NONE, None);
+        let public_key = private_key.public_key();
+        let signature = private_key.sign(b"data");
+        assert!(public_key.verify_signature(b"data", &signature));
+        assert!(!public_key.verify_signature(b"other data", &signature));
+    }
+
+    #[test]
+    fn ecdsa_sha2_nistp256_signature_wire_encoding() {
+        let private_key = parse_private_key(TEST_ECDSA_SHA2_NISTP256_NONE, None);
+        let signature = private_key.sign(b"data");
+        let signature = Signature::from_wire_encoding(&signature.to_wire_encoding()).unwrap();
+        assert!(private_key
+            .public_key()
+            .verify_signature(b"data", &signature));
+    }
+
+    #[test]
+    fn rsa_none() {
+        assert!(matches!(
+            parse_private_key(TEST_RSA_NONE, None),
+            PrivateKey::Rsa { .. }
+        ));
+    }
+
+    #[test]
+    fn rsa_signatures() {
+        let private_key = parse_private_key(TEST_RSA_NONE, None);
+        let public_key = private_key.public_key();
+        for algorithm in public_key.signature_algorithms() {
+            let signature = private_key.sign_for_algorithm(algorithm, b"data");
+            assert_eq!(signature.algorithm_name(), *algorithm);
+            assert!(public_key.verify_signature(b"data", &signature));
+            assert!(!public_key.verify_signature(b"other data", &signature));
+        }
+        assert_eq!(private_key.sign(b"data").algorithm_name(), "rsa-sha2-512");
+    }
+
     #[test]
     fn ed25519_aes256ctr() {
         assert!(matches!(
@@ -507,6 +738,102 @@ NZ1XxE87G/z54ftU4Nhj9SCIDPNXB5/1xu/6mA==
         ));
     }
 
+    #[test]
+    fn ed25519_pkcs8() {
+        assert!(PlaintextPrivateKey::is_pkcs8_pem(TEST_ED25519_PKCS8));
+        let key = PlaintextPrivateKey::from_pkcs8_pem(TEST_ED25519_PKCS8).unwrap();
+        assert!(matches!(key.private_key, PrivateKey::Ed25519 { .. }));
+    }
+
+    #[test]
+    fn ecdsa_sha2_nistp256_pkcs8() {
+        let key = PlaintextPrivateKey::from_pkcs8_pem(TEST_ECDSA_SHA2_NISTP256_PKCS8).unwrap();
+        assert!(matches!(
+            key.private_key,
+            PrivateKey::EcdsaSha2NistP256 { .. }
+        ));
+    }
+
+    #[test]
+    fn rsa_pkcs8() {
+        assert!(PlaintextPrivateKey::is_pkcs8_pem(TEST_RSA_PKCS8));
+        let key = PlaintextPrivateKey::from_pkcs8_pem(TEST_RSA_PKCS8).unwrap();
+        assert!(matches!(key.private_key, PrivateKey::Rsa { .. }));
+        assert_eq!(
+            key.private_key.public_key(),
+            parse_private_key(TEST_RSA_NONE, None).public_key()
+        );
+    }
+
+    #[test]
+    fn encrypted_pkcs8() {
+        assert!(PlaintextPrivateKey::is_pkcs8_pem(
+            TEST_ED25519_PKCS8_ENCRYPTED
+        ));
+        let err = PlaintextPrivateKey::from_pkcs8_pem(TEST_ED25519_PKCS8_ENCRYPTED).unwrap_err();
+        assert!(err.0.contains("encrypted PKCS#8"), "{err:?}");
+    }
+
+    #[test]
+    fn pkcs8_after_leading_text() {
+        let content = [b"\n\n".as_slice(), TEST_ED25519_PKCS8].concat();
+        assert!(PlaintextPrivateKey::is_pkcs8_pem(&content));
+        let key = PlaintextPrivateKey::from_pkcs8_pem(&content).unwrap();
+        assert!(matches!(key.private_key, PrivateKey::Ed25519 { .. }));
+    }
+
+    #[test]
+    fn openssh_is_not_pkcs8() {
+        assert!(!PlaintextPrivateKey::is_pkcs8_pem(TEST_ED25519_NONE));
+        assert!(PlaintextPrivateKey::from_pkcs8_pem(TEST_ED25519_NONE).is_err());
+    }
+
+    #[test]
+    fn generate() {
+        use rand::SeedableRng;
+
+        let rng = &mut rand::rngs::StdRng::seed_from_u64(0);
+        for key_type in [KeyType::Ed25519, KeyType::Ecdsa] {
+            let key = PrivateKey::generate(key_type, rng);
+            let public_key = key.public_key();
+
+            let signature = key.sign(b"message");
+            assert_eq!(signature.algorithm_name(), key.algorithm_name());
+            assert!(public_key.verify_signature(b"message", &signature));
+            assert!(!public_key.verify_signature(b"massage", &signature));
+
+            // It can be stored and loaded again.
+            let stored = key.to_openssh("host key".into(), None).unwrap();
+            let stored = pem::parse(stored).unwrap();
+            let loaded = parse_private_key(stored.contents(), None);
+            assert_eq!(loaded.public_key(), public_key);
+        }
+    }
+
+    #[test]
+    fn to_openssh_encrypted() {
+        for key_type in [KeyType::Ed25519, KeyType::Ecdsa] {
+            let key = PrivateKey::generate(key_type, &mut rand::rngs::OsRng);
+            let stored = key
+                .to_openssh("host key".into(), Some("hunter2".into()))
+                .unwrap();
+
+            let stored = pem::parse(stored).unwrap();
+            let keys = EncryptedPrivateKeys::parse(stored.contents()).unwrap();
+            assert!(keys.requires_passphrase());
+            assert!(keys.decrypt(Some("hunter3")).is_err());
+
+            let mut decrypted = keys.decrypt(Some("hunter2")).unwrap();
+            assert_eq!(decrypted.len(), 1);
+            let loaded = decrypted.remove(0);
+            assert_eq!(loaded.comment, "host key");
+            assert_eq!(loaded.private_key.public_key(), key.public_key());
+
+            let signature = loaded.private_key.sign(b"message");
+            assert!(key.public_key().verify_signature(b"message", &signature));
+        }
+    }
+
     #[test]
     fn roundtrip_ed25519_none() {
         roundtrip(&[TEST_ED25519_NONE], None);
@@ -526,4 +853,9 @@ NZ1XxE87G/z54ftU4Nhj9SCIDPNXB5/1xu/6mA==
     fn roundtrip_ecdsa_sha2_nistp256_aes256_ctr() {
         roundtrip(&[TEST_ECDSA_SHA2_NISTP256_AES256_CTR], Some("test"));
     }
+
+    #[test]
+    fn roundtrip_rsa_none() {
+        roundtrip(&[TEST_RSA_NONE], None);
+    }
 }
@@ -11,7 +11,10 @@ use base64::Engine;
 
 use cluelessh_format::{ParseError, Reader, Writer};
 
-use crate::signature::Signature;
+use crate::{
+    authorized_keys::{self, AuthorizedKey, AuthorizedKeyOptions},
+    signature::{RsaHash, Signature},
+};
 
 #[derive(Clone, PartialEq, Eq)]
 pub enum PublicKey {
@@ -21,6 +24,8 @@ pub enum PublicKey {
     EcdsaSha2NistP256 {
         public_key: p256::ecdsa::VerifyingKey,
     },
+    /// Only supported for user authentication with the SHA-2 signature algorithms of RFC 8332.
+    Rsa { public_key: rsa::RsaPublicKey },
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -36,6 +41,9 @@ impl FromStr for PublicKeyWithComment {
         let alg = parts
             .next()
             .ok_or_else(|| ParseError("missing algorithm on line".to_owned()))?;
+        if !PublicKey::ALGORITHM_NAMES.contains(&alg) {
+            return Err(ParseError(format!("unsupported key type: {alg}")));
+        }
         let key_blob = parts
             .next()
             .ok_or_else(|| ParseError("missing key on line".to_owned()))?;
@@ -63,8 +71,21 @@ impl FromStr for PublicKeyWithComment {
 }
 
 impl PublicKey {
+    /// The names of all supported key types.
+    pub const ALGORITHM_NAMES: &'static [&'static str] =
+        &["ssh-ed25519", "ecdsa-sha2-nistp256", "ssh-rsa"];
+
+    /// Parses a single line of an authorized_keys file into the key and the options restricting it.
+    /// The comment is discarded.
+    pub fn from_authorized_keys_line(
+        line: &str,
+    ) -> Result<(Self, AuthorizedKeyOptions), authorized_keys::Error> {
+        let AuthorizedKey { options, key } = AuthorizedKey::parse(line)?;
+        Ok((key.key, options))
+    }
+
     /// Parses an SSH public key from its wire encoding as specified in
-    /// RFC4253, RFC5656, and RFC8709.
+    /// RFC4253, RFC5656, RFC8332, and RFC8709.
     pub fn from_wire_encoding(bytes: &[u8]) -> cluelessh_format::Result<Self> {
         let mut p = Reader::new(bytes);
         let alg = p.utf8_string()?;
@@ -93,6 +114,18 @@ impl PublicKey {
 
                 Self::EcdsaSha2NistP256 { public_key }
             }
+            "ssh-rsa" => {
+                // <https://datatracker.ietf.org/doc/html/rfc4253#section-6.6>
+                let e = p.mpint()?;
+                let n = p.mpint()?;
+                let public_key = rsa::RsaPublicKey::new(
+                    rsa::BigUint::from_bytes_be(n),
+                    rsa::BigUint::from_bytes_be(e),
+                )
+                .map_err(|err| ParseError(format!("invalid RSA public key: {err}")))?;
+
+                Self::Rsa { public_key }
+            }
             _ => return Err(ParseError(format!("unsupported key type: {alg}"))),
         };
         Ok(k)
@@ -113,14 +146,51 @@ impl PublicKey {
                 // But OpenSSH does not appear to support that, so let's NOT use it.
                 p.string(public_key.to_encoded_point(false).as_bytes());
             }
+            Self::Rsa { public_key } => {
+                // <https://datatracker.ietf.org/doc/html/rfc4253#section-6.6>
+                use rsa::traits::PublicKeyParts;
+                p.mpint_bytes(&public_key.e().to_bytes_be());
+                p.mpint_bytes(&public_key.n().to_bytes_be());
+            }
         }
         p.finish()
     }
 
+    /// The OpenSSH-style `SHA256:` fingerprint of the key, as printed by `ssh-keygen -l`.
+    pub fn fingerprint_sha256(&self) -> String {
+        use sha2::Digest;
+        let hash = sha2::Sha256::digest(self.to_wire_encoding());
+        let encoded = base64::prelude::BASE64_STANDARD_NO_PAD.encode(hash);
+        format!("SHA256:{encoded}")
+    }
+
+    /// The legacy `MD5:` fingerprint of the key, as printed by `ssh-keygen -l -E md5`.
+    pub fn fingerprint_md5(&self) -> String {
+        use md5::Digest;
+        let hash = md5::Md5::digest(self.to_wire_encoding());
+        let hex = hash
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<Vec<_>>()
+            .join(":");
+        format!("MD5:{hex}")
+    }
+
     pub fn algorithm_name(&self) -> &'static str {
         match self {
             Self::Ed25519 { .. } => "ssh-ed25519",
             Self::EcdsaSha2NistP256 { .. } => "ecdsa-sha2-nistp256",
+            Self::Rsa { .. } => "ssh-rsa",
+        }
+    }
+
+    /// The signature algorithms that can be used with this key.
+    /// This is the same as the key algorithm, except for RSA, where the SHA-1 based `ssh-rsa` is not supported.
+    pub fn signature_algorithms(&self) -> &'static [&'static str] {
+        match self {
+            Self::Ed25519 { .. } => &["ssh-ed25519"],
+            Self::EcdsaSha2NistP256 { .. } => &["ecdsa-sha2-nistp256"],
+            Self::Rsa { .. } => &["rsa-sha2-256", "rsa-sha2-512"],
         }
     }
 
@@ -128,13 +198,42 @@ impl PublicKey {
         match self {
             PublicKey::Ed25519 { public_key } => match signature {
                 Signature::Ed25519 { signature } => {
-                    public_key.verify_strict(data, &signature).is_ok()
+                    public_key.verify_strict(data, signature).is_ok()
+                }
+                _ => false,
+            },
+            PublicKey::EcdsaSha2NistP256 { public_key } => match signature {
+                Signature::EcdsaSha2NistP256 { signature } => {
+                    use p256::ecdsa::signature::Verifier;
+                    public_key.verify(data, signature).is_ok()
+                }
+                _ => false,
+            },
+            PublicKey::Rsa { public_key } => match signature {
+                Signature::Rsa { hash, signature } => {
+                    // <https://datatracker.ietf.org/doc/html/rfc8332#section-3>
+                    use sha2::Digest;
+                    let result = match hash {
+                        RsaHash::Sha1 => public_key.verify(
+                            rsa::Pkcs1v15Sign::new::<sha1::Sha1>(),
+                            &sha1::Sha1::digest(data),
+                            signature,
+                        ),
+                        RsaHash::Sha256 => public_key.verify(
+                            rsa::Pkcs1v15Sign::new::<sha2::Sha256>(),
+                            &sha2::Sha256::digest(data),
+                            signature,
+                        ),
+                        RsaHash::Sha512 => public_key.verify(
+                            rsa::Pkcs1v15Sign::new::<sha2::Sha512>(),
+                            &sha2::Sha512::digest(data),
+                            signature,
+                        ),
+                    };
+                    result.is_ok()
                 }
                 _ => false,
             },
-            PublicKey::EcdsaSha2NistP256 { .. } => {
-                todo!("ecdsa-sha2-nistp256 signature verification")
-            }
         }
     }
 }
@@ -156,6 +255,10 @@ impl Display for PublicKey {
                 let encoded_pubkey = b64encode(&self.to_wire_encoding());
                 write!(f, "{} {encoded_pubkey}", self.algorithm_name())
             }
+            Self::Rsa { .. } => {
+                let encoded_pubkey = b64encode(&self.to_wire_encoding());
+                write!(f, "{} {encoded_pubkey}", self.algorithm_name())
+            }
         }
     }
 }
@@ -237,4 +340,79 @@ mod tests {
             "AAAAE2VjZHNhLXNoYTItbmlzdHAyNTYAAAAIbmlzdHAyNTYAAABBBCv8bAwK5tZBEpOgFe6tmnog6GHKzeXnOK/qewbH4yiGb9fq4LkSY8oK3WhVZdIwtc1n8j9dNc4aGMURNlVBNKc=",
         ]);
     }
+
+    #[test]
+    fn authorized_keys_line() {
+        for line in [
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIJywXJkHQ4tt5psnIUnvfal8qAPyzF/xq1Z9LgMdY0ff",
+            "ecdsa-sha2-nistp256 AAAAE2VjZHNhLXNoYTItbmlzdHAyNTYAAAAIbmlzdHAyNTYAAABBBAyIVj+OQbE7zjETv3EWgU3mc51N5UgdvkUAB9LwCUdijHKuUE4eqWfuUieY5ey2qGoGvxf5pLvL0+o9X8c66k4=",
+            "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAAAgQDUxcLgMVcKLfpTHcMooGucEwUSuhrhYG/SOzWEAA6AYdKRZHabJWngE3ibDOcMF3oRkLweh6UgL2073kuyM6RSLnFylfO+b0qGZIAW79HSqHZlM9uJys27N6K5p89NR+ZE22H2atKjk3OqpRxVR8owHBF/rs1pTNu9DpGAGjzbcQ==",
+        ] {
+            let (key, options) =
+                PublicKey::from_authorized_keys_line(&format!("no-pty {line} test")).unwrap();
+            assert_eq!(key.to_string(), line);
+            assert!(options.no_pty);
+        }
+    }
+
+    #[test]
+    fn algorithm_names() {
+        for (key, name) in [
+            (
+                "AAAAC3NzaC1lZDI1NTE5AAAAIJJKT1n+xPwS4ECXXPVB5U5gWwMpqa+FMvVuyFwbfvEg",
+                "ssh-ed25519",
+            ),
+            (
+                "AAAAE2VjZHNhLXNoYTItbmlzdHAyNTYAAAAIbmlzdHAyNTYAAABBBHZTdlJoLNb701EWnahywBv032Aby+Piza7TzKW1H6Z//Hni/rBcUgnMmG+Kc4XWp6zgny3FMFpviuL01eJbpY8=",
+                "ecdsa-sha2-nistp256",
+            ),
+            (
+                "AAAAB3NzaC1yc2EAAAADAQABAAAAgQDUxcLgMVcKLfpTHcMooGucEwUSuhrhYG/SOzWEAA6AYdKRZHabJWngE3ibDOcMF3oRkLweh6UgL2073kuyM6RSLnFylfO+b0qGZIAW79HSqHZlM9uJys27N6K5p89NR+ZE22H2atKjk3OqpRxVR8owHBF/rs1pTNu9DpGAGjzbcQ==",
+                "ssh-rsa",
+            ),
+        ] {
+            let key_bytes = base64::prelude::BASE64_STANDARD.decode(key).unwrap();
+            let key = PublicKey::from_wire_encoding(&key_bytes).unwrap();
+            assert_eq!(key.algorithm_name(), name);
+            // The name is also the key type at the start of the wire encoding.
+            let encoded_name = &key_bytes[4..][..name.len()];
+            assert_eq!(key_bytes[..4], (name.len() as u32).to_be_bytes());
+            assert_eq!(encoded_name, name.as_bytes());
+        }
+    }
+
+    #[test]
+    fn authorized_keys_line_invalid() {
+        let err =
+            PublicKey::from_authorized_keys_line("ssh-ed25519 AAAAC3Nza!C1lZDI1NTE5").unwrap_err();
+        assert!(err.to_string().contains("invalid base64"), "{err}");
+
+        let err = PublicKey::from_authorized_keys_line(
+            "ssh-dss AAAAC3NzaC1lZDI1NTE5AAAAIJywXJkHQ4tt5psnIUnvfal8qAPyzF/xq1Z9LgMdY0ff",
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string().contains("unsupported key type: ssh-dss"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn fingerprint() {
+        let key: super::PublicKeyWithComment =
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIJJKT1n+xPwS4ECXXPVB5U5gWwMpqa+FMvVuyFwbfvEg"
+                .parse()
+                .unwrap();
+
+        // ssh-keygen -lf
+        assert_eq!(
+            key.key.fingerprint_sha256(),
+            "SHA256:DadWku4tOqm4DfUlDcgFmGRj3AH18E8sNWbIs1jMn7s"
+        );
+        // ssh-keygen -E md5 -lf
+        assert_eq!(
+            key.key.fingerprint_md5(),
+            "MD5:b5:55:3c:64:6a:4b:5a:f1:2f:e3:38:1c:55:b7:98:ee"
+        );
+    }
 }
@@ -2,7 +2,24 @@ use cluelessh_format::{ParseError, Reader, Writer};
 
 use crate::{private::PrivateKey, public::PublicKey};
 
-pub fn signature_data(session_id: [u8; 32], username: &str, pubkey: &PublicKey) -> Vec<u8> {
+/// The signature algorithms we accept for `publickey` user authentication,
+/// advertised to the client in the `server-sig-algs` extension.
+/// The deprecated `ssh-rsa` is never advertised, even when it is allowed for legacy clients.
+pub const USERAUTH_SIGNATURE_ALGORITHMS: &str =
+    "ssh-ed25519,ecdsa-sha2-nistp256,rsa-sha2-256,rsa-sha2-512";
+
+pub fn signature_data(session_id: &[u8], username: &str, pubkey: &PublicKey) -> Vec<u8> {
+    signature_data_for_algorithm(session_id, username, pubkey.algorithm_name(), pubkey)
+}
+
+/// Like [`signature_data`], but for a signature algorithm that differs from the key algorithm,
+/// like `rsa-sha2-256` for `ssh-rsa` keys.
+pub fn signature_data_for_algorithm(
+    session_id: &[u8],
+    username: &str,
+    algorithm_name: &str,
+    pubkey: &PublicKey,
+) -> Vec<u8> {
     let mut s = Writer::new();
 
     s.string(session_id);
@@ -11,16 +28,28 @@ pub fn signature_data(session_id: [u8; 32], username: &str, pubkey: &PublicKey)
     s.string("ssh-connection");
     s.string("publickey");
     s.bool(true);
-    s.string(pubkey.algorithm_name());
+    s.string(algorithm_name);
     s.string(pubkey.to_wire_encoding());
 
     s.finish()
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub enum Signature {
     Ed25519 { signature: ed25519_dalek::Signature },
     EcdsaSha2NistP256 { signature: p256::ecdsa::Signature },
+    Rsa { hash: RsaHash, signature: Vec<u8> },
+}
+
+/// The hash algorithm of an RSA signature.
+/// <https://datatracker.ietf.org/doc/html/rfc8332#section-3>
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RsaHash {
+    /// The deprecated `ssh-rsa`, which is only accepted with an explicit opt-in as SHA-1 is broken.
+    /// <https://datatracker.ietf.org/doc/html/rfc4253#section-6.6>
+    Sha1,
+    Sha256,
+    Sha512,
 }
 
 impl Signature {
@@ -42,22 +71,29 @@ impl Signature {
             }
             "ecdsa-sha2-nistp256" => {
                 // <https://datatracker.ietf.org/doc/html/rfc5656#section-3.1.2>
-                let r: [u8; 32] = sig
-                    .mpint()?
-                    .try_into()
-                    .map_err(|_| ParseError(format!("invalid r scaler byte length")))?;
-                let s: [u8; 32] = sig
-                    .mpint()?
-                    .try_into()
-                    .map_err(|_| ParseError(format!("invalid s scaler byte length")))?;
-
-                // TODO: fix stupid length
+                let mut signature_blob = Reader::new(sig.string()?);
+                let r = scalar_from_mpint(signature_blob.mpint()?)
+                    .ok_or_else(|| ParseError(format!("invalid r scaler byte length")))?;
+                let s = scalar_from_mpint(signature_blob.mpint()?)
+                    .ok_or_else(|| ParseError(format!("invalid s scaler byte length")))?;
 
                 let signature = p256::ecdsa::Signature::from_scalars(r, s)
                     .map_err(|_| ParseError(format!("invalid signature")))?;
 
                 Self::EcdsaSha2NistP256 { signature }
             }
+            "ssh-rsa" | "rsa-sha2-256" | "rsa-sha2-512" => {
+                // <https://datatracker.ietf.org/doc/html/rfc8332#section-3>
+                let hash = match algorithm_name {
+                    "ssh-rsa" => RsaHash::Sha1,
+                    "rsa-sha2-256" => RsaHash::Sha256,
+                    _ => RsaHash::Sha512,
+                };
+                Self::Rsa {
+                    hash,
+                    signature: sig.string()?.to_vec(),
+                }
+            }
             _ => {
                 return Err(ParseError(format!(
                     "unsupported signature algorithm: {algorithm_name}"
@@ -85,6 +121,10 @@ impl Signature {
                 signature_blob.mpint(p256::U256::from(s.as_ref()));
                 data.string(signature_blob.finish());
             }
+            Self::Rsa { signature, .. } => {
+                // <https://datatracker.ietf.org/doc/html/rfc8332#section-3>
+                data.string(signature);
+            }
         }
         data.finish()
     }
@@ -93,6 +133,18 @@ impl Signature {
         match self {
             Self::Ed25519 { .. } => "ssh-ed25519",
             Self::EcdsaSha2NistP256 { .. } => "ecdsa-sha2-nistp256",
+            Self::Rsa {
+                hash: RsaHash::Sha1,
+                ..
+            } => "ssh-rsa",
+            Self::Rsa {
+                hash: RsaHash::Sha256,
+                ..
+            } => "rsa-sha2-256",
+            Self::Rsa {
+                hash: RsaHash::Sha512,
+                ..
+            } => "rsa-sha2-512",
         }
     }
 }
@@ -136,6 +188,14 @@ impl<'de> serde::Deserialize<'de> for Signature {
     }
 }
 
+/// Left-pads the big-endian bytes of an mpint to a 32 byte scalar, as mpints have their leading zeros stripped.
+fn scalar_from_mpint(int: &[u8]) -> Option<[u8; 32]> {
+    let mut scalar = [0; 32];
+    let offset = scalar.len().checked_sub(int.len())?;
+    scalar[offset..].copy_from_slice(int);
+    Some(scalar)
+}
+
 impl PrivateKey {
     pub fn sign(&self, data: &[u8]) -> Signature {
         match self {
@@ -151,6 +211,46 @@ impl PrivateKey {
                 let sig = private_key.sign(data);
                 Signature::EcdsaSha2NistP256 { signature: sig }
             }
+            Self::Rsa { private_key, .. } => sign_rsa(private_key, RsaHash::Sha512, data),
+        }
+    }
+
+    /// Like [`Self::sign`], but with one of the [`PublicKey::signature_algorithms`] of the key.
+    /// This only makes a difference for RSA keys, which can sign with `rsa-sha2-256` and `rsa-sha2-512`.
+    pub fn sign_for_algorithm(&self, algorithm_name: &str, data: &[u8]) -> Signature {
+        match self {
+            Self::Rsa { private_key, .. } if algorithm_name == "rsa-sha2-256" => {
+                sign_rsa(private_key, RsaHash::Sha256, data)
+            }
+            _ => self.sign(data),
         }
     }
 }
+
+fn sign_rsa(private_key: &rsa::RsaPrivateKey, hash: RsaHash, data: &[u8]) -> Signature {
+    // <https://datatracker.ietf.org/doc/html/rfc8332#section-3>
+    use sha2::Digest;
+    let mut rng = rand::rngs::OsRng;
+    let signature = match hash {
+        RsaHash::Sha1 => private_key.sign_with_rng(
+            &mut rng,
+            rsa::Pkcs1v15Sign::new::<sha1::Sha1>(),
+            &sha1::Sha1::digest(data),
+        ),
+        RsaHash::Sha256 => private_key.sign_with_rng(
+            &mut rng,
+            rsa::Pkcs1v15Sign::new::<sha2::Sha256>(),
+            &sha2::Sha256::digest(data),
+        ),
+        RsaHash::Sha512 => private_key.sign_with_rng(
+            &mut rng,
+            rsa::Pkcs1v15Sign::new::<sha2::Sha512>(),
+            &sha2::Sha512::digest(data),
+        ),
+    };
+    Signature::Rsa {
+        hash,
+        // Only fails for keys that are too small for the digest, far below the size we accept.
+        signature: signature.expect("signing with RSA key"),
+    }
+}
@@ -1,9 +1,25 @@
+//! The SSH protocol as a sans-IO state machine, combining the transport, authentication and connection layers.
+//!
+//! Nothing in here does IO or needs an async runtime, so a connection can be driven from any event loop
+//! or from blocking code. A [`ServerConnection`] is driven by repeating these steps:
+//!
+//! 1. Pass all bytes read from the client to [`ServerConnection::recv_bytes`].
+//! 2. Answer what is pending: the key exchange with [`ServerConnection::is_waiting_on_key_exchange`]
+//!    and [`ServerConnection::do_key_exchange`], authentication requests from [`ServerConnection::auth`]
+//!    and channel updates from [`ServerConnection::channels`].
+//! 3. Call [`ServerConnection::progress`] and write every message from [`ServerConnection::next_msg_to_send`] to the client.
+//!    Repeat this until no more messages come out if [`ChannelConfig::max_outbound_data_packets`] is set,
+//!    as channels then only release a bounded amount of data at once.
+//!
+//! [`ClientConnection`] works the same way. `cluelessh-tokio` is such a driver on top of tokio.
+
 use core::panic;
 use std::collections::HashSet;
 use std::mem;
 
 use auth::AuthOption;
-use cluelessh_connection::ChannelOperation;
+use cluelessh_connection::{ChannelConfig, ChannelOperation, InvalidConfig, OperationResult};
+use cluelessh_transport::peer_error;
 use tracing::debug;
 
 // Re-exports
@@ -23,12 +39,33 @@ impl transport::SshRng for OsRng {
 pub struct ServerConnection {
     transport: cluelessh_transport::server::ServerConnection,
     state: ServerConnectionState,
+    channel_config: ChannelConfig,
+    allow_insecure_ssh_rsa: bool,
+    max_auth_packets: Option<usize>,
+    /// The [`transport::server::ServerConnection::ignored_packets`] that authentication has been told about.
+    ignored_packets: u64,
+}
+
+/// How far a [`ServerConnection`] has gotten, see [`ServerConnection::phase`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionPhase {
+    /// Exchanging the identification strings.
+    ProtocolExchange,
+    /// Exchanging keys, up to the request for the authentication service.
+    /// Key re-exchanges of an open connection do not count.
+    KeyExchange,
+    /// The client is authenticating.
+    Authentication,
+    /// The client has authenticated and channels can be used.
+    Open,
 }
 
 enum ServerConnectionState {
     Setup(HashSet<AuthOption>, Option<String>),
-    Auth(auth::ServerAuth),
-    Open(cluelessh_connection::ChannelsState, String),
+    Auth(Box<auth::ServerAuth>),
+    /// The user is `None` if the client has been authenticated externally and went straight to `ssh-connection`,
+    /// see [`transport::server::ServerConfig::extra_services`].
+    Open(Box<cluelessh_connection::ChannelsState>, Option<String>),
 }
 
 impl ServerConnection {
@@ -40,22 +77,73 @@ impl ServerConnection {
         Self {
             transport,
             state: ServerConnectionState::Setup(auth_options, auth_banner),
+            channel_config: ChannelConfig::default(),
+            allow_insecure_ssh_rsa: false,
+            max_auth_packets: None,
+            ignored_packets: 0,
         }
     }
 
+    /// Sets the configuration for the channels, which are set up after authentication.
+    pub fn set_channel_config(
+        &mut self,
+        channel_config: ChannelConfig,
+    ) -> Result<(), InvalidConfig> {
+        channel_config.validate()?;
+        self.channel_config = channel_config;
+        Ok(())
+    }
+
+    /// See [`auth::ServerAuth::set_allow_insecure_ssh_rsa`]. Must be called before authentication starts.
+    pub fn set_allow_insecure_ssh_rsa(&mut self, allow: bool) {
+        self.allow_insecure_ssh_rsa = allow;
+    }
+
+    /// See [`auth::ServerAuth::set_max_packets`], this also counts `SSH_MSG_IGNORE` and `SSH_MSG_DEBUG`
+    /// packets, which the transport handles itself. Must be called before authentication starts.
+    pub fn set_max_auth_packets(&mut self, max: Option<usize>) {
+        self.max_auth_packets = max;
+    }
+
+    /// Processes bytes received from the client, which do not have to contain whole packets.
     pub fn recv_bytes(&mut self, bytes: &[u8]) -> Result<()> {
         self.transport.recv_bytes(bytes)?;
 
         if let ServerConnectionState::Setup(options, auth_banner) = &mut self.state {
             if let Some(session_id) = self.transport.is_open() {
-                self.state = ServerConnectionState::Auth(auth::ServerAuth::new(
-                    mem::take(options),
-                    auth_banner.take(),
-                    session_id,
-                ));
+                match self.transport.service() {
+                    Some("ssh-connection") => {
+                        debug!("Skipping authentication for ssh-connection");
+                        let channels = cluelessh_connection::ChannelsState::with_config(
+                            true,
+                            self.channel_config.clone(),
+                        );
+                        self.state = ServerConnectionState::Open(Box::new(channels), None);
+                    }
+                    Some("ssh-userauth") => {
+                        let mut auth = auth::ServerAuth::new(
+                            mem::take(options),
+                            auth_banner.take(),
+                            session_id,
+                        );
+                        auth.set_allow_insecure_ssh_rsa(self.allow_insecure_ssh_rsa);
+                        auth.set_max_packets(self.max_auth_packets);
+                        self.ignored_packets = self.transport.ignored_packets();
+                        self.state = ServerConnectionState::Auth(Box::new(auth));
+                    }
+                    service => {
+                        return Err(peer_error!("no protocol for service: {service:?}"));
+                    }
+                }
             }
         }
 
+        if let ServerConnectionState::Auth(auth) = &mut self.state {
+            let ignored_packets = self.transport.ignored_packets();
+            auth.recv_ignored_packets((ignored_packets - self.ignored_packets) as usize)?;
+            self.ignored_packets = ignored_packets;
+        }
+
         while let Some(packet) = self.transport.next_plaintext_packet() {
             match &mut self.state {
                 ServerConnectionState::Setup(_, _) => unreachable!(),
@@ -84,6 +172,25 @@ impl ServerConnection {
         self.transport.do_key_exchange(response);
     }
 
+    /// See [`transport::server::ServerConnection::rekey_interval_elapsed`].
+    pub fn rekey_interval_elapsed(&mut self) {
+        self.transport.rekey_interval_elapsed();
+    }
+
+    /// The phase that the connection is in, for example to find connections that are stuck.
+    pub fn phase(&self) -> ConnectionPhase {
+        match self.state {
+            ServerConnectionState::Setup(..) if self.transport.is_exchanging_identification() => {
+                ConnectionPhase::ProtocolExchange
+            }
+            ServerConnectionState::Setup(..) => ConnectionPhase::KeyExchange,
+            ServerConnectionState::Auth(_) => ConnectionPhase::Authentication,
+            ServerConnectionState::Open(..) => ConnectionPhase::Open,
+        }
+    }
+
+    /// The next message that needs to be written to the client, see [`cluelessh_transport::Msg::to_bytes`].
+    /// Call [`Self::progress`] first to queue everything that the other layers want to send.
     pub fn next_msg_to_send(&mut self) -> Option<cluelessh_transport::Msg> {
         self.transport.next_msg_to_send()
     }
@@ -95,18 +202,49 @@ impl ServerConnection {
         }
     }
 
-    pub fn do_operation(&mut self, op: ChannelOperation) {
+    /// Sends all pending packets, followed by an `SSH_MSG_DISCONNECT`.
+    /// See [`transport::server::ServerConnection::disconnect`].
+    pub fn disconnect(&mut self, reason: u32, description: &str) {
+        self.progress();
+        self.transport.disconnect(reason, description);
+    }
+
+    pub fn next_global_request(&mut self) -> Option<cluelessh_connection::GlobalRequest> {
+        match &mut self.state {
+            ServerConnectionState::Setup(..) | ServerConnectionState::Auth(_) => None,
+            ServerConnectionState::Open(con, _) => con.next_global_request(),
+        }
+    }
+
+    /// See [`cluelessh_connection::ChannelsState::reply_global_request`].
+    pub fn reply_global_request(&mut self, response: Option<&[u8]>) {
         match &mut self.state {
             ServerConnectionState::Setup(..) | ServerConnectionState::Auth(_) => {
                 panic!("tried to get connection before it is ready")
             }
             ServerConnectionState::Open(con, _) => {
-                con.do_operation(op);
+                con.reply_global_request(response);
                 self.progress();
             }
         }
     }
 
+    pub fn do_operation(&mut self, op: ChannelOperation) -> OperationResult {
+        match &mut self.state {
+            ServerConnectionState::Setup(..) | ServerConnectionState::Auth(_) => {
+                panic!("tried to get connection before it is ready")
+            }
+            ServerConnectionState::Open(con, _) => {
+                let result = con.do_operation(op);
+                self.progress();
+                result
+            }
+        }
+    }
+
+    /// Queues the packets of the authentication and connection layers for sending
+    /// and opens the connection once the client has been authenticated.
+    /// Must be called after answering requests from [`Self::auth`] or doing operations on [`Self::channels`].
     pub fn progress(&mut self) {
         match &mut self.state {
             ServerConnectionState::Setup(..) => {}
@@ -115,10 +253,13 @@ impl ServerConnection {
                     self.transport.send_plaintext_packet(to_send);
                 }
                 if let Some(user) = auth.authenticated_user() {
-                    self.state = ServerConnectionState::Open(
-                        cluelessh_connection::ChannelsState::new(true),
-                        user.to_owned(),
+                    let mut channels = cluelessh_connection::ChannelsState::with_config(
+                        true,
+                        self.channel_config.clone(),
                     );
+                    channels.set_key_options(auth.key_options().clone());
+                    self.state =
+                        ServerConnectionState::Open(Box::new(channels), Some(user.to_owned()));
                 }
             }
             ServerConnectionState::Open(con, _) => {
@@ -129,6 +270,7 @@ impl ServerConnection {
         }
     }
 
+    /// The channels, once the client has been authenticated.
     pub fn channels(&mut self) -> Option<&mut cluelessh_connection::ChannelsState> {
         match &mut self.state {
             ServerConnectionState::Open(channels, _) => Some(channels),
@@ -136,6 +278,8 @@ impl ServerConnection {
         }
     }
 
+    /// The authentication state, while the client is authenticating.
+    /// Its [`auth::ServerAuth::server_requests`] need to be answered.
     pub fn auth(&mut self) -> Option<&mut auth::ServerAuth> {
         match &mut self.state {
             ServerConnectionState::Auth(auth) => Some(auth),
@@ -143,9 +287,11 @@ impl ServerConnection {
         }
     }
 
+    /// The user that the client has authenticated as.
+    /// `None` until then, and for clients that have been authenticated externally.
     pub fn authenticated_user(&self) -> Option<&str> {
         match &self.state {
-            ServerConnectionState::Open(_, user) => Some(user),
+            ServerConnectionState::Open(_, user) => user.as_deref(),
             _ => None,
         }
     }
@@ -154,12 +300,13 @@ impl ServerConnection {
 pub struct ClientConnection {
     transport: cluelessh_transport::client::ClientConnection,
     state: ClientConnectionState,
+    channel_config: ChannelConfig,
 }
 
 enum ClientConnectionState {
     Setup(Option<auth::ClientAuth>),
     Auth(auth::ClientAuth),
-    Open(cluelessh_connection::ChannelsState),
+    Open(Box<cluelessh_connection::ChannelsState>),
 }
 
 impl ClientConnection {
@@ -170,19 +317,38 @@ impl ClientConnection {
         Self {
             transport,
             state: ClientConnectionState::Setup(Some(auth)),
+            channel_config: ChannelConfig::default(),
         }
     }
 
+    /// Sets the configuration for the channels, which are set up after authentication.
+    pub fn set_channel_config(
+        &mut self,
+        channel_config: ChannelConfig,
+    ) -> Result<(), InvalidConfig> {
+        channel_config.validate()?;
+        self.channel_config = channel_config;
+        Ok(())
+    }
+
     pub fn recv_bytes(&mut self, bytes: &[u8]) -> Result<()> {
         self.transport.recv_bytes(bytes)?;
 
         if let ClientConnectionState::Setup(auth) = &mut self.state {
             if let Some(session_id) = self.transport.is_open() {
-                let mut auth = mem::take(auth).unwrap();
-                auth.set_session_id(session_id);
-
                 debug!("Connection has been opened");
-                self.state = ClientConnectionState::Auth(auth);
+                if self.transport.service() == "ssh-connection" {
+                    self.state = ClientConnectionState::Open(Box::new(
+                        cluelessh_connection::ChannelsState::with_config(
+                            false,
+                            self.channel_config.clone(),
+                        ),
+                    ));
+                } else {
+                    let mut auth = mem::take(auth).unwrap();
+                    auth.set_session_id(session_id);
+                    self.state = ClientConnectionState::Auth(auth);
+                }
                 self.progress();
             }
         }
@@ -196,9 +362,12 @@ impl ClientConnection {
                         self.transport.send_plaintext_packet(to_send);
                     }
                     if auth.is_authenticated() {
-                        self.state = ClientConnectionState::Open(
-                            cluelessh_connection::ChannelsState::new(false),
-                        );
+                        self.state = ClientConnectionState::Open(Box::new(
+                            cluelessh_connection::ChannelsState::with_config(
+                                false,
+                                self.channel_config.clone(),
+                            ),
+                        ));
                     }
                 }
                 ClientConnectionState::Open(con) => {
@@ -213,6 +382,19 @@ impl ClientConnection {
         Ok(())
     }
 
+    /// Returns the host key of the server once its signature has been verified.
+    /// The consumer must check whether it trusts the key (for example using known_hosts)
+    /// and pass the result to [`Self::host_key_verification_result`].
+    pub fn is_waiting_on_host_key_verification(
+        &self,
+    ) -> Option<&cluelessh_keys::public::PublicKey> {
+        self.transport.is_waiting_on_host_key_verification()
+    }
+
+    pub fn host_key_verification_result(&mut self, is_ok: bool) {
+        self.transport.host_key_verification_result(is_ok);
+    }
+
     pub fn auth(&mut self) -> Option<&mut auth::ClientAuth> {
         match &mut self.state {
             ClientConnectionState::Auth(auth) => Some(auth),
@@ -222,7 +404,7 @@ impl ClientConnection {
 
     pub fn channels(&mut self) -> Option<&mut cluelessh_connection::ChannelsState> {
         match &mut self.state {
-            ClientConnectionState::Open(channels) => Some(channels),
+            ClientConnectionState::Open(channels) => Some(channels.as_mut()),
             _ => None,
         }
     }
@@ -243,14 +425,15 @@ impl ClientConnection {
         }
     }
 
-    pub fn do_operation(&mut self, op: ChannelOperation) {
+    pub fn do_operation(&mut self, op: ChannelOperation) -> OperationResult {
         match &mut self.state {
             ClientConnectionState::Setup(_) | ClientConnectionState::Auth(_) => {
                 panic!("tried to get connection during auth")
             }
             ClientConnectionState::Open(con) => {
-                con.do_operation(op);
+                let result = con.do_operation(op);
                 self.progress();
+                result
             }
         }
     }
@@ -272,12 +455,121 @@ impl ClientConnection {
     }
 }
 
+/// Helpers for testing consumer logic on top of the connection protocol without a transport.
+pub mod testing {
+    use std::collections::VecDeque;
+
+    use cluelessh_connection::{ChannelOperation, ChannelUpdate, ChannelsState, OperationResult};
+    use cluelessh_transport::packet::Packet;
+    use cluelessh_transport::Result;
+
+    /// The channel layer of an already authenticated connection, driven with plaintext packets.
+    /// Packets are fed and produced without any encryption or key exchange, and updates
+    /// can be injected directly to drive a consumer into a specific state.
+    pub struct PlaintextChannels {
+        channels: ChannelsState,
+        injected_updates: VecDeque<ChannelUpdate>,
+    }
+
+    impl PlaintextChannels {
+        pub fn new(is_server: bool) -> Self {
+            Self {
+                channels: ChannelsState::new(is_server),
+                injected_updates: VecDeque::new(),
+            }
+        }
+
+        /// Feed a plaintext packet from the peer to the channel layer.
+        pub fn recv_packet(&mut self, packet: Packet) -> Result<()> {
+            self.channels.recv_packet(packet)
+        }
+
+        /// Queue an update as if it had been produced by the channel layer.
+        /// Injected updates are returned before the ones from received packets.
+        pub fn inject_update(&mut self, update: ChannelUpdate) {
+            self.injected_updates.push_back(update);
+        }
+
+        pub fn next_channel_update(&mut self) -> Option<ChannelUpdate> {
+            self.injected_updates
+                .pop_front()
+                .or_else(|| self.channels.next_channel_update())
+        }
+
+        pub fn do_operation(&mut self, op: ChannelOperation) -> OperationResult {
+            self.channels.do_operation(op)
+        }
+
+        /// The plaintext packets that would have been sent to the peer.
+        pub fn packets_to_send(&mut self) -> impl Iterator<Item = Packet> + '_ {
+            self.channels.packets_to_send()
+        }
+
+        pub fn channels(&mut self) -> &mut ChannelsState {
+            &mut self.channels
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use cluelessh_connection::{
+            ChannelNumber, ChannelOperationKind, ChannelRequest, ChannelUpdate, ChannelUpdateKind,
+        };
+        use cluelessh_format::numbers;
+        use cluelessh_transport::packet::Packet;
+
+        use super::PlaintextChannels;
+
+        /// A tiny consumer that accepts every shell request.
+        fn accept_shells(channels: &mut PlaintextChannels) {
+            while let Some(update) = channels.next_channel_update() {
+                if let ChannelUpdateKind::Request(ChannelRequest::Shell { want_reply: true }) =
+                    update.kind
+                {
+                    channels
+                        .do_operation(update.number.construct_op(ChannelOperationKind::Success));
+                }
+            }
+        }
+
+        #[test]
+        fn consumer_accepts_injected_shell_request() {
+            let mut channels = PlaintextChannels::new(true);
+            channels
+                .recv_packet(Packet::new_msg_channel_open_session(
+                    b"session", 0, 2048, 1024,
+                ))
+                .unwrap();
+            channels.inject_update(ChannelUpdate {
+                number: ChannelNumber(0),
+                kind: ChannelUpdateKind::Request(ChannelRequest::Shell { want_reply: true }),
+            });
+
+            accept_shells(&mut channels);
+
+            let sent = channels
+                .packets_to_send()
+                .map(|packet| packet.packet_type())
+                .collect::<Vec<_>>();
+            assert_eq!(
+                sent,
+                [
+                    numbers::SSH_MSG_CHANNEL_OPEN_CONFIRMATION,
+                    numbers::SSH_MSG_CHANNEL_SUCCESS
+                ]
+            );
+        }
+    }
+}
+
 /// <https://datatracker.ietf.org/doc/html/rfc4252>
 pub mod auth {
     use std::collections::{HashSet, VecDeque};
 
-    use cluelessh_format::{numbers, NameList};
-    use cluelessh_keys::{public::PublicKey, signature::Signature};
+    use cluelessh_format::{numbers, NameList, Writer};
+    use cluelessh_keys::{
+        authorized_keys::AuthorizedKeyOptions, public::PublicKey, signature::Signature,
+    };
     use cluelessh_transport::{packet::Packet, peer_error, Result, SessionId};
     use tracing::debug;
 
@@ -285,10 +577,19 @@ pub mod auth {
         has_failed: bool,
         packets_to_send: VecDeque<Packet>,
         is_authenticated: Option<String>,
+        key_options: AuthorizedKeyOptions,
+        /// The user of the keyboard-interactive exchange that is currently in progress.
+        keyboard_interactive_user: Option<String>,
+        /// The number of prompts of the info request that the client still has to answer.
+        keyboard_interactive_prompts: Option<usize>,
         options: HashSet<AuthOption>,
         banner: Option<String>,
         server_requests: VecDeque<ServerRequest>,
         session_id: SessionId,
+        allow_insecure_ssh_rsa: bool,
+        /// See [`ServerAuth::set_max_packets`].
+        max_packets: Option<usize>,
+        packets_received: usize,
     }
 
     pub enum ServerRequest {
@@ -297,6 +598,11 @@ pub mod auth {
         CheckPubkey(CheckPublicKey),
         /// Verify the signature from a pubkey.
         VerifySignature(VerifySignature),
+        /// Start a keyboard-interactive exchange.
+        /// Should be answered with [`ServerAuth::keyboard_interactive_info_request`].
+        KeyboardInteractive(KeyboardInteractive),
+        /// The client answered the prompts of the last info request.
+        KeyboardInteractiveResponse(KeyboardInteractiveResponse),
     }
 
     #[derive(Debug, Clone)]
@@ -316,14 +622,30 @@ pub mod auth {
         pub user: String,
         pub session_id: SessionId,
         pub public_key: PublicKey,
-        /// The signature. Guaranteed to match the algorithm of `public_key`.
+        /// The signature. Guaranteed to use one of the signature algorithms of `public_key`.
         pub signature: Signature,
     }
 
+    #[derive(Debug, Clone)]
+    pub struct KeyboardInteractive {
+        pub user: String,
+        /// The submethods requested by the client, like `pam`, in order of preference.
+        /// Empty if the client lets the server choose.
+        pub submethods: Vec<String>,
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct KeyboardInteractiveResponse {
+        pub user: String,
+        /// One response for every prompt of the info request.
+        pub responses: Vec<String>,
+    }
+
     #[derive(Debug, PartialEq, Eq, Hash)]
     pub enum AuthOption {
         Password,
         PublicKey,
+        KeyboardInteractive,
     }
 
     impl ServerAuth {
@@ -337,22 +659,92 @@ pub mod auth {
                 packets_to_send: VecDeque::new(),
                 options,
                 is_authenticated: None,
+                key_options: AuthorizedKeyOptions::default(),
+                keyboard_interactive_user: None,
+                keyboard_interactive_prompts: None,
                 session_id,
                 banner,
                 server_requests: VecDeque::new(),
+                allow_insecure_ssh_rsa: false,
+                max_packets: None,
+                packets_received: 0,
             }
         }
 
+        /// Accept signatures with the deprecated SHA-1 based `ssh-rsa` algorithm, for legacy clients and devices
+        /// that don't support `rsa-sha2-256` or `rsa-sha2-512` yet. Off by default, as SHA-1 is broken.
+        /// `ssh-rsa` is never advertised in `server-sig-algs`, clients that use it don't check it anyways.
+        pub fn set_allow_insecure_ssh_rsa(&mut self, allow: bool) {
+            self.allow_insecure_ssh_rsa = allow;
+        }
+
+        /// Treat it as a protocol violation if the client sends more packets than this before it has authenticated.
+        /// Unlike a limit on the failed attempts, this also counts packets that never reach a verification,
+        /// like `none` requests or requests for methods that we don't support.
+        pub fn set_max_packets(&mut self, max: Option<usize>) {
+            self.max_packets = max;
+        }
+
+        /// Counts packets like `SSH_MSG_IGNORE` that the transport has handled itself towards
+        /// [`ServerAuth::set_max_packets`].
+        pub fn recv_ignored_packets(&mut self, count: usize) -> Result<()> {
+            self.count_packets(count)
+        }
+
+        fn count_packets(&mut self, count: usize) -> Result<()> {
+            self.packets_received += count;
+            if let Some(max) = self.max_packets {
+                if self.packets_received > max {
+                    return Err(peer_error!(
+                        "sent more than {max} packets without authenticating"
+                    ));
+                }
+            }
+            Ok(())
+        }
+
         pub fn recv_packet(&mut self, packet: Packet) -> Result<()> {
             assert!(self.is_authenticated.is_none(), "Must not feed more packets to authentication after authentication is been completed, check with .is_authenticated()");
 
             // This is a super simplistic implementation of RFC4252 SSH authentication.
+            self.count_packets(1)?;
+
             // We ask for a public key, and always let that one pass.
             // The reason for this is that this makes it a lot easier to test locally.
             // It's not very good, but it's good enough for now.
             let mut p = packet.payload_parser();
 
-            if p.u8()? != numbers::SSH_MSG_USERAUTH_REQUEST {
+            let packet_type = p.u8()?;
+            if packet_type == numbers::SSH_MSG_USERAUTH_INFO_RESPONSE {
+                // <https://datatracker.ietf.org/doc/html/rfc4256#section-3.4>
+                let (Some(user), Some(num_prompts)) = (
+                    self.keyboard_interactive_user.clone(),
+                    self.keyboard_interactive_prompts.take(),
+                ) else {
+                    return Err(peer_error!(
+                        "client sent SSH_MSG_USERAUTH_INFO_RESPONSE without a request"
+                    ));
+                };
+                let num_responses = p.u32()?;
+                if num_responses as usize != num_prompts {
+                    debug!(%num_prompts, %num_responses, "Client sent the wrong number of keyboard-interactive responses");
+                    self.keyboard_interactive_user = None;
+                    self.has_failed = true;
+                    self.send_failure();
+                    return Ok(());
+                }
+                let mut responses = Vec::new();
+                for _ in 0..num_responses {
+                    responses.push(p.utf8_string()?.to_owned());
+                }
+
+                self.server_requests
+                    .push_back(ServerRequest::KeyboardInteractiveResponse(
+                        KeyboardInteractiveResponse { user, responses },
+                    ));
+                return Ok(());
+            }
+            if packet_type != numbers::SSH_MSG_USERAUTH_REQUEST {
                 return Err(peer_error!("did not send SSH_MSG_SERVICE_REQUEST"));
             }
             let username = p.utf8_string()?;
@@ -405,7 +797,18 @@ pub mod auth {
                     let public_key_blob = p.string()?;
 
                     let public_key = PublicKey::from_wire_encoding(public_key_blob)?;
-                    if pubkey_alg_name != public_key.algorithm_name() {
+                    let is_insecure_ssh_rsa =
+                        matches!(public_key, PublicKey::Rsa { .. }) && pubkey_alg_name == "ssh-rsa";
+                    if is_insecure_ssh_rsa && !self.allow_insecure_ssh_rsa {
+                        // Like OpenSSH, let the client try another key or algorithm.
+                        debug!("Rejecting the insecure ssh-rsa signature algorithm");
+                        self.has_failed = true;
+                        self.send_failure();
+                        return Ok(());
+                    }
+                    if !public_key.signature_algorithms().contains(&pubkey_alg_name)
+                        && !is_insecure_ssh_rsa
+                    {
                         return Err(peer_error!("algorithm name mismatch"));
                     }
 
@@ -420,18 +823,40 @@ pub mod auth {
                     } else {
                         let signature = p.string()?;
                         let signature = Signature::from_wire_encoding(signature)?;
-                        if signature.algorithm_name() != public_key.algorithm_name() {
+                        if signature.algorithm_name() != pubkey_alg_name {
                             return Err(peer_error!("signature algorithm name mismatch"));
                         }
                         self.server_requests
                             .push_back(ServerRequest::VerifySignature(VerifySignature {
                                 user: username.to_owned(),
-                                session_id: self.session_id,
+                                session_id: self.session_id.clone(),
                                 public_key,
                                 signature,
                             }));
                     }
                 }
+                "keyboard-interactive" => {
+                    // <https://datatracker.ietf.org/doc/html/rfc4256#section-3.1>
+                    if !self.options.contains(&AuthOption::KeyboardInteractive) {
+                        self.has_failed = true;
+                        self.send_failure();
+                        return Ok(());
+                    }
+
+                    let _language_tag = p.string()?;
+                    let submethods = p.name_list()?;
+
+                    self.keyboard_interactive_user = Some(username.to_owned());
+                    self.server_requests
+                        .push_back(ServerRequest::KeyboardInteractive(KeyboardInteractive {
+                            user: username.to_owned(),
+                            submethods: submethods
+                                .iter()
+                                .filter(|submethod| !submethod.is_empty())
+                                .map(ToOwned::to_owned)
+                                .collect(),
+                        }));
+                }
                 _ if self.has_failed => {
                     return Err(peer_error!(
                         "client tried unsupported method twice: {method_name}"
@@ -461,8 +886,36 @@ pub mod auth {
             }
         }
 
+        /// Send prompts to the client for the keyboard-interactive exchange in progress.
+        /// The prompts are pairs of the prompt text and whether the response should be echoed.
+        /// The client answers with a [`ServerRequest::KeyboardInteractiveResponse`].
+        pub fn keyboard_interactive_info_request(
+            &mut self,
+            name: &str,
+            instruction: &str,
+            prompts: &[(&str, bool)],
+        ) {
+            // <https://datatracker.ietf.org/doc/html/rfc4256#section-3.2>
+            let mut w = Writer::new();
+            w.u8(numbers::SSH_MSG_USERAUTH_INFO_REQUEST);
+            w.string(name);
+            w.string(instruction);
+            w.string(b""); // language tag
+            w.u32(prompts.len() as u32);
+            for (prompt, echo) in prompts {
+                w.string(prompt);
+                w.bool(*echo);
+            }
+            self.queue_packet(Packet {
+                payload: w.finish(),
+            });
+            self.keyboard_interactive_prompts = Some(prompts.len());
+        }
+
         // TODO: improve types with a newtype around an authenticated user
         pub fn verification_result(&mut self, is_ok: bool, user: String) {
+            self.keyboard_interactive_user = None;
+            self.keyboard_interactive_prompts = None;
             if is_ok {
                 self.queue_packet(Packet::new_msg_userauth_success());
                 self.is_authenticated = Some(user);
@@ -472,6 +925,18 @@ pub mod auth {
             }
         }
 
+        /// Like [`Self::verification_result`], but for a public key that came with
+        /// authorized_keys options that restrict the session. `None` means that verification failed.
+        pub fn verification_result_with_options(
+            &mut self,
+            key_options: Option<AuthorizedKeyOptions>,
+            user: String,
+        ) {
+            let is_ok = key_options.is_some();
+            self.key_options = key_options.unwrap_or_default();
+            self.verification_result(is_ok, user);
+        }
+
         pub fn packets_to_send(&mut self) -> impl Iterator<Item = Packet> + '_ {
             self.packets_to_send.drain(..)
         }
@@ -480,6 +945,11 @@ pub mod auth {
             self.is_authenticated.as_deref()
         }
 
+        /// The restrictions of the key that the user authenticated with.
+        pub fn key_options(&self) -> &AuthorizedKeyOptions {
+            &self.key_options
+        }
+
         pub fn server_requests(&mut self) -> impl Iterator<Item = ServerRequest> + '_ {
             self.server_requests.drain(..)
         }
@@ -497,6 +967,7 @@ pub mod auth {
                 .map(|op| match op {
                     AuthOption::Password => "password",
                     AuthOption::PublicKey => "publickey",
+                    AuthOption::KeyboardInteractive => "keyboard-interactive",
                 })
                 .collect::<Vec<&str>>()
                 .join(",")
@@ -607,6 +1078,7 @@ pub mod auth {
                             .push_back(ClientUserRequest::PrivateKeySign {
                                 session_id: self
                                     .session_id
+                                    .clone()
                                     .expect("set_session_id has not been called"),
                             });
                     } else {
@@ -629,4 +1101,504 @@ pub mod auth {
             Ok(())
         }
     }
+    #[cfg(test)]
+    mod tests {
+        use std::collections::HashSet;
+
+        use cluelessh_format::{numbers, Writer};
+        use cluelessh_keys::{
+            public::PublicKey,
+            signature::{signature_data_for_algorithm, RsaHash, Signature},
+        };
+        use cluelessh_transport::{packet::Packet, SessionId};
+        use sha2::Digest;
+
+        use super::{AuthOption, ServerAuth, ServerRequest};
+
+        fn test_rsa_key() -> rsa::RsaPrivateKey {
+            use rsa::pkcs8::DecodePrivateKey;
+            rsa::RsaPrivateKey::from_pkcs8_pem(include_str!(
+                "../../cluelessh-keys/testdata/rsa_pkcs8.pem"
+            ))
+            .unwrap()
+        }
+
+        #[test]
+        fn max_packets() {
+            let mut auth = ServerAuth::new(
+                HashSet::from([AuthOption::Password]),
+                None,
+                SessionId(vec![0; 32]),
+            );
+            auth.set_max_packets(Some(5));
+
+            let none =
+                || Packet::new_msg_userauth_request_none(b"user", b"ssh-connection", b"none");
+            for _ in 0..5 {
+                auth.recv_packet(none()).unwrap();
+                let sent = auth.packets_to_send().collect::<Vec<_>>();
+                assert_eq!(sent.len(), 1);
+                assert_eq!(sent[0].packet_type(), numbers::SSH_MSG_USERAUTH_FAILURE);
+            }
+            assert!(auth.recv_packet(none()).is_err());
+            assert_eq!(auth.packets_to_send().count(), 0);
+        }
+
+        #[test]
+        fn max_packets_counts_ignored_packets() {
+            let mut auth = ServerAuth::new(
+                HashSet::from([AuthOption::Password]),
+                None,
+                SessionId(vec![0; 32]),
+            );
+            auth.set_max_packets(Some(5));
+
+            auth.recv_ignored_packets(4).unwrap();
+            auth.recv_packet(Packet::new_msg_userauth_request_none(
+                b"user",
+                b"ssh-connection",
+                b"none",
+            ))
+            .unwrap();
+            assert!(auth.recv_ignored_packets(1).is_err());
+        }
+
+        #[test]
+        fn keyboard_interactive_submethods() {
+            let mut auth = ServerAuth::new(
+                HashSet::from([AuthOption::KeyboardInteractive]),
+                None,
+                SessionId(vec![0; 32]),
+            );
+            auth.recv_packet(Packet::new_msg_userauth_request_keyboard_interactive(
+                b"user",
+                b"ssh-connection",
+                b"keyboard-interactive",
+                b"",
+                b"pam,otp",
+            ))
+            .unwrap();
+
+            let requests = auth.server_requests().collect::<Vec<_>>();
+            let [ServerRequest::KeyboardInteractive(request)] = requests.as_slice() else {
+                panic!("expected a keyboard-interactive request");
+            };
+            assert_eq!(request.user, "user");
+            assert_eq!(request.submethods, ["pam", "otp"]);
+
+            auth.keyboard_interactive_info_request("", "", &[("Password: ", false)]);
+            let sent = auth.packets_to_send().collect::<Vec<_>>();
+            assert_eq!(sent.len(), 1);
+            assert_eq!(
+                sent[0].packet_type(),
+                numbers::SSH_MSG_USERAUTH_INFO_REQUEST
+            );
+
+            let mut w = Writer::new();
+            w.u8(numbers::SSH_MSG_USERAUTH_INFO_RESPONSE);
+            w.u32(1);
+            w.string(b"hunter2");
+            auth.recv_packet(Packet {
+                payload: w.finish(),
+            })
+            .unwrap();
+
+            let requests = auth.server_requests().collect::<Vec<_>>();
+            let [ServerRequest::KeyboardInteractiveResponse(response)] = requests.as_slice() else {
+                panic!("expected a keyboard-interactive response");
+            };
+            assert_eq!(response.user, "user");
+            assert_eq!(response.responses, ["hunter2"]);
+
+            auth.verification_result(true, response.user.clone());
+            assert_eq!(auth.authenticated_user(), Some("user"));
+        }
+
+        #[test]
+        fn keyboard_interactive_no_submethods() {
+            let mut auth = ServerAuth::new(
+                HashSet::from([AuthOption::KeyboardInteractive]),
+                None,
+                SessionId(vec![0; 32]),
+            );
+            auth.recv_packet(Packet::new_msg_userauth_request_keyboard_interactive(
+                b"user",
+                b"ssh-connection",
+                b"keyboard-interactive",
+                b"",
+                b"",
+            ))
+            .unwrap();
+
+            let requests = auth.server_requests().collect::<Vec<_>>();
+            let [ServerRequest::KeyboardInteractive(request)] = requests.as_slice() else {
+                panic!("expected a keyboard-interactive request");
+            };
+            assert!(request.submethods.is_empty());
+        }
+
+        #[test]
+        fn keyboard_interactive_wrong_response_count() {
+            let mut auth = ServerAuth::new(
+                HashSet::from([AuthOption::KeyboardInteractive]),
+                None,
+                SessionId(vec![0; 32]),
+            );
+            auth.recv_packet(Packet::new_msg_userauth_request_keyboard_interactive(
+                b"user",
+                b"ssh-connection",
+                b"keyboard-interactive",
+                b"",
+                b"",
+            ))
+            .unwrap();
+            let _ = auth.server_requests().count();
+
+            auth.keyboard_interactive_info_request(
+                "",
+                "",
+                &[("Password: ", false), ("Code: ", true)],
+            );
+            let _ = auth.packets_to_send().count();
+
+            let mut w = Writer::new();
+            w.u8(numbers::SSH_MSG_USERAUTH_INFO_RESPONSE);
+            w.u32(1);
+            w.string(b"hunter2");
+            auth.recv_packet(Packet {
+                payload: w.finish(),
+            })
+            .unwrap();
+
+            assert_eq!(auth.server_requests().count(), 0);
+            let sent = auth.packets_to_send().collect::<Vec<_>>();
+            assert_eq!(sent.len(), 1);
+            assert_eq!(sent[0].packet_type(), numbers::SSH_MSG_USERAUTH_FAILURE);
+            assert_eq!(auth.authenticated_user(), None);
+        }
+
+        #[test]
+        fn rsa_sha256_signature() {
+            let private_key = test_rsa_key();
+            let public_key = PublicKey::Rsa {
+                public_key: private_key.to_public_key(),
+            };
+            let session_id = SessionId(vec![1; 32]);
+
+            let sign_data =
+                signature_data_for_algorithm(&session_id.0, "user", "rsa-sha2-256", &public_key);
+            let signature = Signature::Rsa {
+                hash: RsaHash::Sha256,
+                signature: private_key
+                    .sign(
+                        rsa::Pkcs1v15Sign::new::<sha2::Sha256>(),
+                        &sha2::Sha256::digest(&sign_data),
+                    )
+                    .unwrap(),
+            };
+
+            let mut auth =
+                ServerAuth::new(HashSet::from([AuthOption::PublicKey]), None, session_id);
+            auth.recv_packet(Packet::new_msg_userauth_request_publickey(
+                b"user",
+                b"ssh-connection",
+                b"publickey",
+                true,
+                b"rsa-sha2-256",
+                &public_key.to_wire_encoding(),
+                &signature.to_wire_encoding(),
+            ))
+            .unwrap();
+
+            let requests = auth.server_requests().collect::<Vec<_>>();
+            let [ServerRequest::VerifySignature(request)] = requests.as_slice() else {
+                panic!("expected a signature verification request");
+            };
+            assert_eq!(request.public_key, public_key);
+            assert_eq!(request.signature.algorithm_name(), "rsa-sha2-256");
+
+            let sign_data = signature_data_for_algorithm(
+                &request.session_id.0,
+                &request.user,
+                request.signature.algorithm_name(),
+                &request.public_key,
+            );
+            assert!(request
+                .public_key
+                .verify_signature(&sign_data, &request.signature));
+
+            // The algorithm name is part of the signed data.
+            let sign_data = signature_data_for_algorithm(
+                &request.session_id.0,
+                &request.user,
+                "rsa-sha2-512",
+                &request.public_key,
+            );
+            assert!(!request
+                .public_key
+                .verify_signature(&sign_data, &request.signature));
+        }
+
+        #[test]
+        fn insecure_ssh_rsa_signature() {
+            let private_key = test_rsa_key();
+            let public_key = PublicKey::Rsa {
+                public_key: private_key.to_public_key(),
+            };
+            let session_id = SessionId(vec![1; 32]);
+
+            let sign_data =
+                signature_data_for_algorithm(&session_id.0, "user", "ssh-rsa", &public_key);
+            let signature = Signature::Rsa {
+                hash: RsaHash::Sha1,
+                signature: private_key
+                    .sign(
+                        rsa::Pkcs1v15Sign::new::<sha1::Sha1>(),
+                        &sha1::Sha1::digest(&sign_data),
+                    )
+                    .unwrap(),
+            };
+            let request = Packet::new_msg_userauth_request_publickey(
+                b"user",
+                b"ssh-connection",
+                b"publickey",
+                true,
+                b"ssh-rsa",
+                &public_key.to_wire_encoding(),
+                &signature.to_wire_encoding(),
+            );
+
+            let mut auth = ServerAuth::new(
+                HashSet::from([AuthOption::PublicKey]),
+                None,
+                session_id.clone(),
+            );
+            auth.recv_packet(Packet {
+                payload: request.payload.clone(),
+            })
+            .unwrap();
+            assert_eq!(auth.server_requests().count(), 0);
+            let packets = auth.packets_to_send().collect::<Vec<_>>();
+            let [packet] = packets.as_slice() else {
+                panic!("expected a single response");
+            };
+            assert_eq!(packet.packet_type(), numbers::SSH_MSG_USERAUTH_FAILURE);
+
+            let mut auth =
+                ServerAuth::new(HashSet::from([AuthOption::PublicKey]), None, session_id);
+            auth.set_allow_insecure_ssh_rsa(true);
+            auth.recv_packet(request).unwrap();
+
+            let requests = auth.server_requests().collect::<Vec<_>>();
+            let [ServerRequest::VerifySignature(request)] = requests.as_slice() else {
+                panic!("expected a signature verification request");
+            };
+            assert_eq!(request.signature.algorithm_name(), "ssh-rsa");
+            assert!(request
+                .public_key
+                .verify_signature(&sign_data, &request.signature));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use cluelessh_connection::{ChannelKind, ChannelUpdateKind};
+    use cluelessh_keys::{private::PlaintextPrivateKey, KeyGenerationParams, KeyType};
+
+    use crate::auth::{AuthOption, ClientAuth, ClientUserRequest, ServerRequest};
+    use crate::{transport, ClientConnection, ConnectionPhase, OsRng, ServerConnection};
+
+    /// Drives a client and a server against each other without any IO, with a buffer for each direction.
+    #[test]
+    fn synchronous_handshake() {
+        let host_key = PlaintextPrivateKey::generate(
+            String::new(),
+            KeyGenerationParams {
+                key_type: KeyType::Ed25519,
+            },
+        );
+        let mut server = ServerConnection::new(
+            transport::server::ServerConnection::new(
+                OsRng,
+                transport::server::ServerConfig {
+                    server_identification: b"SSH-2.0-ClueleSSH_0.1\r\n".to_vec(),
+                    host_keys: vec![host_key.private_key.public_key()],
+                    ..Default::default()
+                },
+            )
+            .unwrap(),
+            HashSet::from([AuthOption::Password]),
+            None,
+        );
+        let mut client = ClientConnection::new(
+            transport::client::ClientConnection::new(OsRng),
+            ClientAuth::new(b"user".to_vec()),
+        );
+
+        let mut to_server = Vec::new();
+        let mut to_client = Vec::new();
+        let mut channel = None;
+        let mut channel_opened = false;
+        let mut phases = vec![server.phase()];
+
+        for _ in 0..100 {
+            server.recv_bytes(&std::mem::take(&mut to_server)).unwrap();
+            if phases.last() != Some(&server.phase()) {
+                phases.push(server.phase());
+            }
+            if let Some(params) = server.is_waiting_on_key_exchange() {
+                let response =
+                    transport::server::do_key_exchange(params, &host_key, &mut OsRng).unwrap();
+                server.do_key_exchange(response);
+            }
+            if let Some(auth) = server.auth() {
+                for request in auth.server_requests().collect::<Vec<_>>() {
+                    let ServerRequest::VerifyPassword(request) = request else {
+                        panic!("unexpected auth request");
+                    };
+                    auth.verification_result(request.password == "meow", request.user);
+                }
+            }
+            server.progress();
+            while let Some(msg) = server.next_msg_to_send() {
+                to_client.extend_from_slice(&msg.to_bytes());
+            }
+
+            client.recv_bytes(&std::mem::take(&mut to_client)).unwrap();
+            if let Some(server_host_key) = client.is_waiting_on_host_key_verification() {
+                assert_eq!(*server_host_key, host_key.private_key.public_key());
+                client.host_key_verification_result(true);
+            }
+            if let Some(auth) = client.auth() {
+                for request in auth.user_requests().collect::<Vec<_>>() {
+                    if let ClientUserRequest::Password = request {
+                        auth.send_password("meow");
+                    }
+                }
+            }
+            if let Some(channels) = client.channels() {
+                if channel.is_none() {
+                    channel = Some(channels.create_channel(ChannelKind::Session));
+                }
+            }
+            while let Some(update) = client.next_channel_update() {
+                if let ChannelUpdateKind::OpenConfirmed {
+                    kind: ChannelKind::Session,
+                    ..
+                } = update.kind
+                {
+                    assert_eq!(Some(update.number), channel);
+                    channel_opened = true;
+                }
+            }
+            client.progress();
+            while let Some(msg) = client.next_msg_to_send() {
+                to_server.extend_from_slice(&msg.to_bytes());
+            }
+
+            if channel_opened {
+                break;
+            }
+        }
+
+        assert!(channel_opened);
+        assert_eq!(server.authenticated_user(), Some("user"));
+        assert_eq!(
+            phases,
+            [
+                ConnectionPhase::ProtocolExchange,
+                ConnectionPhase::KeyExchange,
+                ConnectionPhase::Authentication,
+                ConnectionPhase::Open,
+            ]
+        );
+    }
+
+    /// A client that has been authenticated externally requests `ssh-connection` right away.
+    #[test]
+    fn extra_service_skips_authentication() {
+        let host_key = PlaintextPrivateKey::generate(
+            String::new(),
+            KeyGenerationParams {
+                key_type: KeyType::Ed25519,
+            },
+        );
+        let mut server = ServerConnection::new(
+            transport::server::ServerConnection::new(
+                OsRng,
+                transport::server::ServerConfig {
+                    server_identification: b"SSH-2.0-ClueleSSH_0.1\r\n".to_vec(),
+                    host_keys: vec![host_key.private_key.public_key()],
+                    extra_services: vec!["ssh-connection".to_owned()],
+                    ..Default::default()
+                },
+            )
+            .unwrap(),
+            HashSet::from([AuthOption::Password]),
+            None,
+        );
+        let mut client_transport = transport::client::ClientConnection::new(OsRng);
+        client_transport.set_service("ssh-connection");
+        let mut client = ClientConnection::new(client_transport, ClientAuth::new(b"user".to_vec()));
+
+        let mut to_server = Vec::new();
+        let mut to_client = Vec::new();
+        let mut phases = vec![server.phase()];
+        let mut channel = None;
+        let mut channel_opened = false;
+
+        for _ in 0..100 {
+            server.recv_bytes(&std::mem::take(&mut to_server)).unwrap();
+            if phases.last() != Some(&server.phase()) {
+                phases.push(server.phase());
+            }
+            if let Some(params) = server.is_waiting_on_key_exchange() {
+                let response =
+                    transport::server::do_key_exchange(params, &host_key, &mut OsRng).unwrap();
+                server.do_key_exchange(response);
+            }
+            server.progress();
+            while let Some(msg) = server.next_msg_to_send() {
+                to_client.extend_from_slice(&msg.to_bytes());
+            }
+
+            client.recv_bytes(&std::mem::take(&mut to_client)).unwrap();
+            if client.is_waiting_on_host_key_verification().is_some() {
+                client.host_key_verification_result(true);
+            }
+            assert!(client.auth().is_none());
+            if let Some(channels) = client.channels() {
+                if channel.is_none() {
+                    channel = Some(channels.create_channel(ChannelKind::Session));
+                }
+            }
+            while let Some(update) = client.next_channel_update() {
+                if let ChannelUpdateKind::OpenConfirmed { .. } = update.kind {
+                    channel_opened = true;
+                }
+            }
+            client.progress();
+            while let Some(msg) = client.next_msg_to_send() {
+                to_server.extend_from_slice(&msg.to_bytes());
+            }
+
+            if channel_opened {
+                break;
+            }
+        }
+
+        assert!(channel_opened);
+        assert_eq!(server.authenticated_user(), None);
+        assert_eq!(
+            phases,
+            [
+                ConnectionPhase::ProtocolExchange,
+                ConnectionPhase::KeyExchange,
+                ConnectionPhase::Open,
+            ]
+        );
+    }
 }
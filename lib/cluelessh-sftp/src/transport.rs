@@ -14,8 +14,8 @@ impl Packet {
         self.payload[4]
     }
 
-    pub fn payload_reader(&self) -> Reader {
-        Reader::new(&&self.payload[5..])
+    pub fn payload_reader(&self) -> Reader<'_> {
+        Reader::new(&self.payload[5..])
     }
 
     pub fn all_payload(&self) -> &[u8] {
@@ -26,7 +26,7 @@ impl Packet {
         let len = body.len() as u32;
         let mut payload = Vec::new();
         payload.extend_from_slice(&u32::to_be_bytes(len));
-        payload.extend_from_slice(&body);
+        payload.extend_from_slice(body);
         Self { payload }
     }
 }
@@ -0,0 +1,74 @@
+//! A minimal server that echoes back every command it is asked to execute.
+//!
+//! ```sh
+//! cargo run -p cluelessh-key -- generate --path host_key
+//! cargo run --example quickstart -- host_key
+//! ssh -p 2222 localhost hello
+//! ```
+
+use std::{path::PathBuf, sync::Arc};
+
+use cluelessh_connection::{ChannelOperationKind, ChannelRequest};
+use cluelessh_protocol::ChannelUpdateKind;
+use cluelessh_tokio::quickstart::{run_shell_server, QuickstartAuth};
+use eyre::{OptionExt, Result};
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt().init();
+
+    let host_key_path = PathBuf::from(
+        std::env::args()
+            .nth(1)
+            .ok_or_eyre("usage: quickstart <host-key-path>")?,
+    );
+
+    let auth = QuickstartAuth {
+        // Everyone is welcome.
+        verify_password: Some(Arc::new(|_| Box::pin(async { Ok(true) }))),
+        ..Default::default()
+    };
+
+    run_shell_server(
+        "127.0.0.1:2222".parse()?,
+        &host_key_path,
+        auth,
+        |mut channel| async move {
+            loop {
+                let ChannelUpdateKind::Request(req) = channel.next_update().await? else {
+                    continue;
+                };
+                match req {
+                    ChannelRequest::Exec {
+                        want_reply,
+                        command,
+                    } => {
+                        if want_reply {
+                            channel.send(ChannelOperationKind::Success).await?;
+                        }
+                        let mut output = command;
+                        output.push(b'\n');
+                        channel.send(ChannelOperationKind::Data(output)).await?;
+                        channel
+                            .send(ChannelOperationKind::Request(ChannelRequest::ExitStatus {
+                                status: 0,
+                            }))
+                            .await?;
+                        channel.send(ChannelOperationKind::Eof).await?;
+                        channel.send(ChannelOperationKind::Close).await?;
+                        return Ok(());
+                    }
+                    ChannelRequest::PtyReq { want_reply, .. }
+                    | ChannelRequest::Shell { want_reply }
+                    | ChannelRequest::Subsystem { want_reply, .. }
+                        if want_reply =>
+                    {
+                        channel.send(ChannelOperationKind::Failure).await?;
+                    }
+                    _ => {}
+                }
+            }
+        },
+    )
+    .await
+}
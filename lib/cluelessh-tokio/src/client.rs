@@ -1,7 +1,13 @@
-use cluelessh_connection::{ChannelKind, ChannelNumber, ChannelOperation};
+use cluelessh_connection::{ChannelKind, ChannelNumber, ChannelOperation, OperationResult};
+use cluelessh_keys::public::PublicKey;
 use cluelessh_transport::SessionId;
-use std::{collections::HashMap, pin::Pin, sync::Arc};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+};
+use tokio::io::AsyncReadExt;
 
 use cluelessh_protocol::{ChannelUpdateKind, SshStatus};
 use eyre::{bail, ContextCompat, Result, WrapErr};
@@ -9,11 +15,13 @@ use futures::future::BoxFuture;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tracing::{debug, info, warn};
 
-use crate::{Channel, ChannelState, PendingChannel};
+use crate::{write_buffered, Channel, ChannelState, PendingChannel};
 
 pub struct ClientConnection<S> {
     stream: Pin<Box<S>>,
     buf: [u8; 1024],
+    /// Bytes of a message that have not been fully written yet.
+    send_buf: Vec<u8>,
 
     proto: cluelessh_protocol::ClientConnection,
     operations_send: tokio::sync::mpsc::Sender<Operation>,
@@ -24,8 +32,17 @@ pub struct ClientConnection<S> {
     channel_ops_recv: tokio::sync::mpsc::Receiver<ChannelOperation>,
 
     channels: HashMap<ChannelNumber, ChannelState>,
+    /// Whether a channel has queued too much data, so no more channel operations are taken for now.
+    channel_ops_blocked: bool,
+    /// Channels opened by the server for our remote forwards.
+    new_channels: VecDeque<Channel>,
 
     auth: ClientAuth,
+    peer_addr: Option<SocketAddr>,
+    /// Whether the host key is currently being verified by the callback.
+    verifying_host_key: bool,
+    /// Whether the server has closed the connection.
+    eof: bool,
 }
 
 pub struct ClientAuth {
@@ -33,11 +50,24 @@ pub struct ClientAuth {
     pub prompt_password: Arc<dyn Fn() -> BoxFuture<'static, Result<String>> + Send + Sync>,
     pub sign_pubkey:
         Arc<dyn Fn(SessionId) -> BoxFuture<'static, Result<SignatureResult>> + Send + Sync>,
+    /// Returns whether the host key of the server is trusted, for example by checking known_hosts or prompting the user.
+    /// Every host key is accepted if this is not set.
+    pub verify_host_key:
+        Option<Arc<dyn Fn(VerifyHostKey) -> BoxFuture<'static, Result<bool>> + Send + Sync>>,
+}
+
+/// The host key presented by the server, after it has proven that it owns the private key.
+#[derive(Debug, Clone)]
+pub struct VerifyHostKey {
+    pub public_key: PublicKey,
+    /// The address of the server, if it was passed to [`ClientConnection::connect_to`].
+    pub peer_addr: Option<SocketAddr>,
 }
 
 enum Operation {
     PasswordEntered(Result<String>),
     Signature(Result<SignatureResult>),
+    HostKeyVerified(Result<bool>),
 }
 
 pub struct SignatureResult {
@@ -48,26 +78,48 @@ pub struct SignatureResult {
 
 impl<S: AsyncRead + AsyncWrite> ClientConnection<S> {
     pub async fn connect(stream: S, auth: ClientAuth) -> Result<Self> {
+        Self::connect_inner(stream, None, auth).await
+    }
+
+    /// Like [`Self::connect`], but passes the address of the server to the host key verification.
+    pub async fn connect_to(stream: S, peer_addr: SocketAddr, auth: ClientAuth) -> Result<Self> {
+        Self::connect_inner(stream, Some(peer_addr), auth).await
+    }
+
+    async fn connect_inner(
+        stream: S,
+        peer_addr: Option<SocketAddr>,
+        auth: ClientAuth,
+    ) -> Result<Self> {
         let (operations_send, operations_recv) = tokio::sync::mpsc::channel(15);
         let (channel_ops_send, channel_ops_recv) = tokio::sync::mpsc::channel(15);
 
         let mut this = Self {
             stream: Box::pin(stream),
             buf: [0; 1024],
+            send_buf: Vec::new(),
             operations_send,
             operations_recv,
             channel_ops_send,
             channel_ops_recv,
             channels: HashMap::new(),
+            channel_ops_blocked: false,
+            new_channels: VecDeque::new(),
             proto: cluelessh_protocol::ClientConnection::new(
                 cluelessh_transport::client::ClientConnection::new(cluelessh_protocol::OsRng),
                 cluelessh_protocol::auth::ClientAuth::new(auth.username.as_bytes().to_vec()),
             ),
             auth,
+            peer_addr,
+            verifying_host_key: false,
+            eof: false,
         };
 
         while !this.proto.is_open() {
             this.progress().await?;
+            if this.eof {
+                bail!("server closed the connection during authentication");
+            }
         }
 
         Ok(this)
@@ -76,6 +128,26 @@ impl<S: AsyncRead + AsyncWrite> ClientConnection<S> {
     /// Executes one loop iteration of the main loop.
     // IMPORTANT: no operations on this struct should ever block the main loop, except this one.
     pub async fn progress(&mut self) -> Result<()> {
+        if !self.verifying_host_key {
+            if let Some(public_key) = self.proto.is_waiting_on_host_key_verification() {
+                match self.auth.verify_host_key.clone() {
+                    Some(verify_host_key) => {
+                        self.verifying_host_key = true;
+                        let send = self.operations_send.clone();
+                        let request = VerifyHostKey {
+                            public_key: public_key.clone(),
+                            peer_addr: self.peer_addr,
+                        };
+                        tokio::spawn(async move {
+                            let result = verify_host_key(request).await;
+                            let _ = send.send(Operation::HostKeyVerified(result)).await;
+                        });
+                    }
+                    None => self.proto.host_key_verification_result(true),
+                }
+            }
+        }
+
         if let Some(auth) = self.proto.auth() {
             for req in auth.user_requests() {
                 match req {
@@ -102,10 +174,112 @@ impl<S: AsyncRead + AsyncWrite> ClientConnection<S> {
             }
         }
 
+        self.route_channel_updates().await?;
+
+        // Make sure that we send all queues messages before going into the select, waiting for things to happen.
+        self.send_off_data().await?;
+
+        tokio::select! {
+            read = self.stream.read(&mut self.buf) => {
+                let read = read.wrap_err("reading from connection")?;
+                if read == 0 {
+                    info!("Did not read any bytes from TCP stream, EOF");
+                    self.eof = true;
+                    return Ok(());
+                }
+                if let Err(err) = self.proto.recv_bytes(&self.buf[..read]) {
+                    match err {
+                        SshStatus::PeerError(err) => {
+                            bail!("disconnecting client after invalid operation: {err}");
+                        }
+                        SshStatus::Disconnect => {
+                            bail!("Received disconnect from server");
+                        }
+                    }
+                }
+                if self.channel_ops_blocked {
+                    if let Some(channels) = self.proto.channels() {
+                        self.channel_ops_blocked =
+                            crate::channels_blocked(channels, self.channels.keys().copied());
+                    }
+                }
+            }
+            channel_op = self.channel_ops_recv.recv(), if !self.channel_ops_blocked => {
+                let channels = self.proto.channels().expect("connection not ready");
+                if let Some(channel_op) = channel_op {
+                    // Handle everything that has piled up in one batch.
+                    let ops = crate::batch_channel_ops(channel_op, &mut self.channel_ops_recv);
+                    if channels.do_operations(ops) == OperationResult::WouldBlock {
+                        // Leave the operations in the queue until the peer has made room,
+                        // which makes the channels wait when sending.
+                        self.channel_ops_blocked = true;
+                    }
+                }
+            }
+            op = self.operations_recv.recv() => {
+                match op {
+                    Some(Operation::PasswordEntered(password)) => {
+                        if let Some(auth) = self.proto.auth() {
+                            auth.send_password(&password?);
+                        } else {
+                            debug!("Ignoring entered password as the state has moved on");
+                        }
+                    }
+                    Some(Operation::Signature(result)) => {
+                        let result = result?;
+                        if let Some(auth) = self.proto.auth() {
+                            auth.send_signature(result.key_alg_name, &result.public_key, &result.signature);
+                        } else {
+                            debug!("Ignoring signature as the state has moved on");
+                        }
+                    }
+                    Some(Operation::HostKeyVerified(result)) => {
+                        self.verifying_host_key = false;
+                        let is_ok = result?;
+                        self.proto.host_key_verification_result(is_ok);
+                        if !is_ok {
+                            self.send_off_data().await?;
+                            bail!("host key verification failed");
+                        }
+                    }
+                    None => {}
+                }
+                self.send_off_data().await?;
+            }
+        }
+
+        // Hand out what we have just received right away instead of waiting for the next event.
+        self.route_channel_updates().await?;
+
+        Ok(())
+    }
+
+    /// Passes the channel updates on to the channels.
+    async fn route_channel_updates(&mut self) -> Result<()> {
         if let Some(channels) = self.proto.channels() {
             while let Some(update) = channels.next_channel_update() {
                 match &update.kind {
-                    ChannelUpdateKind::Open(_) => {
+                    ChannelUpdateKind::Open(channel_kind) => {
+                        if self.channels.contains_key(&update.number) {
+                            bail!("attemping to open channel twice: {}", update.number);
+                        }
+
+                        let (updates_send, updates_recv) = tokio::sync::mpsc::channel(10);
+
+                        let number = update.number;
+
+                        self.channels
+                            .insert(number, ChannelState::Ready(updates_send));
+
+                        let channel = Channel {
+                            number,
+                            updates_recv,
+                            ops_send: self.channel_ops_send.clone(),
+                            kind: channel_kind.clone(),
+                        };
+                        self.new_channels.push_back(channel);
+                    }
+                    ChannelUpdateKind::OpenConfirmed { .. } => {
                         let channel = self
                             .channels
                             .get_mut(&update.number)
@@ -163,69 +337,29 @@ impl<S: AsyncRead + AsyncWrite> ClientConnection<S> {
                 }
             }
         }
-
-        // Make sure that we send all queues messages before going into the select, waiting for things to happen.
-        self.send_off_data().await?;
-
-        tokio::select! {
-            read = self.stream.read(&mut self.buf) => {
-                let read = read.wrap_err("reading from connection")?;
-                if read == 0 {
-                    info!("Did not read any bytes from TCP stream, EOF");
-                    return Ok(());
-                }
-                if let Err(err) = self.proto.recv_bytes(&self.buf[..read]) {
-                    match err {
-                        SshStatus::PeerError(err) => {
-                            bail!("disconnecting client after invalid operation: {err}");
-                        }
-                        SshStatus::Disconnect => {
-                            bail!("Received disconnect from server");
-                        }
-                    }
-                }
-            }
-            channel_op = self.channel_ops_recv.recv() => {
-                let channels = self.proto.channels().expect("connection not ready");
-                if let Some(channel_op) = channel_op {
-                    channels.do_operation(channel_op);
-                }
-            }
-            op = self.operations_recv.recv() => {
-                match op {
-                    Some(Operation::PasswordEntered(password)) => {
-                        if let Some(auth) = self.proto.auth() {
-                            auth.send_password(&password?);
-                        } else {
-                            debug!("Ignoring entered password as the state has moved on");
-                        }
-                    }
-                    Some(Operation::Signature(result)) => {
-                        let result = result?;
-                        if let Some(auth) = self.proto.auth() {
-                            auth.send_signature(result.key_alg_name, &result.public_key, &result.signature);
-                        } else {
-                            debug!("Ignoring signature as the state has moved on");
-                        }
-                    }
-                    None => {}
-                }
-                self.send_off_data().await?;
-            }
-        }
-
         Ok(())
     }
 
     async fn send_off_data(&mut self) -> Result<()> {
         self.proto.progress();
-        while let Some(msg) = self.proto.next_msg_to_send() {
-            self.stream
-                .write_all(&msg.to_bytes())
+        loop {
+            // We may have been cancelled in the middle of writing a packet, so finish that one first.
+            write_buffered(&mut self.stream, &mut self.send_buf)
                 .await
                 .wrap_err("writing response")?;
+            let msg = match self.proto.next_msg_to_send() {
+                Some(msg) => msg,
+                None => {
+                    // Channels only release a bounded amount of data at once, see if there is more now.
+                    self.proto.progress();
+                    match self.proto.next_msg_to_send() {
+                        Some(msg) => msg,
+                        None => return Ok(()),
+                    }
+                }
+            };
+            self.send_buf = msg.to_bytes();
         }
-        Ok(())
     }
 
     pub fn open_channel(&mut self, kind: ChannelKind) -> PendingChannel {
@@ -255,4 +389,18 @@ impl<S: AsyncRead + AsyncWrite> ClientConnection<S> {
             },
         }
     }
+
+    /// Asks the server to forward connections to `address` and `port` to us.
+    /// The server opens a channel for every connection, see [`Self::next_new_channel`].
+    pub fn request_tcpip_forward(&mut self, address: &str, port: u32) {
+        let Some(channels) = self.proto.channels() else {
+            panic!("connection not ready yet")
+        };
+        channels.request_tcpip_forward(address, port);
+    }
+
+    /// Returns the next channel that the server has opened for one of our remote forwards.
+    pub fn next_new_channel(&mut self) -> Option<Channel> {
+        self.new_channels.pop_front()
+    }
 }
@@ -1,9 +1,19 @@
 pub mod client;
+#[cfg(unix)]
+pub mod pty;
+pub mod quickstart;
 pub mod server;
+pub mod stream;
 
-use cluelessh_connection::{ChannelKind, ChannelNumber, ChannelOperation, ChannelOperationKind};
+use std::pin::Pin;
+
+use cluelessh_connection::{
+    ChannelKind, ChannelNumber, ChannelOperation, ChannelOperationKind, ChannelRequest,
+    ChannelsState, OperationResult,
+};
 use cluelessh_protocol::ChannelUpdateKind;
 use eyre::{OptionExt, Result};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 
 pub struct Channel {
     number: ChannelNumber,
@@ -20,6 +30,26 @@ impl Channel {
             .map_err(Into::into)
     }
 
+    /// Ends the channel after its command has exited: sends the `exit-status`, EOF and closes the channel,
+    /// in the order that clients expect, after all data that has been sent before.
+    /// Nothing else sent on the channel can end up in between.
+    pub async fn finish(&self, exit_status: u32) -> Result<()> {
+        let mut permits = self.ops_send.reserve_many(3).await?;
+        for kind in [
+            ChannelOperationKind::Request(ChannelRequest::ExitStatus {
+                status: exit_status,
+            }),
+            ChannelOperationKind::Eof,
+            ChannelOperationKind::Close,
+        ] {
+            let permit = permits
+                .next()
+                .expect("reserved a permit for every operation");
+            permit.send(self.number.construct_op(kind));
+        }
+        Ok(())
+    }
+
     pub async fn next_update(&mut self) -> Result<ChannelUpdateKind> {
         self.updates_recv
             .recv()
@@ -40,6 +70,53 @@ enum ChannelState {
     Ready(tokio::sync::mpsc::Sender<ChannelUpdateKind>),
 }
 
+/// The most channel operations that are handled at once, so that a busy channel cannot stall the main loop.
+const MAX_BATCHED_CHANNEL_OPS: usize = 64;
+
+/// Takes `first` and the operations that have piled up after it, for [`ChannelsState::do_operations`].
+fn batch_channel_ops(
+    first: ChannelOperation,
+    ops_recv: &mut tokio::sync::mpsc::Receiver<ChannelOperation>,
+) -> Vec<ChannelOperation> {
+    let mut ops = vec![first];
+    while ops.len() < MAX_BATCHED_CHANNEL_OPS {
+        let Ok(op) = ops_recv.try_recv() else {
+            break;
+        };
+        ops.push(op);
+    }
+    ops
+}
+
+/// Whether any of the channels still has too much data queued after [`OperationResult::WouldBlock`],
+/// see [`ChannelsState::resume_channel`]. No more operations are taken until it has been sent.
+fn channels_blocked(
+    channels: &mut ChannelsState,
+    numbers: impl IntoIterator<Item = ChannelNumber>,
+) -> bool {
+    numbers
+        .into_iter()
+        .any(|number| channels.resume_channel(number) == OperationResult::WouldBlock)
+}
+
+/// Writes all of `buf` to the stream, removing the written bytes from it.
+///
+/// Unlike `write_all`, this is cancellation safe: if the future is dropped,
+/// `buf` contains exactly the bytes that have not been written yet.
+async fn write_buffered<S: AsyncWrite>(
+    stream: &mut Pin<Box<S>>,
+    buf: &mut Vec<u8>,
+) -> std::io::Result<()> {
+    while !buf.is_empty() {
+        let written = stream.write(buf).await?;
+        if written == 0 {
+            return Err(std::io::ErrorKind::WriteZero.into());
+        }
+        buf.drain(..written);
+    }
+    Ok(())
+}
+
 pub struct PendingChannel {
     ready_recv: tokio::sync::oneshot::Receiver<Result<(), String>>,
     channel: Channel,
@@ -53,3 +130,74 @@ impl PendingChannel {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use cluelessh_connection::{ChannelKind, ChannelNumber, ChannelOperationKind, ChannelsState};
+    use cluelessh_transport::packet::Packet;
+
+    use crate::Channel;
+
+    #[tokio::test]
+    async fn finish() {
+        let (_updates_send, updates_recv) = tokio::sync::mpsc::channel(10);
+        let (ops_send, mut ops_recv) = tokio::sync::mpsc::channel(10);
+        let channel = Channel {
+            number: ChannelNumber(0),
+            updates_recv,
+            ops_send,
+            kind: ChannelKind::Session,
+        };
+
+        channel
+            .send(ChannelOperationKind::Data(b"output".to_vec()))
+            .await
+            .unwrap();
+        channel.finish(3).await.unwrap();
+        drop(channel);
+
+        let mut state = ChannelsState::new(true);
+        state
+            .recv_packet(Packet::new_msg_channel_open_session(
+                b"session", 0, 2048, 1024,
+            ))
+            .unwrap();
+        let _confirmation = state.packets_to_send().count();
+        while let Some(op) = ops_recv.recv().await {
+            state.do_operation(op);
+        }
+
+        let packets = state.packets_to_send().collect::<Vec<_>>();
+        assert_eq!(
+            packets.iter().map(Packet::packet_type).collect::<Vec<_>>(),
+            [
+                94, // SSH_MSG_CHANNEL_DATA
+                98, // SSH_MSG_CHANNEL_REQUEST
+                96, // SSH_MSG_CHANNEL_EOF
+                97, // SSH_MSG_CHANNEL_CLOSE
+            ]
+        );
+        let mut p = packets[1].payload_parser();
+        p.u8().unwrap();
+        assert_eq!(p.u32().unwrap(), 0);
+        assert_eq!(p.utf8_string().unwrap(), "exit-status");
+        assert!(!p.bool().unwrap());
+        assert_eq!(p.u32().unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn batches_are_bounded() {
+        let (ops_send, mut ops_recv) = tokio::sync::mpsc::channel(200);
+        for _ in 0..100 {
+            ops_send
+                .send(ChannelNumber(0).construct_op(ChannelOperationKind::Eof))
+                .await
+                .unwrap();
+        }
+
+        let first = ops_recv.recv().await.unwrap();
+        let ops = crate::batch_channel_ops(first, &mut ops_recv);
+        assert_eq!(ops.len(), crate::MAX_BATCHED_CHANNEL_OPS);
+        assert_eq!(ops_recv.len(), 100 - crate::MAX_BATCHED_CHANNEL_OPS);
+    }
+}
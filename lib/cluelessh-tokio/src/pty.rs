@@ -0,0 +1,95 @@
+//! Applying the terminal size requested by the client to a PTY backing a session.
+
+use std::{io, os::fd::AsFd};
+
+use cluelessh_connection::ChannelRequest;
+use rustix::termios::Winsize;
+
+/// Applies the dimensions of a `window-change` or `pty-req` request to the PTY `pty` with `TIOCSWINSZ`,
+/// which sends `SIGWINCH` to its foreground process group.
+///
+/// Returns `false` without touching the PTY for all other requests.
+/// Dimensions that don't fit into the kernel's 16 bits are saturated.
+pub fn resize_pty(pty: impl AsFd, request: &ChannelRequest) -> io::Result<bool> {
+    let (width_chars, height_rows, width_px, height_px) = match *request {
+        ChannelRequest::WindowChange {
+            width_chars,
+            height_rows,
+            width_px,
+            height_px,
+        }
+        | ChannelRequest::PtyReq {
+            width_chars,
+            height_rows,
+            width_px,
+            height_px,
+            ..
+        } => (width_chars, height_rows, width_px, height_px),
+        _ => return Ok(false),
+    };
+
+    let dimension = |value: u32| u16::try_from(value).unwrap_or(u16::MAX);
+    let winsize = Winsize {
+        ws_row: dimension(height_rows),
+        ws_col: dimension(width_chars),
+        ws_xpixel: dimension(width_px),
+        ws_ypixel: dimension(height_px),
+    };
+    rustix::termios::tcsetwinsize(pty, winsize)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use cluelessh_connection::{ChannelNumber, ChannelRequest, ChannelUpdateKind, ChannelsState};
+    use cluelessh_transport::packet::Packet;
+    use rustix::pty::OpenptFlags;
+
+    use super::resize_pty;
+
+    #[test]
+    fn window_change_resizes_pty() {
+        let pty = rustix::pty::openpt(OpenptFlags::RDWR | OpenptFlags::NOCTTY).unwrap();
+        rustix::pty::unlockpt(&pty).unwrap();
+
+        let state = &mut ChannelsState::new(true);
+        state
+            .recv_packet(Packet::new_msg_channel_open_session(
+                b"session", 0, 2048, 1024,
+            ))
+            .unwrap();
+        let _open = state.next_channel_update().unwrap();
+
+        state
+            .recv_packet(Packet::new_msg_channel_request_window_change(
+                0,
+                b"window-change",
+                false,
+                132,
+                43,
+                1056,
+                688,
+            ))
+            .unwrap();
+        let update = state.next_channel_update().unwrap();
+        assert_eq!(update.number, ChannelNumber(0));
+        let ChannelUpdateKind::Request(req) = update.kind else {
+            panic!("unexpected update: {:?}", update.kind);
+        };
+        assert!(matches!(req, ChannelRequest::WindowChange { .. }));
+
+        assert!(resize_pty(&pty, &req).unwrap());
+        let winsize = rustix::termios::tcgetwinsize(&pty).unwrap();
+        assert_eq!(
+            (
+                winsize.ws_col,
+                winsize.ws_row,
+                winsize.ws_xpixel,
+                winsize.ws_ypixel
+            ),
+            (132, 43, 1056, 688)
+        );
+
+        assert!(!resize_pty(&pty, &ChannelRequest::Shell { want_reply: false }).unwrap());
+    }
+}
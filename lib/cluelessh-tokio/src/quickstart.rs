@@ -0,0 +1,402 @@
+//! Helpers for getting a working server up in a few lines.
+//!
+//! This takes care of loading the host key, doing the key exchange and running the accept loop.
+//! All that's left to the caller is deciding who gets in and what happens in a session channel.
+
+use std::{future::Future, net::SocketAddr, path::Path, sync::Arc};
+
+use cluelessh_connection::ChannelKind;
+use cluelessh_keys::{
+    authorized_keys::AuthorizedKeyOptions,
+    private::{EncryptedPrivateKeys, PlaintextPrivateKey},
+};
+use cluelessh_protocol::{
+    auth::{
+        CheckPublicKey, KeyboardInteractive, KeyboardInteractiveResponse, VerifyPassword,
+        VerifySignature,
+    },
+    SshStatus,
+};
+use eyre::{bail, eyre, Context, Result};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, error, info, info_span, warn, Instrument};
+
+use crate::{
+    server::{
+        AuthFn, Error, KeyboardInteractivePrompts, ServerAuth, ServerConnection, ServerListener,
+    },
+    Channel,
+};
+
+/// The authentication callbacks of [`ServerAuth`], without the key exchange, which is handled by the quickstart server.
+#[derive(Clone, Default)]
+pub struct QuickstartAuth {
+    pub verify_password: Option<AuthFn<VerifyPassword, Result<bool>>>,
+    /// Returns the options of the matching authorized_keys entry if the signature is valid.
+    pub verify_signature: Option<AuthFn<VerifySignature, Result<Option<AuthorizedKeyOptions>>>>,
+    pub check_pubkey: Option<AuthFn<CheckPublicKey, Result<bool>>>,
+    pub keyboard_interactive_prompts:
+        Option<AuthFn<KeyboardInteractive, Result<KeyboardInteractivePrompts>>>,
+    pub verify_keyboard_interactive: Option<AuthFn<KeyboardInteractiveResponse, Result<bool>>>,
+    pub auth_banner: Option<String>,
+}
+
+/// Binds to `addr` and serves SSH connections until accepting fails.
+///
+/// Every session channel opened by a client is passed to `spawn_shell`, which runs on its own task.
+pub async fn run_shell_server<F, Fut>(
+    addr: SocketAddr,
+    host_key_path: &Path,
+    auth: QuickstartAuth,
+    spawn_shell: F,
+) -> Result<()>
+where
+    F: Fn(Channel) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    let listener = TcpListener::bind(addr).await.wrap_err("binding listener")?;
+    serve_shell(listener, host_key_path, auth, spawn_shell).await
+}
+
+/// Like [`run_shell_server`], but on an existing listener.
+pub async fn serve_shell<F, Fut>(
+    listener: TcpListener,
+    host_key_path: &Path,
+    auth: QuickstartAuth,
+    spawn_shell: F,
+) -> Result<()>
+where
+    F: Fn(Channel) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    let host_key = load_host_key(host_key_path)
+        .await
+        .wrap_err_with(|| format!("loading host key at '{}'", host_key_path.display()))?;
+
+    let transport_config = cluelessh_transport::server::ServerConfig {
+        server_identification: b"SSH-2.0-ClueleSSH_0.1\r\n".to_vec(),
+        host_keys: vec![host_key.private_key.public_key()],
+        ..Default::default()
+    };
+
+    let auth_verify = ServerAuth {
+        verify_password: auth.verify_password,
+        verify_signature: auth.verify_signature,
+        check_pubkey: auth.check_pubkey,
+        keyboard_interactive_prompts: auth.keyboard_interactive_prompts,
+        verify_keyboard_interactive: auth.verify_keyboard_interactive,
+        auth_banner: auth.auth_banner,
+        do_key_exchange: Arc::new(move |msg| {
+            let host_key = host_key.clone();
+            Box::pin(async move {
+                cluelessh_protocol::transport::server::do_key_exchange(
+                    msg,
+                    &host_key,
+                    &mut cluelessh_protocol::OsRng,
+                )
+                .map_err(|_| eyre!("error during key exchange"))
+            })
+        }),
+    };
+
+    let mut listener = ServerListener::new(listener, auth_verify, transport_config);
+    let spawn_shell = Arc::new(spawn_shell);
+
+    loop {
+        let conn = listener.accept().await?;
+        let span = info_span!("connection", addr = %conn.peer_addr());
+        let spawn_shell = spawn_shell.clone();
+        tokio::spawn(
+            async move {
+                if let Err(err) = handle_connection(conn, &*spawn_shell).await {
+                    error!(?err, "error handling connection");
+                }
+            }
+            .instrument(span),
+        );
+    }
+}
+
+async fn load_host_key(path: &Path) -> Result<PlaintextPrivateKey> {
+    let key = tokio::fs::read_to_string(path)
+        .await
+        .wrap_err("failed to open")?;
+    if PlaintextPrivateKey::is_pkcs8_pem(key.as_bytes()) {
+        return PlaintextPrivateKey::from_pkcs8_pem(key.as_bytes()).wrap_err("failed to parse");
+    }
+    let key = EncryptedPrivateKeys::parse(key.as_bytes()).wrap_err("failed to parse")?;
+
+    if key.requires_passphrase() {
+        bail!("host key requires a passphrase, which is not allowed");
+    }
+    let mut key = key.decrypt(None).wrap_err("failed to parse")?;
+    if key.len() != 1 {
+        bail!("host key must contain a single key");
+    }
+    Ok(key.remove(0))
+}
+
+async fn handle_connection<F, Fut>(
+    mut conn: ServerConnection<TcpStream>,
+    spawn_shell: &F,
+) -> Result<()>
+where
+    F: Fn(Channel) -> Fut,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    info!("Received a new connection");
+
+    loop {
+        match conn.progress().await {
+            Ok(()) => {}
+            Err(Error::ServerError(err)) => return Err(err),
+            Err(Error::SshStatus(SshStatus::PeerError(err))) => {
+                info!(?err, "disconnecting client after invalid operation");
+                return Ok(());
+            }
+            Err(Error::SshStatus(SshStatus::Disconnect)) => {
+                debug!("Received disconnect from client");
+                return Ok(());
+            }
+        }
+
+        while let Some(channel) = conn.next_new_channel() {
+            if *channel.kind() == ChannelKind::Session {
+                let shell = spawn_shell(channel);
+                tokio::spawn(async move {
+                    if let Err(err) = shell.await {
+                        error!(?err, "error in session channel");
+                    }
+                });
+            } else {
+                warn!("Trying to open non-session channel");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use cluelessh_connection::{ChannelKind, ChannelOperationKind, ChannelRequest};
+    use cluelessh_keys::{
+        private::{KeyEncryptionParams, PlaintextPrivateKey},
+        KeyGenerationParams, KeyType,
+    };
+    use cluelessh_protocol::ChannelUpdateKind;
+    use eyre::bail;
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::{serve_shell, QuickstartAuth};
+    use crate::client::{ClientAuth, ClientConnection};
+
+    const TEST_ED25519_PKCS8: &[u8] =
+        include_bytes!("../../cluelessh-keys/testdata/ed25519_pkcs8.pem");
+    const TEST_ECDSA_SHA2_NISTP256_PKCS8: &[u8] =
+        include_bytes!("../../cluelessh-keys/testdata/ecdsa_p256_pkcs8.pem");
+    const TEST_RSA_PKCS8: &[u8] = include_bytes!("../../cluelessh-keys/testdata/rsa_pkcs8.pem");
+
+    #[tokio::test]
+    async fn run_command() {
+        let host_key = PlaintextPrivateKey::generate(
+            "".into(),
+            KeyGenerationParams {
+                key_type: KeyType::Ed25519,
+            },
+        );
+        let host_key = host_key
+            .encrypt(KeyEncryptionParams::plaintext())
+            .unwrap()
+            .to_bytes_armored();
+        run_echo_command("openssh", host_key.as_bytes()).await;
+    }
+
+    #[tokio::test]
+    async fn run_command_pkcs8_ed25519() {
+        run_echo_command("pkcs8-ed25519", TEST_ED25519_PKCS8).await;
+    }
+
+    #[tokio::test]
+    async fn run_command_pkcs8_ecdsa() {
+        run_echo_command("pkcs8-ecdsa", TEST_ECDSA_SHA2_NISTP256_PKCS8).await;
+    }
+
+    #[tokio::test]
+    async fn run_command_pkcs8_rsa() {
+        run_echo_command("pkcs8-rsa", TEST_RSA_PKCS8).await;
+    }
+
+    #[tokio::test]
+    async fn reject_unexpected_host_key() {
+        let generate = || {
+            PlaintextPrivateKey::generate(
+                "".into(),
+                KeyGenerationParams {
+                    key_type: KeyType::Ed25519,
+                },
+            )
+        };
+        let host_key = generate();
+        let expected_host_key = generate().private_key.public_key();
+
+        let host_key_path = std::env::temp_dir().join(format!(
+            "cluelessh-quickstart-test-reject-{}",
+            std::process::id()
+        ));
+        std::fs::write(
+            &host_key_path,
+            host_key
+                .encrypt(KeyEncryptionParams::plaintext())
+                .unwrap()
+                .to_bytes_armored(),
+        )
+        .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server_host_key_path = host_key_path.clone();
+        tokio::spawn(async move {
+            serve_shell(
+                listener,
+                &server_host_key_path,
+                QuickstartAuth {
+                    verify_password: Some(Arc::new(|_| Box::pin(async { Ok(false) }))),
+                    ..Default::default()
+                },
+                |_| async { bail!("no shell must be opened") },
+            )
+            .await
+            .unwrap();
+        });
+
+        let (seen_send, mut seen_recv) = tokio::sync::mpsc::unbounded_channel();
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let result = tokio::time::timeout(
+            std::time::Duration::from_secs(10),
+            ClientConnection::connect_to(
+                stream,
+                addr,
+                ClientAuth {
+                    username: "user".into(),
+                    prompt_password: Arc::new(|| {
+                        Box::pin(async { bail!("must not authenticate") })
+                    }),
+                    sign_pubkey: Arc::new(|_| Box::pin(async { bail!("must not authenticate") })),
+                    verify_host_key: Some(Arc::new(move |request| {
+                        let _ = seen_send.send(request.clone());
+                        let is_ok = request.public_key == expected_host_key;
+                        Box::pin(async move { Ok(is_ok) })
+                    })),
+                },
+            ),
+        )
+        .await
+        .expect("client hung");
+        std::fs::remove_file(&host_key_path).unwrap();
+
+        let Err(err) = result else {
+            panic!("connected with an unexpected host key");
+        };
+        assert_eq!(err.to_string(), "host key verification failed");
+        let request = seen_recv.recv().await.unwrap();
+        assert_eq!(request.public_key, host_key.private_key.public_key());
+        assert_eq!(request.peer_addr, Some(addr));
+    }
+
+    /// Serves a command echoing shell with the host key file `host_key` and runs a command against it.
+    async fn run_echo_command(name: &str, host_key: &[u8]) {
+        let host_key_path = std::env::temp_dir().join(format!(
+            "cluelessh-quickstart-test-{name}-{}",
+            std::process::id()
+        ));
+        std::fs::write(&host_key_path, host_key).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let auth = QuickstartAuth {
+            verify_password: Some(Arc::new(|auth| {
+                Box::pin(async move { Ok(auth.password == "meow") })
+            })),
+            ..Default::default()
+        };
+        let server_host_key_path = host_key_path.clone();
+        tokio::spawn(async move {
+            serve_shell(
+                listener,
+                &server_host_key_path,
+                auth,
+                |mut channel| async move {
+                    loop {
+                        if let ChannelUpdateKind::Request(ChannelRequest::Exec {
+                            want_reply,
+                            command,
+                        }) = channel.next_update().await?
+                        {
+                            if want_reply {
+                                channel.send(ChannelOperationKind::Success).await?;
+                            }
+                            channel.send(ChannelOperationKind::Data(command)).await?;
+                            channel
+                                .send(ChannelOperationKind::Request(ChannelRequest::ExitStatus {
+                                    status: 0,
+                                }))
+                                .await?;
+                            channel.send(ChannelOperationKind::Eof).await?;
+                            channel.send(ChannelOperationKind::Close).await?;
+                            return Ok(());
+                        }
+                    }
+                },
+            )
+            .await
+            .unwrap();
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut conn = ClientConnection::connect(
+            stream,
+            ClientAuth {
+                username: "user".into(),
+                prompt_password: Arc::new(|| Box::pin(async { Ok("meow".to_owned()) })),
+                sign_pubkey: Arc::new(|_| Box::pin(async { bail!("no keys") })),
+                verify_host_key: None,
+            },
+        )
+        .await
+        .unwrap();
+        std::fs::remove_file(&host_key_path).unwrap();
+
+        let channel = conn.open_channel(ChannelKind::Session);
+        tokio::spawn(async move {
+            loop {
+                conn.progress().await.unwrap();
+            }
+        });
+
+        let mut channel = channel.wait_ready().await.unwrap();
+        channel
+            .send(ChannelOperationKind::Request(ChannelRequest::Exec {
+                want_reply: true,
+                command: b"echo hi".to_vec(),
+            }))
+            .await
+            .unwrap();
+
+        let mut output = Vec::new();
+        let mut exit_status = None;
+        loop {
+            match channel.next_update().await.unwrap() {
+                ChannelUpdateKind::Data { data } => output.extend_from_slice(&data),
+                ChannelUpdateKind::Request(ChannelRequest::ExitStatus { status }) => {
+                    exit_status = Some(status);
+                }
+                ChannelUpdateKind::Closed { .. } => break,
+                _ => {}
+            }
+        }
+        assert_eq!(output, b"echo hi");
+        assert_eq!(exit_status, Some(0));
+    }
+}
@@ -1,38 +1,144 @@
-use cluelessh_connection::{ChannelKind, ChannelNumber, ChannelOperation};
-use cluelessh_keys::public::PublicKey;
+use cluelessh_connection::{ChannelKind, ChannelNumber, ChannelOperation, OperationResult};
+use cluelessh_format::numbers;
+use cluelessh_keys::{authorized_keys::AuthorizedKeyOptions, public::PublicKey};
 use cluelessh_transport::server::{KeyExchangeParameters, KeyExchangeResponse};
-use futures::future::BoxFuture;
+use futures::{future::BoxFuture, stream::FuturesUnordered, FutureExt, StreamExt};
 use std::{
     collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    future::Future,
+    io,
     net::SocketAddr,
+    panic::AssertUnwindSafe,
+    path::PathBuf,
     pin::Pin,
     sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
 };
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncReadExt, AsyncWriteExt, ReadBuf},
     net::{TcpListener, TcpStream},
+    time::{sleep_until, Instant},
 };
 
 use cluelessh_protocol::{
-    auth::{AuthOption, CheckPublicKey, VerifyPassword, VerifySignature},
-    ChannelUpdateKind, SshStatus,
+    auth::{
+        AuthOption, CheckPublicKey, KeyboardInteractive, KeyboardInteractiveResponse,
+        VerifyPassword, VerifySignature,
+    },
+    ChannelUpdateKind, ConnectionPhase, SshStatus,
 };
 use eyre::{eyre, ContextCompat, OptionExt, Result, WrapErr};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tracing::info;
 
-use crate::{Channel, ChannelState, PendingChannel};
+use crate::{write_buffered, Channel, ChannelState, PendingChannel};
 
 pub struct ServerListener {
     listener: TcpListener,
     auth_verify: ServerAuth,
     transport_config: cluelessh_transport::server::ServerConfig, // TODO ratelimits etc
+    socket_options: SocketOptions,
+}
+
+/// Socket options applied to every connection accepted by a [`ServerListener`].
+#[derive(Debug, Clone, Copy)]
+pub struct SocketOptions {
+    /// Set `TCP_NODELAY`. Enabled by default, as interactive sessions suffer a lot from Nagle's algorithm.
+    pub nodelay: bool,
+    /// Set `SO_KEEPALIVE`.
+    pub keepalive: bool,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            keepalive: false,
+        }
+    }
+}
+
+impl SocketOptions {
+    fn apply(&self, stream: &TcpStream) -> std::io::Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+        socket2::SockRef::from(stream).set_keepalive(self.keepalive)?;
+        Ok(())
+    }
+}
+
+/// Accepts connections from several listeners at once, like IPv4 and IPv6 sockets
+/// and a Unix socket for local administration.
+pub struct MultiListener {
+    listeners: Vec<Listener>,
+    auth_verify: ServerAuth,
+    transport_config: cluelessh_transport::server::ServerConfig,
+    socket_options: SocketOptions,
+}
+
+/// One of the listeners of a [`MultiListener`].
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl From<TcpListener> for Listener {
+    fn from(value: TcpListener) -> Self {
+        Self::Tcp(value)
+    }
+}
+#[cfg(unix)]
+impl From<UnixListener> for Listener {
+    fn from(value: UnixListener) -> Self {
+        Self::Unix(value)
+    }
+}
+
+/// A connection accepted by a [`MultiListener`].
+pub enum MultiStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+/// The address of the peer of a [`ServerConnection`].
+#[derive(Debug, Clone)]
+pub enum PeerAddr {
+    Tcp(SocketAddr),
+    /// The path of the client socket, clients of Unix sockets are usually unnamed.
+    #[cfg(unix)]
+    Unix(Option<PathBuf>),
+}
+
+impl From<SocketAddr> for PeerAddr {
+    fn from(value: SocketAddr) -> Self {
+        Self::Tcp(value)
+    }
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            #[cfg(unix)]
+            Self::Unix(path) => match path {
+                Some(path) => write!(f, "unix:{}", path.display()),
+                None => write!(f, "unix:unnamed"),
+            },
+        }
+    }
 }
 
 pub struct ServerConnection<S> {
     stream: Pin<Box<S>>,
-    peer_addr: SocketAddr,
+    peer_addr: PeerAddr,
     buf: [u8; 1024],
+    /// Bytes of a message that have not been fully written yet.
+    send_buf: Vec<u8>,
 
     proto: cluelessh_protocol::ServerConnection,
     operations_send: tokio::sync::mpsc::Sender<Operation>,
@@ -43,18 +149,60 @@ pub struct ServerConnection<S> {
     channel_ops_recv: tokio::sync::mpsc::Receiver<ChannelOperation>,
 
     channels: HashMap<ChannelNumber, ChannelState>,
+    /// Whether a channel has queued too much data, so no more channel operations are taken for now.
+    channel_ops_blocked: bool,
 
     /// New channels opened by the peer.
     new_channels: VecDeque<Channel>,
 
     signature_in_progress: bool,
     auth_verify: ServerAuth,
+    /// See [`ServerConnection::set_inline_auth`].
+    inline_auth: bool,
+    /// Inline verifications that have not completed yet.
+    /// They are stored here so that cancelling [`ServerConnection::progress`] does not lose them.
+    inline_verifications: VecDeque<BoxFuture<'static, Operation>>,
+    /// See [`cluelessh_transport::server::ServerConfig::rekey_interval`].
+    rekey_interval: Option<Duration>,
+    rekey_deadline: Option<Instant>,
+    /// See [`ServerConnection::set_max_channel_open_rate`].
+    max_channel_open_rate: Option<ChannelOpenRate>,
+    /// When the client opened channels, for [`ServerConnection::set_max_channel_open_rate`].
+    recent_channel_opens: VecDeque<Instant>,
+
+    /// Whether [`ServerConnection::flush_and_close`] has queued the disconnect.
+    disconnect_queued: bool,
+    /// Whether [`ServerConnection::flush_and_close`] has shut down the stream.
+    closed: bool,
+    /// Set by [`ServerConnection::abort`] and [`AbortHandle::abort`].
+    aborted: Arc<tokio::sync::watch::Sender<bool>>,
+}
+
+/// See [`ServerConnection::set_max_channel_open_rate`].
+#[derive(Debug, Clone, Copy)]
+pub struct ChannelOpenRate {
+    pub max_opens: usize,
+    pub per: Duration,
+}
+
+/// Aborts a [`ServerConnection`] from anywhere, see [`ServerConnection::abort_handle`].
+#[derive(Clone)]
+pub struct AbortHandle(Arc<tokio::sync::watch::Sender<bool>>);
+
+impl AbortHandle {
+    /// Makes the current or next [`ServerConnection::progress`] of the connection abort it,
+    /// like [`ServerConnection::abort`].
+    pub fn abort(&self) {
+        self.0.send_replace(true);
+    }
 }
 
 enum Operation {
     VerifyPassword(String, Result<bool>),
     CheckPubkey(Result<bool>, PublicKey),
-    VerifySignature(String, Result<bool>),
+    VerifySignature(String, Result<Option<AuthorizedKeyOptions>>),
+    KeyboardInteractivePrompts(Result<KeyboardInteractivePrompts>),
+    VerifyKeyboardInteractive(String, Result<bool>),
     KeyExchangeResponseReceived(Result<KeyExchangeResponse>),
 }
 
@@ -63,8 +211,14 @@ pub type AuthFn<A, R> = Arc<dyn Fn(A) -> BoxFuture<'static, R> + Send + Sync>;
 #[derive(Clone)]
 pub struct ServerAuth {
     pub verify_password: Option<AuthFn<VerifyPassword, Result<bool>>>,
-    pub verify_signature: Option<AuthFn<VerifySignature, Result<bool>>>,
+    /// Returns the options of the matching authorized_keys entry if the signature is valid.
+    pub verify_signature: Option<AuthFn<VerifySignature, Result<Option<AuthorizedKeyOptions>>>>,
     pub check_pubkey: Option<AuthFn<CheckPublicKey, Result<bool>>>,
+    /// Returns the prompts that are sent to a client that starts keyboard-interactive auth.
+    pub keyboard_interactive_prompts:
+        Option<AuthFn<KeyboardInteractive, Result<KeyboardInteractivePrompts>>>,
+    /// Checks the responses of the client to the prompts.
+    pub verify_keyboard_interactive: Option<AuthFn<KeyboardInteractiveResponse, Result<bool>>>,
     pub do_key_exchange: AuthFn<KeyExchangeParameters, Result<KeyExchangeResponse>>,
     pub auth_banner: Option<String>,
 }
@@ -73,13 +227,24 @@ fn _assert_send_sync() {
     send::<ServerAuth>();
 }
 
+/// The info request of keyboard-interactive auth, see [`cluelessh_protocol::auth::ServerAuth::keyboard_interactive_info_request`].
+#[derive(Debug, Clone, Default)]
+pub struct KeyboardInteractivePrompts {
+    pub name: String,
+    pub instruction: String,
+    /// The prompt texts and whether the response should be echoed.
+    pub prompts: Vec<(String, bool)>,
+}
+
 pub struct SignWithHostKey {
     pub hash: [u8; 32],
     pub public_key: PublicKey,
 }
 
 pub enum Error {
+    /// The peer disconnected or violated the protocol, which is not a bug on our side.
     SshStatus(SshStatus),
+    /// An internal error of the server.
     ServerError(eyre::Report),
 }
 impl From<eyre::Report> for Error {
@@ -87,6 +252,16 @@ impl From<eyre::Report> for Error {
         Self::ServerError(value)
     }
 }
+impl From<SshStatus> for Error {
+    fn from(value: SshStatus) -> Self {
+        Self::SshStatus(value)
+    }
+}
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Self {
+        Self::ServerError(value.into())
+    }
+}
 
 impl ServerListener {
     pub fn new(
@@ -98,30 +273,140 @@ impl ServerListener {
             listener,
             auth_verify,
             transport_config,
+            socket_options: SocketOptions::default(),
         }
     }
 
+    pub fn set_socket_options(&mut self, socket_options: SocketOptions) {
+        self.socket_options = socket_options;
+    }
+
     pub async fn accept(&mut self) -> Result<ServerConnection<TcpStream>> {
         let (conn, peer_addr) = self.listener.accept().await?;
+        self.socket_options
+            .apply(&conn)
+            .wrap_err("setting socket options")?;
+
+        ServerConnection::new(
+            conn,
+            peer_addr,
+            self.auth_verify.clone(),
+            self.transport_config.clone(),
+        )
+    }
+}
+
+impl MultiListener {
+    /// Panics if there are no listeners.
+    pub fn new(
+        listeners: Vec<Listener>,
+        auth_verify: ServerAuth,
+        transport_config: cluelessh_transport::server::ServerConfig,
+    ) -> Self {
+        assert!(!listeners.is_empty(), "no listeners provided");
+        Self {
+            listeners,
+            auth_verify,
+            transport_config,
+            socket_options: SocketOptions::default(),
+        }
+    }
+
+    /// The options for TCP connections, see [`ServerListener::set_socket_options`].
+    pub fn set_socket_options(&mut self, socket_options: SocketOptions) {
+        self.socket_options = socket_options;
+    }
 
-        Ok(ServerConnection::new(
+    /// Accepts the next connection from any of the listeners.
+    pub async fn accept(&mut self) -> Result<ServerConnection<MultiStream>> {
+        let socket_options = self.socket_options;
+        let mut accepts = self
+            .listeners
+            .iter()
+            .map(|listener| async move {
+                match listener {
+                    Listener::Tcp(listener) => {
+                        let (conn, peer_addr) = listener.accept().await?;
+                        socket_options
+                            .apply(&conn)
+                            .wrap_err("setting socket options")?;
+                        Ok((MultiStream::Tcp(conn), PeerAddr::Tcp(peer_addr)))
+                    }
+                    #[cfg(unix)]
+                    Listener::Unix(listener) => {
+                        let (conn, peer_addr) = listener.accept().await?;
+                        let path = peer_addr.as_pathname().map(ToOwned::to_owned);
+                        Ok((MultiStream::Unix(conn), PeerAddr::Unix(path)))
+                    }
+                }
+            })
+            .collect::<FuturesUnordered<_>>();
+        let result: Result<_> = accepts.next().await.expect("no listeners");
+        let (conn, peer_addr) = result?;
+
+        ServerConnection::new(
             conn,
             peer_addr,
             self.auth_verify.clone(),
             self.transport_config.clone(),
-        ))
+        )
+    }
+}
+
+impl AsyncRead for MultiStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for MultiStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
     }
 }
 
 impl<S: AsyncRead + AsyncWrite> ServerConnection<S> {
     pub fn new(
         stream: S,
-        peer_addr: SocketAddr,
+        peer_addr: impl Into<PeerAddr>,
         auth_verify: ServerAuth,
         transport_config: cluelessh_transport::server::ServerConfig,
-    ) -> Self {
+    ) -> Result<Self> {
         let (operations_send, operations_recv) = tokio::sync::mpsc::channel(15);
         let (channel_ops_send, channel_ops_recv) = tokio::sync::mpsc::channel(15);
+        let rekey_interval = transport_config.rekey_interval;
 
         let mut options = HashSet::new();
         if auth_verify.verify_password.is_some() {
@@ -130,6 +415,9 @@ impl<S: AsyncRead + AsyncWrite> ServerConnection<S> {
         if auth_verify.verify_signature.is_some() {
             options.insert(AuthOption::PublicKey);
         }
+        if auth_verify.verify_keyboard_interactive.is_some() {
+            options.insert(AuthOption::KeyboardInteractive);
+        }
 
         if options.is_empty() {
             panic!("no auth options provided");
@@ -139,37 +427,74 @@ impl<S: AsyncRead + AsyncWrite> ServerConnection<S> {
             auth_verify.verify_signature.is_some(),
             "Public key auth only partially supported"
         );
+        assert_eq!(
+            auth_verify.keyboard_interactive_prompts.is_some(),
+            auth_verify.verify_keyboard_interactive.is_some(),
+            "Keyboard-interactive auth only partially supported"
+        );
 
-        Self {
+        let transport = cluelessh_transport::server::ServerConnection::new(
+            cluelessh_protocol::OsRng,
+            transport_config,
+        )?;
+
+        Ok(Self {
             stream: Box::pin(stream),
-            peer_addr,
+            peer_addr: peer_addr.into(),
             buf: [0; 1024],
+            send_buf: Vec::new(),
             operations_send,
             operations_recv,
             channel_ops_send,
             channel_ops_recv,
             channels: HashMap::new(),
+            channel_ops_blocked: false,
             proto: cluelessh_protocol::ServerConnection::new(
-                cluelessh_transport::server::ServerConnection::new(
-                    cluelessh_protocol::OsRng,
-                    transport_config,
-                ),
+                transport,
                 options,
                 auth_verify.auth_banner.clone(),
             ),
             new_channels: VecDeque::new(),
             auth_verify,
+            inline_auth: false,
+            inline_verifications: VecDeque::new(),
+            max_channel_open_rate: None,
+            recent_channel_opens: VecDeque::new(),
+            rekey_interval,
+            rekey_deadline: rekey_interval.map(|interval| Instant::now() + interval),
             signature_in_progress: false,
-        }
+            disconnect_queued: false,
+            closed: false,
+            aborted: Arc::new(tokio::sync::watch::Sender::new(false)),
+        })
     }
 
-    pub fn peer_addr(&self) -> SocketAddr {
-        self.peer_addr
+    pub fn peer_addr(&self) -> &PeerAddr {
+        &self.peer_addr
     }
 
     /// Executes one loop iteration of the main loop.
+    ///
+    /// Once the connection has been aborted, this shuts down the stream and returns [`SshStatus::Disconnect`].
     // IMPORTANT: no operations on this struct should ever block the main loop, except this one.
     pub async fn progress(&mut self) -> Result<(), Error> {
+        let mut aborted = self.aborted.subscribe();
+        let result = tokio::select! {
+            biased;
+            _ = aborted.wait_for(|aborted| *aborted) => None,
+            // Cancelling it is fine, a previous progress may always have been cancelled.
+            result = self.progress_inner() => Some(result),
+        };
+        match result {
+            Some(result) => result,
+            None => {
+                self.abort().await;
+                Err(Error::SshStatus(SshStatus::Disconnect))
+            }
+        }
+    }
+
+    async fn progress_inner(&mut self) -> Result<(), Error> {
         if let Some(params) = self.proto.is_waiting_on_key_exchange() {
             if !self.signature_in_progress {
                 self.signature_in_progress = true;
@@ -178,7 +503,7 @@ impl<S: AsyncRead + AsyncWrite> ServerConnection<S> {
 
                 let do_key_exchange = self.auth_verify.do_key_exchange.clone();
                 tokio::spawn(async move {
-                    let result = do_key_exchange(params).await;
+                    let result = catch_panic(async { do_key_exchange(params).await }).await;
                     let _ = send
                         .send(Operation::KeyExchangeResponseReceived(result))
                         .await;
@@ -187,63 +512,99 @@ impl<S: AsyncRead + AsyncWrite> ServerConnection<S> {
         }
 
         if let Some(auth) = self.proto.auth() {
-            for req in auth.server_requests() {
-                match req {
+            let requests = auth.server_requests().collect::<Vec<_>>();
+            for req in requests {
+                let verification: BoxFuture<'static, Operation> = match req {
                     cluelessh_protocol::auth::ServerRequest::VerifyPassword(password_verify) => {
-                        let send = self.operations_send.clone();
                         let verify = self
                             .auth_verify
                             .verify_password
                             .clone()
                             .ok_or_eyre("password auth not supported")?;
-                        tokio::spawn(async move {
-                            let result = verify(password_verify.clone()).await;
-                            let _ = send
-                                .send(Operation::VerifyPassword(password_verify.user, result))
-                                .await;
-                        });
+                        Box::pin(async move {
+                            let result =
+                                catch_panic(async { verify(password_verify.clone()).await }).await;
+                            Operation::VerifyPassword(password_verify.user, result)
+                        })
                     }
                     cluelessh_protocol::auth::ServerRequest::CheckPubkey(check_pubkey) => {
-                        let send = self.operations_send.clone();
                         let check = self
                             .auth_verify
                             .check_pubkey
                             .clone()
                             .ok_or_eyre("pubkey auth not supported")?;
-                        tokio::spawn(async move {
-                            let result = check(check_pubkey.clone()).await;
-                            let _ = send
-                                .send(Operation::CheckPubkey(result, check_pubkey.public_key))
-                                .await;
-                        });
+                        Box::pin(async move {
+                            let result =
+                                catch_panic(async { check(check_pubkey.clone()).await }).await;
+                            Operation::CheckPubkey(result, check_pubkey.public_key)
+                        })
                     }
                     cluelessh_protocol::auth::ServerRequest::VerifySignature(pubkey_verify) => {
-                        let send = self.operations_send.clone();
                         let verify = self
                             .auth_verify
                             .verify_signature
                             .clone()
                             .ok_or_eyre("pubkey auth not supported")?;
-                        tokio::spawn(async move {
-                            let result = verify(pubkey_verify.clone()).await;
-                            let _ = send
-                                .send(Operation::VerifySignature(pubkey_verify.user, result))
-                                .await;
-                        });
+                        Box::pin(async move {
+                            let result =
+                                catch_panic(async { verify(pubkey_verify.clone()).await }).await;
+                            Operation::VerifySignature(pubkey_verify.user, result)
+                        })
+                    }
+                    cluelessh_protocol::auth::ServerRequest::KeyboardInteractive(start) => {
+                        let prompts = self
+                            .auth_verify
+                            .keyboard_interactive_prompts
+                            .clone()
+                            .ok_or_eyre("keyboard-interactive auth not supported")?;
+                        Box::pin(async move {
+                            let result = catch_panic(async { prompts(start).await }).await;
+                            Operation::KeyboardInteractivePrompts(result)
+                        })
+                    }
+                    cluelessh_protocol::auth::ServerRequest::KeyboardInteractiveResponse(
+                        response,
+                    ) => {
+                        let verify = self
+                            .auth_verify
+                            .verify_keyboard_interactive
+                            .clone()
+                            .ok_or_eyre("keyboard-interactive auth not supported")?;
+                        Box::pin(async move {
+                            let result =
+                                catch_panic(async { verify(response.clone()).await }).await;
+                            Operation::VerifyKeyboardInteractive(response.user, result)
+                        })
                     }
+                };
+                if self.inline_auth {
+                    self.inline_verifications.push_back(verification);
+                } else {
+                    let send = self.operations_send.clone();
+                    tokio::spawn(async move {
+                        let _ = send.send(verification.await).await;
+                    });
                 }
             }
         }
 
+        while let Some(verification) = self.inline_verifications.front_mut() {
+            let op = verification.await;
+            self.inline_verifications.pop_front();
+            self.apply_operation(op)?;
+        }
+
+        let mut too_many_opens = false;
         if let Some(channels) = self.proto.channels() {
             while let Some(update) = channels.next_channel_update() {
                 match &update.kind {
-                    ChannelUpdateKind::Open(channel_kind) => {
-                        let channel = self.channels.get_mut(&update.number);
-
+                    ChannelUpdateKind::OpenConfirmed { .. } => {
+                        let channel = self
+                            .channels
+                            .get_mut(&update.number)
+                            .wrap_err("unknown channel")?;
                         match channel {
-                            // We opened.
-                            Some(ChannelState::Pending { updates_send, .. }) => {
+                            ChannelState::Pending { updates_send, .. } => {
                                 let updates_send = updates_send.clone();
                                 let old = self
                                     .channels
@@ -255,30 +616,51 @@ impl<S: AsyncRead + AsyncWrite> ServerConnection<S> {
                                     _ => unreachable!(),
                                 }
                             }
-                            Some(ChannelState::Ready(_)) => {
+                            ChannelState::Ready(_) => {
                                 return Err(Error::ServerError(eyre!(
                                     "attemping to open channel twice: {}",
                                     update.number
                                 )))
                             }
-                            // They opened.
-                            None => {
-                                let (updates_send, updates_recv) = tokio::sync::mpsc::channel(10);
-
-                                let number = update.number;
-
-                                self.channels
-                                    .insert(number, ChannelState::Ready(updates_send));
-
-                                let channel = Channel {
-                                    number,
-                                    updates_recv,
-                                    ops_send: self.channel_ops_send.clone(),
-                                    kind: channel_kind.clone(),
-                                };
-                                self.new_channels.push_back(channel);
+                        }
+                    }
+                    ChannelUpdateKind::Open(channel_kind) => {
+                        if self.channels.contains_key(&update.number) {
+                            return Err(Error::ServerError(eyre!(
+                                "attemping to open channel twice: {}",
+                                update.number
+                            )));
+                        }
+                        if let Some(rate) = self.max_channel_open_rate {
+                            let now = Instant::now();
+                            while self
+                                .recent_channel_opens
+                                .front()
+                                .is_some_and(|&open| now.duration_since(open) >= rate.per)
+                            {
+                                self.recent_channel_opens.pop_front();
                             }
+                            if self.recent_channel_opens.len() >= rate.max_opens {
+                                too_many_opens = true;
+                                break;
+                            }
+                            self.recent_channel_opens.push_back(now);
                         }
+
+                        let (updates_send, updates_recv) = tokio::sync::mpsc::channel(10);
+
+                        let number = update.number;
+
+                        self.channels
+                            .insert(number, ChannelState::Ready(updates_send));
+
+                        let channel = Channel {
+                            number,
+                            updates_recv,
+                            ops_send: self.channel_ops_send.clone(),
+                            kind: channel_kind.clone(),
+                        };
+                        self.new_channels.push_back(channel);
                     }
                     ChannelUpdateKind::OpenFailed { message, .. } => {
                         let channel = self
@@ -320,6 +702,18 @@ impl<S: AsyncRead + AsyncWrite> ServerConnection<S> {
                 }
             }
         }
+        if too_many_opens {
+            let rate = self.max_channel_open_rate.unwrap();
+            self.flush_and_close(
+                numbers::SSH_DISCONNECT_TOO_MANY_CONNECTIONS,
+                "too many channels opened",
+            )
+            .await?;
+            return Err(Error::SshStatus(SshStatus::PeerError(format!(
+                "opened more than {} channels within {:?}",
+                rate.max_opens, rate.per
+            ))));
+        }
 
         // Make sure that we send all queued messages before going into the select, waiting for things to happen.
         self.send_off_data().await?;
@@ -332,48 +726,154 @@ impl<S: AsyncRead + AsyncWrite> ServerConnection<S> {
                     return Err(Error::SshStatus(SshStatus::Disconnect));
                 }
                 if let Err(err) = self.proto.recv_bytes(&self.buf[..read]) {
-                    return Err(Error::SshStatus(err));
+                    // Send the SSH_MSG_DISCONNECT that may have been queued for the error.
+                    let _ = self.send_off_data().await;
+                    return Err(err.into());
+                }
+                if self.channel_ops_blocked {
+                    if let Some(channels) = self.proto.channels() {
+                        self.channel_ops_blocked =
+                            crate::channels_blocked(channels, self.channels.keys().copied());
+                    }
                 }
             }
-            channel_op = self.channel_ops_recv.recv() => {
+            channel_op = self.channel_ops_recv.recv(), if !self.channel_ops_blocked => {
                 let channels = self.proto.channels().expect("connection not ready");
                 if let Some(channel_op) = channel_op {
-                    channels.do_operation(channel_op);
+                    // Handle everything that has piled up in one batch.
+                    let ops = crate::batch_channel_ops(channel_op, &mut self.channel_ops_recv);
+                    if channels.do_operations(ops) == OperationResult::WouldBlock {
+                        // Leave the operations in the queue until the peer has made room,
+                        // which makes the channels wait when sending.
+                        self.channel_ops_blocked = true;
+                    }
                 }
             }
             op = self.operations_recv.recv() => {
-                match op {
-                    Some(Operation::VerifySignature(user, result)) => if let Some(auth) = self.proto.auth() {
-                        auth.verification_result(result?, user);
-                    },
-                    Some(Operation::CheckPubkey(result, public_key)) => if let Some(auth) = self.proto.auth() {
-                        auth.pubkey_check_result(result?, public_key);
-                    },
-                    Some(Operation::VerifyPassword(user, result)) => if let Some(auth) = self.proto.auth() {
-                        auth.verification_result(result?, user);
-                    },
-                    Some(Operation::KeyExchangeResponseReceived(signature)) => {
-                        let signature = signature?;
-                        self.proto.do_key_exchange(signature);
-                    }
-                    None => {}
+                if let Some(op) = op {
+                    self.apply_operation(op)?;
                 }
                 self.send_off_data().await?;
             }
+            _ = sleep_until(self.rekey_deadline.unwrap_or_else(Instant::now)), if self.rekey_deadline.is_some() => {
+                self.rekey_deadline = self.rekey_interval.map(|interval| Instant::now() + interval);
+                self.proto.rekey_interval_elapsed();
+                self.send_off_data().await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn apply_operation(&mut self, op: Operation) -> Result<(), Error> {
+        match op {
+            Operation::VerifySignature(user, result) => {
+                if let Some(auth) = self.proto.auth() {
+                    auth.verification_result_with_options(result?, user);
+                }
+            }
+            Operation::CheckPubkey(result, public_key) => {
+                if let Some(auth) = self.proto.auth() {
+                    auth.pubkey_check_result(result?, public_key);
+                }
+            }
+            Operation::VerifyPassword(user, result) => {
+                if let Some(auth) = self.proto.auth() {
+                    auth.verification_result(result?, user);
+                }
+            }
+            Operation::KeyboardInteractivePrompts(result) => {
+                let request = result?;
+                if let Some(auth) = self.proto.auth() {
+                    let prompts = request
+                        .prompts
+                        .iter()
+                        .map(|(prompt, echo)| (prompt.as_str(), *echo))
+                        .collect::<Vec<_>>();
+                    auth.keyboard_interactive_info_request(
+                        &request.name,
+                        &request.instruction,
+                        &prompts,
+                    );
+                }
+            }
+            Operation::VerifyKeyboardInteractive(user, result) => {
+                if let Some(auth) = self.proto.auth() {
+                    auth.verification_result(result?, user);
+                }
+            }
+            Operation::KeyExchangeResponseReceived(signature) => {
+                let signature = signature?;
+                self.proto.do_key_exchange(signature);
+            }
         }
+        Ok(())
+    }
 
+    /// Ends the connection cleanly: sends an `SSH_MSG_DISCONNECT` with a reason code from
+    /// `cluelessh_format::numbers` after all pending output, writes everything and shuts down the stream.
+    /// Calling it again does nothing more than finishing a previous call that was cancelled or failed.
+    pub async fn flush_and_close(&mut self, reason: u32, description: &str) -> Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        if !self.disconnect_queued {
+            self.proto.disconnect(reason, description);
+            self.disconnect_queued = true;
+        }
+        self.send_off_data().await?;
+        self.stream
+            .shutdown()
+            .await
+            .wrap_err("shutting down connection")?;
+        self.closed = true;
         Ok(())
     }
 
+    /// Ends the connection right away, without sending anything that is still pending or an `SSH_MSG_DISCONNECT`.
+    /// The stream is shut down, all channels are closed and later calls to [`ServerConnection::progress`] fail.
+    ///
+    /// This is meant for peers that should not get any more of our time, like abusive ones.
+    /// Use [`ServerConnection::flush_and_close`] to end a connection cleanly.
+    pub async fn abort(&mut self) {
+        self.aborted.send_replace(true);
+        if self.closed {
+            return;
+        }
+        info!("Aborting connection");
+        self.closed = true;
+        self.send_buf = Vec::new();
+        // Dropping the senders lets all channels see the end of their updates.
+        self.channels.clear();
+        self.new_channels.clear();
+        let _ = self.stream.shutdown().await;
+    }
+
+    /// A handle for aborting the connection from another task, see [`ServerConnection::abort`].
+    pub fn abort_handle(&self) -> AbortHandle {
+        AbortHandle(self.aborted.clone())
+    }
+
     async fn send_off_data(&mut self) -> Result<()> {
         self.proto.progress();
-        while let Some(msg) = self.proto.next_msg_to_send() {
-            self.stream
-                .write_all(&msg.to_bytes())
+        loop {
+            // We may have been cancelled in the middle of writing a packet, so finish that one first.
+            write_buffered(&mut self.stream, &mut self.send_buf)
                 .await
                 .wrap_err("writing response")?;
+            let msg = match self.proto.next_msg_to_send() {
+                Some(msg) => msg,
+                None => {
+                    // Channels only release a bounded amount of data at once, see if there is more now.
+                    self.proto.progress();
+                    match self.proto.next_msg_to_send() {
+                        Some(msg) => msg,
+                        None => return Ok(()),
+                    }
+                }
+            };
+            self.send_buf = msg.to_bytes();
         }
-        Ok(())
     }
 
     pub fn open_channel(&mut self, kind: ChannelKind) -> PendingChannel {
@@ -408,7 +908,784 @@ impl<S: AsyncRead + AsyncWrite> ServerConnection<S> {
         self.new_channels.pop_front()
     }
 
+    /// Returns the next global request of the client that is in [`cluelessh_connection::ChannelConfig::allowed_global_requests`].
+    /// If it wants a reply, the consumer must reply to it with [`Self::reply_global_request`].
+    pub fn next_global_request(&mut self) -> Option<cluelessh_connection::GlobalRequest> {
+        self.proto.next_global_request()
+    }
+
+    /// See [`cluelessh_connection::ChannelsState::reply_global_request`].
+    pub fn reply_global_request(&mut self, response: Option<&[u8]>) {
+        self.proto.reply_global_request(response);
+    }
+
+    /// Run the authentication callbacks of the [`ServerAuth`] inline in [`ServerConnection::progress`]
+    /// instead of spawning a task for each of them, which saves a round-trip through the task and a channel.
+    /// The main loop is blocked while they run, so only enable this for callbacks that complete immediately,
+    /// like looking up an in-memory table.
+    pub fn set_inline_auth(&mut self, inline: bool) {
+        self.inline_auth = inline;
+    }
+
+    /// Sets the configuration for the channels, see [`cluelessh_protocol::ServerConnection::set_channel_config`].
+    pub fn set_channel_config(
+        &mut self,
+        channel_config: cluelessh_connection::ChannelConfig,
+    ) -> Result<(), cluelessh_connection::InvalidConfig> {
+        self.proto.set_channel_config(channel_config)
+    }
+
+    /// Disconnect the client with `SSH_DISCONNECT_TOO_MANY_CONNECTIONS` if it opens more channels than this
+    /// in a sliding window, to stop it from creating work for us by opening and closing channels over and over.
+    pub fn set_max_channel_open_rate(&mut self, rate: Option<ChannelOpenRate>) {
+        self.max_channel_open_rate = rate;
+    }
+
+    /// See [`cluelessh_protocol::auth::ServerAuth::set_allow_insecure_ssh_rsa`].
+    pub fn set_allow_insecure_ssh_rsa(&mut self, allow: bool) {
+        self.proto.set_allow_insecure_ssh_rsa(allow);
+    }
+
+    /// See [`cluelessh_protocol::auth::ServerAuth::set_max_packets`].
+    pub fn set_max_auth_packets(&mut self, max: Option<usize>) {
+        self.proto.set_max_auth_packets(max);
+    }
+
+    /// See [`cluelessh_protocol::ServerConnection::phase`].
+    pub fn phase(&self) -> ConnectionPhase {
+        self.proto.phase()
+    }
+
     pub fn inner(&self) -> &cluelessh_protocol::ServerConnection {
         &self.proto
     }
 }
+
+/// Runs a callback of [`ServerAuth`], turning a panic into an error.
+/// Otherwise, the result would never arrive and the connection would wait for it forever.
+async fn catch_panic<T>(callback: impl Future<Output = Result<T>>) -> Result<T> {
+    match AssertUnwindSafe(callback).catch_unwind().await {
+        Ok(result) => result,
+        Err(_) => Err(eyre!("authentication callback panicked")),
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> ServerConnection<S> {
+    /// Tears down the connection and recovers the underlying stream.
+    ///
+    /// Also returns the bytes that the protocol has queued for sending but that have not been written yet.
+    /// All bytes read from the stream have already been passed on to the protocol, so there is no unread data left over.
+    pub fn into_parts(mut self) -> (S, Vec<u8>) {
+        let mut unsent = self.send_buf;
+        while let Some(msg) = self.proto.next_msg_to_send() {
+            unsent.extend_from_slice(&msg.to_bytes());
+        }
+        (*Pin::into_inner(self.stream), unsent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cluelessh_connection::ChannelKind;
+    use cluelessh_format::numbers;
+    use cluelessh_keys::{private::PlaintextPrivateKey, KeyGenerationParams, KeyType};
+    use cluelessh_protocol::{ConnectionPhase, SshStatus};
+    use eyre::eyre;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use std::{
+        collections::HashSet,
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
+
+    use cluelessh_transport::packet::{PacketDirection, PacketTracer};
+    use tokio::time::Instant;
+
+    use super::{
+        ChannelOpenRate, Error, MultiListener, MultiStream, PeerAddr, ServerAuth, ServerConnection,
+        ServerListener, SocketOptions,
+    };
+
+    #[tokio::test]
+    async fn flush_and_close() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut conn = ServerConnection::new(
+            server,
+            "127.0.0.1:22".parse::<SocketAddr>().unwrap(),
+            test_auth(),
+            cluelessh_transport::server::ServerConfig {
+                server_identification: b"SSH-2.0-ClueleSSH_0.1\r\n".to_vec(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let (mut client_read, mut client_write) = tokio::io::split(client);
+        assert_eq!(conn.phase(), ConnectionPhase::ProtocolExchange);
+        client_write
+            .write_all(b"SSH-2.0-OpenSSH_9.7\r\n")
+            .await
+            .unwrap();
+        assert!(conn.progress().await.is_ok());
+        assert_eq!(conn.phase(), ConnectionPhase::KeyExchange);
+
+        // SSH_DISCONNECT_BY_APPLICATION
+        conn.flush_and_close(11, "bye").await.unwrap();
+        conn.flush_and_close(11, "bye").await.unwrap();
+
+        let mut output = Vec::new();
+        client_read.read_to_end(&mut output).await.unwrap();
+        let packet = output
+            .strip_prefix(b"SSH-2.0-ClueleSSH_0.1\r\n".as_slice())
+            .unwrap();
+        let packet_len = u32::from_be_bytes(packet[..4].try_into().unwrap()) as usize;
+        // A single packet, then EOF.
+        assert_eq!(packet.len(), 4 + packet_len);
+        let payload = &packet[5..];
+        // SSH_MSG_DISCONNECT
+        assert_eq!(payload[0], 1);
+        assert_eq!(payload[1..5], 11_u32.to_be_bytes());
+        assert_eq!(payload[5..9], 3_u32.to_be_bytes());
+        assert_eq!(&payload[9..12], b"bye");
+    }
+
+    #[tokio::test]
+    async fn rejected_client_identification() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut conn = ServerConnection::new(
+            server,
+            "127.0.0.1:22".parse::<SocketAddr>().unwrap(),
+            test_auth(),
+            cluelessh_transport::server::ServerConfig {
+                server_identification: b"SSH-2.0-ClueleSSH_0.1\r\n".to_vec(),
+                accept_client_identification: Some(Arc::new(|ident| {
+                    !ident.starts_with(b"SSH-2.0-libssh")
+                })),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let (mut client_read, mut client_write) = tokio::io::split(client);
+        client_write
+            .write_all(b"SSH-2.0-libssh_0.9.6\r\n")
+            .await
+            .unwrap();
+        assert!(conn.progress().await.is_err());
+        drop(conn);
+
+        let mut output = Vec::new();
+        client_read.read_to_end(&mut output).await.unwrap();
+        let packet = output
+            .strip_prefix(b"SSH-2.0-ClueleSSH_0.1\r\n".as_slice())
+            .unwrap();
+        // SSH_MSG_DISCONNECT with SSH_DISCONNECT_HOST_NOT_ALLOWED_TO_CONNECT
+        assert_eq!(packet[5], 1);
+        assert_eq!(packet[6..10], 1_u32.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn abort() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut conn = ServerConnection::new(
+            server,
+            "127.0.0.1:22".parse::<SocketAddr>().unwrap(),
+            test_auth(),
+            cluelessh_transport::server::ServerConfig {
+                server_identification: b"SSH-2.0-ClueleSSH_0.1\r\n".to_vec(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let (mut client_read, mut client_write) = tokio::io::split(client);
+        client_write
+            .write_all(b"SSH-2.0-OpenSSH_9.7\r\n")
+            .await
+            .unwrap();
+        assert!(conn.progress().await.is_ok());
+
+        let handle = conn.abort_handle();
+        let server = tokio::spawn(async move {
+            loop {
+                if let Err(err) = conn.progress().await {
+                    return (conn, err);
+                }
+            }
+        });
+        // Wait until the server is waiting for the client.
+        let mut identification = [0; 23];
+        client_read.read_exact(&mut identification).await.unwrap();
+        assert_eq!(&identification, b"SSH-2.0-ClueleSSH_0.1\r\n");
+
+        handle.abort();
+        let (mut conn, err) = server.await.unwrap();
+        assert!(matches!(err, Error::SshStatus(SshStatus::Disconnect)));
+        assert!(conn.progress().await.is_err());
+        conn.abort().await;
+
+        let mut output = Vec::new();
+        client_read.read_to_end(&mut output).await.unwrap();
+        // EOF right away, without an SSH_MSG_DISCONNECT.
+        assert_eq!(output, b"");
+    }
+
+    fn test_auth() -> ServerAuth {
+        ServerAuth {
+            verify_password: Some(Arc::new(|_| Box::pin(async { Ok(false) }))),
+            verify_signature: None,
+            check_pubkey: None,
+            do_key_exchange: Arc::new(|_| Box::pin(async { Err(eyre!("no host keys")) })),
+            keyboard_interactive_prompts: None,
+            verify_keyboard_interactive: None,
+            auth_banner: None,
+        }
+    }
+
+    /// Like [`test_auth`], but with a working key exchange.
+    fn test_auth_with_host_key() -> (ServerAuth, cluelessh_transport::server::ServerConfig) {
+        let host_key = PlaintextPrivateKey::generate(
+            "".into(),
+            KeyGenerationParams {
+                key_type: KeyType::Ed25519,
+            },
+        );
+        let transport_config = cluelessh_transport::server::ServerConfig {
+            server_identification: b"SSH-2.0-ClueleSSH_0.1\r\n".to_vec(),
+            host_keys: vec![host_key.private_key.public_key()],
+            ..Default::default()
+        };
+        let auth = ServerAuth {
+            do_key_exchange: Arc::new(move |msg| {
+                let host_key = host_key.clone();
+                Box::pin(async move {
+                    cluelessh_protocol::transport::server::do_key_exchange(
+                        msg,
+                        &host_key,
+                        &mut cluelessh_protocol::OsRng,
+                    )
+                    .map_err(|_| eyre!("error during key exchange"))
+                })
+            }),
+            ..test_auth()
+        };
+        (auth, transport_config)
+    }
+
+    /// Like [`test_auth_with_host_key`], but accepts the password of [`test_client_auth`].
+    fn test_auth_with_password() -> (ServerAuth, cluelessh_transport::server::ServerConfig) {
+        let (auth, transport_config) = test_auth_with_host_key();
+        let auth = ServerAuth {
+            verify_password: Some(Arc::new(|verify| {
+                Box::pin(async move { Ok(verify.password == "meow") })
+            })),
+            ..auth
+        };
+        (auth, transport_config)
+    }
+
+    fn test_client_auth() -> crate::client::ClientAuth {
+        crate::client::ClientAuth {
+            username: "user".into(),
+            prompt_password: Arc::new(|| Box::pin(async { Ok("meow".to_owned()) })),
+            sign_pubkey: Arc::new(|_| Box::pin(async { Err(eyre!("no keys")) })),
+            verify_host_key: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn panicking_auth_callback() {
+        let (auth, transport_config) = test_auth_with_host_key();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let auth = ServerAuth {
+            verify_password: Some(Arc::new(|_| Box::pin(async { panic!("buggy callback") }))),
+            ..auth
+        };
+        let mut listener = ServerListener::new(listener, auth, transport_config);
+
+        let server = tokio::spawn(async move {
+            let mut conn = listener.accept().await.unwrap();
+            loop {
+                if let Err(err) = conn.progress().await {
+                    return err;
+                }
+            }
+        });
+
+        let client = TcpStream::connect(addr).await.unwrap();
+        let timeout = std::time::Duration::from_secs(10);
+        let client = tokio::time::timeout(
+            timeout,
+            crate::client::ClientConnection::connect(client, test_client_auth()),
+        );
+        let (client, server) = tokio::join!(client, tokio::time::timeout(timeout, server));
+
+        let err = server.expect("server hung").unwrap();
+        assert!(
+            matches!(&err, Error::ServerError(err) if err.to_string() == "authentication callback panicked")
+        );
+        // The client gets disconnected instead of waiting.
+        assert!(client.expect("client hung").is_err());
+    }
+
+    #[tokio::test]
+    async fn inline_auth() {
+        let (auth, transport_config) = test_auth_with_host_key();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::task_local! {
+            static CONNECTION_TASK: ();
+        }
+
+        let (verified_send, verified_recv) = std::sync::mpsc::channel();
+        let auth = ServerAuth {
+            verify_password: Some(Arc::new(move |verify| {
+                let verified_send = verified_send.clone();
+                Box::pin(async move {
+                    verified_send
+                        .send(CONNECTION_TASK.try_with(|_| ()).is_ok())
+                        .unwrap();
+                    Ok(verify.password == "meow")
+                })
+            })),
+            ..auth
+        };
+        let mut listener = ServerListener::new(listener, auth, transport_config);
+
+        let server = tokio::spawn(CONNECTION_TASK.scope((), async move {
+            let mut conn = listener.accept().await.unwrap();
+            conn.set_inline_auth(true);
+            loop {
+                if conn.progress().await.is_err() {
+                    return conn.phase();
+                }
+            }
+        }));
+
+        let client = TcpStream::connect(addr).await.unwrap();
+        let timeout = std::time::Duration::from_secs(10);
+        let client = tokio::time::timeout(
+            timeout,
+            crate::client::ClientConnection::connect(client, test_client_auth()),
+        )
+        .await;
+        // Disconnect once we are authenticated.
+        drop(client.expect("client hung").unwrap());
+
+        let phase = tokio::time::timeout(timeout, server).await;
+        assert_eq!(phase.expect("server hung").unwrap(), ConnectionPhase::Open);
+        // The verifier ran in the connection's own task, not in a task spawned for it.
+        assert!(verified_recv.try_recv().unwrap());
+        assert!(verified_recv.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn cancelled_inline_auth_resumes() {
+        let (auth, transport_config) = test_auth_with_host_key();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let verifications = Arc::new(AtomicUsize::new(0));
+        let auth = ServerAuth {
+            verify_password: Some(Arc::new({
+                let verifications = verifications.clone();
+                move |verify| {
+                    verifications.fetch_add(1, Ordering::SeqCst);
+                    Box::pin(async move {
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                        Ok(verify.password == "meow")
+                    })
+                }
+            })),
+            ..auth
+        };
+        let mut listener = ServerListener::new(listener, auth, transport_config);
+
+        let server = tokio::spawn(async move {
+            let mut conn = listener.accept().await.unwrap();
+            conn.set_inline_auth(true);
+            loop {
+                // Keeps cancelling the verification, which must carry on in the next call.
+                if let Ok(Err(_)) =
+                    tokio::time::timeout(Duration::from_millis(5), conn.progress()).await
+                {
+                    return conn.phase();
+                }
+            }
+        });
+
+        let client = TcpStream::connect(addr).await.unwrap();
+        let timeout = std::time::Duration::from_secs(10);
+        let client = tokio::time::timeout(
+            timeout,
+            crate::client::ClientConnection::connect(client, test_client_auth()),
+        )
+        .await;
+        drop(client.expect("client hung").unwrap());
+
+        let phase = tokio::time::timeout(timeout, server).await;
+        assert_eq!(phase.expect("server hung").unwrap(), ConnectionPhase::Open);
+        assert_eq!(verifications.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rekey_interval() {
+        const INTERVAL: Duration = Duration::from_secs(3600);
+
+        /// Records when the client receives an `SSH_MSG_KEXINIT`.
+        struct KexInitTracer(Arc<Mutex<Vec<Instant>>>);
+        impl PacketTracer for KexInitTracer {
+            fn trace_packet(
+                &mut self,
+                direction: PacketDirection,
+                packet_type: u8,
+                _: usize,
+                _: u32,
+            ) {
+                if direction == PacketDirection::Recv && packet_type == numbers::SSH_MSG_KEXINIT {
+                    self.0.lock().unwrap().push(Instant::now());
+                }
+            }
+        }
+
+        let (auth, mut transport_config) = test_auth_with_host_key();
+        transport_config.rekey_interval = Some(INTERVAL);
+        let (mut client_stream, server) = tokio::io::duplex(1 << 16);
+        let start = Instant::now();
+        let mut conn = ServerConnection::new(
+            server,
+            "127.0.0.1:22".parse::<SocketAddr>().unwrap(),
+            auth,
+            transport_config,
+        )
+        .unwrap();
+        tokio::spawn(async move { while conn.progress().await.is_ok() {} });
+
+        let kexinits = Arc::new(Mutex::new(Vec::new()));
+        let mut client =
+            cluelessh_transport::client::ClientConnection::new(cluelessh_protocol::OsRng);
+        client.set_packet_tracer(KexInitTracer(kexinits.clone()));
+
+        // The connection goes quiet after the handshake, until the server initiates the re-exchange.
+        let mut buf = [0; 1024];
+        while kexinits.lock().unwrap().len() < 2 {
+            while let Some(msg) = client.next_msg_to_send() {
+                client_stream.write_all(&msg.to_bytes()).await.unwrap();
+            }
+            let read = client_stream.read(&mut buf).await.unwrap();
+            assert_ne!(read, 0, "server closed the connection");
+            client.recv_bytes(&buf[..read]).unwrap();
+            if client.is_waiting_on_host_key_verification().is_some() {
+                client.host_key_verification_result(true);
+            }
+        }
+
+        let kexinits = kexinits.lock().unwrap();
+        assert!(client.is_open().is_some());
+        assert!(kexinits[0] - start < INTERVAL);
+        assert!(kexinits[1] - start >= INTERVAL);
+    }
+
+    #[tokio::test]
+    async fn remote_forward() {
+        let (auth, transport_config) = test_auth_with_password();
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let mut conn = ServerConnection::new(
+            server,
+            "127.0.0.1:22".parse::<SocketAddr>().unwrap(),
+            auth,
+            transport_config,
+        )
+        .unwrap();
+        conn.set_channel_config(cluelessh_connection::ChannelConfig {
+            allowed_global_requests: HashSet::from(["tcpip-forward".to_owned()]),
+            ..Default::default()
+        })
+        .unwrap();
+        let forwarded = ChannelKind::ForwardedTcpip {
+            connected_address: "127.0.0.1".to_owned(),
+            connected_port: 8080,
+            originator_address: "192.0.2.1".to_owned(),
+            originator_port: 50000,
+        };
+        tokio::spawn({
+            let forwarded = forwarded.clone();
+            async move {
+                let mut pending = Vec::new();
+                while conn.progress().await.is_ok() {
+                    if let Some(request) = conn.next_global_request() {
+                        assert_eq!(request.name, "tcpip-forward");
+                        conn.reply_global_request(Some(&[]));
+                        pending.push(conn.open_channel(forwarded.clone()));
+                    }
+                }
+            }
+        });
+
+        let timeout = Duration::from_secs(10);
+        let mut client = tokio::time::timeout(
+            timeout,
+            crate::client::ClientConnection::connect(client, test_client_auth()),
+        )
+        .await
+        .expect("client hung")
+        .unwrap();
+        client.request_tcpip_forward("127.0.0.1", 8080);
+
+        let channel = tokio::time::timeout(timeout, async {
+            loop {
+                client.progress().await.unwrap();
+                if let Some(channel) = client.next_new_channel() {
+                    return channel;
+                }
+            }
+        })
+        .await
+        .expect("server did not open the forwarded channel");
+        assert_eq!(*channel.kind(), forwarded);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn max_channel_open_rate() {
+        const PER: Duration = Duration::from_secs(60);
+
+        let (auth, transport_config) = test_auth_with_password();
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let mut conn = ServerConnection::new(
+            server,
+            "127.0.0.1:22".parse::<SocketAddr>().unwrap(),
+            auth,
+            transport_config,
+        )
+        .unwrap();
+        conn.set_max_channel_open_rate(Some(ChannelOpenRate {
+            max_opens: 2,
+            per: PER,
+        }));
+        let server = tokio::spawn(async move {
+            loop {
+                if let Err(err) = conn.progress().await {
+                    return err;
+                }
+            }
+        });
+
+        let mut client = crate::client::ClientConnection::connect(client, test_client_auth())
+            .await
+            .unwrap();
+        async fn open_session<S: tokio::io::AsyncRead + tokio::io::AsyncWrite>(
+            client: &mut crate::client::ClientConnection<S>,
+        ) -> eyre::Result<()> {
+            let mut ready = std::pin::pin!(client.open_channel(ChannelKind::Session).wait_ready());
+            loop {
+                tokio::select! {
+                    ready = &mut ready => {
+                        ready.map_err(|err| eyre!("channel open failed: {err:?}"))?;
+                        return Ok(());
+                    }
+                    progress = client.progress() => progress?,
+                }
+            }
+        }
+
+        open_session(&mut client).await.unwrap();
+        open_session(&mut client).await.unwrap();
+        // The first opens have left the window.
+        tokio::time::sleep(PER).await;
+        open_session(&mut client).await.unwrap();
+        open_session(&mut client).await.unwrap();
+
+        let err = open_session(&mut client).await.unwrap_err();
+        assert_eq!(err.to_string(), "Received disconnect from server");
+        assert!(matches!(
+            server.await.unwrap(),
+            Error::SshStatus(SshStatus::PeerError(msg)) if msg == "opened more than 2 channels within 60s"
+        ));
+    }
+
+    #[test]
+    fn error_conversions() {
+        let err = Error::from(cluelessh_transport::peer_error!("invalid packet"));
+        assert!(
+            matches!(err, Error::SshStatus(SshStatus::PeerError(msg)) if msg == "invalid packet")
+        );
+
+        let err = Error::from(std::io::Error::from(std::io::ErrorKind::BrokenPipe));
+        assert!(matches!(err, Error::ServerError(_)));
+    }
+
+    async fn accept_with(socket_options: Option<SocketOptions>) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut listener = ServerListener::new(listener, test_auth(), Default::default());
+        if let Some(socket_options) = socket_options {
+            listener.set_socket_options(socket_options);
+        }
+
+        let _client = TcpStream::connect(addr).await.unwrap();
+        let conn = listener.accept().await.unwrap();
+        *std::pin::Pin::into_inner(conn.stream)
+    }
+
+    #[tokio::test]
+    async fn nodelay_by_default() {
+        let stream = accept_with(None).await;
+        assert!(stream.nodelay().unwrap());
+        assert!(!socket2::SockRef::from(&stream).keepalive().unwrap());
+    }
+
+    #[tokio::test]
+    async fn custom_socket_options() {
+        let stream = accept_with(Some(SocketOptions {
+            nodelay: false,
+            keepalive: true,
+        }))
+        .await;
+        assert!(!stream.nodelay().unwrap());
+        assert!(socket2::SockRef::from(&stream).keepalive().unwrap());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn multi_listener() {
+        let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tcp_addr = tcp.local_addr().unwrap();
+        let unix_path =
+            std::env::temp_dir().join(format!("cluelessh-multi-listener-{}", std::process::id()));
+        let _ = std::fs::remove_file(&unix_path);
+        let unix = tokio::net::UnixListener::bind(&unix_path).unwrap();
+
+        let mut listener = MultiListener::new(
+            vec![tcp.into(), unix.into()],
+            test_auth(),
+            Default::default(),
+        );
+
+        let mut unix_client = tokio::net::UnixStream::connect(&unix_path).await.unwrap();
+        let conn = listener.accept().await.unwrap();
+        assert!(matches!(conn.peer_addr(), PeerAddr::Unix(None)));
+        assert_eq!(conn.peer_addr().to_string(), "unix:unnamed");
+        let (mut stream, _) = conn.into_parts();
+        stream.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        unix_client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        let mut tcp_client = TcpStream::connect(tcp_addr).await.unwrap();
+        let conn = listener.accept().await.unwrap();
+        assert!(
+            matches!(conn.peer_addr(), PeerAddr::Tcp(addr) if *addr == tcp_client.local_addr().unwrap())
+        );
+        let (mut stream, _) = conn.into_parts();
+        assert!(matches!(stream, MultiStream::Tcp(_)));
+        tcp_client.write_all(b"world").await.unwrap();
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"world");
+
+        std::fs::remove_file(&unix_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn recovered_stream_still_works() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut listener = ServerListener::new(listener, test_auth(), Default::default());
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let conn = listener.accept().await.unwrap();
+
+        let (mut stream, unsent) = conn.into_parts();
+        assert!(unsent.is_empty());
+
+        stream.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        client.write_all(b"world").await.unwrap();
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"world");
+    }
+
+    #[tokio::test]
+    async fn cancelled_write_resumes() {
+        const IDENT: &[u8] = b"SSH-2.0-ClueleSSH_0.1\r\n";
+
+        let host_key = PlaintextPrivateKey::generate(
+            "".into(),
+            KeyGenerationParams {
+                key_type: KeyType::Ed25519,
+            },
+        );
+
+        // A tiny buffer makes sure that writing the KEXINIT gets stuck halfway through.
+        let (client_stream, server) = tokio::io::duplex(64);
+        let mut conn = ServerConnection::new(
+            server,
+            "127.0.0.1:22".parse::<SocketAddr>().unwrap(),
+            test_auth(),
+            cluelessh_transport::server::ServerConfig {
+                server_identification: IDENT.to_vec(),
+                host_keys: vec![host_key.private_key.public_key()],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        // Drive a real client, writing on a separate task so that it never gets cancelled.
+        let mut client =
+            cluelessh_transport::client::ClientConnection::new(cluelessh_protocol::OsRng);
+        let (mut client_read, mut client_write) = tokio::io::split(client_stream);
+        let (client_send, mut client_recv) = tokio::sync::mpsc::unbounded_channel::<Vec<u8>>();
+        tokio::spawn(async move {
+            while let Some(bytes) = client_recv.recv().await {
+                client_write.write_all(&bytes).await.unwrap();
+            }
+        });
+
+        let is_complete = |data: &[u8]| {
+            let Some(packet) = data.strip_prefix(IDENT) else {
+                return false;
+            };
+            packet.len() >= 4
+                && packet.len() >= 4 + u32::from_be_bytes(packet[..4].try_into().unwrap()) as usize
+        };
+
+        let timeout = std::time::Duration::from_millis(10);
+        let mut data = Vec::new();
+        let mut buf = [0; 64];
+        for _ in 0..100 {
+            if is_complete(&data) {
+                break;
+            }
+            while let Some(msg) = client.next_msg_to_send() {
+                client_send.send(msg.to_bytes()).unwrap();
+            }
+
+            // Either handles a read or gets stuck writing and is cancelled.
+            if let Ok(result) = tokio::time::timeout(timeout, conn.progress()).await {
+                assert!(result.is_ok(), "server failed to make progress");
+            }
+
+            if let Ok(read) = tokio::time::timeout(timeout, client_read.read(&mut buf)).await {
+                let read = read.unwrap();
+                data.extend_from_slice(&buf[..read]);
+                client
+                    .recv_bytes(&buf[..read])
+                    .expect("client received a corrupted stream");
+            }
+        }
+
+        let packet = data.strip_prefix(IDENT).expect("identification");
+        let len = u32::from_be_bytes(packet[..4].try_into().unwrap()) as usize;
+        assert_eq!(packet.len(), 4 + len);
+        // The payload starts after the padding length, with SSH_MSG_KEXINIT.
+        assert_eq!(packet[5], 20);
+    }
+}
@@ -0,0 +1,391 @@
+//! Using a channel as a byte stream, for forwarding it to and from other readers and writers.
+
+use std::{
+    io, mem,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use cluelessh_connection::{
+    ChannelExit, ChannelNumber, ChannelOperation, ChannelOperationKind, ChannelRequest,
+};
+use cluelessh_protocol::ChannelUpdateKind;
+use futures::future::BoxFuture;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::{
+        mpsc::{self, error::SendError, OwnedPermit},
+        oneshot,
+    },
+};
+
+use crate::Channel;
+
+/// The extended data type code of stderr.
+const SSH_EXTENDED_DATA_STDERR: u32 = 1;
+
+impl Channel {
+    /// Splits the channel into a reader for the data (stdout), a reader for the extended data with code 1 (stderr),
+    /// a writer for sending data and a receiver for the `exit-status` or `exit-signal` of the command.
+    ///
+    /// Both readers reach EOF once the peer sends EOF or closes the channel.
+    /// Data for one reader is buffered until it is read, so the readers can be consumed independently.
+    /// Requests that want a reply are answered with a failure, all other updates are dropped.
+    ///
+    /// Must be called from within a tokio runtime.
+    pub fn split_streams(
+        self,
+    ) -> (
+        ChannelReader,
+        ChannelReader,
+        ChannelWriter,
+        ChannelExitReceiver,
+    ) {
+        let Channel {
+            number,
+            mut updates_recv,
+            ops_send,
+            kind: _,
+        } = self;
+
+        let (stdout_send, stdout_recv) = mpsc::unbounded_channel();
+        let (stderr_send, stderr_recv) = mpsc::unbounded_channel();
+        let (exit_send, exit_recv) = oneshot::channel();
+
+        let replies_send = ops_send.clone();
+        tokio::spawn(async move {
+            // Dropped on EOF, the exit status often comes after it.
+            let mut streams = Some((stdout_send, stderr_send));
+            let mut exit_send = Some(exit_send);
+            while let Some(update) = updates_recv.recv().await {
+                match update {
+                    ChannelUpdateKind::Data { data } => {
+                        if let Some((stdout, _)) = &streams {
+                            let _ = stdout.send(data);
+                        }
+                    }
+                    ChannelUpdateKind::ExtendedData { code, data }
+                        if code == SSH_EXTENDED_DATA_STDERR =>
+                    {
+                        if let Some((_, stderr)) = &streams {
+                            let _ = stderr.send(data);
+                        }
+                    }
+                    ChannelUpdateKind::Request(ChannelRequest::ExitStatus { status }) => {
+                        if let Some(exit_send) = exit_send.take() {
+                            let _ = exit_send.send(ChannelExit::Status(status));
+                        }
+                    }
+                    ChannelUpdateKind::Request(ChannelRequest::ExitSignal {
+                        signal_name,
+                        error_message,
+                        ..
+                    }) => {
+                        if let Some(exit_send) = exit_send.take() {
+                            let _ = exit_send.send(ChannelExit::Signal {
+                                name: signal_name,
+                                message: error_message,
+                            });
+                        }
+                    }
+                    ChannelUpdateKind::Request(req) if req.want_reply() => {
+                        // The peer would wait for the reply forever.
+                        let _ = replies_send
+                            .send(number.construct_op(ChannelOperationKind::Failure))
+                            .await;
+                    }
+                    ChannelUpdateKind::Eof => streams = None,
+                    ChannelUpdateKind::Closed { .. } => break,
+                    _ => {}
+                }
+            }
+        });
+
+        (
+            ChannelReader::new(stdout_recv),
+            ChannelReader::new(stderr_recv),
+            ChannelWriter {
+                number,
+                ops_send,
+                reserve: None,
+                shutdown: false,
+                buf: Vec::new(),
+                chunk_size: 0,
+            },
+            ChannelExitReceiver(exit_recv),
+        )
+    }
+}
+
+/// The `exit-status` or `exit-signal` of the command on a channel, see [`Channel::split_streams`].
+pub struct ChannelExitReceiver(oneshot::Receiver<ChannelExit>);
+
+impl ChannelExitReceiver {
+    /// Waits for the channel to close, returns `None` if the peer did not send how the command exited.
+    pub async fn wait(self) -> Option<ChannelExit> {
+        self.0.await.ok()
+    }
+}
+
+/// One of the incoming data streams of a channel, see [`Channel::split_streams`].
+pub struct ChannelReader {
+    recv: mpsc::UnboundedReceiver<Vec<u8>>,
+    buf: Vec<u8>,
+}
+
+impl ChannelReader {
+    fn new(recv: mpsc::UnboundedReceiver<Vec<u8>>) -> Self {
+        Self {
+            recv,
+            buf: Vec::new(),
+        }
+    }
+}
+
+impl AsyncRead for ChannelReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.buf.is_empty() {
+            match ready!(this.recv.poll_recv(cx)) {
+                Some(data) => this.buf = data,
+                // EOF.
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+
+        let len = out.remaining().min(this.buf.len());
+        out.put_slice(&this.buf[..len]);
+        this.buf.drain(..len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// The outgoing data stream of a channel, see [`Channel::split_streams`].
+///
+/// Shutting it down sends EOF.
+pub struct ChannelWriter {
+    number: ChannelNumber,
+    ops_send: mpsc::Sender<ChannelOperation>,
+    reserve: Option<BoxFuture<'static, Result<OwnedPermit<ChannelOperation>, SendError<()>>>>,
+    shutdown: bool,
+    /// Written data that has not been sent yet, see [`Self::coalesce_writes`].
+    buf: Vec<u8>,
+    chunk_size: usize,
+}
+
+impl ChannelWriter {
+    /// Collects small writes until `chunk_size` bytes have been written or the writer is flushed,
+    /// so that they are sent in fewer data packets. Without this, every write is sent on its own.
+    ///
+    /// `chunk_size` should be the maximum packet size of the peer.
+    pub fn coalesce_writes(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size;
+        self.buf = Vec::with_capacity(chunk_size);
+        self
+    }
+
+    fn poll_send_buf(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.buf.is_empty() {
+            let permit = ready!(self.poll_permit(cx))?;
+            let data = mem::replace(&mut self.buf, Vec::with_capacity(self.chunk_size));
+            permit.send(self.number.construct_op(ChannelOperationKind::Data(data)));
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_permit(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<OwnedPermit<ChannelOperation>>> {
+        let reserve = self
+            .reserve
+            .get_or_insert_with(|| Box::pin(self.ops_send.clone().reserve_owned()));
+        let result = ready!(reserve.as_mut().poll(cx));
+        self.reserve = None;
+        Poll::Ready(
+            result.map_err(|_| {
+                io::Error::new(io::ErrorKind::BrokenPipe, "connection has been closed")
+            }),
+        )
+    }
+}
+
+impl AsyncWrite for ChannelWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        if this.chunk_size > 0 {
+            if this.buf.len() >= this.chunk_size {
+                ready!(this.poll_send_buf(cx))?;
+            }
+            let len = buf.len().min(this.chunk_size - this.buf.len());
+            this.buf.extend_from_slice(&buf[..len]);
+            return Poll::Ready(Ok(len));
+        }
+        let permit = ready!(this.poll_permit(cx))?;
+        permit.send(
+            this.number
+                .construct_op(ChannelOperationKind::Data(buf.to_vec())),
+        );
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_send_buf(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.shutdown {
+            ready!(this.poll_send_buf(cx))?;
+            let permit = ready!(this.poll_permit(cx))?;
+            permit.send(this.number.construct_op(ChannelOperationKind::Eof));
+            this.shutdown = true;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cluelessh_connection::{
+        ChannelExit, ChannelKind, ChannelNumber, ChannelOperationKind, ChannelRequest,
+    };
+    use cluelessh_protocol::ChannelUpdateKind;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::Channel;
+
+    #[tokio::test]
+    async fn separate_stdout_and_stderr() {
+        let (updates_send, updates_recv) = tokio::sync::mpsc::channel(10);
+        let (ops_send, mut ops_recv) = tokio::sync::mpsc::channel(10);
+        let channel = Channel {
+            number: ChannelNumber(0),
+            updates_recv,
+            ops_send,
+            kind: ChannelKind::Session,
+        };
+
+        // Sent before splitting, this must not get lost.
+        updates_send
+            .send(ChannelUpdateKind::ExtendedData {
+                code: 1,
+                data: b"err1 ".to_vec(),
+            })
+            .await
+            .unwrap();
+
+        let (mut stdout, mut stderr, mut writer, exit) = channel.split_streams();
+
+        for update in [
+            ChannelUpdateKind::Data {
+                data: b"out1 ".to_vec(),
+            },
+            ChannelUpdateKind::ExtendedData {
+                code: 2,
+                data: b"unknown".to_vec(),
+            },
+            ChannelUpdateKind::Data {
+                data: b"out2".to_vec(),
+            },
+            ChannelUpdateKind::ExtendedData {
+                code: 1,
+                data: b"err2".to_vec(),
+            },
+            ChannelUpdateKind::Request(ChannelRequest::Env {
+                want_reply: true,
+                name: "LANG".to_owned(),
+                value: b"C".to_vec(),
+            }),
+            ChannelUpdateKind::Eof,
+            ChannelUpdateKind::Request(ChannelRequest::ExitStatus { status: 3 }),
+            ChannelUpdateKind::Closed {
+                reason: cluelessh_connection::CloseReason::PeerClosed,
+            },
+        ] {
+            updates_send.send(update).await.unwrap();
+        }
+
+        let mut out = Vec::new();
+        stdout.read_to_end(&mut out).await.unwrap();
+        assert_eq!(out, b"out1 out2");
+
+        let mut err = Vec::new();
+        stderr.read_to_end(&mut err).await.unwrap();
+        assert_eq!(err, b"err1 err2");
+
+        assert_eq!(exit.wait().await, Some(ChannelExit::Status(3)));
+
+        // The env request has been rejected.
+        let op = ops_recv.recv().await.unwrap();
+        assert_eq!(op.number, ChannelNumber(0));
+        assert!(matches!(op.kind, ChannelOperationKind::Failure));
+
+        writer.write_all(b"input").await.unwrap();
+        writer.shutdown().await.unwrap();
+
+        let op = ops_recv.recv().await.unwrap();
+        assert_eq!(op.number, ChannelNumber(0));
+        assert!(matches!(op.kind, ChannelOperationKind::Data(data) if data == b"input"));
+        let op = ops_recv.recv().await.unwrap();
+        assert!(matches!(op.kind, ChannelOperationKind::Eof));
+    }
+
+    #[tokio::test]
+    async fn coalesce_small_writes() {
+        let (_updates_send, updates_recv) = tokio::sync::mpsc::channel(10);
+        let (ops_send, mut ops_recv) = tokio::sync::mpsc::channel(10);
+        let channel = Channel {
+            number: ChannelNumber(0),
+            updates_recv,
+            ops_send,
+            kind: ChannelKind::Session,
+        };
+
+        let (_, _, writer, _) = channel.split_streams();
+        let mut writer = writer.coalesce_writes(1024);
+
+        let data = (0..3000).map(|i| i as u8).collect::<Vec<_>>();
+        for chunk in data.chunks(10) {
+            writer.write_all(chunk).await.unwrap();
+        }
+        // Only full chunks have been sent so far.
+        let mut sent = Vec::new();
+        while let Ok(op) = ops_recv.try_recv() {
+            let ChannelOperationKind::Data(data) = op.kind else {
+                panic!("expected data");
+            };
+            assert_eq!(data.len(), 1024);
+            sent.extend(data);
+        }
+        assert_eq!(sent.len(), 2048);
+
+        writer.flush().await.unwrap();
+        let ChannelOperationKind::Data(rest) = ops_recv.recv().await.unwrap().kind else {
+            panic!("expected data");
+        };
+        assert_eq!(rest.len(), 952);
+        sent.extend(rest);
+        assert_eq!(sent, data);
+
+        // Flushing an empty buffer sends nothing, shutting down sends buffered data before EOF.
+        writer.flush().await.unwrap();
+        writer.write_all(b"bye").await.unwrap();
+        writer.shutdown().await.unwrap();
+        let op = ops_recv.recv().await.unwrap();
+        assert!(matches!(op.kind, ChannelOperationKind::Data(data) if data == b"bye"));
+        let op = ops_recv.recv().await.unwrap();
+        assert!(matches!(op.kind, ChannelOperationKind::Eof));
+    }
+}
@@ -1,16 +1,17 @@
 use std::{collections::VecDeque, mem};
 
-use tracing::{debug, info, trace};
+use tracing::{debug, info, trace, warn};
 
 use crate::{
     crypto::{
-        self, AlgorithmName, EncodedSshSignature, EncryptionAlgorithm, HostKeyVerifyAlgorithm,
-        KeyExchangeSecret, SharedSecret, SupportedAlgorithms,
+        self, AlgorithmName, AlgorithmNegotiation, EncodedSshSignature, EncryptionAlgorithm,
+        HostKeyVerifyAlgorithm, KexHash, KeyExchangeSecret, SharedSecret, SupportedAlgorithms,
     },
-    packet::{Packet, PacketTransport, ProtocolIdentParser, RecvBytesResult},
-    peer_error, Msg, Result, SessionId, SshRng, SshStatus,
+    packet::{Packet, PacketTracer, PacketTransport, ProtocolIdentParser, RecvBytesResult},
+    peer_error, InvalidConfig, Msg, Result, SessionId, SshRng, SshStatus,
 };
 use cluelessh_format::{numbers, NameList, Reader, Writer};
+use cluelessh_keys::public::PublicKey;
 
 pub struct ClientConnection {
     state: ClientState,
@@ -19,11 +20,27 @@ pub struct ClientConnection {
 
     plaintext_packets: VecDeque<Packet>,
 
-    supported_algorithms: SupportedAlgorithms,
+    pub(crate) supported_algorithms: SupportedAlgorithms,
+
+    /// The service requested after the key exchange.
+    service: String,
+    /// Kept around for the exchange hash of key re-exchanges.
+    client_ident: Vec<u8>,
+    server_ident: Vec<u8>,
+    rekey: Option<Rekey>,
+    /// The host key of the initial key exchange, which must not change during re-exchanges.
+    server_host_key: Option<PublicKey>,
 
     pub abort_for_dos: bool,
 }
 
+/// State for a key re-exchange initiated by the server.
+struct Rekey {
+    session_id: SessionId,
+    /// Packets that have to wait until the new keys are in use.
+    queued_packets: Vec<Packet>,
+}
+
 enum ClientState {
     ProtoExchange {
         client_ident: Vec<u8>,
@@ -38,6 +55,7 @@ enum ClientState {
         client_ident: Vec<u8>,
         server_ident: Vec<u8>,
         kex_secret: Option<KeyExchangeSecret>,
+        kex_hash: KexHash,
         server_hostkey_algorithm: HostKeyVerifyAlgorithm,
         encryption_client_to_server: EncryptionAlgorithm,
         encryption_server_to_client: EncryptionAlgorithm,
@@ -45,21 +63,28 @@ enum ClientState {
         server_kexinit: Vec<u8>,
     },
     NewKeys {
-        h: [u8; 32],
+        h: Vec<u8>,
         k: SharedSecret,
+        kex_hash: KexHash,
         encryption_client_to_server: EncryptionAlgorithm,
         encryption_server_to_client: EncryptionAlgorithm,
     },
+    /// Waiting for the user to accept the host key before anything is sent over the new keys.
+    HostKeyVerification {
+        session_id: SessionId,
+    },
     ServiceRequest {
         session_id: SessionId,
     },
     Open {
         session_id: SessionId,
     },
+    /// The host key has been rejected and we sent `SSH_MSG_DISCONNECT`, nothing is received anymore.
+    HostKeyRejected,
 }
 
 impl ClientConnection {
-    pub fn new(rng: impl SshRng + Send + Sync + 'static) -> Self {
+    pub fn new(rng: impl SshRng + 'static) -> Self {
         let client_ident = b"SSH-2.0-ClueleSSH\r\n".to_vec();
 
         let mut packet_transport = PacketTransport::new();
@@ -68,16 +93,55 @@ impl ClientConnection {
         Self {
             state: ClientState::ProtoExchange {
                 ident_parser: ProtocolIdentParser::new(),
-                client_ident,
+                client_ident: client_ident.clone(),
             },
             packet_transport,
             rng: Box::new(rng),
             supported_algorithms: SupportedAlgorithms::secure(&[]),
             plaintext_packets: VecDeque::new(),
+            service: "ssh-userauth".to_owned(),
+            client_ident,
+            server_ident: Vec::new(),
+            rekey: None,
+            server_host_key: None,
             abort_for_dos: false,
         }
     }
 
+    /// Pad encrypted packets to a multiple of `granularity` bytes instead of the cipher block size.
+    /// Must be a power of two.
+    pub fn set_padding_granularity(&mut self, granularity: usize) -> Result<(), InvalidConfig> {
+        crate::packet::check_padding_granularity(granularity)?;
+        self.packet_transport.set_padding_granularity(granularity);
+        Ok(())
+    }
+
+    /// Also offer the `curve448-sha512` key exchange, see [`SupportedAlgorithms::with_curve448`].
+    /// Must be called before the key exchange has started.
+    pub fn enable_curve448(&mut self) {
+        let algs = std::mem::replace(
+            &mut self.supported_algorithms,
+            SupportedAlgorithms::secure(&[]),
+        );
+        self.supported_algorithms = algs.with_curve448();
+    }
+
+    /// Passes the type and length of every packet that is sent or received to `tracer`.
+    pub fn set_packet_tracer(&mut self, tracer: impl PacketTracer + 'static) {
+        self.packet_transport.set_tracer(Box::new(tracer));
+    }
+
+    /// Request a different service than `ssh-userauth` after the key exchange,
+    /// like `ssh-connection` when authentication happened externally.
+    pub fn set_service(&mut self, service: impl Into<String>) {
+        self.service = service.into();
+    }
+
+    /// The service that is requested after the key exchange, see [`Self::set_service`].
+    pub fn service(&self) -> &str {
+        &self.service
+    }
+
     pub fn recv_bytes(&mut self, mut bytes: &[u8]) -> Result<()> {
         while let RecvBytesResult::Partial { consumed } = self.recv_bytes_inner(bytes)? {
             bytes = &bytes[consumed..];
@@ -89,6 +153,10 @@ impl ClientConnection {
     }
 
     fn recv_bytes_inner(&mut self, bytes: &[u8]) -> Result<RecvBytesResult> {
+        if let ClientState::HostKeyRejected = self.state {
+            return Err(SshStatus::Disconnect);
+        }
+
         if let ClientState::ProtoExchange {
             ident_parser,
             client_ident,
@@ -97,6 +165,7 @@ impl ClientConnection {
             ident_parser.recv_bytes(bytes);
             if let Some(server_ident) = ident_parser.get_peer_ident() {
                 let client_ident = mem::take(client_ident);
+                self.server_ident = server_ident.clone();
                 // This moves to the next state.
                 self.send_kexinit(client_ident, server_ident);
                 return Ok(RecvBytesResult::Full);
@@ -148,11 +217,34 @@ impl ClientConnection {
                     }
                     continue;
                 }
+                Some(numbers::SSH_MSG_UNIMPLEMENTED) => {
+                    // <https://datatracker.ietf.org/doc/html/rfc4253#section-11.4>
+                    let mut p = Reader::new(&packet.payload[1..]);
+                    let seq = p.u32()?;
+                    warn!(%seq, "Peer does not support a packet we sent (SSH_MSG_UNIMPLEMENTED)");
+                    continue;
+                }
                 _ => {}
             }
 
+            if *packet_type == numbers::SSH_MSG_KEXINIT {
+                if let ClientState::Open { session_id } = &self.state {
+                    debug!("Server initiated key re-exchange");
+                    self.rekey = Some(Rekey {
+                        session_id: session_id.clone(),
+                        queued_packets: Vec::new(),
+                    });
+                    self.send_kexinit(self.client_ident.clone(), self.server_ident.clone());
+                }
+            } else if self.rekey.is_some() && *packet_type >= numbers::SSH_MSG_USERAUTH_REQUEST {
+                // The server may have sent these before it received our SSH_MSG_KEXINIT.
+                self.plaintext_packets.push_back(packet);
+                continue;
+            }
+
             match &mut self.state {
                 ClientState::ProtoExchange { .. } => unreachable!("handled above"),
+                ClientState::HostKeyRejected => unreachable!("handled above"),
                 ClientState::KexInit {
                     client_ident,
                     server_ident,
@@ -172,7 +264,11 @@ impl ClientConnection {
                     let _cookie = kexinit.array::<16>()?;
 
                     let kex_algorithm = kexinit.name_list()?;
-                    let kex_algorithm = sup_algs.key_exchange.find(true, kex_algorithm.0)?;
+                    // The key exchange may have been extended with opt-in algorithms, negotiate what we offered.
+                    let kex_algorithm = AlgorithmNegotiation {
+                        supported: self.supported_algorithms.key_exchange.supported.clone(),
+                    }
+                    .find(true, kex_algorithm.0)?;
                     debug!(name = %kex_algorithm.name(), "Using KEX algorithm");
 
                     let server_hostkey_algorithm = kexinit.name_list()?;
@@ -194,13 +290,15 @@ impl ClientConnection {
                     debug!(name = %encryption_server_to_client.name(), "Using encryption algorithm S->C");
 
                     let mac_algorithms_client_to_server = kexinit.name_list()?;
-                    let _mac_client_to_server = sup_algs
+                    let mac_client_to_server = sup_algs
                         .mac_to_peer
                         .find(true, mac_algorithms_client_to_server.0)?;
+                    encryption_client_to_server.check_mac(mac_client_to_server)?;
                     let mac_algorithms_server_to_client = kexinit.name_list()?;
-                    let _mac_server_to_client = sup_algs
+                    let mac_server_to_client = sup_algs
                         .mac_from_peer
                         .find(true, mac_algorithms_server_to_client.0)?;
+                    encryption_server_to_client.check_mac(mac_server_to_client)?;
 
                     let compression_algorithms_client_to_server = kexinit.name_list()?;
                     let _compression_client_to_server = sup_algs
@@ -227,6 +325,7 @@ impl ClientConnection {
                         client_ident: mem::take(client_ident),
                         server_ident: mem::take(server_ident),
                         kex_secret: Some(kex_secret),
+                        kex_hash: kex_algorithm.hash,
                         server_hostkey_algorithm,
                         encryption_client_to_server,
                         encryption_server_to_client,
@@ -238,6 +337,7 @@ impl ClientConnection {
                     client_ident,
                     server_ident,
                     kex_secret,
+                    kex_hash,
                     server_hostkey_algorithm,
                     encryption_client_to_server,
                     encryption_server_to_client,
@@ -267,6 +367,7 @@ impl ClientConnection {
 
                     // The exchange hash serves as the session identifier.
                     let hash = crypto::key_exchange_hash(
+                        *kex_hash,
                         client_ident,
                         server_ident,
                         client_kexinit,
@@ -283,6 +384,18 @@ impl ClientConnection {
                         &EncodedSshSignature(signature.to_vec()),
                     )?;
 
+                    let server_host_key = PublicKey::from_wire_encoding(server_hostkey)
+                        .map_err(|err| peer_error!("invalid host key: {err}"))?;
+                    match &self.server_host_key {
+                        Some(previous) if *previous != server_host_key => {
+                            return Err(peer_error!(
+                                "server changed its host key during key re-exchange"
+                            ));
+                        }
+                        Some(_) => {}
+                        None => self.server_host_key = Some(server_host_key),
+                    }
+
                     // eprintln!("client_public_key: {:x?}", kex_secret.pubkey);
                     // eprintln!("server_public_key: {:x?}", server_ephermal_key);
                     // eprintln!("shared_secret:     {:x?}", shared_secret);
@@ -294,6 +407,7 @@ impl ClientConnection {
                     self.state = ClientState::NewKeys {
                         h: hash,
                         k: shared_secret,
+                        kex_hash: *kex_hash,
                         encryption_client_to_server: *encryption_client_to_server,
                         encryption_server_to_client: *encryption_server_to_client,
                     };
@@ -301,6 +415,7 @@ impl ClientConnection {
                 ClientState::NewKeys {
                     h,
                     k,
+                    kex_hash,
                     encryption_client_to_server,
                     encryption_server_to_client,
                 } => {
@@ -309,21 +424,35 @@ impl ClientConnection {
                     }
 
                     self.packet_transport.set_key(
-                        *h,
+                        h,
                         k,
+                        *kex_hash,
                         *encryption_client_to_server,
                         *encryption_server_to_client,
                         false,
                     );
 
-                    debug!("Requesting ssh-userauth service");
-                    self.packet_transport
-                        .queue_packet(Packet::new_msg_service_request(b"ssh-userauth"));
+                    if let Some(rekey) = self.rekey.take() {
+                        debug!("Completed key re-exchange");
+                        self.state = ClientState::Open {
+                            session_id: rekey.session_id,
+                        };
+                        for packet in rekey.queued_packets {
+                            self.packet_transport.queue_packet(packet);
+                        }
+                        continue;
+                    }
 
-                    self.state = ClientState::ServiceRequest {
-                        session_id: SessionId(*h),
+                    debug!("Waiting for the host key to be verified");
+                    self.state = ClientState::HostKeyVerification {
+                        session_id: SessionId(mem::take(h)),
                     };
                 }
+                ClientState::HostKeyVerification { .. } => {
+                    return Err(peer_error!(
+                        "unexpected packet before the host key has been verified"
+                    ));
+                }
                 ClientState::ServiceRequest { session_id } => {
                     let mut accept = packet.payload_parser();
                     let packet_type = accept.u8()?;
@@ -331,13 +460,13 @@ impl ClientConnection {
                         return Err(peer_error!("did not accept service"));
                     }
                     let service = accept.utf8_string()?;
-                    if service != "ssh-userauth" {
+                    if service != self.service {
                         return Err(peer_error!("server accepted the wrong service: {service}"));
                     }
 
                     debug!("Connection has been opened successfully");
                     self.state = ClientState::Open {
-                        session_id: *session_id,
+                        session_id: session_id.clone(),
                     };
                 }
                 ClientState::Open { .. } => {
@@ -357,12 +486,55 @@ impl ClientConnection {
     }
 
     pub fn send_plaintext_packet(&mut self, packet: Packet) {
+        if let Some(rekey) = &mut self.rekey {
+            rekey.queued_packets.push(packet);
+            return;
+        }
         self.packet_transport.queue_packet(packet);
     }
 
+    /// Returns the host key of the server once its signature has been verified,
+    /// until [`Self::host_key_verification_result`] is called.
+    pub fn is_waiting_on_host_key_verification(&self) -> Option<&PublicKey> {
+        match &self.state {
+            ClientState::HostKeyVerification { .. } => self.server_host_key.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Continues the connection if the host key is trusted, and disconnects otherwise.
+    /// After disconnecting, [`Self::recv_bytes`] fails with [`SshStatus::Disconnect`].
+    pub fn host_key_verification_result(&mut self, is_ok: bool) {
+        let ClientState::HostKeyVerification { session_id } = &self.state else {
+            return;
+        };
+        if !is_ok {
+            debug!("Host key has been rejected, disconnecting");
+            self.packet_transport
+                .queue_packet(Packet::new_msg_disconnect(
+                    numbers::SSH_DISCONNECT_HOST_KEY_NOT_VERIFIABLE,
+                    b"host key verification failed",
+                    b"",
+                ));
+            self.state = ClientState::HostKeyRejected;
+            return;
+        }
+
+        debug!(service = %self.service, "Requesting service");
+        self.packet_transport
+            .queue_packet(Packet::new_msg_service_request(self.service.as_bytes()));
+        self.state = ClientState::ServiceRequest {
+            session_id: session_id.clone(),
+        };
+    }
+
+    /// Returns the session ID once the connection is open. Stays open during key re-exchanges.
     pub fn is_open(&self) -> Option<SessionId> {
-        match self.state {
-            ClientState::Open { session_id } => Some(session_id),
+        if let Some(rekey) = &self.rekey {
+            return Some(rekey.session_id.clone());
+        }
+        match &self.state {
+            ClientState::Open { session_id } => Some(session_id.clone()),
             _ => None,
         }
     }
@@ -1,8 +1,11 @@
 pub mod encrypt;
 
-use cluelessh_keys::{public::PublicKey, signature::Signature};
+use cluelessh_keys::{
+    public::PublicKey,
+    signature::{RsaHash, Signature},
+};
 use p256::ecdsa::signature::Verifier;
-use secrecy::ExposeSecret;
+use secrecy::{zeroize::Zeroizing, ExposeSecret};
 use sha2::Digest;
 
 use crate::{
@@ -35,6 +38,10 @@ impl AlgorithmName for &'static str {
 #[derive(Clone, Copy)]
 pub struct KexAlgorithm {
     name: &'static str,
+    /// The length of the encoded ephemeral public keys Q_C and Q_S.
+    pub public_key_len: usize,
+    /// The hash used for the exchange hash H and for deriving the keys.
+    pub hash: KexHash,
     /// Generate an ephemeral key for the exchange.
     pub generate_secret: fn(random: &mut (dyn SshRng + Send + Sync)) -> KeyExchangeSecret,
 }
@@ -44,6 +51,21 @@ impl AlgorithmName for KexAlgorithm {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KexHash {
+    Sha256,
+    Sha512,
+}
+
+impl KexHash {
+    fn hasher(self) -> Box<dyn sha2::digest::DynDigest> {
+        match self {
+            Self::Sha256 => Box::new(sha2::Sha256::new()),
+            Self::Sha512 => Box::new(sha2::Sha512::new()),
+        }
+    }
+}
+
 pub struct KeyExchangeSecret {
     /// Q_x
     pub pubkey: Vec<u8>,
@@ -55,6 +77,7 @@ pub fn kex_algorithm_by_name(name: &str) -> Option<KexAlgorithm> {
     match name {
         "curve25519-sha256" => Some(KEX_CURVE_25519_SHA256),
         "ecdh-sha2-nistp256" => Some(KEX_ECDH_SHA2_NISTP256),
+        "curve448-sha512" => Some(KEX_CURVE_448_SHA512),
         _ => None,
     }
 }
@@ -62,6 +85,8 @@ pub fn kex_algorithm_by_name(name: &str) -> Option<KexAlgorithm> {
 /// <https://datatracker.ietf.org/doc/html/rfc8731>
 pub const KEX_CURVE_25519_SHA256: KexAlgorithm = KexAlgorithm {
     name: "curve25519-sha256",
+    public_key_len: 32,
+    hash: KexHash::Sha256,
     generate_secret: |rng| {
         let secret = x25519_dalek::EphemeralSecret::random_from_rng(crate::SshRngRandAdapter(rng));
         let my_public_key = x25519_dalek::PublicKey::from(&secret);
@@ -88,6 +113,9 @@ pub const KEX_CURVE_25519_SHA256: KexAlgorithm = KexAlgorithm {
 /// <https://datatracker.ietf.org/doc/html/rfc5656>
 pub const KEX_ECDH_SHA2_NISTP256: KexAlgorithm = KexAlgorithm {
     name: "ecdh-sha2-nistp256",
+    // Uncompressed SEC1 point.
+    public_key_len: 65,
+    hash: KexHash::Sha256,
     generate_secret: |rng| {
         let secret = p256::ecdh::EphemeralSecret::random(&mut crate::SshRngRandAdapter(rng));
         let my_public_key = p256::EncodedPoint::from(secret.public_key());
@@ -113,36 +141,111 @@ pub const KEX_ECDH_SHA2_NISTP256: KexAlgorithm = KexAlgorithm {
     },
 };
 
+/// <https://datatracker.ietf.org/doc/html/rfc8731>
+pub const KEX_CURVE_448_SHA512: KexAlgorithm = KexAlgorithm {
+    name: "curve448-sha512",
+    public_key_len: 56,
+    hash: KexHash::Sha512,
+    generate_secret: |rng| {
+        let mut secret = Zeroizing::new([0; 56]);
+        rng.fill_bytes(&mut *secret);
+        let secret = x448::Secret::from_bytes(&*secret).unwrap();
+        let my_public_key = x448::PublicKey::from(&secret);
+
+        KeyExchangeSecret {
+            pubkey: my_public_key.as_bytes().to_vec(),
+            exchange: Box::new(move |peer_public_key| {
+                let peer_public_key =
+                    x448::PublicKey::from_bytes(peer_public_key).ok_or_else(|| {
+                        crate::peer_error!(
+                            "invalid x448 public key, should be a point of large order of length 56, was length: {}",
+                            peer_public_key.len()
+                        )
+                    })?;
+                let shared_secret = secret
+                    .as_diffie_hellman(&peer_public_key)
+                    .ok_or_else(|| crate::peer_error!("invalid x448 public key"))?; // K
+
+                Ok(secrecy::Secret::new(SharedSecretInner(
+                    shared_secret.as_bytes().to_vec(),
+                )))
+            }),
+        }
+    },
+};
+
 #[derive(Clone, Copy)]
 pub struct EncryptionAlgorithm {
     name: &'static str,
     iv_size: usize,
     key_size: usize,
+    /// The size of the integrity key for a separate MAC, zero for AEAD ciphers.
+    mac_key_size: usize,
+    /// The size of the MAC (or AEAD tag) after every packet.
+    mac_len: usize,
     decrypt_len: fn(state: &mut [u8], bytes: &mut [u8], packet_number: u64),
     decrypt_packet: fn(state: &mut [u8], bytes: RawPacket, packet_number: u64) -> Result<Packet>,
-    encrypt_packet: fn(state: &mut [u8], packet: Packet, packet_number: u64) -> EncryptedPacket,
+    encrypt_packet: fn(
+        state: &mut [u8],
+        packet: Packet,
+        packet_number: u64,
+        padding_granularity: usize,
+    ) -> EncryptedPacket,
 }
 impl AlgorithmName for EncryptionAlgorithm {
     fn name(&self) -> &'static str {
         self.name
     }
 }
+impl EncryptionAlgorithm {
+    /// Checks that the negotiated MAC can be used with this cipher.
+    /// AEAD ciphers authenticate the packets themselves and ignore it, the others only support
+    /// `hmac-sha2-256-etm@openssh.com`, which is the only MAC we implement.
+    pub(crate) fn check_mac(&self, mac: &str) -> Result<()> {
+        if self.mac_key_size > 0 && mac != "hmac-sha2-256-etm@openssh.com" {
+            return Err(peer_error!(
+                "{} is only supported with hmac-sha2-256-etm@openssh.com, not {mac}",
+                self.name
+            ));
+        }
+        Ok(())
+    }
+}
 pub struct EncodedSshSignature(pub Vec<u8>);
 
 #[derive(Clone)]
 pub struct HostKeySigningAlgorithm {
+    name: &'static str,
     public_key: PublicKey,
 }
 
 impl AlgorithmName for HostKeySigningAlgorithm {
     fn name(&self) -> &'static str {
-        self.public_key.algorithm_name()
+        self.name
     }
 }
 
 impl HostKeySigningAlgorithm {
+    /// Signing with the first of the [`PublicKey::signature_algorithms`] of the key.
     pub fn new(public_key: PublicKey) -> Self {
-        Self { public_key }
+        let name = public_key.signature_algorithms()[0];
+        Self { name, public_key }
+    }
+    /// Signing with the signature algorithm called `name`, which must be one of the
+    /// [`PublicKey::signature_algorithms`] of the key. `None` if it is not.
+    pub fn with_algorithm(public_key: PublicKey, name: &str) -> Option<Self> {
+        let name = *public_key
+            .signature_algorithms()
+            .iter()
+            .find(|alg| **alg == name)?;
+        Some(Self { name, public_key })
+    }
+    /// All algorithms that can sign with the key, like `rsa-sha2-512` and `rsa-sha2-256` for RSA keys.
+    pub fn all_for_key(public_key: &PublicKey) -> impl Iterator<Item = Self> + '_ {
+        public_key.signature_algorithms().iter().map(|name| Self {
+            name,
+            public_key: public_key.clone(),
+        })
     }
     pub fn public_key(&self) -> PublicKey {
         self.public_key.clone()
@@ -161,13 +264,18 @@ impl AlgorithmName for HostKeyVerifyAlgorithm {
     }
 }
 
+// Host key verification only ever sees public data: the host key, the signature and the exchange hash,
+// which the server knows too. Branching on them, like on the algorithm of the key, leaks nothing secret.
+// The key and signature are checked the same way for every algorithm, so mismatched algorithms
+// fail with the same errors, before the (constant time) verification of the signature itself.
+
 const HOSTKEY_VERIFY_ED25519: HostKeyVerifyAlgorithm = HostKeyVerifyAlgorithm {
     name: "ssh-ed25519",
     verify: |public_key, message, signature| {
         let public_key = PublicKey::from_wire_encoding(public_key)
             .map_err(|err| peer_error!("incorrect public host key: {err}"))?;
         let PublicKey::Ed25519 { public_key } = public_key else {
-            return Err(peer_error!("incorrect algorithm public host key"));
+            return Err(peer_error!("incorrect algorithm for public host key"));
         };
 
         let signature = Signature::from_wire_encoding(&signature.0)
@@ -186,8 +294,6 @@ const HOSTKEY_VERIFY_ECDSA_SHA2_NISTP256: HostKeyVerifyAlgorithm = HostKeyVerify
     verify: |public_key, message, signature| {
         let public_key = PublicKey::from_wire_encoding(public_key)
             .map_err(|err| peer_error!("incorrect public host key: {err}"))?;
-
-        dbg!(&public_key);
         let PublicKey::EcdsaSha2NistP256 { public_key } = public_key else {
             return Err(peer_error!("incorrect algorithm for public host key"));
         };
@@ -203,6 +309,67 @@ const HOSTKEY_VERIFY_ECDSA_SHA2_NISTP256: HostKeyVerifyAlgorithm = HostKeyVerify
             .map_err(|err| peer_error!("incorrect signature: {err}"))
     },
 };
+const HOSTKEY_VERIFY_RSA_SHA2_512: HostKeyVerifyAlgorithm = HostKeyVerifyAlgorithm {
+    name: "rsa-sha2-512",
+    verify: |public_key, message, signature| {
+        verify_rsa(public_key, message, signature, RsaHash::Sha512)
+    },
+};
+const HOSTKEY_VERIFY_RSA_SHA2_256: HostKeyVerifyAlgorithm = HostKeyVerifyAlgorithm {
+    name: "rsa-sha2-256",
+    verify: |public_key, message, signature| {
+        verify_rsa(public_key, message, signature, RsaHash::Sha256)
+    },
+};
+/// <https://datatracker.ietf.org/doc/html/rfc8332#section-3>
+fn verify_rsa(
+    public_key: &[u8],
+    message: &[u8],
+    signature: &EncodedSshSignature,
+    hash: RsaHash,
+) -> Result<()> {
+    let public_key = PublicKey::from_wire_encoding(public_key)
+        .map_err(|err| peer_error!("incorrect public host key: {err}"))?;
+    if !matches!(public_key, PublicKey::Rsa { .. }) {
+        return Err(peer_error!("incorrect algorithm for public host key"));
+    }
+
+    let signature = Signature::from_wire_encoding(&signature.0)
+        .map_err(|err| peer_error!("incorrect signature: {err}"))?;
+    if !matches!(signature, Signature::Rsa { hash: signature_hash, .. } if signature_hash == hash) {
+        return Err(peer_error!("incorrect algorithm for signature"));
+    }
+
+    if !public_key.verify_signature(message, &signature) {
+        return Err(peer_error!("incorrect signature: verification failed"));
+    }
+    Ok(())
+}
+/// Entries of algorithm lists that only signal support for an extension and are never negotiated.
+const MARKER_ALGORITHMS: &[&str] = &[
+    // <https://datatracker.ietf.org/doc/html/rfc8308#section-2.1>
+    "ext-info-c",
+    "ext-info-s",
+    // <https://github.com/openssh/openssh-portable/blob/master/PROTOCOL>
+    "kex-strict-c-v00@openssh.com",
+    "kex-strict-s-v00@openssh.com",
+    // Dropbear
+    "kexguess2@matt.ucc.asn.au",
+    // PuTTY
+    "simple@putty.projects.tartarus.org",
+];
+
+/// Whose preference decides when both sides support several of the same algorithms.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AlgorithmOrder {
+    /// The first algorithm of the client that the server supports, as in RFC 4253.
+    #[default]
+    Client,
+    /// The first algorithm of the server that the client supports.
+    /// This only works if the server advertises nothing but the chosen algorithm afterwards.
+    Server,
+}
+
 pub struct AlgorithmNegotiation<T> {
     pub supported: Vec<T>,
 }
@@ -216,25 +383,41 @@ impl<T: AlgorithmName> AlgorithmNegotiation<T> {
             .join(",")
     }
 
-    pub fn find(mut self, this_is_client: bool, peer_supports: &str) -> Result<T> {
+    pub fn find(self, this_is_client: bool, peer_supports: &str) -> Result<T> {
         // <https://datatracker.ietf.org/doc/html/rfc4253#section-7.1>
         // We let the client guide the algorithm search.
+        self.find_in_order(this_is_client, AlgorithmOrder::Client, peer_supports)
+    }
 
+    pub fn find_in_order(
+        mut self,
+        this_is_client: bool,
+        order: AlgorithmOrder,
+        peer_supports: &str,
+    ) -> Result<T> {
         let my_algs = self
             .supported
             .iter()
             .map(|alg| alg.name())
             .collect::<Vec<_>>();
-        let peer_algs = peer_supports.split(',').collect();
+        let peer_algs = peer_supports
+            .split(',')
+            .filter(|alg| !MARKER_ALGORITHMS.contains(alg))
+            .collect::<Vec<_>>();
+        let peer_supports = peer_algs.join(",");
 
         let (client_algs, server_algs) = if this_is_client {
             (my_algs, peer_algs)
         } else {
             (peer_algs, my_algs)
         };
+        let (preferred_algs, other_algs) = match order {
+            AlgorithmOrder::Client => (client_algs, server_algs),
+            AlgorithmOrder::Server => (server_algs, client_algs),
+        };
 
-        for alg_name in client_algs {
-            if server_algs.iter().any(|peer| *peer == alg_name) {
+        for alg_name in preferred_algs {
+            if other_algs.contains(&alg_name) {
                 // Algorithm is supported
                 if let Some(alg) = self.supported.iter().position(|alg| alg.name() == alg_name) {
                     return Ok(self.supported.remove(alg));
@@ -268,11 +451,11 @@ pub struct SupportedAlgorithms {
 }
 
 impl SupportedAlgorithms {
-    /// A secure default using elliptic curves and AEAD.
+    /// A secure default using elliptic curves and AEAD, with `aes128-ctr` as a last resort for peers without AEAD.
     pub fn secure(host_keys: &[PublicKey]) -> Self {
         let supported_host_keys = host_keys
             .iter()
-            .map(|key| HostKeySigningAlgorithm::new(key.clone()))
+            .flat_map(HostKeySigningAlgorithm::all_for_key)
             .collect();
 
         Self {
@@ -283,19 +466,32 @@ impl SupportedAlgorithms {
                 supported: supported_host_keys,
             },
             hostkey_verify: AlgorithmNegotiation {
-                supported: vec![HOSTKEY_VERIFY_ECDSA_SHA2_NISTP256, HOSTKEY_VERIFY_ED25519],
+                supported: vec![
+                    HOSTKEY_VERIFY_ECDSA_SHA2_NISTP256,
+                    HOSTKEY_VERIFY_ED25519,
+                    HOSTKEY_VERIFY_RSA_SHA2_512,
+                    HOSTKEY_VERIFY_RSA_SHA2_256,
+                ],
             },
             encryption_to_peer: AlgorithmNegotiation {
-                supported: vec![encrypt::CHACHA20POLY1305, encrypt::AES256_GCM],
+                supported: vec![
+                    encrypt::CHACHA20POLY1305,
+                    encrypt::AES256_GCM,
+                    encrypt::ENC_AES128_CTR,
+                ],
             },
             encryption_from_peer: AlgorithmNegotiation {
-                supported: vec![encrypt::CHACHA20POLY1305, encrypt::AES256_GCM],
+                supported: vec![
+                    encrypt::CHACHA20POLY1305,
+                    encrypt::AES256_GCM,
+                    encrypt::ENC_AES128_CTR,
+                ],
             },
             mac_to_peer: AlgorithmNegotiation {
-                supported: vec!["hmac-sha2-256", "hmac-sha2-256-etm@openssh.com"],
+                supported: vec!["hmac-sha2-256-etm@openssh.com", "hmac-sha2-256"],
             },
             mac_from_peer: AlgorithmNegotiation {
-                supported: vec!["hmac-sha2-256", "hmac-sha2-256-etm@openssh.com"],
+                supported: vec!["hmac-sha2-256-etm@openssh.com", "hmac-sha2-256"],
             },
             compression_to_peer: AlgorithmNegotiation {
                 supported: vec!["none"],
@@ -305,6 +501,13 @@ impl SupportedAlgorithms {
             },
         }
     }
+
+    /// Additionally offers `curve448-sha512` for key exchange, after the default algorithms.
+    /// It is not offered by default as it is rarely used and has seen much less scrutiny than curve25519.
+    pub fn with_curve448(mut self) -> Self {
+        self.key_exchange.supported.push(KEX_CURVE_448_SHA512);
+        self
+    }
 }
 
 pub(crate) struct Session {
@@ -314,8 +517,8 @@ pub(crate) struct Session {
 }
 
 struct Tunnel {
-    /// `key || IV`
-    state: Vec<u8>,
+    /// `key || IV || integrity key`, scrubbed when the tunnel is dropped on rekey or at the end of the session.
+    state: Zeroizing<Vec<u8>>,
     algorithm: EncryptionAlgorithm,
 }
 
@@ -323,14 +526,20 @@ pub(crate) trait Keys: Send + Sync + 'static {
     fn decrypt_len(&mut self, bytes: &mut [u8; 4], packet_number: u64);
     fn decrypt_packet(&mut self, raw_packet: RawPacket, packet_number: u64) -> Result<Packet>;
 
-    fn encrypt_packet_to_msg(&mut self, packet: Packet, packet_number: u64) -> Msg;
+    fn encrypt_packet_to_msg(
+        &mut self,
+        packet: Packet,
+        packet_number: u64,
+        padding_granularity: usize,
+    ) -> Msg;
 
     fn additional_mac_len(&self) -> usize;
     // TODO: actually rekey...
     fn rekey(
         &mut self,
-        h: [u8; 32],
+        h: &[u8],
         k: &SharedSecret,
+        hash: KexHash,
         encryption_client_to_server: EncryptionAlgorithm,
         encryption_server_to_client: EncryptionAlgorithm,
         is_server: bool,
@@ -343,7 +552,7 @@ impl Keys for Plaintext {
     fn decrypt_packet(&mut self, raw: RawPacket, _: u64) -> Result<Packet> {
         Packet::from_full(raw.rest())
     }
-    fn encrypt_packet_to_msg(&mut self, packet: Packet, _: u64) -> Msg {
+    fn encrypt_packet_to_msg(&mut self, packet: Packet, _: u64, _: usize) -> Msg {
         Msg(MsgKind::PlaintextPacket(packet))
     }
     fn additional_mac_len(&self) -> usize {
@@ -351,8 +560,9 @@ impl Keys for Plaintext {
     }
     fn rekey(
         &mut self,
-        _: [u8; 32],
+        _: &[u8],
         _: &SharedSecret,
+        _: KexHash,
         _: EncryptionAlgorithm,
         _: EncryptionAlgorithm,
         _: bool,
@@ -365,14 +575,16 @@ impl Session {
     pub(crate) fn new(
         h: SessionId,
         k: &SharedSecret,
+        hash: KexHash,
         encryption_client_to_server: EncryptionAlgorithm,
         encryption_server_to_client: EncryptionAlgorithm,
         is_server: bool,
     ) -> Self {
         Self::from_keys(
-            h,
-            h.0,
+            h.clone(),
+            &h.0,
             k,
+            hash,
             encryption_client_to_server,
             encryption_server_to_client,
             is_server,
@@ -382,28 +594,20 @@ impl Session {
     /// <https://datatracker.ietf.org/doc/html/rfc4253#section-7.2>
     fn from_keys(
         session_id: SessionId,
-        h: [u8; 32],
+        h: &[u8],
         k: &SharedSecret,
+        hash: KexHash,
         alg_c2s: EncryptionAlgorithm,
         alg_s2c: EncryptionAlgorithm,
         is_server: bool,
     ) -> Self {
         let c2s = Tunnel {
             algorithm: alg_c2s,
-            state: {
-                let mut state = derive_key(k, h, "C", session_id, alg_c2s.key_size);
-                let iv = derive_key(k, h, "A", session_id, alg_c2s.iv_size);
-                state.extend_from_slice(&iv);
-                state
-            },
+            state: derive_tunnel_state(k, h, hash, ("C", "A", "E"), &session_id, alg_c2s),
         };
         let s2c = Tunnel {
             algorithm: alg_s2c,
-            state: {
-                let mut state = derive_key(k, h, "D", session_id, alg_s2c.key_size);
-                state.extend_from_slice(&derive_key(k, h, "B", session_id, alg_s2c.iv_size));
-                state
-            },
+            state: derive_tunnel_state(k, h, hash, ("D", "B", "F"), &session_id, alg_s2c),
         };
 
         let (from_peer, to_peer) = if is_server { (c2s, s2c) } else { (s2c, c2s) };
@@ -412,8 +616,6 @@ impl Session {
             session_id,
             from_peer,
             to_peer,
-            // integrity_key_client_to_server: derive("E").into(),
-            // integrity_key_server_to_client: derive("F").into(),
         }
     }
 }
@@ -427,28 +629,39 @@ impl Keys for Session {
         (self.from_peer.algorithm.decrypt_packet)(&mut self.from_peer.state, bytes, packet_number)
     }
 
-    fn encrypt_packet_to_msg(&mut self, packet: Packet, packet_number: u64) -> Msg {
-        let packet =
-            (self.to_peer.algorithm.encrypt_packet)(&mut self.to_peer.state, packet, packet_number);
+    fn encrypt_packet_to_msg(
+        &mut self,
+        packet: Packet,
+        packet_number: u64,
+        padding_granularity: usize,
+    ) -> Msg {
+        let packet = (self.to_peer.algorithm.encrypt_packet)(
+            &mut self.to_peer.state,
+            packet,
+            packet_number,
+            padding_granularity,
+        );
         Msg(MsgKind::EncryptedPacket(packet))
     }
 
     fn additional_mac_len(&self) -> usize {
-        poly1305::BLOCK_SIZE
+        self.from_peer.algorithm.mac_len
     }
 
     fn rekey(
         &mut self,
-        h: [u8; 32],
+        h: &[u8],
         k: &SharedSecret,
+        hash: KexHash,
         encryption_client_to_server: EncryptionAlgorithm,
         encryption_server_to_client: EncryptionAlgorithm,
         is_server: bool,
     ) -> Result<(), ()> {
         *self = Self::from_keys(
-            self.session_id,
+            self.session_id.clone(),
             h,
             k,
+            hash,
             encryption_client_to_server,
             encryption_server_to_client,
             is_server,
@@ -457,32 +670,54 @@ impl Keys for Session {
     }
 }
 
+/// Derive `key || IV || integrity key` for one direction.
+/// The buffer is allocated with the final size up front, so no unscrubbed copies are left behind.
+fn derive_tunnel_state(
+    k: &SharedSecret,
+    h: &[u8],
+    hash: KexHash,
+    (key_letter, iv_letter, mac_letter): (&str, &str, &str),
+    session_id: &SessionId,
+    algorithm: EncryptionAlgorithm,
+) -> Zeroizing<Vec<u8>> {
+    let key = derive_key(k, h, hash, key_letter, session_id, algorithm.key_size);
+    let iv = derive_key(k, h, hash, iv_letter, session_id, algorithm.iv_size);
+    let mac_key = derive_key(k, h, hash, mac_letter, session_id, algorithm.mac_key_size);
+
+    let mut state = Zeroizing::new(Vec::with_capacity(key.len() + iv.len() + mac_key.len()));
+    state.extend_from_slice(&key);
+    state.extend_from_slice(&iv);
+    state.extend_from_slice(&mac_key);
+    state
+}
+
 /// Derive a key from the shared secret K and exchange hash H.
 /// <https://datatracker.ietf.org/doc/html/rfc4253#section-7.2>
 fn derive_key(
     k: &SharedSecret,
-    h: [u8; 32],
+    h: &[u8],
+    hash: KexHash,
     letter: &str,
-    session_id: SessionId,
+    session_id: &SessionId,
     key_size: usize,
-) -> Vec<u8> {
-    let sha2len = sha2::Sha256::output_size();
-    let padded_key_size = key_size.next_multiple_of(sha2len);
-    let mut output = vec![0; padded_key_size];
+) -> Zeroizing<Vec<u8>> {
+    let hash_len = hash.hasher().output_size();
+    let padded_key_size = key_size.next_multiple_of(hash_len);
+    let mut output = Zeroizing::new(vec![0; padded_key_size]);
 
-    for i in 0..(padded_key_size / sha2len) {
-        let mut hash = <sha2::Sha256 as sha2::Digest>::new();
-        encode_mpint_for_hash(k.expose_secret().0.as_slice(), |data| hash.update(data));
-        hash.update(h);
+    for i in 0..(padded_key_size / hash_len) {
+        let mut hasher = hash.hasher();
+        encode_mpint_for_hash(k.expose_secret().0.as_slice(), |data| hasher.update(data));
+        hasher.update(h);
 
         if i == 0 {
-            hash.update(letter.as_bytes());
-            hash.update(session_id.0);
+            hasher.update(letter.as_bytes());
+            hasher.update(&session_id.0);
         } else {
-            hash.update(&output[..(i * sha2len)]);
+            hasher.update(&output[..(i * hash_len)]);
         }
 
-        output[(i * sha2len)..][..sha2len].copy_from_slice(&hash.finalize())
+        output[(i * hash_len)..][..hash_len].copy_from_slice(&hasher.finalize())
     }
 
     output.truncate(key_size);
@@ -499,6 +734,7 @@ pub(crate) fn encode_mpint_for_hash(key: &[u8], mut add_to_hash: impl FnMut(&[u8
 }
 
 pub fn key_exchange_hash(
+    hash: KexHash,
     client_ident: &[u8],
     server_ident: &[u8],
     client_kexinit: &[u8],
@@ -507,16 +743,16 @@ pub fn key_exchange_hash(
     eph_client_public_key: &[u8],
     eph_server_public_key: &[u8],
     shared_secret: &SharedSecret,
-) -> [u8; 32] {
-    let mut hash = sha2::Sha256::new();
-    let add_hash = |hash: &mut sha2::Sha256, bytes: &[u8]| {
+) -> Vec<u8> {
+    let mut hash = hash.hasher();
+    let add_hash = |hash: &mut Box<dyn sha2::digest::DynDigest>, bytes: &[u8]| {
         hash.update(bytes);
     };
-    let hash_string = |hash: &mut sha2::Sha256, bytes: &[u8]| {
+    let hash_string = |hash: &mut Box<dyn sha2::digest::DynDigest>, bytes: &[u8]| {
         add_hash(hash, &u32::to_be_bytes(bytes.len() as u32));
         add_hash(hash, bytes);
     };
-    let hash_mpint = |hash: &mut sha2::Sha256, bytes: &[u8]| {
+    let hash_mpint = |hash: &mut Box<dyn sha2::digest::DynDigest>, bytes: &[u8]| {
         encode_mpint_for_hash(bytes, |data| add_hash(hash, data));
     };
 
@@ -535,15 +771,71 @@ pub fn key_exchange_hash(
     hash_string(&mut hash, eph_server_public_key); // Q_S
     hash_mpint(&mut hash, shared_secret.expose_secret().0.as_slice()); // K
 
-    let hash = hash.finalize();
-    hash.into()
+    hash.finalize().into_vec()
 }
 
 #[cfg(test)]
 mod tests {
-    use super::AlgorithmNegotiation;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    use secrecy::zeroize::Zeroizing;
+
+    use cluelessh_keys::{private::PlaintextPrivateKey, KeyGenerationParams, KeyType};
+
+    use super::{
+        kex_algorithm_by_name, AlgorithmName, AlgorithmNegotiation, EncodedSshSignature, KexHash,
+        Keys, Session, SharedSecretInner, SupportedAlgorithms, HOSTKEY_VERIFY_ECDSA_SHA2_NISTP256,
+        HOSTKEY_VERIFY_ED25519, KEX_CURVE_25519_SHA256, KEX_ECDH_SHA2_NISTP256,
+    };
+    use crate::{crypto::encrypt, SessionId, SshRng, SshStatus};
+    use secrecy::ExposeSecret;
 
     #[test]
+    fn host_key_algorithm_mismatch() {
+        let generate = |key_type| {
+            let key =
+                PlaintextPrivateKey::generate(String::new(), KeyGenerationParams { key_type });
+            let public_key = key.private_key.public_key().to_wire_encoding();
+            let signature = key.private_key.sign(b"hash").to_wire_encoding();
+            (public_key, EncodedSshSignature(signature))
+        };
+        let ed25519 = generate(KeyType::Ed25519);
+        let ecdsa = generate(KeyType::Ecdsa);
+
+        let error = |result: Result<(), SshStatus>| match result {
+            Err(SshStatus::PeerError(err)) => err,
+            _ => panic!("verification succeeded"),
+        };
+
+        for (verify, (public_key, signature), (other_public_key, other_signature)) in [
+            (HOSTKEY_VERIFY_ED25519, &ed25519, &ecdsa),
+            (HOSTKEY_VERIFY_ECDSA_SHA2_NISTP256, &ecdsa, &ed25519),
+        ] {
+            (verify.verify)(public_key, b"hash", signature).unwrap();
+            assert_eq!(
+                error((verify.verify)(other_public_key, b"hash", signature)),
+                "incorrect algorithm for public host key",
+                "{}",
+                verify.name()
+            );
+            assert_eq!(
+                error((verify.verify)(public_key, b"hash", other_signature)),
+                "incorrect algorithm for signature",
+                "{}",
+                verify.name()
+            );
+            assert!(
+                error((verify.verify)(public_key, b"other hash", signature))
+                    .starts_with("incorrect signature: "),
+                "{}",
+                verify.name()
+            );
+        }
+    }
+
+    #[test]
+    #[allow(clippy::iter_cloned_collect)]
     fn alg_negotation() {
         let server_algs = [
             "ssh-ed25519",
@@ -575,4 +867,197 @@ mod tests {
             .unwrap();
         assert_eq!(chosen, "ssh-ed25519");
     }
+
+    #[test]
+    fn putty_kex_markers() {
+        // As sent by PuTTY 0.81.
+        let putty_kex = "mlkem768x25519-sha256,curve448-sha512,curve25519-sha256,\
+            curve25519-sha256@libssh.org,ecdh-sha2-nistp256,ecdh-sha2-nistp384,ecdh-sha2-nistp521,\
+            diffie-hellman-group-exchange-sha256,diffie-hellman-group16-sha512,\
+            diffie-hellman-group14-sha256,ext-info-c,kex-strict-c-v00@openssh.com";
+
+        // PuTTY prefers curve448 over curve25519, which we only offer when opted in.
+        let chosen = SupportedAlgorithms::secure(&[])
+            .key_exchange
+            .find(false, putty_kex)
+            .unwrap();
+        assert_eq!(chosen.name(), "curve25519-sha256");
+        let chosen = SupportedAlgorithms::secure(&[])
+            .with_curve448()
+            .key_exchange
+            .find(false, putty_kex)
+            .unwrap();
+        assert_eq!(chosen.name(), "curve448-sha512");
+        let chosen = AlgorithmNegotiation {
+            supported: vec![KEX_CURVE_25519_SHA256, KEX_ECDH_SHA2_NISTP256],
+        }
+        .find(false, putty_kex)
+        .unwrap();
+        assert_eq!(chosen.name(), "curve25519-sha256");
+
+        let err = AlgorithmNegotiation {
+            supported: vec!["curve25519-sha256"],
+        }
+        .find(
+            false,
+            "ext-info-c,diffie-hellman-group14-sha256,simple@putty.projects.tartarus.org",
+        )
+        .unwrap_err();
+        let SshStatus::PeerError(err) = err else {
+            panic!("wrong error: {err:?}");
+        };
+        assert!(err.contains("diffie-hellman-group14-sha256"), "{err}");
+        assert!(!err.contains("ext-info-c"), "{err}");
+        assert!(!err.contains("putty"), "{err}");
+    }
+
+    struct CountingRng(u8);
+    impl SshRng for CountingRng {
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            for byte in dest {
+                *byte = self.0;
+                self.0 = self.0.wrapping_add(1);
+            }
+        }
+    }
+
+    #[test]
+    fn curve448_exchange() {
+        let kex = kex_algorithm_by_name("curve448-sha512").unwrap();
+        assert_eq!(kex.hash, KexHash::Sha512);
+
+        let client = (kex.generate_secret)(&mut CountingRng(0));
+        let server = (kex.generate_secret)(&mut CountingRng(100));
+        assert_eq!(client.pubkey.len(), kex.public_key_len);
+        assert_eq!(server.pubkey.len(), kex.public_key_len);
+
+        let client_k = (client.exchange)(&server.pubkey).unwrap();
+        let server_k = (server.exchange)(&client.pubkey).unwrap();
+        assert_eq!(client_k.expose_secret().0.len(), 56);
+        assert_eq!(client_k.expose_secret().0, server_k.expose_secret().0);
+
+        // Points of low order (0 and 1) and public keys of the wrong length are rejected.
+        let mut one = [0; 56];
+        one[0] = 1;
+        for peer_public_key in [&[0; 56][..], &one, &[9; 32], &[9; 57]] {
+            let secret = (kex.generate_secret)(&mut CountingRng(0));
+            assert!((secret.exchange)(peer_public_key).is_err());
+        }
+    }
+
+    #[test]
+    fn curve448_negotiation() {
+        assert!(SupportedAlgorithms::secure(&[])
+            .key_exchange
+            .find(false, "curve448-sha512")
+            .is_err());
+
+        let negotiate = |this_is_client, peer_supports| {
+            SupportedAlgorithms::secure(&[])
+                .with_curve448()
+                .key_exchange
+                .find(this_is_client, peer_supports)
+                .unwrap()
+                .name()
+        };
+        // As the server, the client's preference wins.
+        assert_eq!(
+            negotiate(false, "curve448-sha512,curve25519-sha256"),
+            "curve448-sha512"
+        );
+        assert_eq!(
+            negotiate(false, "curve25519-sha256,curve448-sha512"),
+            "curve25519-sha256"
+        );
+        // As the client, we prefer curve25519 but accept curve448.
+        assert_eq!(
+            negotiate(true, "curve448-sha512,curve25519-sha256"),
+            "curve25519-sha256"
+        );
+        assert_eq!(negotiate(true, "curve448-sha512"), "curve448-sha512");
+    }
+
+    /// Checks whether watched allocations only contain zeroes when they are freed, which is still allowed to be read.
+    struct ScrubCheckingAllocator;
+
+    std::thread_local! {
+        /// The address of the watched allocation, and whether it was zeroed when it was freed.
+        static WATCHED_ALLOCATION: Cell<(usize, Option<bool>)> = const { Cell::new((0, None)) };
+    }
+
+    unsafe impl GlobalAlloc for ScrubCheckingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            let _ = WATCHED_ALLOCATION.try_with(|watched| {
+                if watched.get().0 == ptr as usize {
+                    let bytes = std::slice::from_raw_parts(ptr, layout.size());
+                    watched.set((0, Some(bytes.iter().all(|&byte| byte == 0))));
+                }
+            });
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: ScrubCheckingAllocator = ScrubCheckingAllocator;
+
+    #[test]
+    fn tunnel_keys_are_scrubbed() {
+        fn assert_zeroizing(state: &Zeroizing<Vec<u8>>) -> Vec<u8> {
+            // No reallocation happened while deriving, which would leave a copy behind.
+            assert_eq!(state.capacity(), state.len());
+            state.to_vec()
+        }
+        fn watch(buffer: &[u8]) {
+            WATCHED_ALLOCATION.with(|watched| watched.set((buffer.as_ptr() as usize, None)));
+        }
+        fn was_scrubbed() -> Option<bool> {
+            WATCHED_ALLOCATION.with(|watched| watched.get().1)
+        }
+
+        let k = secrecy::Secret::new(SharedSecretInner(vec![1; 32]));
+        let mut session = Session::new(
+            SessionId(vec![2; 32]),
+            &k,
+            KexHash::Sha256,
+            encrypt::CHACHA20POLY1305,
+            encrypt::AES256_GCM,
+            true,
+        );
+        let old_from_peer = assert_zeroizing(&session.from_peer.state);
+        let old_to_peer = assert_zeroizing(&session.to_peer.state);
+        assert_eq!(old_from_peer.len(), 64);
+        assert_eq!(old_to_peer.len(), 44);
+
+        watch(&session.from_peer.state);
+        let k = secrecy::Secret::new(SharedSecretInner(vec![3; 32]));
+        session
+            .rekey(
+                &[4; 32],
+                &k,
+                KexHash::Sha256,
+                encrypt::CHACHA20POLY1305,
+                encrypt::AES256_GCM,
+                true,
+            )
+            .unwrap();
+        // The old tunnels have been dropped, which scrubs them before they are freed.
+        assert_eq!(was_scrubbed(), Some(true));
+        assert_ne!(assert_zeroizing(&session.from_peer.state), old_from_peer);
+        assert_ne!(assert_zeroizing(&session.to_peer.state), old_to_peer);
+        assert_eq!(session.session_id.0, [2; 32]);
+
+        watch(&session.to_peer.state);
+        drop(session);
+        assert_eq!(was_scrubbed(), Some(true));
+
+        // A plain buffer is not scrubbed, so the check does notice leftover keys.
+        let plain = old_to_peer.clone();
+        watch(&plain);
+        drop(plain);
+        assert_eq!(was_scrubbed(), Some(false));
+    }
 }
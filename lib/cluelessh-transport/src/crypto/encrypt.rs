@@ -1,6 +1,7 @@
 use crate::Result;
 use aes_gcm::{aead::AeadMutInPlace, KeyInit};
 use chacha20::cipher::{StreamCipher, StreamCipherSeek};
+use hmac::Mac;
 use subtle::ConstantTimeEq;
 
 use crate::packet::{EncryptedPacket, Packet, RawPacket};
@@ -11,6 +12,8 @@ pub const CHACHA20POLY1305: EncryptionAlgorithm = EncryptionAlgorithm {
     name: "chacha20-poly1305@openssh.com",
     iv_size: 0,
     key_size: 64, // 32 for header, 32 for main
+    mac_key_size: 0,
+    mac_len: 16,
     decrypt_len: |state, bytes, packet_number| {
         let alg = ChaCha20Poly1305OpenSsh::from_state(state);
         alg.decrypt_len(bytes, packet_number)
@@ -19,15 +22,17 @@ pub const CHACHA20POLY1305: EncryptionAlgorithm = EncryptionAlgorithm {
         let alg = ChaCha20Poly1305OpenSsh::from_state(state);
         alg.decrypt_packet(bytes, packet_number)
     },
-    encrypt_packet: |state, packet, packet_number| {
+    encrypt_packet: |state, packet, packet_number, padding_granularity| {
         let alg = ChaCha20Poly1305OpenSsh::from_state(state);
-        alg.encrypt_packet(packet, packet_number)
+        alg.encrypt_packet(packet, packet_number, padding_granularity)
     },
 };
 pub const AES256_GCM: EncryptionAlgorithm = EncryptionAlgorithm {
     name: "aes256-gcm@openssh.com",
     iv_size: 12,
     key_size: 32,
+    mac_key_size: 0,
+    mac_len: 16,
     decrypt_len: |state, bytes, packet_number| {
         let mut alg = Aes256GcmOpenSsh::from_state(state);
         alg.decrypt_len(bytes, packet_number)
@@ -36,28 +41,30 @@ pub const AES256_GCM: EncryptionAlgorithm = EncryptionAlgorithm {
         let mut alg = Aes256GcmOpenSsh::from_state(state);
         alg.decrypt_packet(bytes, packet_number)
     },
-    encrypt_packet: |state, packet, packet_number| {
+    encrypt_packet: |state, packet, packet_number, padding_granularity| {
         let mut alg = Aes256GcmOpenSsh::from_state(state);
-        alg.encrypt_packet(packet, packet_number)
+        alg.encrypt_packet(packet, packet_number, padding_granularity)
     },
 };
-/// RFC 4344 AES128 in counter mode.
+/// RFC 4344 AES128 in counter mode, with `hmac-sha2-256-etm@openssh.com` as the MAC, the only one we support with it.
 /// <https://datatracker.ietf.org/doc/html/rfc4344#section-4>
 pub const ENC_AES128_CTR: EncryptionAlgorithm = EncryptionAlgorithm {
     name: "aes128-ctr",
-    iv_size: 12,
-    key_size: 32,
+    iv_size: 16,
+    key_size: 16,
+    mac_key_size: 32,
+    mac_len: 32,
     decrypt_len: |state, bytes, packet_number| {
-        let mut alg = Aes128Ctr::from_state(state);
+        let mut alg = Aes128CtrHmacSha256Etm::from_state(state);
         alg.decrypt_len(bytes, packet_number)
     },
     decrypt_packet: |state, bytes, packet_number| {
-        let mut state = Aes128Ctr::from_state(state);
+        let mut state = Aes128CtrHmacSha256Etm::from_state(state);
         state.decrypt_packet(bytes, packet_number)
     },
-    encrypt_packet: |state, packet, packet_number| {
-        let mut state = Aes128Ctr::from_state(state);
-        state.encrypt_packet(packet, packet_number)
+    encrypt_packet: |state, packet, packet_number, padding_granularity| {
+        let mut state = Aes128CtrHmacSha256Etm::from_state(state);
+        state.encrypt_packet(packet, packet_number, padding_granularity)
     },
 };
 
@@ -123,8 +130,13 @@ impl ChaCha20Poly1305OpenSsh {
         Packet::from_full(encrypted_packet_content)
     }
 
-    fn encrypt_packet(&self, packet: Packet, packet_number: u64) -> EncryptedPacket {
-        let mut bytes = packet.to_bytes(false, Packet::DEFAULT_BLOCK_SIZE);
+    fn encrypt_packet(
+        &self,
+        packet: Packet,
+        packet_number: u64,
+        padding_granularity: usize,
+    ) -> EncryptedPacket {
+        let mut bytes = packet.to_bytes(false, Packet::DEFAULT_BLOCK_SIZE, padding_granularity);
 
         // Prepare the main cipher.
         let mut main_cipher = <SshChaCha20 as chacha20::cipher::KeyIvInit>::new(
@@ -181,8 +193,6 @@ impl<'a> Aes256GcmOpenSsh<'a> {
     }
 
     fn decrypt_packet(&mut self, mut bytes: RawPacket, _packet_number: u64) -> Result<Packet> {
-        let mut cipher = aes_gcm::Aes256Gcm::new(&self.key);
-
         let mut len = [0; 4];
         len.copy_from_slice(&bytes.full_packet()[..4]);
 
@@ -191,63 +201,340 @@ impl<'a> Aes256GcmOpenSsh<'a> {
         tag.copy_from_slice(&bytes.full_packet()[tag_offset..]);
 
         let encrypted_packet_content = bytes.content_mut();
-
-        cipher
-            .decrypt_in_place_detached(
-                (&*self.nonce).into(),
-                &len,
-                encrypted_packet_content,
-                (&tag).into(),
-            )
-            .map_err(|_| crate::peer_error!("failed to decrypt: invalid GCM MAC"))?;
-        self.inc_nonce();
+        self.open(&len, encrypted_packet_content, &tag)?;
 
         Packet::from_full(encrypted_packet_content)
     }
 
-    fn encrypt_packet(&mut self, packet: Packet, _packet_number: u64) -> EncryptedPacket {
+    fn encrypt_packet(
+        &mut self,
+        packet: Packet,
+        _packet_number: u64,
+        padding_granularity: usize,
+    ) -> EncryptedPacket {
         let mut bytes = packet.to_bytes(
             false,
             <aes_gcm::aes::Aes256 as aes_gcm::aes::cipher::BlockSizeUser>::block_size() as u8,
+            padding_granularity,
         );
 
-        let mut cipher = aes_gcm::Aes256Gcm::new(&self.key);
-
         let (aad, plaintext) = bytes.split_at_mut(4);
+        let tag = self.seal(aad, plaintext);
+        bytes.extend_from_slice(&tag);
+
+        EncryptedPacket::from_encrypted_full_bytes(bytes)
+    }
+
+    /// Decrypts `data` in place with the current nonce, which is then incremented.
+    fn open(&mut self, aad: &[u8], data: &mut [u8], tag: &[u8; 16]) -> Result<()> {
+        let mut cipher = aes_gcm::Aes256Gcm::new(&self.key);
+        cipher
+            .decrypt_in_place_detached((&*self.nonce).into(), aad, data, tag.into())
+            .map_err(|_| crate::peer_error!("failed to decrypt: invalid GCM MAC"))?;
+        self.inc_nonce();
+        Ok(())
+    }
 
+    /// Encrypts `data` in place with the current nonce, which is then incremented.
+    fn seal(&mut self, aad: &[u8], data: &mut [u8]) -> [u8; 16] {
+        let mut cipher = aes_gcm::Aes256Gcm::new(&self.key);
         let tag = cipher
-            .encrypt_in_place_detached((&*self.nonce).into(), aad, plaintext)
+            .encrypt_in_place_detached((&*self.nonce).into(), aad, data)
             .unwrap();
-        bytes.extend_from_slice(&tag);
         self.inc_nonce();
-
-        EncryptedPacket::from_encrypted_full_bytes(bytes)
+        tag.into()
     }
 
     fn inc_nonce(&mut self) {
-        let mut carry = 1;
-        for i in (0..self.nonce.len()).rev() {
-            let n = self.nonce[i] as u16 + carry;
-            self.nonce[i] = n as u8;
-            carry = n >> 8;
-        }
+        // Only the 64-bit invocation counter is incremented, the 32-bit fixed field stays the same.
+        // <https://datatracker.ietf.org/doc/html/rfc5647#section-7.1>
+        let counter = <&mut [u8; 8]>::try_from(&mut self.nonce[4..]).unwrap();
+        *counter = u64::from_be_bytes(*counter).wrapping_add(1).to_be_bytes();
     }
 }
 
-struct Aes128Ctr {
-    _key: ctr::Ctr128BE<aes::Aes128>,
+type HmacSha256 = hmac::Hmac<sha2::Sha256>;
+
+/// With an encrypt-then-mac MAC, the length is sent in the clear and the MAC is computed over
+/// `sequence number || length || ciphertext`, so it can be verified before decrypting anything.
+/// <https://github.com/openssh/openssh-portable/blob/1ec0a64c5dc57b8a2053a93b5ef0d02ff8598e5c/PROTOCOL#L56>
+struct Aes128CtrHmacSha256Etm<'a> {
+    key: aes::cipher::Key<aes::Aes128>,
+    /// The counter, which continues over all packets.
+    counter: &'a mut [u8; 16],
+    mac_key: &'a [u8],
+}
+
+impl<'a> Aes128CtrHmacSha256Etm<'a> {
+    const BLOCK_SIZE: usize = 16;
+
+    fn from_state(keys: &'a mut [u8]) -> Self {
+        assert_eq!(keys.len(), 64);
+        let (key, rest) = keys.split_at_mut(16);
+        let (counter, mac_key) = rest.split_at_mut(16);
+        Self {
+            key: <[u8; 16]>::try_from(&*key).unwrap().into(),
+            counter: <&mut [u8; 16]>::try_from(counter).unwrap(),
+            mac_key,
+        }
+    }
+
+    fn decrypt_len(&mut self, _: &mut [u8], _: u64) {
+        // The length is not encrypted with encrypt-then-mac.
+    }
+
+    fn mac(&self, packet_number: u64, length_and_ciphertext: &[u8]) -> HmacSha256 {
+        let mut mac = <HmacSha256 as Mac>::new_from_slice(self.mac_key).unwrap();
+        mac.update(&(packet_number as u32).to_be_bytes());
+        mac.update(length_and_ciphertext);
+        mac
+    }
+
+    fn apply_keystream(&mut self, data: &mut [u8]) {
+        assert!(data.len().is_multiple_of(Self::BLOCK_SIZE));
+        let mut cipher = <ctr::Ctr128BE<aes::Aes128> as aes::cipher::KeyIvInit>::new(
+            &self.key,
+            (&*self.counter).into(),
+        );
+        cipher.apply_keystream(data);
+
+        let blocks = (data.len() / Self::BLOCK_SIZE) as u128;
+        *self.counter = u128::from_be_bytes(*self.counter)
+            .wrapping_add(blocks)
+            .to_be_bytes();
+    }
+
+    fn decrypt_packet(&mut self, mut bytes: RawPacket, packet_number: u64) -> Result<Packet> {
+        let mac_offset = bytes.full_packet().len() - self.mac_len();
+
+        // Authenticate first, nothing may be decrypted before that.
+        self.mac(packet_number, &bytes.full_packet()[..mac_offset])
+            .verify_slice(&bytes.full_packet()[mac_offset..])
+            .map_err(|_| crate::peer_error!("failed to decrypt: invalid hmac-sha2-256-etm MAC"))?;
+
+        let encrypted_packet_content = bytes.content_mut();
+        if !encrypted_packet_content
+            .len()
+            .is_multiple_of(Self::BLOCK_SIZE)
+        {
+            return Err(crate::peer_error!(
+                "packet length is not a multiple of the block size: {}",
+                encrypted_packet_content.len()
+            ));
+        }
+        self.apply_keystream(encrypted_packet_content);
+
+        Packet::from_full(encrypted_packet_content)
+    }
+
+    fn encrypt_packet(
+        &mut self,
+        packet: Packet,
+        packet_number: u64,
+        padding_granularity: usize,
+    ) -> EncryptedPacket {
+        let mut bytes = packet.to_bytes(false, Self::BLOCK_SIZE as u8, padding_granularity);
+
+        self.apply_keystream(&mut bytes[4..]);
+        let mac = self.mac(packet_number, &bytes).finalize().into_bytes();
+        bytes.extend_from_slice(&mac);
+
+        EncryptedPacket::from_encrypted_full_bytes(bytes)
+    }
+
+    fn mac_len(&self) -> usize {
+        ENC_AES128_CTR.mac_len
+    }
 }
-impl Aes128Ctr {
-    fn from_state(_keys: &mut [u8]) -> Self {
-        todo!()
+
+#[cfg(test)]
+mod tests {
+    use super::{Aes128CtrHmacSha256Etm, Aes256GcmOpenSsh, AES256_GCM, ENC_AES128_CTR};
+    use crate::packet::{Packet, RawPacket};
+
+    fn hex(s: &str) -> Vec<u8> {
+        hex::decode(s).unwrap()
+    }
+
+    #[test]
+    fn aes256_gcm_spec_vector() {
+        // Test Case 16 of "The Galois/Counter Mode of Operation (GCM)" by McGrew and Viega,
+        // which NIST SP 800-38D refers to for test vectors: the key is followed by the nonce in our state.
+        // <https://csrc.nist.gov/CSRC/media/Projects/Block-Cipher-Techniques/documents/BCM/proposed-modes/gcm/gcm-spec.pdf>
+        let mut state = hex("feffe9928665731c6d6a8f9467308308feffe9928665731c6d6a8f9467308308");
+        state.extend_from_slice(&hex("cafebabefacedbaddecaf888"));
+        let aad = hex("feedfacedeadbeeffeedfacedeadbeefabaddad2");
+        let plaintext = hex("d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a721c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b39");
+        let ciphertext = hex("522dc1f099567d07f47f37a32a84427d643a8cdcbfe5c0c97598a2bd2555d1aa8cb08e48590dbb3da7b08b1056828838c5f61e6393ba7a0abcc9f662");
+        let tag = hex("76fc6ece0f4e1768cddf8853bb2d551b");
+
+        let mut data = plaintext.clone();
+        let mut encrypt_state = state.clone();
+        let actual_tag = Aes256GcmOpenSsh::from_state(&mut encrypt_state).seal(&aad, &mut data);
+        assert_eq!(data, ciphertext);
+        assert_eq!(actual_tag[..], tag);
+
+        Aes256GcmOpenSsh::from_state(&mut state)
+            .open(&aad, &mut data, &actual_tag)
+            .unwrap();
+        assert_eq!(data, plaintext);
+        assert_eq!(state[32..], hex("cafebabefacedbaddecaf889"));
+    }
+
+    #[test]
+    fn aes256_gcm_length_is_cleartext_aad() {
+        // Two SSH_MSG_IGNORE packets in the `aes256-gcm@openssh.com` framing, computed with an independent
+        // AES-GCM implementation (Python's `cryptography`): the length is sent in the clear and authenticated as AAD,
+        // padding length, payload and padding are encrypted.
+        // The invocation counter starts at the maximum to check that it wraps without touching the fixed field.
+        let mut state = (0..32).collect::<Vec<u8>>();
+        state.extend_from_slice(&hex("0a0b0c0dffffffffffffffff"));
+
+        let packets = [
+            (
+                "000000103b059b6306f71e07077bee8e2f465692cc85166a1d79cf347cbe0ebe2e545e48",
+                b"meow",
+            ),
+            (
+                "00000010d05ed383b83b02278acf4de1973c6d850854774094d01e507f715959cdf3be57",
+                b"purr",
+            ),
+        ];
+
+        for (packet_number, (raw, data)) in packets.into_iter().enumerate() {
+            let raw = hex(raw);
+
+            let mut len = raw[..4].to_vec();
+            (AES256_GCM.decrypt_len)(&mut state, &mut len, packet_number as u64);
+            assert_eq!(len, raw[..4]);
+
+            let packet = (AES256_GCM.decrypt_packet)(
+                &mut state,
+                RawPacket { mac_len: 16, raw },
+                packet_number as u64,
+            )
+            .unwrap();
+            assert_eq!(packet, Packet::new_msg_ignore(data));
+        }
+        assert_eq!(state[32..], hex("0a0b0c0d0000000000000001"));
+
+        let mut tampered = hex(packets[0].0);
+        tampered[3] ^= 0x10;
+        assert!((AES256_GCM.decrypt_packet)(
+            &mut state,
+            RawPacket {
+                mac_len: 16,
+                raw: tampered,
+            },
+            2,
+        )
+        .is_err());
     }
 
-    fn decrypt_len(&mut self, _: &mut [u8], _: u64) {}
+    #[test]
+    fn aes128_ctr_hmac_sha256_etm() {
+        // Two SSH_MSG_IGNORE packets with aes128-ctr and hmac-sha2-256-etm@openssh.com, computed with independent
+        // implementations (Python's `cryptography` and `hmac`): cleartext length, then the ciphertext of
+        // padding length, payload and padding, then the MAC over sequence number, length and ciphertext.
+        // The counter continues from the first packet into the second.
+        let fresh_state = || (0..64).collect::<Vec<u8>>();
+        let packets = [
+            (
+                3,
+                "0000001001fcef74e1d16e0bff79ee118e9492938d6cd05557dad6711c0a53126e2923f734d3ec714d1c5af2c2f529f59921f799",
+                b"meow",
+            ),
+            (
+                4,
+                "0000001094c0c504d808f277340b7d628bf9de0806277a3e640af3278a6eea06400c3cc3c4c06e27890891789d5ec6e7fb13af70",
+                b"purr",
+            ),
+        ];
+
+        let mut state = fresh_state();
+        for (packet_number, raw, data) in packets {
+            let encrypted = (ENC_AES128_CTR.encrypt_packet)(
+                &mut state,
+                Packet::new_msg_ignore(data),
+                packet_number,
+                0,
+            );
+            assert_eq!(encrypted.into_bytes(), hex(raw));
+        }
+
+        let mut state = fresh_state();
+        for (packet_number, raw, data) in packets {
+            let raw = hex(raw);
+
+            let mut len = raw[..4].to_vec();
+            (ENC_AES128_CTR.decrypt_len)(&mut state, &mut len, packet_number);
+            assert_eq!(len, raw[..4]);
+
+            let packet = (ENC_AES128_CTR.decrypt_packet)(
+                &mut state,
+                RawPacket {
+                    mac_len: ENC_AES128_CTR.mac_len,
+                    raw,
+                },
+                packet_number,
+            )
+            .unwrap();
+            assert_eq!(packet, Packet::new_msg_ignore(data));
+        }
+        assert_eq!(state[16..32], hex("101112131415161718191a1b1c1d1e21"));
+    }
 
-    fn decrypt_packet(&mut self, _bytes: RawPacket, _packet_number: u64) -> Result<Packet> {
-        todo!()
+    #[test]
+    fn aes128_ctr_hmac_sha256_etm_tampered() {
+        let raw = hex("0000001001fcef74e1d16e0bff79ee118e9492938d6cd05557dad6711c0a53126e2923f734d3ec714d1c5af2c2f529f59921f799");
+
+        // The length, the ciphertext and the MAC are authenticated, and so is the sequence number.
+        let tampered = [3, 10, 40].map(|index| {
+            let mut raw = raw.clone();
+            raw[index] ^= 1;
+            (raw, 3)
+        });
+        for (raw, packet_number) in tampered.into_iter().chain([(raw.clone(), 4)]) {
+            let mut state = (0..64).collect::<Vec<u8>>();
+            let result = (ENC_AES128_CTR.decrypt_packet)(
+                &mut state,
+                RawPacket {
+                    mac_len: ENC_AES128_CTR.mac_len,
+                    raw,
+                },
+                packet_number,
+            );
+            assert!(result.is_err());
+            // Nothing was decrypted, so the counter did not advance.
+            assert_eq!(state, (0..64).collect::<Vec<u8>>());
+        }
     }
-    fn encrypt_packet(&mut self, _packet: Packet, _packet_number: u64) -> EncryptedPacket {
-        todo!()
+
+    #[test]
+    fn aes128_ctr_nist_vectors() {
+        // CTR-AES128.Encrypt from NIST SP 800-38A, F.5.1.
+        // <https://nvlpubs.nist.gov/nistpubs/Legacy/SP/nistspecialpublication800-38a.pdf>
+        let mut state = hex("2b7e151628aed2a6abf7158809cf4f3c");
+        state.extend_from_slice(&hex("f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff"));
+        state.extend_from_slice(&[0; 32]);
+        let blocks = [
+            (
+                "6bc1bee22e409f96e93d7e117393172aae2d8a571e03ac9c9eb76fac45af8e51",
+                "874d6191b620e3261bef6864990db6ce9806f66b7970fdff8617187bb9fffdff",
+            ),
+            (
+                "30c81c46a35ce411e5fbc1191a0a52eff69f2445df4f9b17ad2b417be66c3710",
+                "5ae4df3edbd5d35e5b4f09020db03eab1e031dda2fbe03d1792170a0f3009cee",
+            ),
+        ];
+
+        // The counter continues from the first call into the second, like from one packet to the next.
+        let mut alg = Aes128CtrHmacSha256Etm::from_state(&mut state);
+        for (plaintext, ciphertext) in blocks {
+            let mut data = hex(plaintext);
+            alg.apply_keystream(&mut data);
+            assert_eq!(data, hex(ciphertext));
+        }
+        assert_eq!(state[16..32], hex("f0f1f2f3f4f5f6f7f8f9fafbfcfdff03"));
     }
 }
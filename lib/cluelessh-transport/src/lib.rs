@@ -3,7 +3,7 @@ pub mod crypto;
 pub mod packet;
 pub mod server;
 
-use std::fmt::Debug;
+use std::fmt::{Debug, Display};
 
 use cluelessh_format::ParseError;
 pub use packet::Msg;
@@ -22,13 +22,35 @@ pub enum SshStatus {
     PeerError(String),
 }
 
-#[derive(Clone, Copy, Serialize, Deserialize)]
-pub struct SessionId(pub [u8; 32]);
+impl Display for SshStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Disconnect => f.write_str("peer disconnected"),
+            Self::PeerError(err) => write!(f, "peer error: {err}"),
+        }
+    }
+}
+impl std::error::Error for SshStatus {}
+
+/// A configuration that was rejected when it was passed in, like a [`server::ServerConfig`]
+/// that fails [`server::ServerConfig::validate`].
+#[derive(Debug)]
+pub struct InvalidConfig(pub String);
+impl Display for InvalidConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid config: {}", self.0)
+    }
+}
+impl std::error::Error for InvalidConfig {}
+
+/// The exchange hash H of the first key exchange, its length depends on the hash of the key exchange algorithm.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionId(pub Vec<u8>);
 
 impl Debug for SessionId {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_tuple("SessionId")
-            .field(&hex::encode(self.0))
+            .field(&hex::encode(&self.0))
             .finish()
     }
 }
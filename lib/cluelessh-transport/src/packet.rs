@@ -5,9 +5,9 @@ use std::mem;
 
 use tracing::{debug, trace};
 
-use crate::crypto::{self, EncryptionAlgorithm, Keys, Plaintext, Session, SharedSecret};
+use crate::crypto::{self, EncryptionAlgorithm, KexHash, Keys, Plaintext, Session, SharedSecret};
 use crate::Result;
-use crate::{peer_error, SessionId};
+use crate::{peer_error, InvalidConfig, SessionId};
 use cluelessh_format::numbers;
 use cluelessh_format::{NameList, Reader, Writer};
 
@@ -22,6 +22,41 @@ pub(crate) struct PacketTransport {
 
     msgs_to_send: VecDeque<Msg>,
     send_next_seq_nr: u64,
+
+    padding_granularity: usize,
+
+    /// Payload bytes sent and received since the last change of keys.
+    bytes_since_rekey: u64,
+    /// Packets sent and received since the last change of keys.
+    packets_since_rekey: u64,
+    /// The maximum length of the payload of received packets after decryption.
+    max_payload_len: Option<usize>,
+    tracer: Option<Box<dyn PacketTracer>>,
+}
+
+/// Whether a packet has been sent or received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketDirection {
+    Send,
+    Recv,
+}
+
+/// Observes every packet going through the transport, for debugging and analyzing connections.
+/// Only the type and length of the payload are passed on, never its contents.
+pub trait PacketTracer: Send + Sync {
+    fn trace_packet(
+        &mut self,
+        direction: PacketDirection,
+        packet_type: u8,
+        payload_len: usize,
+        seq_nr: u32,
+    );
+}
+
+/// Sequence numbers are 32 bit and wrap around.
+/// <https://datatracker.ietf.org/doc/html/rfc4253#section-6.4>
+fn next_seq_nr(seq_nr: u64) -> u64 {
+    (seq_nr + 1) % (1 << 32)
 }
 
 #[derive(Debug)]
@@ -38,7 +73,7 @@ impl Msg {
     pub fn to_bytes(self) -> Vec<u8> {
         match self.0 {
             MsgKind::ServerProtocolInfo(v) => v,
-            MsgKind::PlaintextPacket(v) => v.to_bytes(true, Packet::DEFAULT_BLOCK_SIZE),
+            MsgKind::PlaintextPacket(v) => v.to_bytes(true, Packet::DEFAULT_BLOCK_SIZE, 0),
             MsgKind::EncryptedPacket(v) => v.into_bytes(),
         }
     }
@@ -61,6 +96,16 @@ enum RecvBytesStepResult {
     ReadPacket { consumed: usize, is_new_keys: bool },
 }
 
+/// Padding is added in whole blocks, so the granularity has to be a power of two.
+pub(crate) fn check_padding_granularity(granularity: usize) -> Result<(), InvalidConfig> {
+    if !granularity.is_power_of_two() {
+        return Err(InvalidConfig(format!(
+            "padding granularity must be a power of two, not {granularity}"
+        )));
+    }
+    Ok(())
+}
+
 impl PacketTransport {
     pub(crate) fn new() -> Self {
         PacketTransport {
@@ -72,8 +117,31 @@ impl PacketTransport {
 
             msgs_to_send: VecDeque::new(),
             send_next_seq_nr: 0,
+
+            padding_granularity: Packet::DEFAULT_BLOCK_SIZE as usize,
+
+            bytes_since_rekey: 0,
+            packets_since_rekey: 0,
+            max_payload_len: None,
+            tracer: None,
         }
     }
+
+    pub(crate) fn set_tracer(&mut self, tracer: Box<dyn PacketTracer>) {
+        self.tracer = Some(tracer);
+    }
+
+    /// Pad all encrypted packets to a multiple of `granularity` bytes to obscure payload sizes.
+    /// Plaintext packets during the initial key exchange always use the default block size.
+    /// The granularity must have passed [`check_padding_granularity`].
+    pub(crate) fn set_padding_granularity(&mut self, granularity: usize) {
+        self.padding_granularity = granularity;
+    }
+
+    /// Reject received packets with a larger payload than `len` bytes.
+    pub(crate) fn set_max_payload_len(&mut self, len: usize) {
+        self.max_payload_len = Some(len);
+    }
     pub(crate) fn recv_bytes(&mut self, mut bytes: &[u8]) -> Result<RecvBytesResult> {
         let mut total_consumed = 0;
         while let RecvBytesStepResult::ReadPacket {
@@ -103,10 +171,30 @@ impl PacketTransport {
             self.recv_next_packet
                 .recv_bytes(bytes, &mut *self.keys, self.recv_next_seq_nr)?;
         if let Some((consumed, result)) = result {
+            if let Some(max) = self.max_payload_len {
+                if result.payload.len() > max {
+                    return Err(peer_error!(
+                        "packet payload too large: {} while the maximum is {max}",
+                        result.payload.len()
+                    ));
+                }
+            }
+
             let is_new_keys = result.packet_type() == numbers::SSH_MSG_NEWKEYS;
+            self.bytes_since_rekey += result.payload.len() as u64;
+            self.packets_since_rekey += 1;
+
+            if let Some(tracer) = &mut self.tracer {
+                tracer.trace_packet(
+                    PacketDirection::Recv,
+                    result.packet_type(),
+                    result.payload.len(),
+                    self.recv_next_seq_nr as u32,
+                );
+            }
 
             self.recv_packets.push_back(result);
-            self.recv_next_seq_nr = self.recv_next_seq_nr.wrapping_add(1);
+            self.recv_next_seq_nr = next_seq_nr(self.recv_next_seq_nr);
             self.recv_next_packet = PacketParser::new();
             return Ok(RecvBytesStepResult::ReadPacket {
                 consumed,
@@ -122,8 +210,20 @@ impl PacketTransport {
         let packet_type_string = numbers::packet_type_to_string(packet_type);
         trace!(%packet_type, %packet_type_string, packet_len = %packet.payload.len(), "Sending packet");
         let seq_nr = self.send_next_seq_nr;
-        self.send_next_seq_nr = self.send_next_seq_nr.wrapping_add(1);
-        let msg = self.keys.encrypt_packet_to_msg(packet, seq_nr);
+        self.send_next_seq_nr = next_seq_nr(self.send_next_seq_nr);
+        if let Some(tracer) = &mut self.tracer {
+            tracer.trace_packet(
+                PacketDirection::Send,
+                packet_type,
+                packet.payload.len(),
+                seq_nr as u32,
+            );
+        }
+        self.bytes_since_rekey += packet.payload.len() as u64;
+        self.packets_since_rekey += 1;
+        let msg = self
+            .keys
+            .encrypt_packet_to_msg(packet, seq_nr, self.padding_granularity);
         self.queue_send_msg(msg);
     }
 
@@ -144,24 +244,43 @@ impl PacketTransport {
         self.msgs_to_send.pop_front()
     }
 
+    pub(crate) fn bytes_since_rekey(&self) -> u64 {
+        self.bytes_since_rekey
+    }
+
+    pub(crate) fn packets_since_rekey(&self) -> u64 {
+        self.packets_since_rekey
+    }
+
+    /// Pretends that `count` more packets have been sent, to test the limits without sending that many.
+    #[cfg(test)]
+    pub(crate) fn add_packets_since_rekey(&mut self, count: u64) {
+        self.packets_since_rekey += count;
+    }
+
     pub(crate) fn set_key(
         &mut self,
-        h: [u8; 32],
+        h: &[u8],
         k: &SharedSecret,
+        hash: KexHash,
         encryption_client_to_server: EncryptionAlgorithm,
         encryption_server_to_client: EncryptionAlgorithm,
         is_server: bool,
     ) {
+        self.bytes_since_rekey = 0;
+        self.packets_since_rekey = 0;
         if let Err(()) = self.keys.rekey(
             h,
             k,
+            hash,
             encryption_client_to_server,
             encryption_server_to_client,
             is_server,
         ) {
             self.keys = Box::new(Session::new(
-                SessionId(h),
+                SessionId(h.to_vec()),
                 k,
+                hash,
                 encryption_client_to_server,
                 encryption_server_to_client,
                 is_server,
@@ -214,7 +333,7 @@ impl Packet {
         //    return Err(peer_error!("full packet length must be multiple of 8: {}", bytes.len()));
         //}
 
-        if payload.len() < 1 {
+        if payload.is_empty() {
             return Err(peer_error!("empty packet without a type"));
         }
 
@@ -223,23 +342,33 @@ impl Packet {
         })
     }
 
-    pub(crate) fn to_bytes(&self, respect_len_for_padding: bool, block_size: u8) -> Vec<u8> {
+    /// Serializes the packet, padding it to a multiple of `block_size`.
+    /// If `padding_granularity` is larger, the packet is padded to a multiple of it instead,
+    /// as far as the padding length field allows.
+    pub(crate) fn to_bytes(
+        &self,
+        respect_len_for_padding: bool,
+        block_size: u8,
+        padding_granularity: usize,
+    ) -> Vec<u8> {
         assert!(block_size.is_power_of_two());
+        let granularity = padding_granularity.max(block_size as usize);
+        assert!(granularity.is_power_of_two());
 
         let let_bytes = if respect_len_for_padding { 4 } else { 0 };
 
         // <https://datatracker.ietf.org/doc/html/rfc4253#section-6>
         let min_full_length = self.payload.len() + let_bytes + 1;
 
-        // The padding must give a factor of block_size.
-        let min_padding_len =
-            (min_full_length.next_multiple_of(block_size as usize) - min_full_length) as u8;
+        // The padding must give a factor of the granularity.
         // > There MUST be at least four bytes of padding.
-        let padding_len = if min_padding_len < 4 {
-            min_padding_len + block_size
-        } else {
-            min_padding_len
-        };
+        let mut padding_len = (min_full_length + 4).next_multiple_of(granularity) - min_full_length;
+        // The padding length is a single byte, so for large granularities we may have to
+        // remove some blocks again. This still leaves more than four bytes of padding.
+        if padding_len > u8::MAX as usize {
+            padding_len -= (padding_len - u8::MAX as usize).next_multiple_of(block_size as usize);
+        }
+        let padding_len = padding_len as u8;
 
         let packet_len = self.payload.len() + (padding_len as usize) + 1;
 
@@ -247,9 +376,12 @@ impl Packet {
         new.extend_from_slice(&u32::to_be_bytes(packet_len as u32));
         new.extend_from_slice(&[padding_len]);
         new.extend_from_slice(&self.payload);
-        new.extend(std::iter::repeat(0).take(padding_len as usize));
+        new.extend(std::iter::repeat_n(0, padding_len as usize));
 
-        assert!((let_bytes + 1 + self.payload.len() + (padding_len as usize)) % 8 == 0);
+        assert!(
+            (let_bytes + 1 + self.payload.len() + (padding_len as usize))
+                .is_multiple_of(block_size as usize)
+        );
 
         new
     }
@@ -289,7 +421,7 @@ pub(crate) struct KeyExchangeInitPacket<'a> {
 }
 
 impl<'a> KeyExchangeInitPacket<'a> {
-    pub(crate) fn parse(payload: &'a [u8]) -> Result<KeyExchangeInitPacket<'_>> {
+    pub(crate) fn parse(payload: &'a [u8]) -> Result<KeyExchangeInitPacket<'a>> {
         let mut c = Reader::new(payload);
 
         let kind = c.u8()?;
@@ -356,7 +488,7 @@ pub(crate) struct KeyExchangeEcDhInitPacket<'a> {
     pub(crate) qc: &'a [u8],
 }
 impl<'a> KeyExchangeEcDhInitPacket<'a> {
-    pub(crate) fn parse(payload: &'a [u8]) -> Result<KeyExchangeEcDhInitPacket<'_>> {
+    pub(crate) fn parse(payload: &'a [u8]) -> Result<KeyExchangeEcDhInitPacket<'a>> {
         let mut c = Reader::new(payload);
 
         let kind = c.u8()?;
@@ -535,7 +667,13 @@ impl ProtocolIdentParser {
 
 #[cfg(test)]
 mod tests {
-    use crate::packet::PacketParser;
+    use crate::{
+        crypto::{
+            encrypt, EncryptionAlgorithm, KexHash, Keys, Session, SharedSecret, SharedSecretInner,
+        },
+        packet::{Packet, PacketParser, RawPacket},
+        Msg, Result, SessionId,
+    };
 
     trait OptionExt {
         fn unwrap_none(self);
@@ -577,4 +715,135 @@ mod tests {
         assert_eq!(consumed, 6);
         assert_eq!(data.rest(), &[1, 2]);
     }
+
+    /// Counts how often the length is decrypted, which must happen exactly once per packet.
+    struct CountingKeys {
+        inner: Session,
+        decrypt_len_calls: usize,
+    }
+    impl Keys for CountingKeys {
+        fn decrypt_len(&mut self, bytes: &mut [u8; 4], packet_number: u64) {
+            self.decrypt_len_calls += 1;
+            self.inner.decrypt_len(bytes, packet_number);
+        }
+        fn decrypt_packet(&mut self, raw_packet: RawPacket, packet_number: u64) -> Result<Packet> {
+            self.inner.decrypt_packet(raw_packet, packet_number)
+        }
+        fn encrypt_packet_to_msg(
+            &mut self,
+            packet: Packet,
+            packet_number: u64,
+            padding_granularity: usize,
+        ) -> Msg {
+            self.inner
+                .encrypt_packet_to_msg(packet, packet_number, padding_granularity)
+        }
+        fn additional_mac_len(&self) -> usize {
+            self.inner.additional_mac_len()
+        }
+        fn rekey(
+            &mut self,
+            h: &[u8],
+            k: &SharedSecret,
+            hash: KexHash,
+            encryption_client_to_server: EncryptionAlgorithm,
+            encryption_server_to_client: EncryptionAlgorithm,
+            is_server: bool,
+        ) -> Result<(), ()> {
+            self.inner.rekey(
+                h,
+                k,
+                hash,
+                encryption_client_to_server,
+                encryption_server_to_client,
+                is_server,
+            )
+        }
+    }
+
+    #[test]
+    fn packet_parser_split_aead_len() {
+        let session = |is_server| {
+            Session::new(
+                SessionId(vec![2; 32]),
+                &secrecy::Secret::new(SharedSecretInner(vec![1; 32])),
+                KexHash::Sha256,
+                encrypt::CHACHA20POLY1305,
+                encrypt::CHACHA20POLY1305,
+                is_server,
+            )
+        };
+        let mut client = session(false);
+        let mut server = CountingKeys {
+            inner: session(true),
+            decrypt_len_calls: 0,
+        };
+
+        for seq_nr in 0..2 {
+            let payload = vec![seq_nr as u8; 20];
+            let bytes = client
+                .encrypt_packet_to_msg(
+                    Packet {
+                        payload: payload.clone(),
+                    },
+                    seq_nr,
+                    0,
+                )
+                .to_bytes();
+
+            // Every possible split of the encrypted length, and the rest byte by byte.
+            for split in 0..=4 {
+                server.decrypt_len_calls = 0;
+                let mut p = PacketParser::new();
+                assert!(p
+                    .recv_bytes(&bytes[..split], &mut server, seq_nr)
+                    .unwrap()
+                    .is_none());
+                let mut rest = &bytes[split..];
+                let received = loop {
+                    let (chunk, next) = rest.split_at(1);
+                    rest = next;
+                    if let Some((consumed, received)) =
+                        p.recv_bytes(chunk, &mut server, seq_nr).unwrap()
+                    {
+                        assert_eq!(consumed, 1);
+                        break received;
+                    }
+                };
+                assert!(rest.is_empty());
+                assert_eq!(received.payload, payload, "split at {split}");
+                assert_eq!(server.decrypt_len_calls, 1, "split at {split}");
+            }
+        }
+    }
+
+    #[test]
+    fn padding_granularity() {
+        for payload_len in [1, 3, 4, 200, 251, 252, 253, 256, 1000] {
+            let packet = Packet {
+                payload: vec![1; payload_len],
+            };
+            let bytes = packet.to_bytes(true, Packet::DEFAULT_BLOCK_SIZE, 256);
+            let padding_len = bytes[4] as usize;
+            assert!(padding_len >= 4);
+            assert!(bytes.len().is_multiple_of(8));
+            assert_eq!(bytes.len(), 4 + 1 + payload_len + padding_len);
+
+            // Unless the padding length field would overflow, we pad to the full granularity.
+            if (payload_len + 5 + 4).next_multiple_of(256) - (payload_len + 5) <= 255 {
+                assert!(bytes.len().is_multiple_of(256), "{payload_len}");
+            }
+        }
+    }
+
+    #[test]
+    fn padding_granularity_default() {
+        let packet = Packet {
+            payload: vec![1; 3],
+        };
+        // 4 + 1 + 3 = 8, so we need another full block for the minimum padding.
+        let bytes = packet.to_bytes(true, Packet::DEFAULT_BLOCK_SIZE, 0);
+        assert_eq!(bytes.len(), 16);
+        assert_eq!(bytes[4], 8);
+    }
 }
@@ -49,6 +49,9 @@ ctors! {
     // Transport layer protocol:
 
     // 1 to 19 Transport layer generic (e.g., disconnect, ignore, debug, etc.)
+    fn new_msg_disconnect(SSH_MSG_DISCONNECT; reason_code: u32, description: string, language_tag: string);
+    fn new_msg_ignore(SSH_MSG_IGNORE; data: string);
+    fn new_msg_debug(SSH_MSG_DEBUG; always_display: bool, message: string, language_tag: string);
     fn new_msg_service_request(SSH_MSG_SERVICE_REQUEST; service_name: string);
     // 20 to 29 Algorithm negotiation
     // 30 to 49 Key exchange method specific (numbers can be reused for different authentication methods)
@@ -84,6 +87,13 @@ ctors! {
         pubkey: string,
         signature: string,
     );
+    fn new_msg_userauth_request_keyboard_interactive(SSH_MSG_USERAUTH_REQUEST;
+        username: string,
+        service_name: string,
+        method_name_keyboard_interactive: string,
+        language_tag: string,
+        submethods: string,
+    );
     fn new_msg_userauth_failure(SSH_MSG_USERAUTH_FAILURE;
         auth_options: name_list,
         partial_success: bool,
@@ -101,6 +111,14 @@ ctors! {
     // Connection protocol:
 
     // 80 to 89   Connection protocol generic
+    fn new_msg_global_request(SSH_MSG_GLOBAL_REQUEST; request_name: string, want_reply: bool);
+    fn new_msg_global_request_tcpip_forward(SSH_MSG_GLOBAL_REQUEST;
+        tcpip_forward: string,
+        want_reply: bool,
+        address_to_bind: string,
+        port_to_bind: u32,
+    );
+    fn new_msg_request_success(SSH_MSG_REQUEST_SUCCESS;);
     fn new_msg_request_failure(SSH_MSG_REQUEST_FAILURE;);
 
     // 90 to 127  Channel related messages
@@ -110,6 +128,16 @@ ctors! {
         initial_window_size: u32,
         maximum_packet_size: u32,
     );
+    fn new_msg_channel_open_forwarded_tcpip(SSH_MSG_CHANNEL_OPEN;
+        forwarded_tcpip: string,
+        sender_channel: u32,
+        initial_window_size: u32,
+        maximum_packet_size: u32,
+        connected_address: string,
+        connected_port: u32,
+        originator_address: string,
+        originator_port: u32,
+    );
     fn new_msg_channel_open_confirmation(SSH_MSG_CHANNEL_OPEN_CONFIRMATION;
         peer_channel: u32,
         sender_channel: u32,
@@ -140,12 +168,49 @@ ctors! {
         term_height_px: u32,
         term_modes: string,
     );
+    fn new_msg_channel_request_window_change(SSH_MSG_CHANNEL_REQUEST;
+        recipient_channel: u32,
+        kind_window_change: string,
+        false_: bool,
+        term_width_char: u32,
+        term_height_rows: u32,
+        term_width_px: u32,
+        term_height_px: u32,
+    );
     fn new_msg_channel_request_shell(SSH_MSG_CHANNEL_REQUEST;
         recipient_channel: u32,
         kind_shell: string,
         want_reply: bool,
     );
+    fn new_msg_channel_request_exec(SSH_MSG_CHANNEL_REQUEST;
+        recipient_channel: u32,
+        kind_exec: string,
+        want_reply: bool,
+        command: string,
+    );
+    fn new_msg_channel_request_env(SSH_MSG_CHANNEL_REQUEST;
+        recipient_channel: u32,
+        kind_env: string,
+        want_reply: bool,
+        variable_name: string,
+        variable_value: string,
+    );
+    fn new_msg_channel_request_subsystem(SSH_MSG_CHANNEL_REQUEST;
+        recipient_channel: u32,
+        kind_subsystem: string,
+        want_reply: bool,
+        subsystem_name: string,
+    );
     fn new_msg_channel_request_exit_status(SSH_MSG_CHANNEL_REQUEST; recipient_channel: u32, kind_exit_status: string, false_: bool, exit_status: u32);
+    fn new_msg_channel_request_exit_signal(SSH_MSG_CHANNEL_REQUEST;
+        recipient_channel: u32,
+        kind_exit_signal: string,
+        false_: bool,
+        signal_name: string,
+        core_dumped: bool,
+        error_message: string,
+        language_tag: string,
+    );
 
     fn new_msg_channel_success(SSH_MSG_CHANNEL_SUCCESS; recipient_channel: u32);
     fn new_msg_channel_failure(SSH_MSG_CHANNEL_FAILURE; recipient_channel: u32);
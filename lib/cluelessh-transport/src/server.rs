@@ -1,20 +1,20 @@
-use std::{collections::VecDeque, mem::take};
+use std::{collections::VecDeque, fmt::Debug, mem::take, sync::Arc, time::Duration};
 
 use crate::crypto::{
-    self, AlgorithmName, EncryptionAlgorithm, HostKeySigningAlgorithm, KexAlgorithm, SharedSecret,
-    SupportedAlgorithms,
+    self, AlgorithmName, AlgorithmNegotiation, AlgorithmOrder, EncryptionAlgorithm,
+    HostKeySigningAlgorithm, KexAlgorithm, SharedSecret, SupportedAlgorithms,
 };
 use crate::packet::{
-    KeyExchangeEcDhInitPacket, KeyExchangeInitPacket, Packet, PacketTransport, ProtocolIdentParser,
-    RecvBytesResult,
+    KeyExchangeEcDhInitPacket, KeyExchangeInitPacket, Packet, PacketTracer, PacketTransport,
+    ProtocolIdentParser, RecvBytesResult,
 };
-use crate::{peer_error, Msg, SshRng, SshStatus};
+use crate::{peer_error, InvalidConfig, Msg, SshRng, SshStatus};
 use crate::{Result, SessionId};
 use cluelessh_format::numbers;
 use cluelessh_format::{NameList, Reader, Writer};
 use cluelessh_keys::private::PlaintextPrivateKey;
-use cluelessh_keys::signature::Signature;
-use tracing::{debug, info, trace};
+use cluelessh_keys::signature::{Signature, USERAUTH_SIGNATURE_ALGORITHMS};
+use tracing::{debug, info, trace, warn};
 
 pub struct ServerConnection {
     state: ServerState,
@@ -22,14 +22,285 @@ pub struct ServerConnection {
     rng: Box<dyn SshRng + Send + Sync>,
 
     config: ServerConfig,
+    /// The service that the client requested and we accepted.
+    service: Option<String>,
+    /// Kept around for the exchange hash of key re-exchanges.
+    client_identification: Vec<u8>,
+    rekey: Option<Rekey>,
+    rekey_events: VecDeque<RekeyEvent>,
+    /// The algorithms of the key exchange in progress, logged once the new keys are in use.
+    negotiated_algorithms: Option<NegotiatedAlgorithms>,
+    /// The algorithms of the initial key exchange, which re-exchanges may not downgrade.
+    initial_algorithms: Option<NegotiatedAlgorithms>,
+    /// Whether the client advertised `ext-info-c` in the initial key exchange.
+    client_supports_extensions: bool,
+    /// See [`ServerConnection::ignored_packets`].
+    ignored_packets: u64,
 
     plaintext_packets: VecDeque<Packet>,
 }
 
-#[derive(Debug, Clone, Default)]
+#[derive(Clone)]
 pub struct ServerConfig {
     pub server_identification: Vec<u8>,
     pub host_keys: Vec<cluelessh_keys::public::PublicKey>,
+    /// Pad encrypted packets to a multiple of this many bytes instead of the cipher block size.
+    /// Must be a power of two.
+    pub padding_granularity: Option<usize>,
+    /// Reject packets from the client whose payload is larger than this many bytes after decryption,
+    /// before they are passed on to the upper layers.
+    /// Must be at least [`MIN_MAX_PACKET_PAYLOAD`], as RFC 4253 requires supporting payloads of that size.
+    pub max_packet_payload: Option<usize>,
+    /// Services that may be requested in addition to `ssh-userauth`,
+    /// like `ssh-connection` if the client has been authenticated externally.
+    pub extra_services: Vec<String>,
+    /// Initiate a key re-exchange after this many payload bytes have been sent and received
+    /// with the same keys. RFC 4253 recommends doing this after a gigabyte.
+    pub rekey_after_bytes: Option<u64>,
+    /// Initiate a key re-exchange once this much time has passed since the connection was opened
+    /// or the last re-exchange that we initiated because of it, even if the connection has been quiet.
+    /// This layer does not keep track of time, the driver calls [`ServerConnection::rekey_interval_elapsed`]
+    /// when it elapses, like `cluelessh-tokio` does.
+    pub rekey_interval: Option<Duration>,
+    /// Called with the identification string of the client, without the trailing CRLF.
+    /// If it returns `false`, the client gets an `SSH_MSG_DISCONNECT` and the connection is aborted
+    /// before the key exchange.
+    pub accept_client_identification: Option<Arc<dyn Fn(&[u8]) -> bool + Send + Sync>>,
+    /// Interleave random-length `SSH_MSG_IGNORE` packets with our key exchange packets,
+    /// so that the packet sizes of the handshake are less recognizable.
+    /// Strict key exchange forbids them, so this has to be skipped once we support it.
+    pub handshake_padding: bool,
+    /// Advertise `ext-info-s` and send `SSH_MSG_EXT_INFO` to clients that support extensions.
+    /// Enabled by default, disable it for clients that cannot handle them.
+    pub send_ext_info: bool,
+    /// Whose preference decides between algorithms that both sides support.
+    /// The client decides by default, like in OpenSSH. As we only advertise the chosen algorithms,
+    /// the client agrees with our choice either way, except in key re-exchanges that we initiate,
+    /// where our full lists have already been sent and the client decides.
+    pub algorithm_order: AlgorithmOrder,
+    /// Also offer the `curve448-sha512` key exchange, see [`SupportedAlgorithms::with_curve448`].
+    pub curve448: bool,
+}
+
+impl ServerConfig {
+    /// Checks the settings that [`ServerConnection::new`] cannot work with.
+    pub fn validate(&self) -> Result<(), InvalidConfig> {
+        if let Some(granularity) = self.padding_granularity {
+            crate::packet::check_padding_granularity(granularity)?;
+        }
+        if let Some(len) = self.max_packet_payload {
+            if len < MIN_MAX_PACKET_PAYLOAD {
+                return Err(InvalidConfig(format!(
+                    "max packet payload must be at least {MIN_MAX_PACKET_PAYLOAD}, not {len}"
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn supported_algorithms(&self) -> SupportedAlgorithms {
+        let algs = SupportedAlgorithms::secure(&self.host_keys);
+        if self.curve448 {
+            algs.with_curve448()
+        } else {
+            algs
+        }
+    }
+}
+
+impl Debug for ServerConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ServerConfig")
+            .field("server_identification", &self.server_identification)
+            .field("host_keys", &self.host_keys)
+            .field("padding_granularity", &self.padding_granularity)
+            .field("max_packet_payload", &self.max_packet_payload)
+            .field("extra_services", &self.extra_services)
+            .field("rekey_after_bytes", &self.rekey_after_bytes)
+            .field("rekey_interval", &self.rekey_interval)
+            .field(
+                "accept_client_identification",
+                &self.accept_client_identification.is_some(),
+            )
+            .field("handshake_padding", &self.handshake_padding)
+            .field("send_ext_info", &self.send_ext_info)
+            .field("algorithm_order", &self.algorithm_order)
+            .field("curve448", &self.curve448)
+            .finish()
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            server_identification: Vec::new(),
+            host_keys: Vec::new(),
+            padding_granularity: None,
+            max_packet_payload: None,
+            extra_services: Vec::new(),
+            rekey_after_bytes: None,
+            rekey_interval: None,
+            accept_client_identification: None,
+            handshake_padding: false,
+            send_ext_info: true,
+            algorithm_order: AlgorithmOrder::Client,
+            curve448: false,
+        }
+    }
+}
+
+/// The smallest [`ServerConfig::max_packet_payload`], all implementations must be able to receive payloads of this size.
+/// <https://datatracker.ietf.org/doc/html/rfc4253#section-6.1>
+pub const MIN_MAX_PACKET_PAYLOAD: usize = 32768;
+
+/// Re-exchange keys after this many packets with the same keys, well before the 32 bit sequence numbers,
+/// which are part of the nonce, wrap around.
+/// <https://datatracker.ietf.org/doc/html/rfc4344#section-3.1>
+pub const REKEY_AFTER_PACKETS: u64 = 1 << 31;
+
+/// How far past a rekey limit the client may go while a re-exchange is in progress before we give up on it.
+const REKEY_STALL_FACTOR: u64 = 2;
+
+/// Why a key re-exchange happened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RekeyReason {
+    /// [`ServerConfig::rekey_after_bytes`] has been reached.
+    Volume,
+    /// Requested with [`ServerConnection::rekey`].
+    Manual,
+    /// [`ServerConfig::rekey_interval`] has elapsed.
+    Time,
+    /// So many packets have been sent with the same keys that the sequence numbers would soon wrap around,
+    /// see [`REKEY_AFTER_PACKETS`].
+    Packets,
+    /// The client initiated the re-exchange.
+    Peer,
+}
+
+/// A completed key re-exchange, see [`ServerConnection::next_rekey_event`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RekeyEvent {
+    pub reason: RekeyReason,
+    pub kex_algorithm: &'static str,
+    pub encryption_client_to_server: &'static str,
+    pub encryption_server_to_client: &'static str,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct NegotiatedAlgorithms {
+    kex: &'static str,
+    host_key: &'static str,
+    encryption_client_to_server: &'static str,
+    encryption_server_to_client: &'static str,
+    mac_client_to_server: &'static str,
+    mac_server_to_client: &'static str,
+    compression_client_to_server: &'static str,
+    compression_server_to_client: &'static str,
+}
+
+/// Refuses a key re-exchange that negotiates an algorithm that we prefer less than the one of the initial key exchange,
+/// like a man-in-the-middle downgrading a long-lived connection would.
+/// Our supported algorithms are ordered by preference, with the strongest first.
+///
+/// The preference is only a measure of strength when the client has stopped offering the initial algorithm.
+/// As long as it still offers it, the client's own order may pick another one, for example in a re-exchange that
+/// we initiated, which is negotiated in the client's order even with [`AlgorithmOrder::Server`].
+fn check_rekey_downgrade(
+    sup_algs: &SupportedAlgorithms,
+    initial: &NegotiatedAlgorithms,
+    rekey: &NegotiatedAlgorithms,
+    kex: &KeyExchangeInitPacket<'_>,
+) -> Result<()> {
+    fn check<T: AlgorithmName>(
+        supported: &AlgorithmNegotiation<T>,
+        kind: &str,
+        offered: &NameList<'_>,
+        initial: &str,
+        chosen: &str,
+    ) -> Result<()> {
+        if offered.contains(initial) {
+            return Ok(());
+        }
+        let rank = |name| {
+            supported
+                .supported
+                .iter()
+                .position(|alg| alg.name() == name)
+        };
+        if rank(chosen) > rank(initial) {
+            return Err(peer_error!(
+                "key re-exchange downgraded the {kind} from {initial} to {chosen}"
+            ));
+        }
+        Ok(())
+    }
+
+    check(
+        &sup_algs.key_exchange,
+        "key exchange",
+        &kex.kex_algorithms,
+        initial.kex,
+        rekey.kex,
+    )?;
+    check(
+        &sup_algs.hostkey_sign,
+        "host key algorithm",
+        &kex.server_host_key_algorithms,
+        initial.host_key,
+        rekey.host_key,
+    )?;
+    check(
+        &sup_algs.encryption_from_peer,
+        "encryption client to server",
+        &kex.encryption_algorithms_client_to_server,
+        initial.encryption_client_to_server,
+        rekey.encryption_client_to_server,
+    )?;
+    check(
+        &sup_algs.encryption_to_peer,
+        "encryption server to client",
+        &kex.encryption_algorithms_server_to_client,
+        initial.encryption_server_to_client,
+        rekey.encryption_server_to_client,
+    )?;
+    check(
+        &sup_algs.mac_from_peer,
+        "MAC client to server",
+        &kex.mac_algorithms_client_to_server,
+        initial.mac_client_to_server,
+        rekey.mac_client_to_server,
+    )?;
+    check(
+        &sup_algs.mac_to_peer,
+        "MAC server to client",
+        &kex.mac_algorithms_server_to_client,
+        initial.mac_server_to_client,
+        rekey.mac_server_to_client,
+    )?;
+    check(
+        &sup_algs.compression_from_peer,
+        "compression client to server",
+        &kex.compression_algorithms_client_to_server,
+        initial.compression_client_to_server,
+        rekey.compression_client_to_server,
+    )?;
+    check(
+        &sup_algs.compression_to_peer,
+        "compression server to client",
+        &kex.compression_algorithms_server_to_client,
+        initial.compression_server_to_client,
+        rekey.compression_server_to_client,
+    )
+}
+
+/// State for a key re-exchange in progress.
+struct Rekey {
+    session_id: SessionId,
+    reason: RekeyReason,
+    /// Our SSH_MSG_KEXINIT, if we initiated the re-exchange.
+    server_kexinit: Option<Vec<u8>>,
+    /// Packets that have to wait until the new keys are in use.
+    queued_packets: Vec<Packet>,
 }
 
 enum ServerState {
@@ -60,9 +331,10 @@ enum ServerState {
     },
     NewKeys {
         /// h
-        hash: [u8; 32],
+        hash: Vec<u8>,
         /// k
         shared_secret: SharedSecret,
+        kex_algorithm: crypto::KexAlgorithm,
         encryption_client_to_server: EncryptionAlgorithm,
         encryption_server_to_client: EncryptionAlgorithm,
     },
@@ -93,16 +365,38 @@ pub struct KeyExchangeResponse {
 }
 
 impl ServerConnection {
-    pub fn new(rng: impl SshRng + Send + Sync + 'static, config: ServerConfig) -> Self {
-        Self {
+    pub fn new(rng: impl SshRng + 'static, config: ServerConfig) -> Result<Self, InvalidConfig> {
+        config.validate()?;
+        let mut packet_transport = PacketTransport::new();
+        if let Some(granularity) = config.padding_granularity {
+            packet_transport.set_padding_granularity(granularity);
+        }
+        if let Some(len) = config.max_packet_payload {
+            packet_transport.set_max_payload_len(len);
+        }
+
+        Ok(Self {
             state: ServerState::ProtoExchange {
                 ident_parser: ProtocolIdentParser::new(),
             },
-            packet_transport: PacketTransport::new(),
+            packet_transport,
             rng: Box::new(rng),
             config,
+            service: None,
+            client_identification: Vec::new(),
+            rekey: None,
+            rekey_events: VecDeque::new(),
+            negotiated_algorithms: None,
+            initial_algorithms: None,
+            client_supports_extensions: false,
+            ignored_packets: 0,
             plaintext_packets: VecDeque::new(),
-        }
+        })
+    }
+
+    /// Passes the type and length of every packet that is sent or received to `tracer`.
+    pub fn set_packet_tracer(&mut self, tracer: impl PacketTracer + 'static) {
+        self.packet_transport.set_tracer(Box::new(tracer));
     }
 
     pub fn recv_bytes(&mut self, mut bytes: &[u8]) -> Result<()> {
@@ -112,6 +406,8 @@ impl ServerConnection {
                 break;
             }
         }
+        self.check_stalled_rekey()?;
+        self.check_rekey_limits();
         Ok(())
     }
 
@@ -119,8 +415,28 @@ impl ServerConnection {
         if let ServerState::ProtoExchange { ident_parser } = &mut self.state {
             ident_parser.recv_bytes(bytes);
             if let Some(client_identification) = ident_parser.get_peer_ident() {
+                if let Some(accept) = &self.config.accept_client_identification {
+                    let identification = client_identification.trim_ascii_end();
+                    if !accept(identification) {
+                        let identification = String::from_utf8_lossy(identification);
+                        info!(%identification, "Rejecting client identification");
+                        // Packets may only follow our identification.
+                        self.packet_transport
+                            .queue_send_protocol_info(self.config.server_identification.clone());
+                        self.packet_transport
+                            .queue_packet(Packet::new_msg_disconnect(
+                                numbers::SSH_DISCONNECT_HOST_NOT_ALLOWED_TO_CONNECT,
+                                b"client software is not allowed",
+                                b"",
+                            ));
+                        return Err(peer_error!(
+                            "rejected client identification: {identification}"
+                        ));
+                    }
+                }
                 self.packet_transport
                     .queue_send_protocol_info(self.config.server_identification.clone());
+                self.client_identification = client_identification.clone();
                 self.state = ServerState::KeyExchangeInit {
                     client_identification,
                 };
@@ -154,12 +470,14 @@ impl ServerConnection {
                 }
                 numbers::SSH_MSG_IGNORE => {
                     // <https://datatracker.ietf.org/doc/html/rfc4253#section-11.2>
+                    self.ignored_packets += 1;
                     let mut p = Reader::new(&packet.payload[1..]);
                     let _ = p.string()?;
                     continue;
                 }
                 numbers::SSH_MSG_DEBUG => {
                     // <https://datatracker.ietf.org/doc/html/rfc4253#section-11.3>
+                    self.ignored_packets += 1;
                     let mut p = Reader::new(&packet.payload[1..]);
                     let always_display = p.bool()?;
                     let msg = p.utf8_string()?;
@@ -172,9 +490,28 @@ impl ServerConnection {
                     }
                     continue;
                 }
+                numbers::SSH_MSG_UNIMPLEMENTED => {
+                    // <https://datatracker.ietf.org/doc/html/rfc4253#section-11.4>
+                    self.ignored_packets += 1;
+                    let mut p = Reader::new(&packet.payload[1..]);
+                    let seq = p.u32()?;
+                    warn!(%seq, "Peer does not support a packet we sent (SSH_MSG_UNIMPLEMENTED)");
+                    continue;
+                }
                 _ => {}
             }
 
+            if packet_type == numbers::SSH_MSG_KEXINIT {
+                if let ServerState::Open { session_id } = &self.state {
+                    debug!("Client initiated key re-exchange");
+                    self.begin_rekey(session_id.clone(), RekeyReason::Peer, None);
+                }
+            } else if self.rekey.is_some() && packet_type >= numbers::SSH_MSG_USERAUTH_REQUEST {
+                // The client may have sent these before it received our SSH_MSG_KEXINIT.
+                self.plaintext_packets.push_back(packet);
+                continue;
+            }
+
             match &mut self.state {
                 ServerState::ProtoExchange { .. } => unreachable!("handled above"),
                 ServerState::KeyExchangeInit {
@@ -182,51 +519,98 @@ impl ServerConnection {
                 } => {
                     let kex = KeyExchangeInitPacket::parse(&packet.payload)?;
 
-                    let sup_algs = SupportedAlgorithms::secure(&self.config.host_keys);
+                    let sup_algs = self.config.supported_algorithms();
+                    let order = match &self.rekey {
+                        Some(rekey) if rekey.server_kexinit.is_some() => AlgorithmOrder::Client,
+                        _ => self.config.algorithm_order,
+                    };
 
-                    let kex_algorithm = sup_algs.key_exchange.find(false, kex.kex_algorithms.0)?;
+                    let kex_algorithm =
+                        sup_algs
+                            .key_exchange
+                            .find_in_order(false, order, kex.kex_algorithms.0)?;
                     debug!(name = %kex_algorithm.name(), "Using KEX algorithm");
 
                     // <https://datatracker.ietf.org/doc/html/rfc8308#section-2.1>
-                    // TODO: Send some extensions
                     // TODO: Because of the terrapin attack, we probably want to implement strict kex for that.
-                    let _client_supports_extensions = kex.kex_algorithms.contains("ext-info-c");
+                    if self.rekey.is_none() {
+                        self.client_supports_extensions =
+                            self.config.send_ext_info && kex.kex_algorithms.contains("ext-info-c");
+                    }
 
-                    let server_host_key_algorithm = sup_algs
-                        .hostkey_sign
-                        .find(false, kex.server_host_key_algorithms.0)?;
+                    let server_host_key_algorithm = sup_algs.hostkey_sign.find_in_order(
+                        false,
+                        order,
+                        kex.server_host_key_algorithms.0,
+                    )?;
                     debug!(name = %server_host_key_algorithm.name(), "Using host key algorithm");
 
-                    // TODO: Implement aes128-ctr
-                    let _ = crypto::encrypt::ENC_AES128_CTR;
-
-                    let encryption_client_to_server = sup_algs
-                        .encryption_from_peer
-                        .find(false, kex.encryption_algorithms_client_to_server.0)?;
+                    let encryption_client_to_server = sup_algs.encryption_from_peer.find_in_order(
+                        false,
+                        order,
+                        kex.encryption_algorithms_client_to_server.0,
+                    )?;
                     debug!(name = %encryption_client_to_server.name(), "Using encryption algorithm C->S");
 
-                    let encryption_server_to_client = sup_algs
-                        .encryption_to_peer
-                        .find(false, kex.encryption_algorithms_server_to_client.0)?;
+                    let encryption_server_to_client = sup_algs.encryption_to_peer.find_in_order(
+                        false,
+                        order,
+                        kex.encryption_algorithms_server_to_client.0,
+                    )?;
                     debug!(name = %encryption_server_to_client.name(), "Using encryption algorithm S->C");
 
-                    let mac_algorithm_client_to_server = sup_algs
-                        .mac_from_peer
-                        .find(false, kex.mac_algorithms_client_to_server.0)?;
-                    let mac_algorithm_server_to_client = sup_algs
-                        .mac_to_peer
-                        .find(false, kex.mac_algorithms_server_to_client.0)?;
+                    let mac_algorithm_client_to_server = sup_algs.mac_from_peer.find_in_order(
+                        false,
+                        order,
+                        kex.mac_algorithms_client_to_server.0,
+                    )?;
+                    let mac_algorithm_server_to_client = sup_algs.mac_to_peer.find_in_order(
+                        false,
+                        order,
+                        kex.mac_algorithms_server_to_client.0,
+                    )?;
+                    encryption_client_to_server.check_mac(mac_algorithm_client_to_server)?;
+                    encryption_server_to_client.check_mac(mac_algorithm_server_to_client)?;
 
-                    let compression_algorithm_client_to_server = sup_algs
-                        .compression_from_peer
-                        .find(false, kex.compression_algorithms_client_to_server.0)?;
-                    let compression_algorithm_server_to_client = sup_algs
-                        .compression_to_peer
-                        .find(false, kex.compression_algorithms_server_to_client.0)?;
+                    let compression_algorithm_client_to_server =
+                        sup_algs.compression_from_peer.find_in_order(
+                            false,
+                            order,
+                            kex.compression_algorithms_client_to_server.0,
+                        )?;
+                    let compression_algorithm_server_to_client =
+                        sup_algs.compression_to_peer.find_in_order(
+                            false,
+                            order,
+                            kex.compression_algorithms_server_to_client.0,
+                        )?;
 
                     let _ = kex.languages_client_to_server;
                     let _ = kex.languages_server_to_client;
 
+                    let negotiated = NegotiatedAlgorithms {
+                        kex: kex_algorithm.name(),
+                        host_key: server_host_key_algorithm.name(),
+                        encryption_client_to_server: encryption_client_to_server.name(),
+                        encryption_server_to_client: encryption_server_to_client.name(),
+                        mac_client_to_server: mac_algorithm_client_to_server,
+                        mac_server_to_client: mac_algorithm_server_to_client,
+                        compression_client_to_server: compression_algorithm_client_to_server,
+                        compression_server_to_client: compression_algorithm_server_to_client,
+                    };
+                    match &self.initial_algorithms {
+                        Some(initial) if self.rekey.is_some() => {
+                            check_rekey_downgrade(
+                                &self.config.supported_algorithms(),
+                                initial,
+                                &negotiated,
+                                &kex,
+                            )?;
+                        }
+                        _ => self.initial_algorithms = Some(negotiated),
+                    }
+                    self.negotiated_algorithms = Some(negotiated);
+
                     if kex.first_kex_packet_follows {
                         return Err(peer_error!(
                             "the client wants to send a guessed packet, that's annoying :("
@@ -236,7 +620,12 @@ impl ServerConnection {
                     let mut cookie = [0; 16];
                     self.rng.fill_bytes(&mut cookie);
                     // <https://datatracker.ietf.org/doc/html/rfc8308#section-2.1>
-                    let kex_algorithms = format!("{},ext-info-s", kex_algorithm.name());
+                    // Extensions are only advertised in the first key exchange.
+                    let kex_algorithms = if self.rekey.is_some() || !self.config.send_ext_info {
+                        kex_algorithm.name().to_owned()
+                    } else {
+                        format!("{},ext-info-s", kex_algorithm.name())
+                    };
                     let server_kexinit = KeyExchangeInitPacket {
                         cookie,
                         // TODO: we should send *all* our algorithms here...
@@ -266,10 +655,22 @@ impl ServerConnection {
                     };
 
                     let client_identification = take(client_identification);
-                    let server_kexinit_payload = server_kexinit.to_bytes();
-                    self.packet_transport.queue_packet(Packet {
-                        payload: server_kexinit_payload.clone(),
-                    });
+                    let sent_kexinit = self
+                        .rekey
+                        .as_mut()
+                        .and_then(|rekey| rekey.server_kexinit.take());
+                    let server_kexinit_payload = match sent_kexinit {
+                        Some(sent_kexinit) => sent_kexinit,
+                        None => {
+                            let server_kexinit_payload = server_kexinit.to_bytes();
+                            self.queue_handshake_padding();
+                            self.packet_transport.queue_packet(Packet {
+                                payload: server_kexinit_payload.clone(),
+                            });
+                            server_kexinit_payload
+                        }
+                    };
+                    self.queue_handshake_padding();
                     self.state = ServerState::DhKeyInit {
                         client_identification,
                         client_kexinit: packet.payload,
@@ -292,6 +693,14 @@ impl ServerConnection {
                     let dh = KeyExchangeEcDhInitPacket::parse(&packet.payload)?;
 
                     let client_ephemeral_public_key = dh.qc;
+                    if client_ephemeral_public_key.len() != kex_algorithm.public_key_len {
+                        return Err(peer_error!(
+                            "invalid ephemeral public key length for {}: expected {}, was {}",
+                            kex_algorithm.name(),
+                            kex_algorithm.public_key_len,
+                            client_ephemeral_public_key.len()
+                        ));
+                    }
 
                     self.state = ServerState::WaitingForKeyExchange {
                         client_identification: client_identification.clone(),
@@ -310,6 +719,7 @@ impl ServerConnection {
                 ServerState::NewKeys {
                     hash: h,
                     shared_secret: k,
+                    kex_algorithm,
                     encryption_client_to_server,
                     encryption_server_to_client,
                 } => {
@@ -322,16 +732,62 @@ impl ServerConnection {
                     });
 
                     self.packet_transport.set_key(
-                        *h,
+                        h,
                         k,
+                        kex_algorithm.hash,
                         *encryption_client_to_server,
                         *encryption_server_to_client,
                         true,
                     );
-                    self.state = ServerState::ServiceRequest {
-                        session_id: SessionId(*h),
-                        may_send_extensions: true, // TODO: false if the client didn't advertise them
-                    };
+
+                    if let Some(algs) = self.negotiated_algorithms.take() {
+                        // Also covers key re-exchanges, which are not logged separately.
+                        info!(
+                            rekey_reason = ?self.rekey.as_ref().map(|rekey| rekey.reason),
+                            kex = %algs.kex,
+                            host_key = %algs.host_key,
+                            encryption_client_to_server = %algs.encryption_client_to_server,
+                            encryption_server_to_client = %algs.encryption_server_to_client,
+                            mac_client_to_server = %algs.mac_client_to_server,
+                            mac_server_to_client = %algs.mac_server_to_client,
+                            compression_client_to_server = %algs.compression_client_to_server,
+                            compression_server_to_client = %algs.compression_server_to_client,
+                            "Using new keys"
+                        );
+                    }
+
+                    if let Some(rekey) = self.rekey.take() {
+                        let event = RekeyEvent {
+                            reason: rekey.reason,
+                            kex_algorithm: kex_algorithm.name(),
+                            encryption_client_to_server: encryption_client_to_server.name(),
+                            encryption_server_to_client: encryption_server_to_client.name(),
+                        };
+                        self.rekey_events.push_back(event);
+
+                        self.state = ServerState::Open {
+                            session_id: rekey.session_id,
+                        };
+                        for packet in rekey.queued_packets {
+                            self.packet_transport.queue_packet(packet);
+                        }
+                    } else {
+                        if self.client_supports_extensions {
+                            // <https://datatracker.ietf.org/doc/html/rfc8308#section-3.1>
+                            let mut ext_info = Writer::new();
+                            ext_info.u8(numbers::SSH_MSG_EXT_INFO);
+                            ext_info.u32(1);
+                            ext_info.string("server-sig-algs");
+                            ext_info.string(USERAUTH_SIGNATURE_ALGORITHMS);
+                            self.packet_transport.queue_packet(Packet {
+                                payload: ext_info.finish(),
+                            });
+                        }
+                        self.state = ServerState::ServiceRequest {
+                            session_id: SessionId(take(h)),
+                            may_send_extensions: true, // TODO: false if the client didn't advertise them
+                        };
+                    }
                 }
                 ServerState::ServiceRequest {
                     session_id,
@@ -340,12 +796,17 @@ impl ServerConnection {
                     numbers::SSH_MSG_SERVICE_REQUEST => {
                         let mut p = packet.payload_parser();
                         p.u8()?;
-                        let service = p.utf8_string()?;
+                        let service = p.utf8_string().map_err(|err| {
+                            peer_error!("invalid SSH_MSG_SERVICE_REQUEST: {}", err.0)
+                        })?;
                         debug!(%service, "Client requesting service");
 
-                        if service != "ssh-userauth" {
-                            return Err(peer_error!("only supports ssh-userauth"));
+                        if service != "ssh-userauth"
+                            && !self.config.extra_services.iter().any(|s| s == service)
+                        {
+                            return Err(peer_error!("unsupported service: {service}"));
                         }
+                        self.service = Some(service.to_owned());
 
                         self.packet_transport.queue_packet(Packet {
                             payload: {
@@ -356,7 +817,7 @@ impl ServerConnection {
                             },
                         });
                         self.state = ServerState::Open {
-                            session_id: *session_id,
+                            session_id: session_id.clone(),
                         };
                     }
                     numbers::SSH_MSG_EXT_INFO if *may_send_extensions => {
@@ -374,7 +835,7 @@ impl ServerConnection {
                         }
 
                         self.state = ServerState::ServiceRequest {
-                            session_id: *session_id,
+                            session_id: session_id.clone(),
                             may_send_extensions: false,
                         };
                     }
@@ -385,6 +846,14 @@ impl ServerConnection {
                     }
                 },
                 ServerState::Open { .. } => {
+                    // <https://datatracker.ietf.org/doc/html/rfc4252#section-5.1>
+                    // The service after authentication is implied by the SSH_MSG_USERAUTH_REQUEST,
+                    // requesting another one is not part of the protocol.
+                    if packet_type == numbers::SSH_MSG_SERVICE_REQUEST {
+                        return Err(peer_error!(
+                            "requested a service after a service has already been accepted"
+                        ));
+                    }
                     self.plaintext_packets.push_back(packet);
                 }
             }
@@ -392,13 +861,166 @@ impl ServerConnection {
         Ok(consumed)
     }
 
+    /// Whether the identification strings are still being exchanged, before the key exchange.
+    pub fn is_exchanging_identification(&self) -> bool {
+        matches!(self.state, ServerState::ProtoExchange { .. })
+    }
+
+    /// Returns the session ID once the connection is open. Stays open during key re-exchanges.
     pub fn is_open(&self) -> Option<SessionId> {
-        match self.state {
-            ServerState::Open { session_id } => Some(session_id),
+        if let Some(rekey) = &self.rekey {
+            return Some(rekey.session_id.clone());
+        }
+        match &self.state {
+            ServerState::Open { session_id } => Some(session_id.clone()),
             _ => None,
         }
     }
 
+    /// Initiate a key re-exchange.
+    /// Does nothing if the connection is not open yet or a re-exchange is already in progress.
+    pub fn rekey(&mut self) {
+        self.start_rekey(RekeyReason::Manual);
+    }
+
+    /// Initiate a key re-exchange because [`ServerConfig::rekey_interval`] has elapsed.
+    /// Does nothing if the connection is not open yet or a re-exchange is already in progress.
+    pub fn rekey_interval_elapsed(&mut self) {
+        self.start_rekey(RekeyReason::Time);
+    }
+
+    /// Queues an `SSH_MSG_DISCONNECT` with a reason code from [`numbers`], ending the connection.
+    /// Nothing is sent if the client has not sent its identification yet, as no packets may be sent before that.
+    /// <https://datatracker.ietf.org/doc/html/rfc4253#section-11.1>
+    pub fn disconnect(&mut self, reason: u32, description: &str) {
+        if let ServerState::ProtoExchange { .. } = self.state {
+            debug!("Not sending disconnect before the protocol identification has been exchanged");
+            return;
+        }
+        let reason_string = numbers::disconnect_reason_to_string(reason);
+        debug!(%reason, %reason_string, %description, "Disconnecting client");
+        self.packet_transport
+            .queue_packet(Packet::new_msg_disconnect(
+                reason,
+                description.as_bytes(),
+                b"",
+            ));
+    }
+
+    /// Key re-exchanges that have been completed since the last call.
+    pub fn next_rekey_event(&mut self) -> Option<RekeyEvent> {
+        self.rekey_events.pop_front()
+    }
+
+    /// A client that does not go along with a re-exchange could keep sending packets with the old keys forever,
+    /// so give up once it has sent [`REKEY_STALL_FACTOR`] times the limit that started the re-exchange.
+    fn check_stalled_rekey(&self) -> Result<()> {
+        if self.rekey.is_none() {
+            return Ok(());
+        }
+        let bytes = self.packet_transport.bytes_since_rekey();
+        let packets = self.packet_transport.packets_since_rekey();
+        let too_many_bytes = self
+            .config
+            .rekey_after_bytes
+            .is_some_and(|limit| bytes >= limit.saturating_mul(REKEY_STALL_FACTOR));
+        if too_many_bytes || packets >= REKEY_AFTER_PACKETS * REKEY_STALL_FACTOR {
+            return Err(peer_error!(
+                "client did not complete the key re-exchange after {bytes} bytes in {packets} packets"
+            ));
+        }
+        Ok(())
+    }
+
+    fn check_rekey_limits(&mut self) {
+        if let Some(limit) = self.config.rekey_after_bytes {
+            if self.packet_transport.bytes_since_rekey() >= limit {
+                self.start_rekey(RekeyReason::Volume);
+            }
+        }
+        if self.packet_transport.packets_since_rekey() >= REKEY_AFTER_PACKETS {
+            self.start_rekey(RekeyReason::Packets);
+        }
+    }
+
+    fn start_rekey(&mut self, reason: RekeyReason) {
+        let ServerState::Open { session_id } = &self.state else {
+            return;
+        };
+        let session_id = session_id.clone();
+        debug!(?reason, "Initiating key re-exchange");
+
+        // We don't know what the client supports yet, so offer everything.
+        let sup_algs = self.config.supported_algorithms();
+        let kex_algorithms = sup_algs.key_exchange.to_name_list();
+        let server_host_key_algorithms = sup_algs.hostkey_sign.to_name_list();
+        let encryption_algorithms_client_to_server = sup_algs.encryption_from_peer.to_name_list();
+        let encryption_algorithms_server_to_client = sup_algs.encryption_to_peer.to_name_list();
+        let mac_algorithms_client_to_server = sup_algs.mac_from_peer.to_name_list();
+        let mac_algorithms_server_to_client = sup_algs.mac_to_peer.to_name_list();
+        let compression_algorithms_client_to_server = sup_algs.compression_from_peer.to_name_list();
+        let compression_algorithms_server_to_client = sup_algs.compression_to_peer.to_name_list();
+
+        let mut cookie = [0; 16];
+        self.rng.fill_bytes(&mut cookie);
+        let server_kexinit = KeyExchangeInitPacket {
+            cookie,
+            kex_algorithms: NameList::multi(&kex_algorithms),
+            server_host_key_algorithms: NameList::multi(&server_host_key_algorithms),
+            encryption_algorithms_client_to_server: NameList::multi(
+                &encryption_algorithms_client_to_server,
+            ),
+            encryption_algorithms_server_to_client: NameList::multi(
+                &encryption_algorithms_server_to_client,
+            ),
+            mac_algorithms_client_to_server: NameList::multi(&mac_algorithms_client_to_server),
+            mac_algorithms_server_to_client: NameList::multi(&mac_algorithms_server_to_client),
+            compression_algorithms_client_to_server: NameList::multi(
+                &compression_algorithms_client_to_server,
+            ),
+            compression_algorithms_server_to_client: NameList::multi(
+                &compression_algorithms_server_to_client,
+            ),
+            languages_client_to_server: NameList::none(),
+            languages_server_to_client: NameList::none(),
+            first_kex_packet_follows: false,
+        }
+        .to_bytes();
+
+        self.packet_transport.queue_packet(Packet {
+            payload: server_kexinit.clone(),
+        });
+        self.begin_rekey(session_id, reason, Some(server_kexinit));
+    }
+
+    fn begin_rekey(
+        &mut self,
+        session_id: SessionId,
+        reason: RekeyReason,
+        server_kexinit: Option<Vec<u8>>,
+    ) {
+        self.rekey = Some(Rekey {
+            session_id,
+            reason,
+            server_kexinit,
+            queued_packets: Vec::new(),
+        });
+        self.state = ServerState::KeyExchangeInit {
+            client_identification: self.client_identification.clone(),
+        };
+    }
+
+    /// The number of `SSH_MSG_IGNORE`, `SSH_MSG_DEBUG` and `SSH_MSG_UNIMPLEMENTED` packets from the client,
+    /// which are handled here instead of being passed on to the upper layers.
+    pub fn ignored_packets(&self) -> u64 {
+        self.ignored_packets
+    }
+
+    /// The service that the client requested, once the connection is open.
+    pub fn service(&self) -> Option<&str> {
+        self.service.as_deref()
+    }
+
     pub fn is_waiting_on_key_exchange(&self) -> Option<KeyExchangeParameters> {
         match &self.state {
             ServerState::WaitingForKeyExchange {
@@ -423,8 +1045,10 @@ impl ServerConnection {
     }
 
     pub fn do_key_exchange(&mut self, response: KeyExchangeResponse) {
+        self.queue_handshake_padding();
         match &self.state {
             ServerState::WaitingForKeyExchange {
+                kex_algorithm,
                 encryption_client_to_server,
                 encryption_server_to_client,
                 server_host_key_algorithm,
@@ -438,8 +1062,9 @@ impl ServerConnection {
 
                 self.packet_transport.queue_packet(packet);
                 self.state = ServerState::NewKeys {
-                    hash: response.hash.0,
+                    hash: response.hash.0.clone(),
                     shared_secret: response.shared_secret.clone(),
+                    kex_algorithm: *kex_algorithm,
                     encryption_client_to_server: *encryption_client_to_server,
                     encryption_server_to_client: *encryption_server_to_client,
                 };
@@ -448,6 +1073,18 @@ impl ServerConnection {
         }
     }
 
+    fn queue_handshake_padding(&mut self) {
+        if !self.config.handshake_padding {
+            return;
+        }
+        let mut len = [0; 1];
+        self.rng.fill_bytes(&mut len);
+        let mut data = vec![0; len[0] as usize];
+        self.rng.fill_bytes(&mut data);
+        self.packet_transport
+            .queue_packet(Packet::new_msg_ignore(&data));
+    }
+
     pub fn next_msg_to_send(&mut self) -> Option<Msg> {
         self.packet_transport.next_msg_to_send()
     }
@@ -457,7 +1094,12 @@ impl ServerConnection {
     }
 
     pub fn send_plaintext_packet(&mut self, packet: Packet) {
+        if let Some(rekey) = &mut self.rekey {
+            rekey.queued_packets.push(packet);
+            return;
+        }
         self.packet_transport.queue_packet(packet);
+        self.check_rekey_limits();
     }
 }
 
@@ -472,6 +1114,7 @@ pub fn do_key_exchange(
     let pub_hostkey = msg.server_host_key_algorithm.public_key();
 
     let hash = crypto::key_exchange_hash(
+        msg.kex_algorithm.hash,
         &msg.client_ident,
         &msg.server_ident,
         &msg.client_kexinit,
@@ -482,22 +1125,37 @@ pub fn do_key_exchange(
         &shared_secret,
     );
 
+    let signature = private
+        .private_key
+        .sign_for_algorithm(msg.server_host_key_algorithm.name(), &hash);
     Ok(KeyExchangeResponse {
         hash: SessionId(hash),
         server_ephemeral_public_key,
         shared_secret,
-        signature: private.private_key.sign(&hash),
+        signature,
     })
 }
 
 #[cfg(test)]
 mod tests {
-    use hex_literal::hex;
+    use std::sync::{Arc, Mutex};
+
+    use cluelessh_format::{numbers, NameList, Writer};
+    use cluelessh_keys::private::{PlaintextPrivateKey, PrivateKey};
+    use cluelessh_keys::{KeyGenerationParams, KeyType};
 
     use crate::{
-        packet::MsgKind,
-        server::{ServerConfig, ServerConnection},
-        SshRng,
+        client::ClientConnection,
+        crypto::{
+            self, kex_algorithm_by_name, AlgorithmName, AlgorithmOrder, EncodedSshSignature,
+            HostKeySigningAlgorithm, SupportedAlgorithms,
+        },
+        packet::{KeyExchangeInitPacket, MsgKind, Packet, PacketDirection, PacketTracer},
+        server::{
+            do_key_exchange, KeyExchangeParameters, RekeyReason, ServerConfig, ServerConnection,
+            ServerState, MIN_MAX_PACKET_PAYLOAD, REKEY_AFTER_PACKETS,
+        },
+        SessionId, SshRng, SshStatus,
     };
 
     struct NoRng;
@@ -507,17 +1165,9 @@ mod tests {
         }
     }
 
-    struct HardcodedRng(Vec<u8>);
-    impl SshRng for HardcodedRng {
-        fn fill_bytes(&mut self, dest: &mut [u8]) {
-            dest.copy_from_slice(&self.0[..dest.len()]);
-            self.0.splice(0..dest.len(), []);
-        }
-    }
-
     #[test]
     fn protocol_exchange() {
-        let mut con = ServerConnection::new(NoRng, ServerConfig::default());
+        let mut con = ServerConnection::new(NoRng, ServerConfig::default()).unwrap();
         con.recv_bytes(b"SSH-2.0-OpenSSH_9.7\r\n").unwrap();
         let msg = con.next_msg_to_send().unwrap();
         assert!(matches!(msg.0, MsgKind::ServerProtocolInfo(_)));
@@ -525,7 +1175,7 @@ mod tests {
 
     #[test]
     fn protocol_exchange_slow_client() {
-        let mut con = ServerConnection::new(NoRng, ServerConfig::default());
+        let mut con = ServerConnection::new(NoRng, ServerConfig::default()).unwrap();
         con.recv_bytes(b"SSH-2.0-").unwrap();
         con.recv_bytes(b"OpenSSH_9.7\r\n").unwrap();
         let msg = con.next_msg_to_send().unwrap();
@@ -533,69 +1183,1219 @@ mod tests {
     }
 
     #[test]
-    #[ignore = "this is super annoying, use expect-test please"]
-    fn handshake() {
-        #[rustfmt::skip]
-        let rng = vec![
-            0x14, 0xa2, 0x04, 0xa5, 0x4b, 0x2f, 0x5f, 0xa7, 0xff, 0x53, 0x13, 0x67, 0x57, 0x67, 0xbc,
-            0x55, 0x3f, 0xc0, 0x6c, 0x0d, 0x07, 0x8f, 0xe2, 0x75, 0x95, 0x18, 0x4b, 0xd2, 0xcb, 0xd0,
-            0x64, 0x06, 0x14, 0xa2, 0x04, 0xa5, 0x4b, 0x2f, 0x5f, 0xa7, 0xff, 0x53, 0x13, 0x67, 0x57,
-            0x67, 0xbc, 0x55, 0x3f, 0xc0, 0x6c, 0x0d, 0x07, 0x8f, 0xe2, 0x75, 0x95, 0x18, 0x4b, 0xd2,
-            0xcb, 0xd0, 0x64, 0x06, 0x67, 0xbc, 0x55, 0x3f, 0xc0, 0x6c, 0x0d, 0x07, 0x8f, 0xe2, 0x75,
-            0x95, 0x18, 0x4b, 0xd2, 0xcb, 0xd0, 0x64, 0x06,
-        ];
-        struct Part {
-            client: &'static [u8],
-            server: &'static [u8],
-        }
-
-        // Extracted from a real OpenSSH client using this server (with hardcoded creds) using Wireshark.
-        let conversation = [
-            Part {
-                client: &hex!("5353482d322e302d4f70656e5353485f392e370d0a"),
-                server: &hex!("5353482d322e302d4f70656e5353485f392e370d0a"),
-            },
-            // KEX Init
-            Part {
-                client: &hex!(
-                    "000005fc0714fd3d911937c7294823f93c5ba691f77e00000131736e747275703736317832353531392d736861353132406f70656e7373682e636f6d2c637572766532353531392d7368613235362c637572766532353531392d736861323536406c69627373682e6f72672c656364682d736861322d6e697374703235362c656364682d736861322d6e697374703338342c656364682d736861322d6e697374703532312c6469666669652d68656c6c6d616e2d67726f75702d65786368616e67652d7368613235362c6469666669652d68656c6c6d616e2d67726f757031362d7368613531322c6469666669652d68656c6c6d616e2d67726f757031382d7368613531322c6469666669652d68656c6c6d616e2d67726f757031342d7368613235362c6578742d696e666f2d632c6b65782d7374726963742d632d763030406f70656e7373682e636f6d000001cf7373682d656432353531392d636572742d763031406f70656e7373682e636f6d2c65636473612d736861322d6e697374703235362d636572742d763031406f70656e7373682e636f6d2c65636473612d736861322d6e697374703338342d636572742d763031406f70656e7373682e636f6d2c65636473612d736861322d6e697374703532312d636572742d763031406f70656e7373682e636f6d2c736b2d7373682d656432353531392d636572742d763031406f70656e7373682e636f6d2c736b2d65636473612d736861322d6e697374703235362d636572742d763031406f70656e7373682e636f6d2c7273612d736861322d3531322d636572742d763031406f70656e7373682e636f6d2c7273612d736861322d3235362d636572742d763031406f70656e7373682e636f6d2c7373682d656432353531392c65636473612d736861322d6e697374703235362c65636473612d736861322d6e697374703338342c65636473612d736861322d6e697374703532312c736b2d7373682d65643235353139406f70656e7373682e636f6d2c736b2d65636473612d736861322d6e69737470323536406f70656e7373682e636f6d2c7273612d736861322d3531322c7273612d736861322d3235360000006c63686163686132302d706f6c7931333035406f70656e7373682e636f6d2c6165733132382d6374722c6165733139322d6374722c6165733235362d6374722c6165733132382d67636d406f70656e7373682e636f6d2c6165733235362d67636d406f70656e7373682e636f6d0000006c63686163686132302d706f6c7931333035406f70656e7373682e636f6d2c6165733132382d6374722c6165733139322d6374722c6165733235362d6374722c6165733132382d67636d406f70656e7373682e636f6d2c6165733235362d67636d406f70656e7373682e636f6d000000d5756d61632d36342d65746d406f70656e7373682e636f6d2c756d61632d3132382d65746d406f70656e7373682e636f6d2c686d61632d736861322d3235362d65746d406f70656e7373682e636f6d2c686d61632d736861322d3531322d65746d406f70656e7373682e636f6d2c686d61632d736861312d65746d406f70656e7373682e636f6d2c756d61632d3634406f70656e7373682e636f6d2c756d61632d313238406f70656e7373682e636f6d2c686d61632d736861322d3235362c686d61632d736861322d3531322c686d61632d73686131000000d5756d61632d36342d65746d406f70656e7373682e636f6d2c756d61632d3132382d65746d406f70656e7373682e636f6d2c686d61632d736861322d3235362d65746d406f70656e7373682e636f6d2c686d61632d736861322d3531322d65746d406f70656e7373682e636f6d2c686d61632d736861312d65746d406f70656e7373682e636f6d2c756d61632d3634406f70656e7373682e636f6d2c756d61632d313238406f70656e7373682e636f6d2c686d61632d736861322d3235362c686d61632d736861322d3531322c686d61632d736861310000001a6e6f6e652c7a6c6962406f70656e7373682e636f6d2c7a6c69620000001a6e6f6e652c7a6c6962406f70656e7373682e636f6d2c7a6c69620000000000000000000000000000000000000000"
-                ),
-                server: &hex!(
-                    "000000bc051414a204a54b2f5fa7ff5313675767bc5500000011637572766532353531392d7368613235360000000b7373682d656432353531390000001d63686163686132302d706f6c7931333035406f70656e7373682e636f6d0000001d63686163686132302d706f6c7931333035406f70656e7373682e636f6d0000000d686d61632d736861322d3235360000000d686d61632d736861322d323536000000046e6f6e65000000046e6f6e65000000000000000000000000000000000000"
-                ),
-            },
-            // ECDH KEX Init
-            Part {
-                client: &hex!(
-                    "0000002c061e000000204c646d1281abf23264d63db96e05c0223cfead668d9d38c62579b8856e67ae19000000000000"
-                ),
-                server: &hex!(
-                    "000000bc081f000000330000000b7373682d6564323535313900000020e939cdfa6fc0d737333b534e913dd332c8d5179fe00c3045575217224b19b8f6000000204260e2c5e5383f1a021c9631fa61f60f305b29183fd219d4c8207c664e063410000000530000000b7373682d65643235353139000000406504a045499f26aa4ee17606ea6bd9e3f288838591f25d8604a63f77a52f5b9e909c00d10f386553e585d86ab329bbde0fca5c64b1b1982d7adcac17cf7f06010000000000000000"
-                ),
-            },
-            // New Keys
-            Part {
-                client: &hex!("0000000c0a1500000000000000000000"),
-                server: &hex!("0000000c0a1500000000000000000000"),
-            },
-            // Service Request (encrypted)
-            Part {
-                client: &hex!("09ca4db7baeb24836a1f7d22368055bf4c26981ed86738ac7a5c31d0730ad656f1967853781dff91ee1c4de8"),
-                server: &hex!("7b444c0d5faf740d350701a054ea469fab1c98e4b669e4872a454163edb42ec5e4fa95c404ab601f016bd259"),
+    fn invalid_max_packet_payload() {
+        let config = ServerConfig {
+            max_packet_payload: Some(MIN_MAX_PACKET_PAYLOAD - 1),
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+        assert!(ServerConnection::new(NoRng, config).is_err());
+
+        let config = ServerConfig {
+            max_packet_payload: Some(MIN_MAX_PACKET_PAYLOAD),
+            ..Default::default()
+        };
+        assert!(ServerConnection::new(NoRng, config).is_ok());
+    }
+
+    #[test]
+    fn invalid_padding_granularity() {
+        let config = ServerConfig {
+            padding_granularity: Some(48),
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+        assert!(ServerConnection::new(NoRng, config).is_err());
+
+        let config = ServerConfig {
+            padding_granularity: Some(64),
+            ..Default::default()
+        };
+        assert!(ServerConnection::new(NoRng, config).is_ok());
+    }
+
+    /// Runs `f` and returns everything that it logged.
+    fn capture_logs(f: impl FnOnce()) -> String {
+        #[derive(Clone, Default)]
+        struct Logs(Arc<Mutex<Vec<u8>>>);
+        impl std::io::Write for Logs {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let logs = Logs::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer({
+                let logs = logs.clone();
+                move || logs.clone()
+            })
+            .with_ansi(false)
+            .finish();
+        tracing::subscriber::with_default(subscriber, f);
+
+        let logs = logs.0.lock().unwrap().clone();
+        String::from_utf8(logs).unwrap()
+    }
+
+    #[test]
+    fn unimplemented_is_not_fatal() {
+        let mut con = ServerConnection::new(NoRng, ServerConfig::default()).unwrap();
+        con.recv_bytes(b"SSH-2.0-OpenSSH_9.7\r\n").unwrap();
+
+        let mut unimplemented = Writer::new();
+        unimplemented.u8(numbers::SSH_MSG_UNIMPLEMENTED);
+        unimplemented.u32(1234);
+        let packet = Packet {
+            payload: unimplemented.finish(),
+        };
+        let logs = capture_logs(|| {
+            con.recv_bytes(&packet.to_bytes(true, Packet::DEFAULT_BLOCK_SIZE, 0))
+                .unwrap();
+        });
+        assert!(logs.contains("seq=1234"), "{logs}");
+    }
+
+    /// A deterministic RNG (splitmix64), so that conversations can be replayed byte for byte.
+    struct SeededRng(u64);
+    impl SshRng for SeededRng {
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            for chunk in dest.chunks_mut(8) {
+                self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+                let mut z = self.0;
+                z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+                z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+                z ^= z >> 31;
+                chunk.copy_from_slice(&z.to_be_bytes()[..chunk.len()]);
+            }
+        }
+    }
+
+    /// Our client talking to our server with seeded RNGs, recording every message on the wire.
+    struct Conversation {
+        client: ClientConnection,
+        server: ServerConnection,
+        host_key: PlaintextPrivateKey,
+        kex_rng: SeededRng,
+        transcript: String,
+        /// Deliver everything a side has queued in a single buffer, like a peer writing several packets
+        /// into one TCP segment.
+        coalesce: bool,
+        /// Reject the host key instead of accepting it.
+        reject_host_key: bool,
+    }
+
+    impl Conversation {
+        fn new(
+            seed: u64,
+            configure: impl FnOnce(&mut ClientConnection, &mut ServerConfig),
+        ) -> Self {
+            let host_key = PlaintextPrivateKey::new(
+                String::new(),
+                PrivateKey::Ed25519 {
+                    public_key: ed25519_dalek::SigningKey::from_bytes(&[seed as u8; 32])
+                        .verifying_key(),
+                    private_key: ed25519_dalek::SigningKey::from_bytes(&[seed as u8; 32]),
+                },
+            );
+
+            let mut client = ClientConnection::new(SeededRng(seed));
+            let mut config = ServerConfig {
+                server_identification: b"SSH-2.0-ClueleSSH_0.1\r\n".to_vec(),
+                host_keys: vec![host_key.private_key.public_key()],
+                ..Default::default()
+            };
+            configure(&mut client, &mut config);
+            let server = ServerConnection::new(SeededRng(seed.wrapping_add(1)), config).unwrap();
+
+            Self {
+                client,
+                server,
+                host_key,
+                kex_rng: SeededRng(seed.wrapping_add(2)),
+                transcript: String::new(),
+                coalesce: false,
+                reject_host_key: false,
+            }
+        }
+
+        /// Exchanges messages until neither side makes progress. Errors from the server are returned.
+        fn pump(&mut self) -> Result<(), SshStatus> {
+            loop {
+                let mut progressed = false;
+                let mut coalesced = Vec::new();
+                while let Some(msg) = self.client.next_msg_to_send() {
+                    let bytes = msg.to_bytes();
+                    self.record("client", &bytes);
+                    if self.coalesce {
+                        coalesced.extend_from_slice(&bytes);
+                    } else {
+                        self.server.recv_bytes(&bytes)?;
+                    }
+                    progressed = true;
+                }
+                if !coalesced.is_empty() {
+                    self.server.recv_bytes(&coalesced)?;
+                }
+                if let Some(params) = self.server.is_waiting_on_key_exchange() {
+                    let response =
+                        do_key_exchange(params, &self.host_key, &mut self.kex_rng).unwrap();
+                    self.server.do_key_exchange(response);
+                    progressed = true;
+                }
+                let mut coalesced = Vec::new();
+                while let Some(msg) = self.server.next_msg_to_send() {
+                    let bytes = msg.to_bytes();
+                    self.record("server", &bytes);
+                    if self.coalesce {
+                        coalesced.extend_from_slice(&bytes);
+                    } else {
+                        self.client.recv_bytes(&bytes).unwrap();
+                    }
+                    progressed = true;
+                }
+                if !coalesced.is_empty() {
+                    self.client.recv_bytes(&coalesced).unwrap();
+                }
+                if self.client.is_waiting_on_host_key_verification().is_some() {
+                    self.client
+                        .host_key_verification_result(!self.reject_host_key);
+                    progressed = true;
+                }
+                if !progressed {
+                    return Ok(());
+                }
+            }
+        }
+
+        /// Passes bytes from a client that is not driven by this conversation, like a real OpenSSH client,
+        /// to the server. Returns what the server sends in response.
+        fn recv_from_external_client(&mut self, bytes: &[u8]) -> Vec<u8> {
+            self.record("client", bytes);
+            self.server.recv_bytes(bytes).unwrap();
+            if let Some(params) = self.server.is_waiting_on_key_exchange() {
+                let response = do_key_exchange(params, &self.host_key, &mut self.kex_rng).unwrap();
+                self.server.do_key_exchange(response);
+            }
+            let mut response = Vec::new();
+            while let Some(msg) = self.server.next_msg_to_send() {
+                let bytes = msg.to_bytes();
+                self.record("server", &bytes);
+                response.extend_from_slice(&bytes);
+            }
+            response
+        }
+
+        fn record(&mut self, from: &str, bytes: &[u8]) {
+            self.transcript.push_str(&format!(
+                "{from} ({} bytes): {}\n",
+                bytes.len(),
+                hex::encode(bytes)
+            ));
+        }
+    }
+
+    fn drive_conversation(
+        seed: u64,
+        configure: impl FnOnce(&mut ClientConnection, &mut ServerConfig),
+    ) -> Result<Conversation, SshStatus> {
+        let mut conversation = Conversation::new(seed, configure);
+        conversation.pump()?;
+        Ok(conversation)
+    }
+
+    /// Drives a conversation until both sides are open.
+    /// The transcript is compared against a snapshot, run with `UPDATE_EXPECT=1` to update it.
+    fn replay_conversation(seed: u64) -> String {
+        let Conversation {
+            client,
+            server,
+            transcript,
+            ..
+        } = drive_conversation(seed, |_, _| {}).unwrap();
+
+        let client_session = client.is_open().expect("client did not open").0;
+        let server_session = server.is_open().expect("server did not open").0;
+        assert_eq!(client_session, server_session);
+        assert_eq!(server.service(), Some("ssh-userauth"));
+
+        transcript
+    }
+
+    #[test]
+    fn handshake_curve25519_chacha20() {
+        let transcript = replay_conversation(0);
+        expect_test::expect_file!["snapshots/handshake_curve25519_chacha20.txt"]
+            .assert_eq(&transcript);
+    }
+
+    /// Replays the messages of an OpenSSH 9.2p1 client, recorded with [`record_openssh_handshake`],
+    /// up to its first `SSH_MSG_USERAUTH_REQUEST`. The client's messages are encrypted with keys that depend
+    /// on our responses, so when they change, the handshake has to be recorded again.
+    #[test]
+    fn handshake_openssh() {
+        let mut conversation = Conversation::new(0, |_, _| {});
+        for line in include_str!("snapshots/handshake_openssh.txt").lines() {
+            if let Some(bytes) = line.strip_prefix("client (") {
+                let (_, bytes) = bytes.split_once("): ").unwrap();
+                conversation.recv_from_external_client(&hex::decode(bytes).unwrap());
+            }
+        }
+
+        assert_eq!(conversation.server.service(), Some("ssh-userauth"));
+        let packet = conversation.server.next_plaintext_packet().unwrap();
+        assert_eq!(packet.packet_type(), numbers::SSH_MSG_USERAUTH_REQUEST);
+        expect_test::expect_file!["snapshots/handshake_openssh.txt"]
+            .assert_eq(&conversation.transcript);
+    }
+
+    /// Records a handshake for [`handshake_openssh`]. Run it with `--ignored --nocapture` and connect with
+    /// `ssh -p <port> -o StrictHostKeyChecking=no -o UserKnownHostsFile=/dev/null test@127.0.0.1`.
+    #[test]
+    #[ignore = "needs an OpenSSH client to connect"]
+    fn record_openssh_handshake() {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        eprintln!(
+            "listening on port {}",
+            listener.local_addr().unwrap().port()
+        );
+        let (mut stream, _) = listener.accept().unwrap();
+
+        let mut conversation = Conversation::new(0, |_, _| {});
+        let mut buf = [0; 65536];
+        while conversation.server.next_plaintext_packet().is_none() {
+            let read = stream.read(&mut buf).unwrap();
+            assert_ne!(read, 0, "client closed the connection");
+            let response = conversation.recv_from_external_client(&buf[..read]);
+            stream.write_all(&response).unwrap();
+        }
+
+        let path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/src/snapshots/handshake_openssh.txt"
+        );
+        std::fs::write(path, &conversation.transcript).unwrap();
+    }
+
+    /// Clients offer the canonical names of the host key algorithms, which our names must match exactly.
+    #[test]
+    fn host_key_algorithm_names() {
+        let keys = [
+            (KeyType::Ed25519, "ssh-ed25519"),
+            (KeyType::Ecdsa, "ecdsa-sha2-nistp256"),
+        ]
+        .map(|(key_type, name)| {
+            let key =
+                PlaintextPrivateKey::generate(String::new(), KeyGenerationParams { key_type });
+            (key.private_key.public_key(), name)
+        });
+        let host_keys = keys.iter().map(|(key, _)| key.clone()).collect::<Vec<_>>();
+
+        for (public_key, name) in &keys {
+            assert_eq!(public_key.algorithm_name(), *name);
+
+            // Part of the list that OpenSSH offers by default, with only this key type being supported by us.
+            let client_offer = format!("ssh-ed25519-cert-v01@openssh.com,{name},rsa-sha2-512");
+            let chosen = SupportedAlgorithms::secure(&host_keys)
+                .hostkey_sign
+                .find(false, &client_offer)
+                .unwrap();
+            assert_eq!(chosen.name(), *name);
+            assert!(chosen.public_key() == *public_key);
+
+            // And our client picks the same name from what the server offers.
+            let verify = SupportedAlgorithms::secure(&[])
+                .hostkey_verify
+                .find(true, chosen.name())
+                .unwrap();
+            assert_eq!(verify.name(), *name);
+        }
+    }
+
+    /// The signature over the exchange hash must verify with the algorithm that the client uses for the host key.
+    #[test]
+    fn host_key_signature_roundtrip() {
+        let generate = |key_type| {
+            PlaintextPrivateKey::generate(String::new(), KeyGenerationParams { key_type })
+        };
+        let rsa = PlaintextPrivateKey::from_pkcs8_pem(include_bytes!(
+            "../../cluelessh-keys/testdata/rsa_pkcs8.pem"
+        ))
+        .unwrap();
+        for (host_key, algorithm) in [
+            (generate(KeyType::Ed25519), "ssh-ed25519"),
+            (generate(KeyType::Ecdsa), "ecdsa-sha2-nistp256"),
+            (rsa.clone(), "rsa-sha2-256"),
+            (rsa, "rsa-sha2-512"),
+        ] {
+            let public_key = host_key.private_key.public_key();
+            let kex_algorithm = kex_algorithm_by_name("curve25519-sha256").unwrap();
+            let client_secret = (kex_algorithm.generate_secret)(&mut SeededRng(0));
+
+            let params = KeyExchangeParameters {
+                client_ident: b"SSH-2.0-OpenSSH_9.7\r\n".to_vec(),
+                server_ident: b"SSH-2.0-ClueleSSH_0.1\r\n".to_vec(),
+                client_kexinit: vec![numbers::SSH_MSG_KEXINIT; 20],
+                server_kexinit: vec![numbers::SSH_MSG_KEXINIT; 30],
+                eph_client_public_key: client_secret.pubkey.clone(),
+                server_host_key_algorithm: HostKeySigningAlgorithm::with_algorithm(
+                    public_key.clone(),
+                    algorithm,
+                )
+                .unwrap(),
+                kex_algorithm,
+            };
+            let response = do_key_exchange(params, &host_key, &mut SeededRng(1)).unwrap();
+            assert_eq!(response.signature.algorithm_name(), algorithm);
+
+            // Do what the client does with the reply.
+            let shared_secret =
+                (client_secret.exchange)(&response.server_ephemeral_public_key).unwrap();
+            let hash = crypto::key_exchange_hash(
+                kex_algorithm.hash,
+                b"SSH-2.0-OpenSSH_9.7\r\n",
+                b"SSH-2.0-ClueleSSH_0.1\r\n",
+                &[numbers::SSH_MSG_KEXINIT; 20],
+                &[numbers::SSH_MSG_KEXINIT; 30],
+                &public_key.to_wire_encoding(),
+                &client_secret.pubkey,
+                &response.server_ephemeral_public_key,
+                &shared_secret,
+            );
+            assert_eq!(hash, response.hash.0);
+
+            let verify = SupportedAlgorithms::secure(&[])
+                .hostkey_verify
+                .find(true, algorithm)
+                .unwrap();
+            assert_eq!(verify.name(), algorithm);
+            let signature = EncodedSshSignature(response.signature.to_wire_encoding());
+            (verify.verify)(&public_key.to_wire_encoding(), &hash, &signature).unwrap();
+
+            let mut tampered_hash = hash.clone();
+            tampered_hash[0] ^= 1;
+            assert!(
+                (verify.verify)(&public_key.to_wire_encoding(), &tampered_hash, &signature)
+                    .is_err(),
+                "{algorithm}"
+            );
+        }
+    }
+
+    /// RSA host keys are offered with the SHA-2 signature algorithms, never with the SHA-1 based `ssh-rsa`.
+    #[test]
+    fn rsa_host_key_algorithms() {
+        let host_key = PlaintextPrivateKey::from_pkcs8_pem(include_bytes!(
+            "../../cluelessh-keys/testdata/rsa_pkcs8.pem"
+        ))
+        .unwrap();
+        let algs = SupportedAlgorithms::secure(&[host_key.private_key.public_key()]);
+        assert_eq!(
+            algs.hostkey_sign.to_name_list(),
+            "rsa-sha2-256,rsa-sha2-512"
+        );
+
+        let chosen = algs
+            .hostkey_sign
+            .find(false, "rsa-sha2-512,rsa-sha2-256,ssh-rsa")
+            .unwrap();
+        assert_eq!(chosen.name(), "rsa-sha2-512");
+        let algs = SupportedAlgorithms::secure(&[host_key.private_key.public_key()]);
+        assert!(algs.hostkey_sign.find(false, "ssh-rsa").is_err());
+    }
+
+    #[derive(Clone, Default)]
+    struct RecordingTracer(Arc<Mutex<Vec<(PacketDirection, u8, usize, u32)>>>);
+    impl PacketTracer for RecordingTracer {
+        fn trace_packet(
+            &mut self,
+            direction: PacketDirection,
+            packet_type: u8,
+            payload_len: usize,
+            seq_nr: u32,
+        ) {
+            self.0
+                .lock()
+                .unwrap()
+                .push((direction, packet_type, payload_len, seq_nr));
+        }
+    }
+
+    #[test]
+    fn packet_tracer() {
+        let client_tracer = RecordingTracer::default();
+        let server_tracer = RecordingTracer::default();
+        let mut conversation = Conversation::new(0, |client, _| {
+            client.set_packet_tracer(client_tracer.clone());
+        });
+        conversation.server.set_packet_tracer(server_tracer.clone());
+        conversation.pump().unwrap();
+
+        let client = client_tracer.0.lock().unwrap().clone();
+        let server = server_tracer.0.lock().unwrap().clone();
+        let types = |packets: &[(PacketDirection, u8, usize, u32)]| {
+            packets
+                .iter()
+                .map(|&(direction, packet_type, _, seq_nr)| (direction, packet_type, seq_nr))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            types(&client[..4]),
+            [
+                (PacketDirection::Send, numbers::SSH_MSG_KEXINIT, 0),
+                (PacketDirection::Recv, numbers::SSH_MSG_KEXINIT, 0),
+                (PacketDirection::Send, numbers::SSH_MSG_KEX_ECDH_INIT, 1),
+                (PacketDirection::Recv, numbers::SSH_MSG_KEX_ECDH_REPLY, 1),
+            ]
+        );
+        assert_eq!(
+            types(&server[..4]),
+            [
+                (PacketDirection::Recv, numbers::SSH_MSG_KEXINIT, 0),
+                (PacketDirection::Send, numbers::SSH_MSG_KEXINIT, 0),
+                (PacketDirection::Recv, numbers::SSH_MSG_KEX_ECDH_INIT, 1),
+                (PacketDirection::Send, numbers::SSH_MSG_KEX_ECDH_REPLY, 1),
+            ]
+        );
+        // Both sides agree on what has been sent.
+        let flip = |packets: &[(PacketDirection, u8, usize, u32)], direction| {
+            packets
+                .iter()
+                .filter(|packet| packet.0 == direction)
+                .map(|&(_, packet_type, len, seq_nr)| (packet_type, len, seq_nr))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            flip(&client, PacketDirection::Send),
+            flip(&server, PacketDirection::Recv)
+        );
+        assert_eq!(
+            flip(&client, PacketDirection::Recv),
+            flip(&server, PacketDirection::Send)
+        );
+    }
+
+    /// Sends a hand-written client SSH_MSG_KEXINIT and returns the types of the packets the server responds with.
+    fn kexinit_response_types(kex_algorithms: &str) -> Vec<u8> {
+        let mut conversation = Conversation::new(0, |_, config| config.handshake_padding = true);
+        let packet = client_kexinit(&conversation, kex_algorithms);
+        let server = &mut conversation.server;
+        server.recv_bytes(b"SSH-2.0-OpenSSH_9.7\r\n").unwrap();
+        server
+            .recv_bytes(&packet.to_bytes(true, Packet::DEFAULT_BLOCK_SIZE, 0))
+            .unwrap();
+
+        std::iter::from_fn(|| server.next_msg_to_send())
+            .filter_map(|msg| match msg.0 {
+                MsgKind::PlaintextPacket(packet) => Some(packet.packet_type()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn algorithm_order() {
+        let negotiated_kex = |algorithm_order| {
+            let mut conversation = Conversation::new(0, |_, config| {
+                config.algorithm_order = algorithm_order;
+                config.curve448 = true;
+            });
+            let packet = client_kexinit(&conversation, "curve448-sha512,curve25519-sha256");
+            let server = &mut conversation.server;
+            server.recv_bytes(b"SSH-2.0-OpenSSH_9.7\r\n").unwrap();
+            server
+                .recv_bytes(&packet.to_bytes(true, Packet::DEFAULT_BLOCK_SIZE, 0))
+                .unwrap();
+
+            let packet = std::iter::from_fn(|| server.next_msg_to_send())
+                .find_map(|msg| match msg.0 {
+                    MsgKind::PlaintextPacket(packet) => Some(packet),
+                    _ => None,
+                })
+                .unwrap();
+            let kexinit = KeyExchangeInitPacket::parse(&packet.payload).unwrap();
+            kexinit.kex_algorithms.0.to_owned()
+        };
+
+        assert_eq!(
+            negotiated_kex(AlgorithmOrder::Client),
+            "curve448-sha512,ext-info-s"
+        );
+        assert_eq!(
+            negotiated_kex(AlgorithmOrder::Server),
+            "curve25519-sha256,ext-info-s"
+        );
+    }
+
+    /// A hand-written client SSH_MSG_KEXINIT, offering all algorithms that the server supports.
+    fn client_kexinit(conversation: &Conversation, kex_algorithms: &str) -> Packet {
+        let algs = SupportedAlgorithms::secure(&[conversation.host_key.private_key.public_key()]);
+        let mut kexinit = Writer::new();
+        kexinit.u8(numbers::SSH_MSG_KEXINIT);
+        kexinit.array([0; 16]); // cookie
+        kexinit.name_list(NameList::multi(kex_algorithms));
+        kexinit.name_list(NameList::multi(&algs.hostkey_sign.to_name_list()));
+        kexinit.name_list(NameList::multi(&algs.encryption_from_peer.to_name_list()));
+        kexinit.name_list(NameList::multi(&algs.encryption_to_peer.to_name_list()));
+        kexinit.name_list(NameList::multi(&algs.mac_from_peer.to_name_list()));
+        kexinit.name_list(NameList::multi(&algs.mac_to_peer.to_name_list()));
+        kexinit.name_list(NameList::multi(&algs.compression_from_peer.to_name_list()));
+        kexinit.name_list(NameList::multi(&algs.compression_to_peer.to_name_list()));
+        kexinit.name_list(NameList::none()); // languages_client_to_server
+        kexinit.name_list(NameList::none()); // languages_server_to_client
+        kexinit.bool(false); // first_kex_packet_follows
+        kexinit.u32(0); // reserved
+        Packet {
+            payload: kexinit.finish(),
+        }
+    }
+
+    #[test]
+    fn handshake_padding() {
+        let types = kexinit_response_types("curve25519-sha256");
+        assert_eq!(
+            types,
+            [
+                numbers::SSH_MSG_IGNORE,
+                numbers::SSH_MSG_KEXINIT,
+                numbers::SSH_MSG_IGNORE
+            ]
+        );
+
+        let conversation =
+            drive_conversation(0, |_, config| config.handshake_padding = true).unwrap();
+        assert!(conversation.client.is_open().is_some());
+        assert!(conversation.server.is_open().is_some());
+    }
+
+    #[test]
+    fn rejected_client_identification() {
+        let mut conversation = Conversation::new(0, |_, config| {
+            config.accept_client_identification =
+                Some(Arc::new(|ident| ident != b"SSH-2.0-ClueleSSH"));
+        });
+        let Err(SshStatus::PeerError(err)) = conversation.pump() else {
+            panic!("server accepted rejected client identification");
+        };
+        assert_eq!(err, "rejected client identification: SSH-2.0-ClueleSSH");
+
+        // The client is told why, after our identification.
+        let ident = conversation.server.next_msg_to_send().unwrap().to_bytes();
+        assert_eq!(ident, b"SSH-2.0-ClueleSSH_0.1\r\n");
+        let disconnect = conversation.server.next_msg_to_send().unwrap().to_bytes();
+        assert_eq!(disconnect[5], numbers::SSH_MSG_DISCONNECT);
+        assert_eq!(
+            disconnect[6..10],
+            numbers::SSH_DISCONNECT_HOST_NOT_ALLOWED_TO_CONNECT.to_be_bytes()
+        );
+        assert!(conversation.server.next_msg_to_send().is_none());
+    }
+
+    #[test]
+    fn unsupported_service() {
+        let result = drive_conversation(0, |client, _| client.set_service("ssh-connection"));
+        let Err(SshStatus::PeerError(err)) = result else {
+            panic!("server accepted unsupported service");
+        };
+        assert_eq!(err, "unsupported service: ssh-connection");
+    }
+
+    #[test]
+    fn extra_service() {
+        let conversation = drive_conversation(0, |client, config| {
+            client.set_service("ssh-connection");
+            config.extra_services = vec!["ssh-connection".to_owned()];
+        })
+        .unwrap();
+        assert!(conversation.client.is_open().is_some());
+        assert!(conversation.server.is_open().is_some());
+        assert_eq!(conversation.server.service(), Some("ssh-connection"));
+    }
+
+    #[test]
+    fn second_service_request() {
+        let mut conversation = drive_conversation(0, |_, _| {}).unwrap();
+        conversation
+            .client
+            .send_plaintext_packet(Packet::new_msg_service_request(b"ssh-connection"));
+        let Err(SshStatus::PeerError(err)) = conversation.pump() else {
+            panic!("server accepted a second service request");
+        };
+        assert_eq!(
+            err,
+            "requested a service after a service has already been accepted"
+        );
+    }
+
+    /// Hand-drives a key exchange with a client that supports extensions.
+    /// Returns the server SSH_MSG_KEXINIT and the messages it sends after receiving SSH_MSG_NEWKEYS.
+    fn kex_with_ext_info_client(send_ext_info: bool) -> (Packet, Vec<MsgKind>) {
+        let mut conversation =
+            Conversation::new(0, |_, config| config.send_ext_info = send_ext_info);
+        let kexinit = client_kexinit(&conversation, "curve25519-sha256,ext-info-c");
+        let server = &mut conversation.server;
+        let send = |server: &mut ServerConnection, packet: Packet| {
+            server
+                .recv_bytes(&packet.to_bytes(true, Packet::DEFAULT_BLOCK_SIZE, 0))
+                .unwrap();
+        };
+
+        server.recv_bytes(b"SSH-2.0-OpenSSH_9.7\r\n").unwrap();
+        send(server, kexinit);
+        let server_kexinit = std::iter::from_fn(|| server.next_msg_to_send())
+            .find_map(|msg| match msg.0 {
+                MsgKind::PlaintextPacket(packet)
+                    if packet.packet_type() == numbers::SSH_MSG_KEXINIT =>
+                {
+                    Some(packet)
+                }
+                _ => None,
+            })
+            .unwrap();
+
+        // The curve25519 base point.
+        let mut client_public_key = [0; 32];
+        client_public_key[0] = 9;
+        send(server, Packet::new_msg_kex_ecdh_init(&client_public_key));
+        let params = server.is_waiting_on_key_exchange().unwrap();
+        let response =
+            do_key_exchange(params, &conversation.host_key, &mut conversation.kex_rng).unwrap();
+        server.do_key_exchange(response);
+        while server.next_msg_to_send().is_some() {}
+
+        send(
+            server,
+            Packet {
+                payload: vec![numbers::SSH_MSG_NEWKEYS],
             },
-        ];
-
-        let mut con = ServerConnection::new(HardcodedRng(rng), ServerConfig::default());
-        for part in conversation {
-            con.recv_bytes(&part.client).unwrap();
-            eprintln!("client: {:x?}", part.client);
-            let bytes = con.next_msg_to_send().unwrap().to_bytes();
-            if part.server != bytes {
-                panic!(
-                    "expected != found\nexpected: {:x?}\nfound:    {:x?}",
-                    part.server, bytes
+        );
+        let msgs = std::iter::from_fn(|| server.next_msg_to_send())
+            .map(|msg| msg.0)
+            .collect();
+        (server_kexinit, msgs)
+    }
+
+    #[test]
+    fn send_ext_info() {
+        let (kexinit, msgs) = kex_with_ext_info_client(true);
+        let kexinit = KeyExchangeInitPacket::parse(&kexinit.payload).unwrap();
+        assert!(kexinit.kex_algorithms.contains("ext-info-s"));
+        // SSH_MSG_NEWKEYS, then SSH_MSG_EXT_INFO with the new keys.
+        assert!(matches!(
+            msgs.as_slice(),
+            [MsgKind::PlaintextPacket(newkeys), MsgKind::EncryptedPacket(_)]
+                if newkeys.packet_type() == numbers::SSH_MSG_NEWKEYS
+        ));
+    }
+
+    #[test]
+    fn no_ext_info() {
+        let (kexinit, msgs) = kex_with_ext_info_client(false);
+        let kexinit = KeyExchangeInitPacket::parse(&kexinit.payload).unwrap();
+        assert!(!kexinit.kex_algorithms.contains("ext-info-s"));
+        assert!(matches!(
+            msgs.as_slice(),
+            [MsgKind::PlaintextPacket(newkeys)] if newkeys.packet_type() == numbers::SSH_MSG_NEWKEYS
+        ));
+    }
+
+    /// Sends an SSH_MSG_KEX_ECDH_INIT with `qc` after negotiating `kex_algorithm`.
+    fn kex_ecdh_init(kex_algorithm: &str, qc: &[u8]) -> Result<(), SshStatus> {
+        let mut conversation = Conversation::new(0, |_, config| config.curve448 = true);
+        let kexinit = client_kexinit(&conversation, kex_algorithm);
+        let server = &mut conversation.server;
+        server.recv_bytes(b"SSH-2.0-OpenSSH_9.7\r\n").unwrap();
+        server
+            .recv_bytes(&kexinit.to_bytes(true, Packet::DEFAULT_BLOCK_SIZE, 0))
+            .unwrap();
+        while server.next_msg_to_send().is_some() {}
+
+        server.recv_bytes(&Packet::new_msg_kex_ecdh_init(qc).to_bytes(
+            true,
+            Packet::DEFAULT_BLOCK_SIZE,
+            0,
+        ))
+    }
+
+    #[test]
+    fn invalid_ephemeral_public_key_length() {
+        for (kex_algorithm, len) in [
+            ("curve25519-sha256", 32),
+            ("ecdh-sha2-nistp256", 65),
+            ("curve448-sha512", 56),
+        ] {
+            for qc in [Vec::new(), vec![4; len + 1]] {
+                let err = kex_ecdh_init(kex_algorithm, &qc).unwrap_err();
+                let expected = format!(
+                    "invalid ephemeral public key length for {kex_algorithm}: expected {len}, was {}",
+                    qc.len()
+                );
+                assert!(
+                    matches!(&err, SshStatus::PeerError(msg) if *msg == expected),
+                    "{err:?}"
                 );
             }
         }
     }
+
+    #[test]
+    fn valid_ephemeral_public_key_length() {
+        // The curve25519 base point.
+        let mut qc = [0; 32];
+        qc[0] = 9;
+        kex_ecdh_init("curve25519-sha256", &qc).unwrap();
+    }
+
+    #[test]
+    fn truncated_service_request() {
+        let mut con = ServerConnection::new(NoRng, ServerConfig::default()).unwrap();
+        con.recv_bytes(b"SSH-2.0-OpenSSH_9.7\r\n").unwrap();
+        // Skip the key exchange, the packets stay unencrypted.
+        con.state = ServerState::ServiceRequest {
+            session_id: SessionId(vec![0; 32]),
+            may_send_extensions: false,
+        };
+
+        let packet = Packet {
+            payload: vec![numbers::SSH_MSG_SERVICE_REQUEST],
+        };
+        let err = con
+            .recv_bytes(&packet.to_bytes(true, Packet::DEFAULT_BLOCK_SIZE, 0))
+            .unwrap_err();
+        assert!(
+            matches!(&err, SshStatus::PeerError(msg) if msg.starts_with("invalid SSH_MSG_SERVICE_REQUEST")),
+            "{err:?}"
+        );
+    }
+
+    #[test]
+    fn max_packet_payload() {
+        let mut conversation = drive_conversation(0, |_, config| {
+            config.max_packet_payload = Some(MIN_MAX_PACKET_PAYLOAD);
+        })
+        .unwrap();
+
+        conversation.client.send_plaintext_packet(Packet {
+            payload: vec![100; MIN_MAX_PACKET_PAYLOAD],
+        });
+        conversation.pump().unwrap();
+        assert_eq!(
+            conversation.server.next_plaintext_packet(),
+            Some(Packet {
+                payload: vec![100; MIN_MAX_PACKET_PAYLOAD],
+            })
+        );
+
+        conversation.client.send_plaintext_packet(Packet {
+            payload: vec![100; MIN_MAX_PACKET_PAYLOAD + 1],
+        });
+        let err = conversation.pump().unwrap_err();
+        assert!(
+            matches!(&err, SshStatus::PeerError(msg) if msg == "packet payload too large: 32769 while the maximum is 32768"),
+            "{err:?}"
+        );
+        assert_eq!(conversation.server.next_plaintext_packet(), None);
+    }
+
+    #[test]
+    fn rekey_after_volume() {
+        let mut conversation = drive_conversation(0, |_, config| {
+            config.rekey_after_bytes = Some(1000);
+        })
+        .unwrap();
+        let session_id = conversation.server.is_open().unwrap().0;
+        assert_eq!(conversation.server.next_rekey_event(), None);
+
+        let data = |byte| Packet {
+            payload: vec![byte; 600],
+        };
+        conversation.client.send_plaintext_packet(data(100));
+        conversation.client.send_plaintext_packet(data(101));
+        conversation.pump().unwrap();
+
+        let event = conversation.server.next_rekey_event().unwrap();
+        assert_eq!(event.reason, RekeyReason::Volume);
+        assert_eq!(event.kex_algorithm, "curve25519-sha256");
+        assert_eq!(
+            event.encryption_client_to_server,
+            "chacha20-poly1305@openssh.com"
+        );
+        assert_eq!(conversation.server.next_rekey_event(), None);
+
+        // The session survives the re-exchange and keeps its ID.
+        assert_eq!(conversation.server.is_open().unwrap().0, session_id);
+        assert_eq!(conversation.client.is_open().unwrap().0, session_id);
+        assert_eq!(conversation.server.next_plaintext_packet(), Some(data(100)));
+        assert_eq!(conversation.server.next_plaintext_packet(), Some(data(101)));
+
+        conversation.server.send_plaintext_packet(data(102));
+        conversation.client.send_plaintext_packet(data(103));
+        conversation.pump().unwrap();
+        assert_eq!(conversation.client.next_plaintext_packet(), Some(data(102)));
+        assert_eq!(conversation.server.next_plaintext_packet(), Some(data(103)));
+    }
+
+    #[test]
+    fn stalled_rekey_after_volume() {
+        let mut conversation = drive_conversation(0, |_, config| {
+            config.rekey_after_bytes = Some(1000);
+        })
+        .unwrap();
+
+        let data = |byte| Packet {
+            payload: vec![byte; 600],
+        };
+        let send = |conversation: &mut Conversation, byte| {
+            conversation.client.send_plaintext_packet(data(byte));
+            let bytes = std::iter::from_fn(|| conversation.client.next_msg_to_send())
+                .flat_map(|msg| msg.to_bytes())
+                .collect::<Vec<_>>();
+            conversation.server.recv_bytes(&bytes)
+        };
+        send(&mut conversation, 100).unwrap();
+        send(&mut conversation, 101).unwrap();
+        // The server has started the re-exchange, but the client never sees its SSH_MSG_KEXINIT
+        // and keeps sending data with the old keys.
+        while conversation.server.next_msg_to_send().is_some() {}
+        // Together with the server's own SSH_MSG_KEXINIT, this is more than twice the limit.
+        let err = send(&mut conversation, 102).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "peer error: client did not complete the key re-exchange after 2163 bytes in 6 packets"
+        );
+    }
+
+    #[test]
+    fn rekey_downgrade() {
+        let mut conversation = drive_conversation(0, |_, _| {}).unwrap();
+        let algs = SupportedAlgorithms::secure(&[conversation.host_key.private_key.public_key()]);
+        let host_key_algorithms = algs.hostkey_sign.to_name_list();
+
+        // The initial key exchange picked chacha20-poly1305, which we prefer over AES-GCM.
+        // A client that only offers the latter now is refused.
+        let kexinit = KeyExchangeInitPacket {
+            cookie: [0; 16],
+            kex_algorithms: NameList::one("curve25519-sha256"),
+            server_host_key_algorithms: NameList::multi(&host_key_algorithms),
+            encryption_algorithms_client_to_server: NameList::one(
+                crypto::encrypt::AES256_GCM.name(),
+            ),
+            encryption_algorithms_server_to_client: NameList::one(
+                crypto::encrypt::CHACHA20POLY1305.name(),
+            ),
+            mac_algorithms_client_to_server: NameList::one("hmac-sha2-256"),
+            mac_algorithms_server_to_client: NameList::one("hmac-sha2-256"),
+            compression_algorithms_client_to_server: NameList::one("none"),
+            compression_algorithms_server_to_client: NameList::one("none"),
+            languages_client_to_server: NameList::none(),
+            languages_server_to_client: NameList::none(),
+            first_kex_packet_follows: false,
+        };
+        conversation.client.send_plaintext_packet(Packet {
+            payload: kexinit.to_bytes(),
+        });
+
+        let err = conversation.pump().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "peer error: key re-exchange downgraded the encryption client to server \
+             from chacha20-poly1305@openssh.com to aes256-gcm@openssh.com"
+        );
+        assert_eq!(conversation.server.next_rekey_event(), None);
+    }
+
+    #[test]
+    fn rekey_before_sequence_numbers_wrap() {
+        let mut conversation = drive_conversation(0, |_, _| {}).unwrap();
+        let sent = conversation.server.packet_transport.packets_since_rekey();
+        conversation
+            .server
+            .packet_transport
+            .add_packets_since_rekey(REKEY_AFTER_PACKETS - sent - 1);
+
+        conversation.client.send_plaintext_packet(Packet {
+            payload: vec![100; 10],
+        });
+        conversation.pump().unwrap();
+
+        let event = conversation.server.next_rekey_event().unwrap();
+        assert_eq!(event.reason, RekeyReason::Packets);
+        assert_eq!(
+            conversation.server.packet_transport.packets_since_rekey(),
+            0
+        );
+        assert_eq!(
+            conversation.server.next_plaintext_packet(),
+            Some(Packet {
+                payload: vec![100; 10],
+            })
+        );
+    }
+
+    #[test]
+    fn manual_rekey() {
+        let mut conversation = drive_conversation(0, |_, _| {}).unwrap();
+        conversation.server.rekey();
+        // Packets sent during the re-exchange are held back until the new keys are in use.
+        conversation.server.send_plaintext_packet(Packet {
+            payload: vec![100, 1, 2, 3],
+        });
+        conversation.pump().unwrap();
+
+        let event = conversation.server.next_rekey_event().unwrap();
+        assert_eq!(event.reason, RekeyReason::Manual);
+        assert_eq!(
+            conversation.client.next_plaintext_packet(),
+            Some(Packet {
+                payload: vec![100, 1, 2, 3],
+            })
+        );
+    }
+
+    #[test]
+    fn server_initiated_rekey_with_server_order() {
+        let mut conversation = drive_conversation(0, |client, config| {
+            // The client prefers AES-GCM, but we pick chacha20-poly1305 in our order.
+            let algs = &mut client.supported_algorithms;
+            let encryption = vec![
+                crypto::encrypt::AES256_GCM,
+                crypto::encrypt::CHACHA20POLY1305,
+            ];
+            algs.encryption_to_peer.supported = encryption.clone();
+            algs.encryption_from_peer.supported = encryption;
+            config.algorithm_order = AlgorithmOrder::Server;
+        })
+        .unwrap();
+
+        // Our re-exchange is negotiated in the client's order, which is no downgrade.
+        conversation.server.rekey();
+        conversation.pump().unwrap();
+
+        let event = conversation.server.next_rekey_event().unwrap();
+        assert_eq!(event.reason, RekeyReason::Manual);
+        assert_eq!(
+            event.encryption_client_to_server,
+            crypto::encrypt::AES256_GCM.name()
+        );
+    }
+
+    #[test]
+    fn host_key_rejected() {
+        let mut conversation = Conversation::new(0, |_, _| {});
+        conversation.reject_host_key = true;
+        let err = conversation.pump().unwrap_err();
+        assert!(matches!(err, SshStatus::Disconnect), "{err:?}");
+
+        let client = &mut conversation.client;
+        assert!(client.is_waiting_on_host_key_verification().is_none());
+        assert!(client.is_open().is_none());
+        let err = client.recv_bytes(&[0; 16]).unwrap_err();
+        assert!(matches!(err, SshStatus::Disconnect), "{err:?}");
+    }
+
+    #[test]
+    fn ignored_packets_are_counted() {
+        let mut conversation = drive_conversation(0, |_, _| {}).unwrap();
+        assert_eq!(conversation.server.ignored_packets(), 0);
+
+        conversation
+            .client
+            .send_plaintext_packet(Packet::new_msg_ignore(b"padding"));
+        conversation
+            .client
+            .send_plaintext_packet(Packet::new_msg_debug(false, b"hello", b""));
+        conversation.pump().unwrap();
+        assert_eq!(conversation.server.ignored_packets(), 2);
+        assert_eq!(conversation.server.next_plaintext_packet(), None);
+    }
+
+    #[test]
+    fn aes128_ctr() {
+        let mut conversation = drive_conversation(0, |client, _| {
+            let algs = &mut client.supported_algorithms;
+            algs.encryption_to_peer.supported = vec![crypto::encrypt::ENC_AES128_CTR];
+            algs.encryption_from_peer.supported = vec![crypto::encrypt::ENC_AES128_CTR];
+        })
+        .unwrap();
+
+        let data = |byte| Packet {
+            payload: vec![byte; 100],
+        };
+        conversation.client.send_plaintext_packet(data(100));
+        conversation.server.send_plaintext_packet(data(101));
+        conversation.pump().unwrap();
+        assert_eq!(conversation.server.next_plaintext_packet(), Some(data(100)));
+        assert_eq!(conversation.client.next_plaintext_packet(), Some(data(101)));
+    }
+
+    #[test]
+    fn curve448_is_opt_in() {
+        let only_curve448 = |client: &mut ClientConnection| {
+            client.supported_algorithms.key_exchange.supported = vec![crypto::KEX_CURVE_448_SHA512];
+        };
+        assert!(drive_conversation(0, |client, _| only_curve448(client)).is_err());
+
+        let mut conversation = drive_conversation(0, |client, config| {
+            only_curve448(client);
+            config.curve448 = true;
+        })
+        .unwrap();
+        let data = || Packet {
+            payload: vec![100; 100],
+        };
+        conversation.client.send_plaintext_packet(data());
+        conversation.pump().unwrap();
+        assert_eq!(conversation.server.next_plaintext_packet(), Some(data()));
+    }
+
+    #[test]
+    fn aes128_ctr_requires_etm() {
+        let result = drive_conversation(0, |client, _| {
+            let algs = &mut client.supported_algorithms;
+            algs.encryption_to_peer.supported = vec![crypto::encrypt::ENC_AES128_CTR];
+            algs.encryption_from_peer.supported = vec![crypto::encrypt::ENC_AES128_CTR];
+            algs.mac_to_peer.supported = vec!["hmac-sha2-256"];
+            algs.mac_from_peer.supported = vec!["hmac-sha2-256"];
+        });
+        let Err(SshStatus::PeerError(err)) = result else {
+            panic!("aes128-ctr was negotiated without hmac-sha2-256-etm@openssh.com");
+        };
+        assert!(err.contains("only supported with"), "{err}");
+    }
+
+    #[test]
+    fn rekey_with_coalesced_packets() {
+        let mut conversation = drive_conversation(0, |_, _| {}).unwrap();
+        let session_id = conversation.server.is_open().unwrap().0;
+        conversation.coalesce = true;
+
+        let data = |byte| Packet {
+            payload: vec![byte; 100],
+        };
+        conversation.server.rekey();
+        // Held back during the re-exchange and sent right after SSH_MSG_NEWKEYS, in the same buffer.
+        conversation.server.send_plaintext_packet(data(100));
+        conversation.server.send_plaintext_packet(data(101));
+        conversation.pump().unwrap();
+
+        let event = conversation.server.next_rekey_event().unwrap();
+        assert_eq!(event.reason, RekeyReason::Manual);
+        assert_eq!(conversation.client.next_plaintext_packet(), Some(data(100)));
+        assert_eq!(conversation.client.next_plaintext_packet(), Some(data(101)));
+
+        // Both sides are using the new keys now.
+        conversation.client.send_plaintext_packet(data(102));
+        conversation.client.send_plaintext_packet(data(103));
+        conversation.server.send_plaintext_packet(data(104));
+        conversation.pump().unwrap();
+        assert_eq!(conversation.server.next_plaintext_packet(), Some(data(102)));
+        assert_eq!(conversation.server.next_plaintext_packet(), Some(data(103)));
+        assert_eq!(conversation.client.next_plaintext_packet(), Some(data(104)));
+        assert_eq!(conversation.server.is_open().unwrap().0, session_id);
+    }
+
+    #[test]
+    fn rekey_with_coalesced_packets_from_client() {
+        // Our client holds back packets until it has received the server's SSH_MSG_NEWKEYS, but other clients
+        // send them right after their own. Conversations are deterministic, so take the packets that the client
+        // sends after a re-exchange in one conversation and send them together with SSH_MSG_NEWKEYS in another.
+        fn from_server(conversation: &mut Conversation) -> Vec<u8> {
+            std::iter::from_fn(|| conversation.server.next_msg_to_send())
+                .flat_map(|msg| msg.to_bytes())
+                .collect()
+        }
+        fn from_client(conversation: &mut Conversation) -> Vec<u8> {
+            std::iter::from_fn(|| conversation.client.next_msg_to_send())
+                .flat_map(|msg| msg.to_bytes())
+                .collect()
+        }
+
+        let data = |byte| Packet {
+            payload: vec![byte; 100],
+        };
+        let mut recorded = drive_conversation(0, |_, _| {}).unwrap();
+        recorded.server.rekey();
+        recorded.pump().unwrap();
+        recorded.client.send_plaintext_packet(data(100));
+        recorded.client.send_plaintext_packet(data(101));
+        let after_newkeys = from_client(&mut recorded);
+
+        let mut conversation = drive_conversation(0, |_, _| {}).unwrap();
+        let session_id = conversation.server.is_open().unwrap().0;
+        conversation.server.rekey();
+        // SSH_MSG_KEXINIT
+        let bytes = from_server(&mut conversation);
+        conversation.client.recv_bytes(&bytes).unwrap();
+        // SSH_MSG_KEXINIT and SSH_MSG_KEX_ECDH_INIT
+        let bytes = from_client(&mut conversation);
+        conversation.server.recv_bytes(&bytes).unwrap();
+        let params = conversation.server.is_waiting_on_key_exchange().unwrap();
+        let response =
+            do_key_exchange(params, &conversation.host_key, &mut conversation.kex_rng).unwrap();
+        conversation.server.do_key_exchange(response);
+        // SSH_MSG_KEX_ECDH_REPLY
+        let bytes = from_server(&mut conversation);
+        conversation.client.recv_bytes(&bytes).unwrap();
+        // SSH_MSG_NEWKEYS, followed by the packets encrypted with the new keys.
+        let mut bytes = from_client(&mut conversation);
+        bytes.extend_from_slice(&after_newkeys);
+        conversation.server.recv_bytes(&bytes).unwrap();
+
+        let event = conversation.server.next_rekey_event().unwrap();
+        assert_eq!(event.reason, RekeyReason::Manual);
+        assert_eq!(conversation.server.next_plaintext_packet(), Some(data(100)));
+        assert_eq!(conversation.server.next_plaintext_packet(), Some(data(101)));
+
+        conversation.pump().unwrap();
+        conversation.server.send_plaintext_packet(data(102));
+        conversation.pump().unwrap();
+        assert_eq!(conversation.client.next_plaintext_packet(), Some(data(102)));
+        assert_eq!(conversation.server.is_open().unwrap().0, session_id);
+    }
+
+    #[test]
+    fn negotiated_algorithms_are_logged() {
+        let logs = capture_logs(|| {
+            drive_conversation(0, |_, _| {}).unwrap();
+        });
+        let line = logs
+            .lines()
+            .find(|line| line.contains("Using new keys"))
+            .unwrap_or_else(|| panic!("missing log line: {logs}"));
+        assert!(line.contains(" INFO "), "{line}");
+        for field in [
+            "kex=curve25519-sha256",
+            "host_key=ssh-ed25519",
+            "encryption_client_to_server=chacha20-poly1305@openssh.com",
+            "encryption_server_to_client=chacha20-poly1305@openssh.com",
+            "mac_client_to_server=hmac-sha2-256",
+            "mac_server_to_client=hmac-sha2-256",
+            "compression_client_to_server=none",
+            "compression_server_to_client=none",
+        ] {
+            assert!(line.contains(field), "{field} is missing: {line}");
+        }
+    }
+
+    #[test]
+    fn rekey_is_logged_once() {
+        let mut conversation = drive_conversation(0, |_, _| {}).unwrap();
+        let logs = capture_logs(|| {
+            conversation.server.rekey();
+            conversation.pump().unwrap();
+        });
+        let lines = logs
+            .lines()
+            .filter(|line| line.contains(" INFO "))
+            .collect::<Vec<_>>();
+        assert_eq!(lines.len(), 1, "{logs}");
+        assert!(lines[0].contains("Using new keys"), "{logs}");
+        assert!(lines[0].contains("rekey_reason=Some(Manual)"), "{logs}");
+    }
 }